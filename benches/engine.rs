@@ -0,0 +1,58 @@
+//! Compares the instructions per second of the interpreter and the block
+//! engine on a loop updating a table in memory. Run with
+//! `cargo bench --no-default-features --bench engine`, adding
+//! `--features jit` to include the JIT.
+
+use riscv_emu::cpu::{Cpu, CpuEvent, Engine};
+use riscv_emu::memory::addressspace::{Address, AddressSpace, MemoryDevice};
+use std::time::Instant;
+
+const PROGRAM: [u32; 14] = [
+    0x00400337, // lui x6, 0x400
+    0x00000293, // li x5, 0
+    0x00000513, // li x10, 0
+    0x000013b7, // lui x7, 1
+    0x3ff2f413, // andi x8, x5, 1023
+    0x00241413, // slli x8, x8, 2
+    0x00740433, // add x8, x8, x7
+    0x00042483, // lw x9, 0(x8)
+    0x005484b3, // add x9, x9, x5
+    0x00942023, // sw x9, 0(x8)
+    0x00954533, // xor x10, x10, x9
+    0x00128293, // addi x5, x5, 1
+    0xfe6290e3, // bne x5, x6, -32
+    0x00100073, // ebreak
+];
+
+/// Returns the speed in MIPS, like the one printed by the emulator
+fn measure(engine: Engine) -> f64 {
+    let mut memory = AddressSpace::new();
+    for (index, word) in PROGRAM.iter().enumerate() {
+        memory.write_word(index as Address * 4, *word);
+    }
+
+    let mut cpu = Cpu::new();
+    cpu.set_engine(engine);
+
+    let before = Instant::now();
+    assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+    let elapsed = before.elapsed().as_micros();
+
+    cpu.get_instruction_counter() as f64 / elapsed as f64
+}
+
+fn main() {
+    let interpreter = measure(Engine::Interpreter);
+    let blocks = measure(Engine::Blocks);
+
+    println!("interpreter: {:8.1} MIPS", interpreter);
+    println!("blocks:      {:8.1} MIPS", blocks);
+    println!("speedup:     {:8.2}x", blocks / interpreter);
+
+    #[cfg(feature = "jit")]
+    {
+        let jit = measure(Engine::Jit);
+        println!("jit:         {:8.1} MIPS", jit);
+        println!("speedup:     {:8.2}x", jit / interpreter);
+    }
+}
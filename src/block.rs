@@ -0,0 +1,421 @@
+use crate::instruction::{Instruction, WrappedInstruction};
+use crate::memory::addressspace::Address;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The longest basic block, longer straight-line code is split into several
+/// blocks chained to each other
+pub const MAX_BLOCK_LENGTH: usize = 64;
+
+const PAGE_SHIFT: u32 = 12;
+
+/// Invalidated blocks stay allocated until the cache is flushed, which
+/// happens once there are this many of them
+const MAX_INVALID_BLOCKS: usize = 1024;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Condition {
+    Equal,
+    NotEqual,
+    Less,
+    GreaterEqual,
+    LessUnsigned,
+    GreaterEqualUnsigned,
+}
+
+impl Condition {
+    #[inline(always)]
+    pub fn holds(self, v1: u32, v2: u32) -> bool {
+        match self {
+            Condition::Equal => v1 == v2,
+            Condition::NotEqual => v1 != v2,
+            Condition::Less => (v1 as i32) < (v2 as i32),
+            Condition::GreaterEqual => (v1 as i32) >= (v2 as i32),
+            Condition::LessUnsigned => v1 < v2,
+            Condition::GreaterEqualUnsigned => v1 >= v2,
+        }
+    }
+}
+
+/// An instruction in the form executed by the block engine. Registers are
+/// bytes, immediates are sign-extended, and values and targets relative to
+/// the pc are resolved when the block is built.
+#[derive(PartialEq, Debug, Clone)]
+pub enum MicroOp {
+    // LUI and AUIPC
+    LoadImmediate(u8, u32),
+    AddImmediate(u8, u8, u32),
+    SetLessThanImmediate(u8, u8, i32),
+    SetLessThanImmediateUnsigned(u8, u8, u32),
+    XorImmediate(u8, u8, u32),
+    OrImmediate(u8, u8, u32),
+    AndImmediate(u8, u8, u32),
+    ShiftLeftImmediate(u8, u8, u32),
+    ShiftRightImmediate(u8, u8, u32),
+    ShiftRightArithmeticImmediate(u8, u8, u32),
+    Add(u8, u8, u8),
+    Sub(u8, u8, u8),
+    ShiftLeft(u8, u8, u8),
+    SetLessThan(u8, u8, u8),
+    SetLessThanUnsigned(u8, u8, u8),
+    Xor(u8, u8, u8),
+    ShiftRight(u8, u8, u8),
+    ShiftRightArithmetic(u8, u8, u8),
+    Or(u8, u8, u8),
+    And(u8, u8, u8),
+    LoadByte(u8, u8, i32),
+    LoadHalfword(u8, u8, i32),
+    LoadWord(u8, u8, i32),
+    LoadByteUnsigned(u8, u8, i32),
+    LoadHalfwordUnsigned(u8, u8, i32),
+    StoreByte(u8, u8, i32),
+    StoreHalfword(u8, u8, i32),
+    StoreWord(u8, u8, i32),
+    Branch(Condition, u8, u8, Address),
+    Jump(u8, Address),
+    JumpRegister(u8, u8, i32),
+    /// Any other instruction, executed by `Cpu::execute_instruction`
+    Interpret(Box<WrappedInstruction>),
+}
+
+impl MicroOp {
+    /// Translates the instruction located at `pc`
+    pub fn new(wrapped_instruction: &WrappedInstruction, pc: Address) -> Self {
+        let target = |imm: u32| (pc as i32).wrapping_add((imm as i32) * 2) as u32;
+
+        match wrapped_instruction.instruction {
+            Instruction::LUI(rd, imm) => MicroOp::LoadImmediate(rd as u8, imm << 12),
+            Instruction::AUIPC(rd, imm) => {
+                MicroOp::LoadImmediate(rd as u8, pc.wrapping_add(imm << 12))
+            }
+            Instruction::JAL(rd, imm) => MicroOp::Jump(rd as u8, target(imm)),
+            Instruction::JALR(rd, rs1, imm) => MicroOp::JumpRegister(rd as u8, rs1 as u8, imm),
+            Instruction::BEQ(rs1, rs2, imm) => {
+                MicroOp::Branch(Condition::Equal, rs1 as u8, rs2 as u8, target(imm))
+            }
+            Instruction::BNE(rs1, rs2, imm) => {
+                MicroOp::Branch(Condition::NotEqual, rs1 as u8, rs2 as u8, target(imm))
+            }
+            Instruction::BLT(rs1, rs2, imm) => {
+                MicroOp::Branch(Condition::Less, rs1 as u8, rs2 as u8, target(imm))
+            }
+            Instruction::BGE(rs1, rs2, imm) => {
+                MicroOp::Branch(Condition::GreaterEqual, rs1 as u8, rs2 as u8, target(imm))
+            }
+            Instruction::BLTU(rs1, rs2, imm) => {
+                MicroOp::Branch(Condition::LessUnsigned, rs1 as u8, rs2 as u8, target(imm))
+            }
+            Instruction::BGEU(rs1, rs2, imm) => MicroOp::Branch(
+                Condition::GreaterEqualUnsigned,
+                rs1 as u8,
+                rs2 as u8,
+                target(imm),
+            ),
+            Instruction::LB(rd, rs1, imm) => MicroOp::LoadByte(rd as u8, rs1 as u8, imm),
+            Instruction::LH(rd, rs1, imm) => MicroOp::LoadHalfword(rd as u8, rs1 as u8, imm),
+            Instruction::LW(rd, rs1, imm) => MicroOp::LoadWord(rd as u8, rs1 as u8, imm),
+            Instruction::LBU(rd, rs1, imm) => MicroOp::LoadByteUnsigned(rd as u8, rs1 as u8, imm),
+            Instruction::LHU(rd, rs1, imm) => {
+                MicroOp::LoadHalfwordUnsigned(rd as u8, rs1 as u8, imm)
+            }
+            Instruction::SB(rs1, rs2, imm) => MicroOp::StoreByte(rs1 as u8, rs2 as u8, imm),
+            Instruction::SH(rs1, rs2, imm) => MicroOp::StoreHalfword(rs1 as u8, rs2 as u8, imm),
+            Instruction::SW(rs1, rs2, imm) => MicroOp::StoreWord(rs1 as u8, rs2 as u8, imm),
+            Instruction::ADDI(rd, rs1, imm) => MicroOp::AddImmediate(rd as u8, rs1 as u8, imm),
+            Instruction::SLTI(rd, rs1, imm) => {
+                MicroOp::SetLessThanImmediate(rd as u8, rs1 as u8, imm as i32)
+            }
+            Instruction::SLTIU(rd, rs1, imm) => {
+                MicroOp::SetLessThanImmediateUnsigned(rd as u8, rs1 as u8, imm)
+            }
+            Instruction::XORI(rd, rs1, imm) => MicroOp::XorImmediate(rd as u8, rs1 as u8, imm),
+            Instruction::ORI(rd, rs1, imm) => MicroOp::OrImmediate(rd as u8, rs1 as u8, imm),
+            Instruction::ANDI(rd, rs1, imm) => MicroOp::AndImmediate(rd as u8, rs1 as u8, imm),
+            Instruction::SLLI(rd, rs1, imm) => {
+                MicroOp::ShiftLeftImmediate(rd as u8, rs1 as u8, imm)
+            }
+            Instruction::SRLI(rd, rs1, imm) => {
+                MicroOp::ShiftRightImmediate(rd as u8, rs1 as u8, imm)
+            }
+            Instruction::SRAI(rd, rs1, imm) => {
+                MicroOp::ShiftRightArithmeticImmediate(rd as u8, rs1 as u8, imm)
+            }
+            Instruction::ADD(rd, rs1, rs2) => MicroOp::Add(rd as u8, rs1 as u8, rs2 as u8),
+            Instruction::SUB(rd, rs1, rs2) => MicroOp::Sub(rd as u8, rs1 as u8, rs2 as u8),
+            Instruction::SLL(rd, rs1, rs2) => MicroOp::ShiftLeft(rd as u8, rs1 as u8, rs2 as u8),
+            Instruction::SLT(rd, rs1, rs2) => MicroOp::SetLessThan(rd as u8, rs1 as u8, rs2 as u8),
+            Instruction::SLTU(rd, rs1, rs2) => {
+                MicroOp::SetLessThanUnsigned(rd as u8, rs1 as u8, rs2 as u8)
+            }
+            Instruction::XOR(rd, rs1, rs2) => MicroOp::Xor(rd as u8, rs1 as u8, rs2 as u8),
+            Instruction::SRL(rd, rs1, rs2) => MicroOp::ShiftRight(rd as u8, rs1 as u8, rs2 as u8),
+            Instruction::SRA(rd, rs1, rs2) => {
+                MicroOp::ShiftRightArithmetic(rd as u8, rs1 as u8, rs2 as u8)
+            }
+            Instruction::OR(rd, rs1, rs2) => MicroOp::Or(rd as u8, rs1 as u8, rs2 as u8),
+            Instruction::AND(rd, rs1, rs2) => MicroOp::And(rd as u8, rs1 as u8, rs2 as u8),
+            _ => MicroOp::Interpret(Box::new(wrapped_instruction.clone())),
+        }
+    }
+
+    /// Whether the micro-op leaves the block: jumps, branches and the
+    /// instructions which trap, return from traps, change the translation
+    /// of addresses or flush the caches
+    pub fn ends_block(&self) -> bool {
+        match self {
+            MicroOp::Branch(..) | MicroOp::Jump(..) | MicroOp::JumpRegister(..) => true,
+            MicroOp::Interpret(wrapped_instruction) => matches!(
+                wrapped_instruction.instruction,
+                Instruction::ECALL
+                    | Instruction::EBREAK
+                    | Instruction::MRET
+                    | Instruction::SRET
+                    | Instruction::WFI
+                    | Instruction::FENCEI
+                    | Instruction::SFENCEVMA(..)
+                    | Instruction::CSRRW(..)
+                    | Instruction::CSRRS(..)
+                    | Instruction::CSRRC(..)
+                    | Instruction::CSRRWI(..)
+                    | Instruction::CSRRSI(..)
+                    | Instruction::CSRRCI(..)
+                    | Instruction::INVALID
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// How a block was left. Blocks are chained along the taken and
+/// fall-through exits, indirect jumps and traps always look up the next
+/// block.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Exit {
+    Taken,
+    FallThrough,
+    Indirect,
+}
+
+/// Straight-line code starting at `start`, which ends with the first
+/// micro-op for which `ends_block` holds, at the end of the page or after
+/// `MAX_BLOCK_LENGTH` instructions.
+#[derive(PartialEq, Debug)]
+pub struct Block {
+    /// The virtual address of the first instruction
+    pub start: Address,
+    /// Identifies the translation of virtual addresses the block was built
+    /// with
+    pub context: u64,
+    /// The micro-ops together with the address of their instruction
+    pub ops: Vec<(Address, MicroOp)>,
+    /// The cycles of the instructions before each micro-op, with a last
+    /// entry for the whole block. Taking and mispredicting branches costs
+    /// additional cycles when the block is left.
+    pub cycles: Vec<u64>,
+    /// The address following the last instruction
+    pub end: Address,
+}
+
+struct Entry {
+    block: Rc<Block>,
+    /// The blocks previously reached through the taken and fall-through
+    /// exits
+    links: [Option<usize>; 2],
+    valid: bool,
+}
+
+/// The basic blocks indexed by the physical address of their first
+/// instruction. Blocks are invalidated by writes to their pages.
+///
+/// Links between blocks are only hints, following one checks that the block
+/// starts at the pc in the current translation context, so a stale link
+/// costs a lookup at worst.
+#[derive(Default)]
+pub struct BlockCache {
+    entries: Vec<Entry>,
+    index: HashMap<Address, usize>,
+    /// The valid blocks covering each physical page
+    pages: HashMap<Address, Vec<usize>>,
+    invalid_entries: usize,
+    /// Incremented by `flush`, which reuses the indexes of the blocks
+    generation: u64,
+}
+
+impl BlockCache {
+    pub fn get(&self, physical_address: Address, start: Address, context: u64) -> Option<usize> {
+        self.index
+            .get(&physical_address)
+            .copied()
+            .filter(|&index| self.matches(index, start, context))
+    }
+
+    /// Adds a block whose first instruction is located at
+    /// `physical_address` and its last one ends at `physical_end`, replacing
+    /// any block at the same address.
+    pub fn insert(
+        &mut self,
+        physical_address: Address,
+        physical_end: Address,
+        block: Block,
+    ) -> usize {
+        if let Some(previous) = self.index.remove(&physical_address) {
+            self.entries[previous].valid = false;
+            self.invalid_entries += 1;
+        }
+
+        let index = self.entries.len();
+        self.entries.push(Entry {
+            block: Rc::new(block),
+            links: [None; 2],
+            valid: true,
+        });
+        self.index.insert(physical_address, index);
+
+        let first_page = physical_address >> PAGE_SHIFT;
+        let last_page = physical_end.wrapping_sub(1) >> PAGE_SHIFT;
+        self.pages.entry(first_page).or_default().push(index);
+        if last_page != first_page {
+            self.pages.entry(last_page).or_default().push(index);
+        }
+        index
+    }
+
+    pub fn block(&self, index: usize) -> Rc<Block> {
+        Rc::clone(&self.entries[index].block)
+    }
+
+    /// The block previously reached from `index` through `exit`, if it is
+    /// still valid and starts at `start` in `context`
+    #[inline(always)]
+    pub fn follow(&self, index: usize, exit: Exit, start: Address, context: u64) -> Option<usize> {
+        let slot = Self::link_slot(exit)?;
+        self.entries
+            .get(index)
+            .and_then(|entry| entry.links[slot])
+            .filter(|&next| self.matches(next, start, context))
+    }
+
+    pub fn link(&mut self, index: usize, exit: Exit, next: usize) {
+        if let (Some(slot), Some(entry)) = (Self::link_slot(exit), self.entries.get_mut(index)) {
+            entry.links[slot] = Some(next);
+        }
+    }
+
+    /// Drops all links, e.g. when the page tables or the PMP change
+    pub fn unlink(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.links = [None; 2];
+        }
+    }
+
+    /// Drops the blocks on the pages touched by a write of `size` bytes,
+    /// including a 32 bit instruction starting two bytes before it
+    pub fn invalidate(&mut self, address: Address, size: u32) {
+        let first_page = address.saturating_sub(2) >> PAGE_SHIFT;
+        let last_page = address.saturating_add(size - 1) >> PAGE_SHIFT;
+
+        for page in first_page..=last_page {
+            for index in self.pages.remove(&page).unwrap_or_default() {
+                let entry = &mut self.entries[index];
+                if entry.valid {
+                    entry.valid = false;
+                    self.invalid_entries += 1;
+                }
+            }
+        }
+        let entries = &self.entries;
+        self.index.retain(|_, index| entries[*index].valid);
+
+        if self.invalid_entries >= MAX_INVALID_BLOCKS {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        *self = BlockCache {
+            generation: self.generation + 1,
+            ..BlockCache::default()
+        };
+    }
+
+    /// Identifies the blocks with the current indexes, which are kept until
+    /// the next flush
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn matches(&self, index: usize, start: Address, context: u64) -> bool {
+        let entry = &self.entries[index];
+        entry.valid && entry.block.start == start && entry.block.context == context
+    }
+
+    fn link_slot(exit: Exit) -> Option<usize> {
+        match exit {
+            Exit::Taken => Some(0),
+            Exit::FallThrough => Some(1),
+            Exit::Indirect => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(start: Address) -> Block {
+        let addi = WrappedInstruction::new(0x00108093); // addi x1, x1, 1
+        Block {
+            start,
+            context: 0,
+            ops: vec![(start, MicroOp::new(&addi, start))],
+            cycles: vec![0, 1],
+            end: start + 4,
+        }
+    }
+
+    #[test]
+    fn test_micro_ops() {
+        // auipc x1, 1 and beq x1, x2, -4
+        assert_eq!(
+            MicroOp::new(&WrappedInstruction::new(0x00001097), 0x100),
+            MicroOp::LoadImmediate(1, 0x1100)
+        );
+        let branch = MicroOp::new(&WrappedInstruction::new(0xfe208ee3), 0x100);
+        assert_eq!(branch, MicroOp::Branch(Condition::Equal, 1, 2, 0xFC));
+        assert!(branch.ends_block());
+
+        // mul x1, x2, x3 and ecall are interpreted
+        let mul = MicroOp::new(&WrappedInstruction::new(0x023100b3), 0x100);
+        assert!(matches!(mul, MicroOp::Interpret(..)));
+        assert!(!mul.ends_block());
+        assert!(MicroOp::new(&WrappedInstruction::new(0x00000073), 0x100).ends_block());
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let mut cache = BlockCache::default();
+        let first = cache.insert(0x1000, 0x1004, block(0x1000));
+        let second = cache.insert(0x2FFE, 0x3002, block(0x2FFE));
+
+        assert_eq!(cache.get(0x1000, 0x1000, 0), Some(first));
+        assert_eq!(cache.get(0x1000, 0x5000, 0), None);
+        assert_eq!(cache.get(0x1000, 0x1000, 1), None);
+
+        cache.link(first, Exit::Taken, second);
+        cache.link(first, Exit::Indirect, second);
+        assert_eq!(cache.follow(first, Exit::Taken, 0x2FFE, 0), Some(second));
+        assert_eq!(cache.follow(first, Exit::Taken, 0x3000, 0), None);
+        assert_eq!(cache.follow(first, Exit::FallThrough, 0x2FFE, 0), None);
+        assert_eq!(cache.follow(first, Exit::Indirect, 0x2FFE, 0), None);
+
+        // The second block extends into the page at 0x3000
+        cache.invalidate(0x3000, 1);
+        assert_eq!(cache.get(0x2FFE, 0x2FFE, 0), None);
+        assert_eq!(cache.follow(first, Exit::Taken, 0x2FFE, 0), None);
+        assert_eq!(cache.get(0x1000, 0x1000, 0), Some(first));
+
+        cache.flush();
+        assert_eq!(cache.get(0x1000, 0x1000, 0), None);
+        assert_eq!(cache.generation(), 1);
+    }
+}
@@ -1,37 +1,178 @@
-use crate::instruction::Instruction;
-use crate::instruction::WrappedInstruction;
+use crate::block::{Block, BlockCache, Exit, MicroOp, MAX_BLOCK_LENGTH};
+#[cfg(feature = "jit")]
+use crate::csr::SATP_MODE_SV32;
+use crate::csr::{
+    CsrFile, PrivilegeLevel, MSTATUS_TSR, MSTATUS_TVM, MSTATUS_TW, PMPADDR15, PMPCFG0, SATP,
+};
+use crate::custom::{CustomExtensions, CustomInstructions, CustomOpcode};
+use crate::environment_call::{EnvironmentCallHandler, EnvironmentCallResult};
+use crate::error::{EmulatorError, EmulatorResult};
+use crate::exception::Exception;
+use crate::instruction::{Instruction, WrappedInstruction};
+use crate::instruction_cache::InstructionCache;
+use crate::isa::{Extension, Extensions, Isa, Xlen};
+#[cfg(feature = "jit")]
+use crate::jit::{self, Jit, Outcome, HOT_BLOCK_THRESHOLD};
 use crate::memory::addressspace::{Address, AddressSpace, MemoryDevice};
+use crate::mmu::{AccessType, Mmu};
+use crate::reservation::Reservation;
+use crate::softfloat::{RoundingMode, DOUBLE, SINGLE};
+use crate::timing::TimingModel;
 use crate::util;
 use core::cmp::max;
 use core::cmp::min;
+use std::str::FromStr;
+use std::time::Instant;
 
 #[cfg(feature = "debugger")]
 use std::collections::HashSet;
 
 #[allow(dead_code)]
+#[derive(PartialEq, Debug)]
 pub enum CpuEvent {
     Halted,
+    Exited(i32),
     Breakpoint,
+    Fault(Exception),
+    /// The instruction budget of `RunLimits` has been used up
+    BudgetExhausted,
+    /// The deadline of `RunLimits` has passed
+    Timeout,
 }
 
+/// Limits of a run with `Cpu::run_with_limits`, which stops when either is
+/// reached
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct RunLimits {
+    /// The number of instructions to execute at most
+    pub max_instructions: Option<u64>,
+    pub deadline: Option<Instant>,
+}
+
+/// The limits of a run are checked after at most this many blocks or
+/// instructions, or attempts to execute them which raised exceptions
+pub(crate) const LIMIT_CHECK_INTERVAL: usize = 1 << 16;
+
+/// How `Cpu::run` executes instructions
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Engine {
+    /// Decodes and executes one instruction at a time
+    Interpreter,
+    /// Translates basic blocks into micro-ops, see `block::BlockCache`
+    Blocks,
+    /// Runs the block engine and compiles hot blocks to machine code, see
+    /// `jit::Jit`
+    #[cfg(feature = "jit")]
+    Jit,
+    /// Compiles blocks when they are first executed, and checks that the
+    /// compiled code and the block engine agree on the state after each
+    /// block. Panics on the first difference.
+    #[cfg(feature = "jit")]
+    VerifiedJit,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interpreter" => Ok(Engine::Interpreter),
+            "blocks" => Ok(Engine::Blocks),
+            #[cfg(feature = "jit")]
+            "jit" => Ok(Engine::Jit),
+            #[cfg(feature = "jit")]
+            "jit-verify" => Ok(Engine::VerifiedJit),
+            _ => Err(format!("unknown engine: {}", s)),
+        }
+    }
+}
+
+// Single-precision values are NaN-boxed in the 64 bit float registers
+const NAN_BOX: u64 = 0xFFFF_FFFF_0000_0000;
+
+const SINGLE_SIGN: u64 = 1 << 31;
+const DOUBLE_SIGN: u64 = 1 << 63;
+
 pub struct Cpu {
     registers: [u32; 32],
+    float_registers: [u64; 32],
     pc: u32,
     running: bool,
-    cycle_counter: u64,
-    saved_pc: u32,
+    csr: CsrFile,
+    mmu: Mmu,
+    reservation: Option<Reservation>,
+    instruction_cache: InstructionCache,
+    blocks: BlockCache,
+    engine: Engine,
+    #[cfg(feature = "jit")]
+    jit: Jit,
+    timing: TimingModel,
+    /// The instructions counted against the budget of `run_with_limits`,
+    /// including those which trapped. Unlike minstret, the program can't
+    /// write it.
+    executed: u64,
+    isa: Isa,
+    extensions: Extensions,
+    custom: CustomExtensions,
+    trace: bool,
+    fault: Option<Exception>,
+    exit_status: Option<i32>,
+    environment_call_handler: Option<Box<dyn EnvironmentCallHandler>>,
+    semihosting_handler: Option<Box<dyn EnvironmentCallHandler>>,
     #[cfg(feature = "debugger")]
     breakpoints: HashSet<Address>,
 }
 
 impl Cpu {
     pub fn new() -> Cpu {
+        Self::from_isa(Isa::supported(Xlen::Rv32))
+    }
+
+    /// Creates a hart implementing `isa`, which must be a subset of the
+    /// extensions supported by the emulator. Instructions of the other
+    /// extensions raise illegal instruction exceptions.
+    pub fn with_isa(isa: &Isa) -> EmulatorResult<Cpu> {
+        if isa.xlen() != Xlen::Rv32 {
+            return Err(EmulatorError::UnsupportedIsa(format!(
+                "{} (requires rv32)",
+                isa
+            )));
+        }
+        isa.check_supported()
+            .map_err(EmulatorError::UnsupportedIsa)?;
+
+        Ok(Self::from_isa(isa.clone()))
+    }
+
+    fn from_isa(isa: Isa) -> Cpu {
+        #[cfg(feature = "jit")]
+        let mut jit = Jit::default();
+        #[cfg(feature = "jit")]
+        jit.set_word_aligned_targets(!isa.extensions().contains(Extension::C));
+
         Self {
             registers: [0u32; 32],
+            float_registers: [0u64; 32],
             pc: 0u32,
             running: true,
-            cycle_counter: 0,
-            saved_pc: 0u32,
+            csr: CsrFile::with_isa(&isa),
+            mmu: Mmu::new(),
+            reservation: None,
+            instruction_cache: InstructionCache::default(),
+            blocks: BlockCache::default(),
+            engine: Engine::Blocks,
+            #[cfg(feature = "jit")]
+            jit,
+            timing: TimingModel::default(),
+            executed: 0,
+            extensions: isa.extensions(),
+            isa,
+            custom: CustomExtensions::default(),
+            trace: false,
+            fault: None,
+            exit_status: None,
+            environment_call_handler: None,
+            semihosting_handler: None,
             #[cfg(feature = "debugger")]
             breakpoints: HashSet::new(),
         }
@@ -39,57 +180,621 @@ impl Cpu {
 
     #[allow(dead_code)]
     pub fn reset(&mut self) {
-        *self = Cpu::new();
+        *self = Cpu::from_isa(self.isa.clone());
     }
 
     pub fn run(&mut self, memory: &mut AddressSpace) -> Option<CpuEvent> {
-        let mut instruction_cache: Vec<WrappedInstruction> = vec![
-            WrappedInstruction {
-                instruction: Instruction::INVALID,
-                size: 0
-            };
-            0x10_0000 // 1 Megabyte
-        ];
+        Some(self.run_with_limits(memory, RunLimits::default()))
+    }
+
+    /// Runs until the program stops, a breakpoint is reached or one of the
+    /// limits is reached. Calling it again continues a run stopped by a
+    /// limit or breakpoint. The budget counts every instruction the hart
+    /// attempts to execute, including those raising exceptions, whatever the
+    /// program writes to minstret.
+    pub fn run_with_limits(&mut self, memory: &mut AddressSpace, limits: RunLimits) -> CpuEvent {
+        let budget_end = limits
+            .max_instructions
+            .map_or(u64::MAX, |budget| self.executed.saturating_add(budget));
 
         while self.running {
-            if let Some(new_pc) = memory.check_for_interrupt() {
-                self.saved_pc = self.pc;
-                self.pc = new_pc;
+            if self.executed >= budget_end {
+                return CpuEvent::BudgetExhausted;
+            }
+            if limits
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return CpuEvent::Timeout;
+            }
+
+            if let Some(event) = self.run_until(budget_end, memory) {
+                return event;
+            }
+        }
+
+        self.stop_event()
+    }
+
+    /// Executes up to `LIMIT_CHECK_INTERVAL` blocks or instructions, until
+    /// the program stops, a breakpoint is reached or `end` instructions have
+    /// been executed in total. Blocks are executed as long as they can't
+    /// exceed `end`.
+    fn run_until(&mut self, end: u64, memory: &mut AddressSpace) -> Option<CpuEvent> {
+        if self.engine != Engine::Interpreter && !self.trace && !self.has_breakpoints() {
+            self.run_blocks(end, memory);
+            if self.executed.saturating_add(MAX_BLOCK_LENGTH as u64) <= end {
+                return None;
+            }
+        }
+
+        for _ in 0..LIMIT_CHECK_INTERVAL {
+            if !self.running || self.executed >= end {
+                break;
+            }
+
+            self.check_for_interrupt(memory);
+
+            if let Err(exception) = self.run_instruction(memory) {
+                self.executed += 1;
+                self.handle_exception(exception, memory);
+            }
+
+            #[cfg(feature = "debugger")]
+            {
+                if self.running && self.is_breakpoint(self.pc) {
+                    return Some(CpuEvent::Breakpoint);
+                }
+            }
+        }
+        None
+    }
+
+    fn run_instruction(&mut self, memory: &mut AddressSpace) -> Result<(), Exception> {
+        let wrapped_instruction = self.decode_instruction(memory, self.pc)?;
+        let instruction = &wrapped_instruction.instruction;
+        let size = wrapped_instruction.size;
+
+        if self.trace {
+            eprintln!("0x{:08x}: {}", self.pc, self.disassemble(instruction));
+        }
+
+        let next_pc = self.pc.wrapping_add(size);
+        self.execute_instruction(instruction, size, memory)?;
+        self.pc = self.pc.wrapping_add(size);
+        let cycles = self.timing.cycles(instruction, self.pc != next_pc);
+        self.retire(1, cycles);
+        Ok(())
+    }
+
+    /// Counts instructions as retired, in minstret and for the budget
+    fn retire(&mut self, instructions: u64, cycles: u64) {
+        self.executed += instructions;
+        self.csr.retire(instructions, cycles);
+    }
+
+    /// Runs up to `LIMIT_CHECK_INTERVAL` basic blocks, while a block can't
+    /// exceed `end` instructions. Blocks are chained, so that the next block
+    /// is usually found without translating the pc and looking it up.
+    /// Interrupts are taken between blocks.
+    fn run_blocks(&mut self, end: u64, memory: &mut AddressSpace) {
+        let mut previous = None;
+
+        for _ in 0..LIMIT_CHECK_INTERVAL {
+            if !self.running || self.executed.saturating_add(MAX_BLOCK_LENGTH as u64) > end {
+                break;
+            }
+
+            let pc = self.pc;
+            self.check_for_interrupt(memory);
+            if self.pc != pc {
+                previous = None;
+            }
+
+            match self.run_block(previous, memory) {
+                Ok(next) => previous = Some(next),
+                Err(exception) => {
+                    previous = None;
+                    self.executed += 1;
+                    self.handle_exception(exception, memory);
+                }
+            }
+        }
+    }
+
+    /// Runs the block at pc, following the link from the exit of the
+    /// previous block if possible. Returns the block and how it was left.
+    fn run_block(
+        &mut self,
+        previous: Option<(usize, Exit)>,
+        memory: &mut AddressSpace,
+    ) -> Result<(usize, Exit), Exception> {
+        self.invalidate_written_code(memory);
+
+        let context = self.translation_context();
+        let linked = previous
+            .and_then(|(previous, exit)| self.blocks.follow(previous, exit, self.pc, context));
+        let index = match linked {
+            Some(index) => index,
+            None => {
+                let index = self.find_block(memory, context)?;
+                if let Some((previous, exit)) = previous {
+                    self.blocks.link(previous, exit, index);
+                }
+                index
+            }
+        };
+
+        let block = self.blocks.block(index);
+        #[cfg(feature = "jit")]
+        {
+            if self.engine != Engine::Blocks {
+                let exit = self.execute_compiled_block(index, &block, memory)?;
+                return Ok((index, exit));
+            }
+        }
+
+        let exit = self.execute_block(&block, 0, memory)?;
+        Ok((index, exit))
+    }
+
+    /// Looks up the block at pc, building it if it isn't cached
+    fn find_block(&mut self, memory: &mut AddressSpace, context: u64) -> Result<usize, Exception> {
+        self.check_fetch_alignment(self.pc)?;
+        let physical_address = self.translate_fetch_address(memory, self.pc)?;
+
+        if let Some(index) = self.blocks.get(physical_address, self.pc, context) {
+            return Ok(index);
+        }
+
+        let start = self.pc;
+        let mut ops = Vec::new();
+        let mut cycles = vec![0];
+        let mut pc = start;
+        loop {
+            // Instructions which can't be fetched start the next block, where
+            // they raise their exception
+            let wrapped_instruction = match self.decode_instruction(memory, pc) {
+                Ok(wrapped_instruction) => wrapped_instruction,
+                Err(exception) if ops.is_empty() => return Err(exception),
+                Err(_) => break,
+            };
+
+            let op = MicroOp::new(&wrapped_instruction, pc);
+            let ends_block = op.ends_block();
+            ops.push((pc, op));
+            let latency = self.timing.latency(&wrapped_instruction.instruction);
+            cycles.push(cycles[cycles.len() - 1] + latency);
+            pc = pc.wrapping_add(wrapped_instruction.size);
+
+            let next_page = (pc ^ start) >> 12 != 0;
+            if ends_block || next_page || ops.len() == MAX_BLOCK_LENGTH {
+                break;
+            }
+        }
+
+        let physical_end = physical_address.wrapping_add(pc.wrapping_sub(start));
+        let block = Block {
+            start,
+            context,
+            ops,
+            cycles,
+            end: pc,
+        };
+        Ok(self.blocks.insert(physical_address, physical_end, block))
+    }
+
+    /// Executes the micro-ops of a block, starting with the one at index
+    /// `first`. On an exception pc points to the instruction raising it, and
+    /// only the instructions before it count as executed.
+    fn execute_block(
+        &mut self,
+        block: &Block,
+        first: usize,
+        memory: &mut AddressSpace,
+    ) -> Result<Exit, Exception> {
+        let mut retired = first;
+        for (index, (pc, op)) in block.ops.iter().enumerate().skip(first) {
+            // The counters are up to date for interpreted instructions, which
+            // may read them
+            if let MicroOp::Interpret(_) = op {
+                self.retire_micro_ops(block, retired, index, 0);
+                retired = index;
+            }
+
+            match self.execute_micro_op(op, *pc, block.end, memory) {
+                Ok(None) => {}
+                Ok(Some(exit)) => {
+                    let penalty = self.timing.exit_penalty(op, *pc, exit);
+                    self.retire_micro_ops(block, retired, index + 1, penalty);
+                    return Ok(exit);
+                }
+                Err(exception) => {
+                    self.pc = *pc;
+                    self.retire_micro_ops(block, retired, index, 0);
+                    return Err(exception);
+                }
+            }
+        }
+
+        self.pc = block.end;
+        self.retire_micro_ops(block, retired, block.ops.len(), 0);
+        Ok(Exit::FallThrough)
+    }
+
+    /// Counts the micro-ops of a block from index `first` up to `end` as
+    /// retired, taking their cycles and `penalty` additional ones
+    #[inline(always)]
+    fn retire_micro_ops(&mut self, block: &Block, first: usize, end: usize, penalty: u64) {
+        let cycles = block.cycles[end] - block.cycles[first] + penalty;
+        self.retire((end - first) as u64, cycles);
+    }
+
+    /// Executes a block with its compiled code once it is hot. The
+    /// micro-ops the compiled code leaves the block at continue in
+    /// `execute_block`.
+    #[cfg(feature = "jit")]
+    fn execute_compiled_block(
+        &mut self,
+        index: usize,
+        block: &Block,
+        memory: &mut AddressSpace,
+    ) -> Result<Exit, Exception> {
+        let compiled = if self.has_direct_memory_access() {
+            self.jit.get(index, block, self.blocks.generation())
+        } else {
+            None
+        };
+        let compiled = match compiled {
+            Some(compiled) => compiled,
+            None => return self.execute_block(block, 0, memory),
+        };
+
+        let expected = match self.engine {
+            Engine::VerifiedJit => Some(self.execute_reference(block, memory)),
+            _ => None,
+        };
+        let outcome = compiled.run(&mut self.registers, memory);
+        if let Some(expected) = expected {
+            self.verify_compiled_block(block, outcome, expected, memory);
+        }
+
+        match outcome {
+            Outcome::Exit(exit, pc) => {
+                let (last_pc, last_op) = &block.ops[block.ops.len() - 1];
+                let penalty = self.timing.exit_penalty(last_op, *last_pc, exit);
+                self.retire_micro_ops(block, 0, block.ops.len(), penalty);
+                self.pc = pc;
+                Ok(exit)
+            }
+            Outcome::SideExit(first) => {
+                self.retire_micro_ops(block, 0, first, 0);
+                self.execute_block(block, first, memory)
+            }
+        }
+    }
+
+    /// Whether data addresses are physical addresses without PMP checks, as
+    /// compiled code expects. Stores also have to drop the reservation, which
+    /// is left to the block engine.
+    #[cfg(feature = "jit")]
+    fn has_direct_memory_access(&self) -> bool {
+        let untranslated = self.csr.effective_data_privilege() == PrivilegeLevel::Machine
+            || self.csr.satp() & SATP_MODE_SV32 == 0;
+
+        untranslated && !self.csr.pmp().is_active() && self.reservation.is_none()
+    }
+
+    /// Executes the micro-ops of a block which its compiled code executes,
+    /// with the block engine. Returns how the block was left, the registers
+    /// and the address, size and value of the stores, then restores the
+    /// state before the block.
+    #[cfg(feature = "jit")]
+    fn execute_reference(&mut self, block: &Block, memory: &mut AddressSpace) -> ExpectedState {
+        let registers = self.registers;
+        let pc = self.pc;
+        let mut overwritten = Vec::new();
+        let mut outcome = Outcome::Exit(Exit::FallThrough, block.end);
+
+        for (index, (op_pc, op)) in block.ops.iter().enumerate() {
+            let access = jit::memory_access(op, &self.registers);
+            let direct = access.map_or(true, |(address, size, store)| {
+                jit::is_direct_access(memory, address, size, store)
+            });
+            let misaligned = !self.extensions.contains(Extension::C)
+                && jit::has_misaligned_target(op, &self.registers);
+            if !jit::is_compiled(op) || !direct || misaligned {
+                outcome = Outcome::SideExit(index);
+                break;
+            }
+
+            if let Some((address, size, true)) = access {
+                overwritten.push((address, size, read_memory(memory, address, size)));
+            }
+            let exit = self
+                .execute_micro_op(op, *op_pc, block.end, memory)
+                .expect("direct memory accesses don't trap");
+            if let Some(exit) = exit {
+                outcome = Outcome::Exit(exit, self.pc);
+                break;
             }
+        }
+
+        let stores = overwritten
+            .iter()
+            .map(|&(address, size, _)| (address, size, read_memory(memory, address, size)))
+            .collect();
+        for &(address, size, value) in overwritten.iter().rev() {
+            write_memory(memory, address, size, value);
+        }
+        let expected = (outcome, self.registers, stores);
+        self.registers = registers;
+        self.pc = pc;
+        expected
+    }
+
+    #[cfg(feature = "jit")]
+    fn verify_compiled_block(
+        &self,
+        block: &Block,
+        outcome: Outcome,
+        expected: ExpectedState,
+        memory: &AddressSpace,
+    ) {
+        let (expected_outcome, expected_registers, expected_stores) = expected;
+        let stores: Vec<_> = expected_stores
+            .iter()
+            .map(|&(address, size, _)| (address, size, read_memory(memory, address, size)))
+            .collect();
+
+        if outcome != expected_outcome
+            || self.registers != expected_registers
+            || stores != expected_stores
+        {
+            panic!(
+                "compiled block at 0x{:08x} differs from the block engine\n\
+                 compiled: {:x?}\n{:x?}\n{:x?}\n\
+                 expected: {:x?}\n{:x?}\n{:x?}",
+                block.start,
+                outcome,
+                self.registers,
+                stores,
+                expected_outcome,
+                expected_registers,
+                expected_stores
+            );
+        }
+    }
+
+    /// Executes the micro-op of the instruction at `pc`, where `end` is the
+    /// address following the block. Returns how the block is left by the
+    /// last micro-op, after setting the pc.
+    #[inline(always)]
+    fn execute_micro_op(
+        &mut self,
+        op: &MicroOp,
+        pc: Address,
+        end: Address,
+        memory: &mut AddressSpace,
+    ) -> Result<Option<Exit>, Exception> {
+        let register = |cpu: &Cpu, index: u8| cpu.registers[index as usize];
 
-            if instruction_cache[self.pc as usize].instruction == Instruction::INVALID {
-                instruction_cache[self.pc as usize] =
-                    WrappedInstruction::new(memory.read_word(self.pc));
+        match *op {
+            MicroOp::LoadImmediate(rd, value) => self.set_register(rd.into(), value),
+            MicroOp::AddImmediate(rd, rs1, imm) => {
+                self.set_register(rd.into(), register(self, rs1).wrapping_add(imm))
+            }
+            MicroOp::SetLessThanImmediate(rd, rs1, imm) => {
+                let result = (register(self, rs1) as i32) < imm;
+                self.set_register(rd.into(), result as u32)
+            }
+            MicroOp::SetLessThanImmediateUnsigned(rd, rs1, imm) => {
+                let result = register(self, rs1) < imm;
+                self.set_register(rd.into(), result as u32)
+            }
+            MicroOp::XorImmediate(rd, rs1, imm) => {
+                self.set_register(rd.into(), register(self, rs1) ^ imm)
+            }
+            MicroOp::OrImmediate(rd, rs1, imm) => {
+                self.set_register(rd.into(), register(self, rs1) | imm)
+            }
+            MicroOp::AndImmediate(rd, rs1, imm) => {
+                self.set_register(rd.into(), register(self, rs1) & imm)
+            }
+            MicroOp::ShiftLeftImmediate(rd, rs1, shamt) => {
+                self.set_register(rd.into(), register(self, rs1) << shamt)
+            }
+            MicroOp::ShiftRightImmediate(rd, rs1, shamt) => {
+                self.set_register(rd.into(), register(self, rs1) >> shamt)
+            }
+            MicroOp::ShiftRightArithmeticImmediate(rd, rs1, shamt) => {
+                let result = (register(self, rs1) as i32) >> shamt;
+                self.set_register(rd.into(), result as u32)
+            }
+            MicroOp::Add(rd, rs1, rs2) => {
+                let result = register(self, rs1).wrapping_add(register(self, rs2));
+                self.set_register(rd.into(), result)
+            }
+            MicroOp::Sub(rd, rs1, rs2) => {
+                let result = register(self, rs1).wrapping_sub(register(self, rs2));
+                self.set_register(rd.into(), result)
+            }
+            MicroOp::ShiftLeft(rd, rs1, rs2) => {
+                let result = register(self, rs1).wrapping_shl(register(self, rs2));
+                self.set_register(rd.into(), result)
+            }
+            MicroOp::SetLessThan(rd, rs1, rs2) => {
+                let result = (register(self, rs1) as i32) < (register(self, rs2) as i32);
+                self.set_register(rd.into(), result as u32)
+            }
+            MicroOp::SetLessThanUnsigned(rd, rs1, rs2) => {
+                let result = register(self, rs1) < register(self, rs2);
+                self.set_register(rd.into(), result as u32)
+            }
+            MicroOp::Xor(rd, rs1, rs2) => {
+                self.set_register(rd.into(), register(self, rs1) ^ register(self, rs2))
+            }
+            MicroOp::ShiftRight(rd, rs1, rs2) => {
+                let result = register(self, rs1).wrapping_shr(register(self, rs2));
+                self.set_register(rd.into(), result)
+            }
+            MicroOp::ShiftRightArithmetic(rd, rs1, rs2) => {
+                let result = (register(self, rs1) as i32).wrapping_shr(register(self, rs2));
+                self.set_register(rd.into(), result as u32)
+            }
+            MicroOp::Or(rd, rs1, rs2) => {
+                self.set_register(rd.into(), register(self, rs1) | register(self, rs2))
+            }
+            MicroOp::And(rd, rs1, rs2) => {
+                self.set_register(rd.into(), register(self, rs1) & register(self, rs2))
+            }
+            MicroOp::LoadByte(rd, rs1, offset) => {
+                let address = self.calculate_address(rs1.into(), offset);
+                let address = self.translate_load_address(memory, address, 1)?;
+                let value = memory.read_byte(address) as i8;
+                self.set_register(rd.into(), value as u32)
+            }
+            MicroOp::LoadHalfword(rd, rs1, offset) => {
+                let address = self.calculate_address(rs1.into(), offset);
+                let address = self.translate_load_address(memory, address, 2)?;
+                let value = memory.read_halfword(address) as i16;
+                self.set_register(rd.into(), value as u32)
+            }
+            MicroOp::LoadWord(rd, rs1, offset) => {
+                let address = self.calculate_address(rs1.into(), offset);
+                let address = self.translate_load_address(memory, address, 4)?;
+                self.set_register(rd.into(), memory.read_word(address))
+            }
+            MicroOp::LoadByteUnsigned(rd, rs1, offset) => {
+                let address = self.calculate_address(rs1.into(), offset);
+                let address = self.translate_load_address(memory, address, 1)?;
+                self.set_register(rd.into(), u32::from(memory.read_byte(address)))
+            }
+            MicroOp::LoadHalfwordUnsigned(rd, rs1, offset) => {
+                let address = self.calculate_address(rs1.into(), offset);
+                let address = self.translate_load_address(memory, address, 2)?;
+                self.set_register(rd.into(), u32::from(memory.read_halfword(address)))
+            }
+            MicroOp::StoreByte(rs1, rs2, offset) => {
+                let address = self.calculate_address(rs1.into(), offset);
+                let address = self.translate_store_address(memory, address, 1)?;
+                memory.write_byte(address, register(self, rs2) as u8)
+            }
+            MicroOp::StoreHalfword(rs1, rs2, offset) => {
+                let address = self.calculate_address(rs1.into(), offset);
+                let address = self.translate_store_address(memory, address, 2)?;
+                memory.write_halfword(address, register(self, rs2) as u16)
+            }
+            MicroOp::StoreWord(rs1, rs2, offset) => {
+                let address = self.calculate_address(rs1.into(), offset);
+                let address = self.translate_store_address(memory, address, 4)?;
+                memory.write_word(address, register(self, rs2))
+            }
+            MicroOp::Branch(condition, rs1, rs2, target) => {
+                return if condition.holds(register(self, rs1), register(self, rs2)) {
+                    self.check_fetch_alignment(target)?;
+                    self.pc = target;
+                    Ok(Some(Exit::Taken))
+                } else {
+                    self.pc = end;
+                    Ok(Some(Exit::FallThrough))
+                };
+            }
+            MicroOp::Jump(rd, target) => {
+                self.check_fetch_alignment(target)?;
+                self.set_register(rd.into(), end);
+                self.pc = target;
+                return Ok(Some(Exit::Taken));
+            }
+            MicroOp::JumpRegister(rd, rs1, offset) => {
+                let target = self.calculate_address(rs1.into(), offset) & !1;
+                self.check_fetch_alignment(target)?;
+                self.set_register(rd.into(), end);
+                self.pc = target;
+                return Ok(Some(Exit::Indirect));
+            }
+            MicroOp::Interpret(ref wrapped_instruction) => {
+                self.pc = pc;
+                self.execute_instruction(
+                    &wrapped_instruction.instruction,
+                    wrapped_instruction.size,
+                    memory,
+                )?;
+
+                if op.ends_block() {
+                    self.pc = self.pc.wrapping_add(wrapped_instruction.size);
+                    return Ok(Some(Exit::Indirect));
+                }
             }
+        }
+
+        Ok(None)
+    }
+
+    /// Identifies the translation of virtual addresses, which depends on
+    /// satp and the privilege level
+    fn translation_context(&self) -> u64 {
+        (self.csr.privilege() as u64) << 32 | self.csr.satp()
+    }
 
-            let wrapped_instruction = &instruction_cache[self.pc as usize];
+    /// Decodes the instruction at `pc`, which is cached by its physical
+    /// address
+    fn decode_instruction(
+        &mut self,
+        memory: &mut AddressSpace,
+        pc: Address,
+    ) -> Result<WrappedInstruction, Exception> {
+        self.check_fetch_alignment(pc)?;
+        let address = self.translate_fetch_address(memory, pc)?;
+        self.invalidate_written_code(memory);
+
+        if let Some(cached) = self.instruction_cache.get(address) {
+            return Ok(cached.clone());
+        }
 
-            let instruction = &wrapped_instruction.instruction;
-            let size = wrapped_instruction.size;
+        let mut fetched = self.fetch_instruction(memory, pc, address)?;
+        fetched.instruction = self.custom.decode(fetched.instruction);
+        self.instruction_cache.insert(address, fetched.clone());
+        // The upper half of a 32 bit instruction can start a new page
+        memory.watch_writes(address);
+        memory.watch_writes(address.wrapping_add(fetched.size - 2));
+        Ok(fetched)
+    }
 
-            self.execute_instruction(instruction, size, memory);
-            self.pc += size;
-            self.cycle_counter += 1;
+    /// Without compressed instructions, jumps and branches can only target
+    /// word-aligned addresses. The jump or branch itself traps, with the
+    /// target in mtval.
+    fn check_fetch_alignment(&self, pc: Address) -> Result<(), Exception> {
+        if pc & 0b10 != 0 && !self.extensions.contains(Extension::C) {
+            Err(Exception::InstructionAddressMisaligned(pc.into()))
+        } else {
+            Ok(())
         }
+    }
 
-        Some(CpuEvent::Halted)
+    /// Drops the cached instructions and blocks overwritten since the last
+    /// fetch
+    fn invalidate_written_code(&mut self, memory: &mut AddressSpace) {
+        if memory.has_code_writes() {
+            for (address, size) in memory.take_code_writes() {
+                self.instruction_cache.invalidate(address, size);
+                self.blocks.invalidate(address, size);
+            }
+        }
     }
 
     #[cfg(feature = "debugger")]
     pub fn step(&mut self, memory: &mut AddressSpace) -> Option<CpuEvent> {
-        let wrapped_instruction = WrappedInstruction::new(memory.read_word(self.pc));
-
-        let instruction = &wrapped_instruction.instruction;
-        let size = wrapped_instruction.size;
+        self.check_for_interrupt(memory);
 
-        self.execute_instruction(instruction, size, memory);
-        self.pc += size;
-        self.cycle_counter += 1;
+        if let Err(exception) = self.run_instruction(memory) {
+            self.handle_exception(exception, memory);
+        }
 
         let breakpoint_hit = self.is_breakpoint(self.pc);
 
         if !self.running {
-            Some(CpuEvent::Halted)
+            Some(self.stop_event())
         } else if breakpoint_hit {
             Some(CpuEvent::Breakpoint)
         } else {
@@ -97,27 +802,303 @@ impl Cpu {
         }
     }
 
-    fn set_pc_for_branch(&mut self, condition: bool, imm: u32, size: u32) {
+    fn stop_event(&mut self) -> CpuEvent {
+        match (self.fault.take(), self.exit_status.take()) {
+            (Some(exception), _) => CpuEvent::Fault(exception),
+            (None, Some(status)) => CpuEvent::Exited(status),
+            (None, None) => CpuEvent::Halted,
+        }
+    }
+
+    fn check_for_interrupt(&mut self, memory: &mut AddressSpace) {
+        self.csr
+            .set_external_interrupt_pending(memory.check_for_interrupt());
+
+        if let Some(cause) = self.csr.pending_interrupt() {
+            self.reservation = None;
+            self.pc = self.csr.enter_trap(self.pc.into(), cause, 0, true) as u32;
+        }
+    }
+
+    /// Takes a trap into machine or supervisor mode. Without a trap handler
+    /// environment calls are passed to the host's handler, and the emulator
+    /// stops for all other exceptions, with EBREAK being used by programs to
+    /// halt the CPU. Semihosting calls are always handled by the host.
+    fn handle_exception(&mut self, exception: Exception, memory: &mut AddressSpace) {
+        self.reservation = None;
+
+        if exception == Exception::Breakpoint
+            && self.semihosting_handler.is_some()
+            && self.is_semihosting_call(memory)
+        {
+            self.call_host_handler(|cpu| &mut cpu.semihosting_handler, exception, memory);
+        } else if self.csr.has_trap_handler(exception.cause()) {
+            self.pc =
+                self.csr
+                    .enter_trap(self.pc.into(), exception.cause(), exception.value(), false)
+                    as u32;
+        } else if exception.is_environment_call() && self.environment_call_handler.is_some() {
+            self.call_host_handler(|cpu| &mut cpu.environment_call_handler, exception, memory);
+        } else {
+            if exception != Exception::Breakpoint {
+                self.fault = Some(exception);
+            }
+            self.running = false;
+        }
+    }
+
+    /// Runs one of the host-side handlers for the ECALL or EBREAK at pc
+    fn call_host_handler(
+        &mut self,
+        slot: fn(&mut Cpu) -> &mut Option<Box<dyn EnvironmentCallHandler>>,
+        exception: Exception,
+        memory: &mut AddressSpace,
+    ) {
+        // The handler is taken out of the CPU while it runs, so that it can
+        // be given mutable access to the CPU
+        let mut handler = match slot(self).take() {
+            Some(handler) => handler,
+            None => return,
+        };
+
+        match handler.environment_call(self, memory) {
+            EnvironmentCallResult::Return => {
+                // The call already counted against the budget when it trapped
+                self.pc = self.pc.wrapping_add(4);
+                self.csr.retire(1, self.timing.base);
+            }
+            EnvironmentCallResult::Halt => self.running = false,
+            EnvironmentCallResult::Exit(status) => {
+                self.exit_status = Some(status);
+                self.running = false;
+            }
+            EnvironmentCallResult::Unsupported => {
+                self.fault = Some(exception);
+                self.running = false;
+            }
+        }
+
+        if slot(self).is_none() {
+            *slot(self) = Some(handler);
+        }
+    }
+
+    /// Checks whether the EBREAK at pc is part of the semihosting sequence
+    /// `slli x0, x0, 0x1f; ebreak; srai x0, x0, 7`. All three instructions
+    /// have to be uncompressed.
+    fn is_semihosting_call(&mut self, memory: &mut AddressSpace) -> bool {
+        const SEMIHOSTING_ENTRY: u32 = 0x01f0_1013;
+        const EBREAK: u32 = 0x0010_0073;
+        const SEMIHOSTING_EXIT: u32 = 0x4070_5013;
+
+        let pc = self.pc;
+        self.read_instruction_word(memory, pc.wrapping_sub(4)) == Some(SEMIHOSTING_ENTRY)
+            && self.read_instruction_word(memory, pc) == Some(EBREAK)
+            && self.read_instruction_word(memory, pc.wrapping_add(4)) == Some(SEMIHOSTING_EXIT)
+    }
+
+    fn read_instruction_word(
+        &mut self,
+        memory: &mut AddressSpace,
+        address: Address,
+    ) -> Option<u32> {
+        let low = self.translate_fetch_address(memory, address).ok()?;
+        let high = self
+            .translate_fetch_address(memory, address.wrapping_add(2))
+            .ok()?;
+
+        Some(u32::from(memory.read_halfword(high)) << 16 | u32::from(memory.read_halfword(low)))
+    }
+
+    fn translate_fetch_address(
+        &mut self,
+        memory: &mut AddressSpace,
+        address: Address,
+    ) -> Result<Address, Exception> {
+        if address & 1 != 0 {
+            return Err(Exception::InstructionAddressMisaligned(address.into()));
+        }
+
+        let physical_address =
+            self.mmu
+                .translate(address, 2, AccessType::Instruction, &self.csr, memory)?;
+
+        let privilege = self.csr.privilege();
+        if memory.is_mapped(physical_address, 2)
+            && self
+                .csr
+                .pmp()
+                .is_permitted(physical_address, 2, AccessType::Instruction, privilege)
+        {
+            Ok(physical_address)
+        } else {
+            Err(Exception::InstructionAccessFault(address.into()))
+        }
+    }
+
+    /// Reads the instruction at `pc`, whose first halfword is located at the
+    /// physical address `address`. The second halfword of a 32 bit
+    /// instruction may be located on the next page.
+    fn fetch_instruction(
+        &mut self,
+        memory: &mut AddressSpace,
+        pc: Address,
+        address: Address,
+    ) -> Result<WrappedInstruction, Exception> {
+        let low = u32::from(memory.read_halfword(address));
+        if low & 0b11 != 0b11 {
+            return Ok(WrappedInstruction::decode(low, self.extensions));
+        }
+
+        let high_address = self.translate_fetch_address(memory, pc.wrapping_add(2))?;
+        let high = u32::from(memory.read_halfword(high_address));
+
+        Ok(WrappedInstruction::decode(
+            high << 16 | low,
+            self.extensions,
+        ))
+    }
+
+    fn translate_load_address(
+        &mut self,
+        memory: &mut AddressSpace,
+        address: Address,
+        size: u32,
+    ) -> Result<Address, Exception> {
+        let physical_address =
+            self.mmu
+                .translate(address, size, AccessType::Load, &self.csr, memory)?;
+
+        let privilege = self.csr.effective_data_privilege();
+        if memory.is_mapped(physical_address, size)
+            && self
+                .csr
+                .pmp()
+                .is_permitted(physical_address, size, AccessType::Load, privilege)
+        {
+            Ok(physical_address)
+        } else {
+            Err(Exception::LoadAccessFault(address.into()))
+        }
+    }
+
+    /// Translates the address of a store, dropping the reservation the store
+    /// touches. Cached instructions are invalidated with the writes recorded
+    /// by the address space.
+    fn translate_store_address(
+        &mut self,
+        memory: &mut AddressSpace,
+        address: Address,
+        size: u32,
+    ) -> Result<Address, Exception> {
+        let physical_address =
+            self.mmu
+                .translate(address, size, AccessType::Store, &self.csr, memory)?;
+
+        let privilege = self.csr.effective_data_privilege();
+        if memory.is_mapped(physical_address, size)
+            && self
+                .csr
+                .pmp()
+                .is_permitted(physical_address, size, AccessType::Store, privilege)
+        {
+            if let Some(reservation) = self.reservation {
+                if reservation.overlaps(physical_address, size) {
+                    self.reservation = None;
+                }
+            }
+            Ok(physical_address)
+        } else {
+            Err(Exception::StoreAccessFault(address.into()))
+        }
+    }
+
+    fn set_pc_for_branch(&mut self, condition: bool, imm: u32, size: u32) -> Result<(), Exception> {
         if condition {
-            let mut new_pc = self.pc as i32;
-            new_pc = new_pc.wrapping_add((imm as i32) * 2);
-            new_pc -= size as i32;
-            self.pc = new_pc as u32;
+            let new_pc = self.pc.wrapping_add(imm.wrapping_mul(2));
+            self.check_fetch_alignment(new_pc)?;
+            self.pc = new_pc.wrapping_sub(size);
         }
+        Ok(())
     }
 
     fn calculate_address(&self, base_reg: usize, offset: i32) -> u32 {
         (self.get_register(base_reg) as i32).wrapping_add(offset) as u32
     }
 
+    /// Supervisor instructions are illegal in user mode, and in supervisor
+    /// mode if the given trap bit of mstatus is set.
+    fn check_privileged_instruction(&self, trap_bit: u64) -> Result<(), Exception> {
+        match self.csr.privilege() {
+            PrivilegeLevel::User => Err(Exception::IllegalInstruction),
+            PrivilegeLevel::Supervisor if self.csr.mstatus() & trap_bit != 0 => {
+                Err(Exception::IllegalInstruction)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn write_csr(&mut self, csr: u32, value: u32) -> Result<(), Exception> {
+        self.csr.write(csr, value.into())?;
+
+        if csr == SATP {
+            self.mmu.flush();
+        }
+        // Following a link skips the translation and PMP check of the pc
+        if csr == SATP || (PMPCFG0..=PMPADDR15).contains(&csr) {
+            self.blocks.unlink();
+        }
+        Ok(())
+    }
+
+    /// Implements CSRRW(I): rd receives the old value of the CSR, unless rd is
+    /// x0, in which case the CSR is not read at all.
+    fn swap_csr(&mut self, rd: usize, csr: u32, value: u32) -> Result<(), Exception> {
+        let old_value = if rd != 0 {
+            self.csr.read(csr)? as u32
+        } else {
+            0
+        };
+        self.write_csr(csr, value)?;
+        self.set_register(rd, old_value);
+        Ok(())
+    }
+
+    /// Implements CSRRS(I) and CSRRC(I): the CSR is only written if `write`
+    /// is set, so that read-only CSRs can be read with rs1 = x0.
+    fn set_or_clear_csr(
+        &mut self,
+        rd: usize,
+        csr: u32,
+        mask: u32,
+        set: bool,
+        write: bool,
+    ) -> Result<(), Exception> {
+        let old_value = self.csr.read(csr)? as u32;
+
+        if write {
+            let new_value = if set {
+                old_value | mask
+            } else {
+                old_value & !mask
+            };
+            self.write_csr(csr, new_value)?;
+        }
+
+        self.set_register(rd, old_value);
+        Ok(())
+    }
+
     #[inline(always)]
     pub fn execute_instruction(
         &mut self,
         instruction: &Instruction,
         size: u32,
         memory: &mut AddressSpace,
-    ) {
-        // println!("{:x}, {:?}", self.pc, instruction);
+    ) -> Result<(), Exception> {
+        if instruction.is_floating_point() && !self.csr.is_float_enabled() {
+            return Err(Exception::IllegalInstruction);
+        }
 
         match *instruction {
             Instruction::LUI(rd, imm) => {
@@ -128,87 +1109,94 @@ impl Cpu {
                 self.set_register(rd, result)
             }
             Instruction::JAL(rd, imm) => {
-                let result = self.pc + size;
-
-                let mut new_pc = self.pc as i32;
-                new_pc = new_pc.wrapping_add((imm as i32) * 2);
+                let result = self.pc.wrapping_add(size);
+                let new_pc = self.pc.wrapping_add(imm.wrapping_mul(2));
+                self.check_fetch_alignment(new_pc)?;
 
-                self.pc = (new_pc - size as i32) as u32;
+                self.pc = new_pc.wrapping_sub(size);
                 self.set_register(rd, result);
             }
             Instruction::JALR(rd, rs1, imm) => {
-                let mut new_pc = self.get_register(rs1) as i32;
-                new_pc = new_pc.wrapping_add(imm as i32);
-                let result = self.pc + size;
-                self.pc = ((new_pc as u32) & !1u32) - size;
+                let new_pc = self.get_register(rs1).wrapping_add(imm as u32) & !1u32;
+                self.check_fetch_alignment(new_pc)?;
+                let result = self.pc.wrapping_add(size);
+                self.pc = new_pc.wrapping_sub(size);
                 self.set_register(rd, result);
             }
             Instruction::BEQ(rs1, rs2, imm) => {
                 let v1 = self.get_register(rs1);
                 let v2 = self.get_register(rs2);
-                self.set_pc_for_branch(v1 == v2, imm, size);
+                self.set_pc_for_branch(v1 == v2, imm, size)?;
             }
             Instruction::BNE(rs1, rs2, imm) => {
                 let v1 = self.get_register(rs1);
                 let v2 = self.get_register(rs2);
-                self.set_pc_for_branch(v1 != v2, imm, size);
+                self.set_pc_for_branch(v1 != v2, imm, size)?;
             }
             Instruction::BLT(rs1, rs2, imm) => {
                 let v1 = self.get_register(rs1) as i32;
                 let v2 = self.get_register(rs2) as i32;
-                self.set_pc_for_branch(v1 < v2, imm, size);
+                self.set_pc_for_branch(v1 < v2, imm, size)?;
             }
             Instruction::BGE(rs1, rs2, imm) => {
                 let v1 = self.get_register(rs1) as i32;
                 let v2 = self.get_register(rs2) as i32;
-                self.set_pc_for_branch(v1 >= v2, imm, size);
+                self.set_pc_for_branch(v1 >= v2, imm, size)?;
             }
             Instruction::BLTU(rs1, rs2, imm) => {
                 let v1 = self.get_register(rs1);
                 let v2 = self.get_register(rs2);
-                self.set_pc_for_branch(v1 < v2, imm, size);
+                self.set_pc_for_branch(v1 < v2, imm, size)?;
             }
             Instruction::BGEU(rs1, rs2, imm) => {
                 let v1 = self.get_register(rs1);
                 let v2 = self.get_register(rs2);
-                self.set_pc_for_branch(v1 >= v2, imm, size);
+                self.set_pc_for_branch(v1 >= v2, imm, size)?;
             }
             Instruction::LB(rd, rs1, imm) => {
                 let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 1)?;
                 let byte = memory.read_byte(addr);
                 self.set_register(rd, util::sign_extend(i32::from(byte), 8) as u32)
             }
             Instruction::LH(rd, rs1, imm) => {
                 let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 2)?;
                 let halfword = memory.read_halfword(addr);
                 self.set_register(rd, util::sign_extend(i32::from(halfword), 16) as u32)
             }
             Instruction::LW(rd, rs1, imm) => {
                 let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 4)?;
                 let word = memory.read_word(addr);
                 self.set_register(rd, word)
             }
             Instruction::LBU(rd, rs1, imm) => {
                 let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 1)?;
                 let byte = memory.read_byte(addr);
                 self.set_register(rd, u32::from(byte))
             }
             Instruction::LHU(rd, rs1, imm) => {
                 let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 2)?;
                 let halfword = memory.read_halfword(addr);
                 self.set_register(rd, u32::from(halfword))
             }
             Instruction::SB(rs1, rs2, imm) => {
                 let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_store_address(memory, addr, 1)?;
                 memory.write_byte(addr, self.get_register(rs2) as u8)
             }
             Instruction::SH(rs1, rs2, imm) => {
                 let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_store_address(memory, addr, 2)?;
                 memory.write_halfword(addr, self.get_register(rs2) as u16)
             }
             Instruction::SW(rs1, rs2, imm) => {
                 let addr = self.calculate_address(rs1, imm);
-                memory.write_word(addr, self.get_register(rs2) as u32)
+                let addr = self.translate_store_address(memory, addr, 4)?;
+                memory.write_word(addr, self.get_register(rs2))
             }
             Instruction::ADDI(rd, rs1, imm) => {
                 let v1 = self.get_register(rs1) as i32;
@@ -307,7 +1295,7 @@ impl Cpu {
                 let v1 = self.get_register(rs1);
                 let v2 = self.get_register(rs2);
                 let result = v1 | v2;
-                self.set_register(rd, result as u32);
+                self.set_register(rd, result);
             }
             Instruction::AND(rd, rs1, rs2) => {
                 let v1 = self.get_register(rs1);
@@ -348,7 +1336,7 @@ impl Cpu {
             Instruction::DIVU(rd, rs1, rs2) => {
                 let v1 = self.get_register(rs1);
                 let v2 = self.get_register(rs2);
-                let result = if v2 == 0 { -1i32 as u32 } else { v1 / v2 };
+                let result = v1.checked_div(v2).unwrap_or(-1i32 as u32);
                 self.set_register(rd, result);
             }
             Instruction::REM(rd, rs1, rs2) => {
@@ -363,154 +1351,837 @@ impl Cpu {
                 let result = if v2 == 0 { v1 } else { v1 % v2 };
                 self.set_register(rd, result);
             }
-            Instruction::EBREAK => {
-                self.running = false;
+            Instruction::ECALL => {
+                return Err(match self.csr.privilege() {
+                    PrivilegeLevel::User => Exception::EnvironmentCallFromUMode,
+                    PrivilegeLevel::Supervisor => Exception::EnvironmentCallFromSMode,
+                    PrivilegeLevel::Machine => Exception::EnvironmentCallFromMMode,
+                });
             }
+            Instruction::EBREAK => return Err(Exception::Breakpoint),
             Instruction::MRET => {
-                self.pc = self.saved_pc - size;
+                if self.csr.privilege() != PrivilegeLevel::Machine {
+                    return Err(Exception::IllegalInstruction);
+                }
+                self.pc = (self.csr.return_from_machine_trap() as u32).wrapping_sub(size);
+            }
+            Instruction::SRET => {
+                self.check_privileged_instruction(MSTATUS_TSR)?;
+                self.pc = (self.csr.return_from_supervisor_trap() as u32).wrapping_sub(size);
+            }
+            Instruction::WFI => {
+                // Waiting is optional, so WFI is implemented as a NOP
+                self.check_privileged_instruction(MSTATUS_TW)?;
+            }
+            Instruction::SFENCEVMA(_, _) => {
+                self.check_privileged_instruction(MSTATUS_TVM)?;
+                self.mmu.flush();
+                self.blocks.unlink();
+            }
+            // Memory accesses are done in program order and are immediately
+            // visible, so there is nothing to order
+            Instruction::FENCE(_, _) | Instruction::FENCETSO => {}
+            Instruction::FENCEI => {
+                self.instruction_cache.flush();
+                self.blocks.flush();
             }
             Instruction::LRW(rd, rs1, _) => {
-                // TODO: 64bit: Sign-Extension ?!?
                 let addr = self.get_register(rs1) as Address;
+                if addr & 0b11 != 0 {
+                    return Err(Exception::LoadAddressMisaligned(addr.into()));
+                }
+                let addr = self.translate_load_address(memory, addr, 4)?;
                 let v = memory.read_word(addr);
                 self.set_register(rd, v);
+                self.reservation = Some(Reservation::new(addr, 4));
             }
-            Instruction::SCW(rd, rs1, rs2) => {
+            Instruction::SCW(rd, rs1, rs2, _) => {
                 let word = self.get_register(rs2);
                 let addr = self.get_register(rs1) as Address;
-                memory.write_word(addr, word);
-                self.set_register(rd, 0); // Always succeed for now!
-            }
-            Instruction::AMOSWAPW(rd, rs1, rs2) => {
+                if addr & 0b11 != 0 {
+                    return Err(Exception::StoreAddressMisaligned(addr.into()));
+                }
+
+                // Every SC drops the reservation, whether it succeeds or not
+                let reservation = self.reservation.take();
+                let addr = self.translate_store_address(memory, addr, 4)?;
+                match reservation {
+                    Some(reservation) if reservation.covers(addr, 4) => {
+                        memory.write_word(addr, word);
+                        self.set_register(rd, 0);
+                    }
+                    _ => self.set_register(rd, 1),
+                }
+            }
+            Instruction::AMOSWAPW(rd, rs1, rs2, _) => {
                 let addr = self.get_register(rs1) as Address;
+                let addr = self.translate_store_address(memory, addr, 4)?;
                 let op1 = memory.read_word(addr);
                 self.set_register(rd, op1);
 
                 let op2 = self.get_register(rs2);
                 memory.write_word(addr, op2);
             }
-            Instruction::AMOADDW(rd, rs1, rs2) => {
+            Instruction::AMOADDW(rd, rs1, rs2, _) => {
                 let addr = self.get_register(rs1) as Address;
+                let addr = self.translate_store_address(memory, addr, 4)?;
                 let op1 = memory.read_word(addr);
                 let op2 = self.get_register(rs2);
                 let result = op1.wrapping_add(op2);
                 memory.write_word(addr, result);
                 self.set_register(rd, op1);
             }
-            Instruction::AMOANDW(rd, rs1, rs2) => {
+            Instruction::AMOANDW(rd, rs1, rs2, _) => {
                 let addr = self.get_register(rs1) as Address;
+                let addr = self.translate_store_address(memory, addr, 4)?;
                 let op1 = memory.read_word(addr);
                 let op2 = self.get_register(rs2);
                 let result = op1 & op2;
                 memory.write_word(addr, result);
                 self.set_register(rd, op1);
             }
-            Instruction::AMOORW(rd, rs1, rs2) => {
+            Instruction::AMOORW(rd, rs1, rs2, _) => {
                 let addr = self.get_register(rs1) as Address;
+                let addr = self.translate_store_address(memory, addr, 4)?;
                 let op1 = memory.read_word(addr);
                 let op2 = self.get_register(rs2);
                 let result = op1 | op2;
                 memory.write_word(addr, result);
                 self.set_register(rd, op1);
             }
-            Instruction::AMOXORW(rd, rs1, rs2) => {
+            Instruction::AMOXORW(rd, rs1, rs2, _) => {
                 let addr = self.get_register(rs1) as Address;
+                let addr = self.translate_store_address(memory, addr, 4)?;
                 let op1 = memory.read_word(addr);
                 let op2 = self.get_register(rs2);
                 let result = op1 ^ op2;
                 memory.write_word(addr, result);
                 self.set_register(rd, op1);
             }
-            Instruction::AMOMAXW(rd, rs1, rs2) => {
+            Instruction::AMOMAXW(rd, rs1, rs2, _) => {
                 let addr = self.get_register(rs1) as Address;
+                let addr = self.translate_store_address(memory, addr, 4)?;
                 let op1 = memory.read_word(addr);
                 let op2 = self.get_register(rs2);
                 let result = max(op1 as i32, op2 as i32) as u32;
                 memory.write_word(addr, result);
                 self.set_register(rd, op1);
             }
-            Instruction::AMOMAXUW(rd, rs1, rs2) => {
+            Instruction::AMOMAXUW(rd, rs1, rs2, _) => {
                 let addr = self.get_register(rs1) as Address;
+                let addr = self.translate_store_address(memory, addr, 4)?;
                 let op1 = memory.read_word(addr);
                 let op2 = self.get_register(rs2);
                 let result = max(op1, op2);
                 memory.write_word(addr, result);
                 self.set_register(rd, op1);
             }
-            Instruction::AMOMINW(rd, rs1, rs2) => {
+            Instruction::AMOMINW(rd, rs1, rs2, _) => {
                 let addr = self.get_register(rs1) as Address;
+                let addr = self.translate_store_address(memory, addr, 4)?;
                 let op1 = memory.read_word(addr);
                 let op2 = self.get_register(rs2);
                 let result = min(op1 as i32, op2 as i32) as u32;
                 memory.write_word(addr, result);
                 self.set_register(rd, op1);
             }
-            Instruction::AMOMINUW(rd, rs1, rs2) => {
+            Instruction::AMOMINUW(rd, rs1, rs2, _) => {
                 let addr = self.get_register(rs1) as Address;
+                let addr = self.translate_store_address(memory, addr, 4)?;
                 let op1 = memory.read_word(addr);
                 let op2 = self.get_register(rs2);
                 let result = min(op1, op2);
                 memory.write_word(addr, result);
                 self.set_register(rd, op1);
             }
-            Instruction::CSRRW => {
-                println!("Unimplemented instruction CSRRW at pc=0x{:x}", self.pc);
+            Instruction::SH1ADD(rd, rs1, rs2) => {
+                let result = (self.get_register(rs1) << 1).wrapping_add(self.get_register(rs2));
+                self.set_register(rd, result);
             }
-            Instruction::CSRRS => {
-                println!("Unimplemented instruction CSRRS at pc=0x{:x}", self.pc);
+            Instruction::SH2ADD(rd, rs1, rs2) => {
+                let result = (self.get_register(rs1) << 2).wrapping_add(self.get_register(rs2));
+                self.set_register(rd, result);
             }
-            Instruction::CSRRC => {
-                println!("Unimplemented instruction CSRRC at pc=0x{:x}", self.pc);
+            Instruction::SH3ADD(rd, rs1, rs2) => {
+                let result = (self.get_register(rs1) << 3).wrapping_add(self.get_register(rs2));
+                self.set_register(rd, result);
             }
-            Instruction::CSRRWI => {
-                println!("Unimplemented instruction CSRRWI at pc=0x{:x}", self.pc);
+            Instruction::ANDN(rd, rs1, rs2) => {
+                let result = self.get_register(rs1) & !self.get_register(rs2);
+                self.set_register(rd, result);
             }
-            Instruction::CSRRSI => {
-                println!("Unimplemented instruction CSRRSI at pc=0x{:x}", self.pc);
+            Instruction::ORN(rd, rs1, rs2) => {
+                let result = self.get_register(rs1) | !self.get_register(rs2);
+                self.set_register(rd, result);
             }
-            Instruction::CSRRCI => {
-                println!("Unimplemented instruction CSRRCI at pc=0x{:x}", self.pc);
+            Instruction::XNOR(rd, rs1, rs2) => {
+                let result = !(self.get_register(rs1) ^ self.get_register(rs2));
+                self.set_register(rd, result);
             }
-            Instruction::INVALID => panic!("Invalid Instruction at pc=0x{:x} detected", self.pc),
-        }
-    }
-
-    #[inline(always)]
-    pub fn get_register(&self, num: usize) -> u32 {
-        self.registers[num]
-    }
-
-    #[inline(always)]
-    pub fn set_register(&mut self, num: usize, value: u32) {
-        if num != 0 {
-            self.registers[num] = value
-        }
-    }
-
-    #[allow(dead_code)]
-    pub fn get_pc(&self) -> u32 {
-        self.pc
-    }
-
-    #[allow(dead_code)]
-    pub fn set_pc(&mut self, value: u32) {
-        self.pc = value;
-    }
-
-    pub fn get_cycle_counter(&self) -> u64 {
-        self.cycle_counter
-    }
-
-    #[cfg(feature = "debugger")]
-    pub fn add_breakpoint(&mut self, address: Address) {
-        self.breakpoints.insert(address);
-    }
-
-    #[cfg(feature = "debugger")]
+            Instruction::CLZ(rd, rs1) => {
+                let result = self.get_register(rs1).leading_zeros();
+                self.set_register(rd, result);
+            }
+            Instruction::CTZ(rd, rs1) => {
+                let result = self.get_register(rs1).trailing_zeros();
+                self.set_register(rd, result);
+            }
+            Instruction::CPOP(rd, rs1) => {
+                let result = self.get_register(rs1).count_ones();
+                self.set_register(rd, result);
+            }
+            Instruction::MAX(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as i32;
+                let v2 = self.get_register(rs2) as i32;
+                self.set_register(rd, max(v1, v2) as u32);
+            }
+            Instruction::MAXU(rd, rs1, rs2) => {
+                let result = max(self.get_register(rs1), self.get_register(rs2));
+                self.set_register(rd, result);
+            }
+            Instruction::MIN(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as i32;
+                let v2 = self.get_register(rs2) as i32;
+                self.set_register(rd, min(v1, v2) as u32);
+            }
+            Instruction::MINU(rd, rs1, rs2) => {
+                let result = min(self.get_register(rs1), self.get_register(rs2));
+                self.set_register(rd, result);
+            }
+            Instruction::SEXTB(rd, rs1) => {
+                let result = self.get_register(rs1) as i8 as i32;
+                self.set_register(rd, result as u32);
+            }
+            Instruction::SEXTH(rd, rs1) => {
+                let result = self.get_register(rs1) as i16 as i32;
+                self.set_register(rd, result as u32);
+            }
+            Instruction::ZEXTH(rd, rs1) => {
+                let result = self.get_register(rs1) & 0xFFFF;
+                self.set_register(rd, result);
+            }
+            Instruction::ROL(rd, rs1, rs2) => {
+                let result = self
+                    .get_register(rs1)
+                    .rotate_left(self.get_register(rs2) & 0x1F);
+                self.set_register(rd, result);
+            }
+            Instruction::ROR(rd, rs1, rs2) => {
+                let result = self
+                    .get_register(rs1)
+                    .rotate_right(self.get_register(rs2) & 0x1F);
+                self.set_register(rd, result);
+            }
+            Instruction::RORI(rd, rs1, imm) => {
+                let result = self.get_register(rs1).rotate_right(imm);
+                self.set_register(rd, result);
+            }
+            Instruction::ORCB(rd, rs1) => {
+                let bytes = self.get_register(rs1).to_le_bytes();
+                let result = bytes.map(|byte| if byte != 0 { 0xFF } else { 0 });
+                self.set_register(rd, u32::from_le_bytes(result));
+            }
+            Instruction::REV8(rd, rs1) => {
+                let result = self.get_register(rs1).swap_bytes();
+                self.set_register(rd, result);
+            }
+            Instruction::CLMUL(rd, rs1, rs2) => {
+                let product = carryless_multiply(self.get_register(rs1), self.get_register(rs2));
+                self.set_register(rd, product as u32);
+            }
+            Instruction::CLMULH(rd, rs1, rs2) => {
+                let product = carryless_multiply(self.get_register(rs1), self.get_register(rs2));
+                self.set_register(rd, (product >> 32) as u32);
+            }
+            Instruction::CLMULR(rd, rs1, rs2) => {
+                let product = carryless_multiply(self.get_register(rs1), self.get_register(rs2));
+                self.set_register(rd, (product >> 31) as u32);
+            }
+            Instruction::BCLR(rd, rs1, rs2) => {
+                let bit = self.get_register(rs2) & 0x1F;
+                self.set_register(rd, self.get_register(rs1) & !(1 << bit));
+            }
+            Instruction::BCLRI(rd, rs1, imm) => {
+                self.set_register(rd, self.get_register(rs1) & !(1 << imm));
+            }
+            Instruction::BEXT(rd, rs1, rs2) => {
+                let bit = self.get_register(rs2) & 0x1F;
+                self.set_register(rd, (self.get_register(rs1) >> bit) & 1);
+            }
+            Instruction::BEXTI(rd, rs1, imm) => {
+                self.set_register(rd, (self.get_register(rs1) >> imm) & 1);
+            }
+            Instruction::BINV(rd, rs1, rs2) => {
+                let bit = self.get_register(rs2) & 0x1F;
+                self.set_register(rd, self.get_register(rs1) ^ (1 << bit));
+            }
+            Instruction::BINVI(rd, rs1, imm) => {
+                self.set_register(rd, self.get_register(rs1) ^ (1 << imm));
+            }
+            Instruction::BSET(rd, rs1, rs2) => {
+                let bit = self.get_register(rs2) & 0x1F;
+                self.set_register(rd, self.get_register(rs1) | (1 << bit));
+            }
+            Instruction::BSETI(rd, rs1, imm) => {
+                self.set_register(rd, self.get_register(rs1) | (1 << imm));
+            }
+            Instruction::CSRRW(rd, rs1, csr) => {
+                let value = self.get_register(rs1);
+                self.swap_csr(rd, csr, value)?;
+            }
+            Instruction::CSRRS(rd, rs1, csr) => {
+                let mask = self.get_register(rs1);
+                self.set_or_clear_csr(rd, csr, mask, true, rs1 != 0)?;
+            }
+            Instruction::CSRRC(rd, rs1, csr) => {
+                let mask = self.get_register(rs1);
+                self.set_or_clear_csr(rd, csr, mask, false, rs1 != 0)?;
+            }
+            Instruction::CSRRWI(rd, uimm, csr) => {
+                self.swap_csr(rd, csr, uimm)?;
+            }
+            Instruction::CSRRSI(rd, uimm, csr) => {
+                self.set_or_clear_csr(rd, csr, uimm, true, uimm != 0)?;
+            }
+            Instruction::CSRRCI(rd, uimm, csr) => {
+                self.set_or_clear_csr(rd, csr, uimm, false, uimm != 0)?;
+            }
+            Instruction::FLW(rd, rs1, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 4)?;
+                let value = memory.read_word(addr);
+                self.set_single(rd, u64::from(value));
+            }
+            Instruction::FSW(rs1, rs2, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_store_address(memory, addr, 4)?;
+                memory.write_word(addr, self.float_registers[rs2] as u32);
+            }
+            Instruction::FLD(rd, rs1, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 8)?;
+                let low = u64::from(memory.read_word(addr));
+                let high = u64::from(memory.read_word(addr + 4));
+                self.set_double(rd, high << 32 | low);
+            }
+            Instruction::FSD(rs1, rs2, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_store_address(memory, addr, 8)?;
+                let value = self.float_registers[rs2];
+                memory.write_word(addr, value as u32);
+                memory.write_word(addr + 4, (value >> 32) as u32);
+            }
+            Instruction::FMADDS(rd, rs1, rs2, rs3, rm) => {
+                let (a, b, c) = (
+                    self.get_single(rs1),
+                    self.get_single(rs2),
+                    self.get_single(rs3),
+                );
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| {
+                    SINGLE.fused_multiply_add(a, b, c, false, false, rm, flags)
+                });
+                self.set_single(rd, result);
+            }
+            Instruction::FMSUBS(rd, rs1, rs2, rs3, rm) => {
+                let (a, b, c) = (
+                    self.get_single(rs1),
+                    self.get_single(rs2),
+                    self.get_single(rs3),
+                );
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| {
+                    SINGLE.fused_multiply_add(a, b, c, false, true, rm, flags)
+                });
+                self.set_single(rd, result);
+            }
+            Instruction::FNMSUBS(rd, rs1, rs2, rs3, rm) => {
+                let (a, b, c) = (
+                    self.get_single(rs1),
+                    self.get_single(rs2),
+                    self.get_single(rs3),
+                );
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| {
+                    SINGLE.fused_multiply_add(a, b, c, true, false, rm, flags)
+                });
+                self.set_single(rd, result);
+            }
+            Instruction::FNMADDS(rd, rs1, rs2, rs3, rm) => {
+                let (a, b, c) = (
+                    self.get_single(rs1),
+                    self.get_single(rs2),
+                    self.get_single(rs3),
+                );
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| {
+                    SINGLE.fused_multiply_add(a, b, c, true, true, rm, flags)
+                });
+                self.set_single(rd, result);
+            }
+            Instruction::FADDS(rd, rs1, rs2, rm) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| SINGLE.add(a, b, rm, flags));
+                self.set_single(rd, result);
+            }
+            Instruction::FSUBS(rd, rs1, rs2, rm) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| SINGLE.sub(a, b, rm, flags));
+                self.set_single(rd, result);
+            }
+            Instruction::FMULS(rd, rs1, rs2, rm) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| SINGLE.mul(a, b, rm, flags));
+                self.set_single(rd, result);
+            }
+            Instruction::FDIVS(rd, rs1, rs2, rm) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| SINGLE.div(a, b, rm, flags));
+                self.set_single(rd, result);
+            }
+            Instruction::FSQRTS(rd, rs1, rm) => {
+                let a = self.get_single(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| SINGLE.sqrt(a, rm, flags));
+                self.set_single(rd, result);
+            }
+            Instruction::FSGNJS(rd, rs1, rs2) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                self.set_single(rd, (a & !SINGLE_SIGN) | (b & SINGLE_SIGN));
+            }
+            Instruction::FSGNJNS(rd, rs1, rs2) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                self.set_single(rd, (a & !SINGLE_SIGN) | (!b & SINGLE_SIGN));
+            }
+            Instruction::FSGNJXS(rd, rs1, rs2) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                self.set_single(rd, a ^ (b & SINGLE_SIGN));
+            }
+            Instruction::FMINS(rd, rs1, rs2) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                let result = self.with_float_flags(|flags| SINGLE.min_max(a, b, false, flags));
+                self.set_single(rd, result);
+            }
+            Instruction::FMAXS(rd, rs1, rs2) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                let result = self.with_float_flags(|flags| SINGLE.min_max(a, b, true, flags));
+                self.set_single(rd, result);
+            }
+            Instruction::FCVTWS(rd, rs1, rm) => {
+                let a = self.get_single(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| SINGLE.to_integer(a, true, rm, flags));
+                self.set_register(rd, result);
+            }
+            Instruction::FCVTWUS(rd, rs1, rm) => {
+                let a = self.get_single(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| SINGLE.to_integer(a, false, rm, flags));
+                self.set_register(rd, result);
+            }
+            Instruction::FEQS(rd, rs1, rs2) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                let result = self.with_float_flags(|flags| SINGLE.eq(a, b, flags));
+                self.set_register(rd, result as u32);
+            }
+            Instruction::FLTS(rd, rs1, rs2) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                let result = self.with_float_flags(|flags| SINGLE.lt(a, b, flags));
+                self.set_register(rd, result as u32);
+            }
+            Instruction::FLES(rd, rs1, rs2) => {
+                let (a, b) = (self.get_single(rs1), self.get_single(rs2));
+                let result = self.with_float_flags(|flags| SINGLE.le(a, b, flags));
+                self.set_register(rd, result as u32);
+            }
+            Instruction::FCLASSS(rd, rs1) => {
+                let a = self.get_single(rs1);
+                self.set_register(rd, SINGLE.classify(a));
+            }
+            Instruction::FCVTSW(rd, rs1, rm) => {
+                let a = self.get_register(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| SINGLE.from_integer(a, true, rm, flags));
+                self.set_single(rd, result);
+            }
+            Instruction::FCVTSWU(rd, rs1, rm) => {
+                let a = self.get_register(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result =
+                    self.with_float_flags(|flags| SINGLE.from_integer(a, false, rm, flags));
+                self.set_single(rd, result);
+            }
+            Instruction::FMVXW(rd, rs1) => {
+                self.set_register(rd, self.float_registers[rs1] as u32);
+            }
+            Instruction::FMVWX(rd, rs1) => {
+                self.set_single(rd, u64::from(self.get_register(rs1)));
+            }
+            Instruction::FMADDD(rd, rs1, rs2, rs3, rm) => {
+                let (a, b, c) = (
+                    self.get_double(rs1),
+                    self.get_double(rs2),
+                    self.get_double(rs3),
+                );
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| {
+                    DOUBLE.fused_multiply_add(a, b, c, false, false, rm, flags)
+                });
+                self.set_double(rd, result);
+            }
+            Instruction::FMSUBD(rd, rs1, rs2, rs3, rm) => {
+                let (a, b, c) = (
+                    self.get_double(rs1),
+                    self.get_double(rs2),
+                    self.get_double(rs3),
+                );
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| {
+                    DOUBLE.fused_multiply_add(a, b, c, false, true, rm, flags)
+                });
+                self.set_double(rd, result);
+            }
+            Instruction::FNMSUBD(rd, rs1, rs2, rs3, rm) => {
+                let (a, b, c) = (
+                    self.get_double(rs1),
+                    self.get_double(rs2),
+                    self.get_double(rs3),
+                );
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| {
+                    DOUBLE.fused_multiply_add(a, b, c, true, false, rm, flags)
+                });
+                self.set_double(rd, result);
+            }
+            Instruction::FNMADDD(rd, rs1, rs2, rs3, rm) => {
+                let (a, b, c) = (
+                    self.get_double(rs1),
+                    self.get_double(rs2),
+                    self.get_double(rs3),
+                );
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| {
+                    DOUBLE.fused_multiply_add(a, b, c, true, true, rm, flags)
+                });
+                self.set_double(rd, result);
+            }
+            Instruction::FADDD(rd, rs1, rs2, rm) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| DOUBLE.add(a, b, rm, flags));
+                self.set_double(rd, result);
+            }
+            Instruction::FSUBD(rd, rs1, rs2, rm) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| DOUBLE.sub(a, b, rm, flags));
+                self.set_double(rd, result);
+            }
+            Instruction::FMULD(rd, rs1, rs2, rm) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| DOUBLE.mul(a, b, rm, flags));
+                self.set_double(rd, result);
+            }
+            Instruction::FDIVD(rd, rs1, rs2, rm) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| DOUBLE.div(a, b, rm, flags));
+                self.set_double(rd, result);
+            }
+            Instruction::FSQRTD(rd, rs1, rm) => {
+                let a = self.get_double(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| DOUBLE.sqrt(a, rm, flags));
+                self.set_double(rd, result);
+            }
+            Instruction::FSGNJD(rd, rs1, rs2) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                self.set_double(rd, (a & !DOUBLE_SIGN) | (b & DOUBLE_SIGN));
+            }
+            Instruction::FSGNJND(rd, rs1, rs2) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                self.set_double(rd, (a & !DOUBLE_SIGN) | (!b & DOUBLE_SIGN));
+            }
+            Instruction::FSGNJXD(rd, rs1, rs2) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                self.set_double(rd, a ^ (b & DOUBLE_SIGN));
+            }
+            Instruction::FMIND(rd, rs1, rs2) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                let result = self.with_float_flags(|flags| DOUBLE.min_max(a, b, false, flags));
+                self.set_double(rd, result);
+            }
+            Instruction::FMAXD(rd, rs1, rs2) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                let result = self.with_float_flags(|flags| DOUBLE.min_max(a, b, true, flags));
+                self.set_double(rd, result);
+            }
+            Instruction::FCVTWD(rd, rs1, rm) => {
+                let a = self.get_double(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| DOUBLE.to_integer(a, true, rm, flags));
+                self.set_register(rd, result);
+            }
+            Instruction::FCVTWUD(rd, rs1, rm) => {
+                let a = self.get_double(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| DOUBLE.to_integer(a, false, rm, flags));
+                self.set_register(rd, result);
+            }
+            Instruction::FEQD(rd, rs1, rs2) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                let result = self.with_float_flags(|flags| DOUBLE.eq(a, b, flags));
+                self.set_register(rd, result as u32);
+            }
+            Instruction::FLTD(rd, rs1, rs2) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                let result = self.with_float_flags(|flags| DOUBLE.lt(a, b, flags));
+                self.set_register(rd, result as u32);
+            }
+            Instruction::FLED(rd, rs1, rs2) => {
+                let (a, b) = (self.get_double(rs1), self.get_double(rs2));
+                let result = self.with_float_flags(|flags| DOUBLE.le(a, b, flags));
+                self.set_register(rd, result as u32);
+            }
+            Instruction::FCLASSD(rd, rs1) => {
+                let a = self.get_double(rs1);
+                self.set_register(rd, DOUBLE.classify(a));
+            }
+            Instruction::FCVTDW(rd, rs1, rm) => {
+                let a = self.get_register(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| DOUBLE.from_integer(a, true, rm, flags));
+                self.set_double(rd, result);
+            }
+            Instruction::FCVTDWU(rd, rs1, rm) => {
+                let a = self.get_register(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result =
+                    self.with_float_flags(|flags| DOUBLE.from_integer(a, false, rm, flags));
+                self.set_double(rd, result);
+            }
+            Instruction::FCVTSD(rd, rs1, rm) => {
+                let a = self.get_double(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| SINGLE.convert(DOUBLE, a, rm, flags));
+                self.set_single(rd, result);
+            }
+            Instruction::FCVTDS(rd, rs1, rm) => {
+                let a = self.get_single(rs1);
+                let rm = self.rounding_mode(rm)?;
+                let result = self.with_float_flags(|flags| DOUBLE.convert(SINGLE, a, rm, flags));
+                self.set_double(rd, result);
+            }
+            Instruction::CUSTOM(custom) => {
+                self.custom.execute(&custom, &mut self.registers, memory)?;
+            }
+            // Instructions which only exist on RV64
+            Instruction::LWU(..)
+            | Instruction::LD(..)
+            | Instruction::SD(..)
+            | Instruction::ADDIW(..)
+            | Instruction::SLLIW(..)
+            | Instruction::SRLIW(..)
+            | Instruction::SRAIW(..)
+            | Instruction::ADDW(..)
+            | Instruction::SUBW(..)
+            | Instruction::SLLW(..)
+            | Instruction::SRLW(..)
+            | Instruction::SRAW(..)
+            | Instruction::MULW(..)
+            | Instruction::DIVW(..)
+            | Instruction::DIVUW(..)
+            | Instruction::REMW(..)
+            | Instruction::REMUW(..)
+            | Instruction::LRD(..)
+            | Instruction::SCD(..)
+            | Instruction::AMOSWAPD(..)
+            | Instruction::AMOADDD(..)
+            | Instruction::AMOXORD(..)
+            | Instruction::AMOANDD(..)
+            | Instruction::AMOORD(..)
+            | Instruction::AMOMIND(..)
+            | Instruction::AMOMAXD(..)
+            | Instruction::AMOMINUD(..)
+            | Instruction::AMOMAXUD(..)
+            | Instruction::INVALID => return Err(Exception::IllegalInstruction),
+        }
+
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn get_register(&self, num: usize) -> u32 {
+        self.registers[num]
+    }
+
+    #[inline(always)]
+    pub fn set_register(&mut self, num: usize, value: u32) {
+        if num != 0 {
+            self.registers[num] = value
+        }
+    }
+
+    /// Reads a single-precision value, which is the canonical NaN if the
+    /// register doesn't hold a properly NaN-boxed value.
+    fn get_single(&self, num: usize) -> u64 {
+        let value = self.float_registers[num];
+        if value & NAN_BOX == NAN_BOX {
+            value & !NAN_BOX
+        } else {
+            SINGLE.canonical_nan()
+        }
+    }
+
+    fn set_single(&mut self, num: usize, value: u64) {
+        self.set_double(num, NAN_BOX | value);
+    }
+
+    fn get_double(&self, num: usize) -> u64 {
+        self.float_registers[num]
+    }
+
+    fn set_double(&mut self, num: usize, value: u64) {
+        self.float_registers[num] = value;
+        self.csr.set_float_dirty();
+    }
+
+    pub fn get_float_register(&self, num: usize) -> u64 {
+        self.float_registers[num]
+    }
+
+    pub fn set_float_register(&mut self, num: usize, value: u64) {
+        self.float_registers[num] = value;
+    }
+
+    /// Resolves the rounding mode of an instruction, where 0b111 selects the
+    /// dynamic rounding mode in frm. Invalid modes are illegal.
+    fn rounding_mode(&self, rm: u32) -> Result<RoundingMode, Exception> {
+        let rm = if rm == 0b111 {
+            self.csr.rounding_mode()
+        } else {
+            rm
+        };
+        RoundingMode::from_bits(rm).ok_or(Exception::IllegalInstruction)
+    }
+
+    /// Runs a floating-point operation and accumulates its exception flags in
+    /// fflags.
+    fn with_float_flags<T>(&mut self, operation: impl FnOnce(&mut u32) -> T) -> T {
+        let mut flags = 0;
+        let result = operation(&mut flags);
+        self.csr.accrue_float_flags(flags);
+        result
+    }
+
+    #[allow(dead_code)]
+    pub fn get_pc(&self) -> u32 {
+        self.pc
+    }
+
+    #[allow(dead_code)]
+    pub fn set_pc(&mut self, value: u32) {
+        self.pc = value;
+    }
+
+    /// Registers the host-side handler for ECALLs which aren't handled by the
+    /// program itself.
+    pub fn set_environment_call_handler(&mut self, handler: impl EnvironmentCallHandler + 'static) {
+        self.environment_call_handler = Some(Box::new(handler));
+    }
+
+    /// Registers the host-side handler for semihosting calls, which take
+    /// precedence over the program's trap handler. The handler returns to the
+    /// instruction after the EBREAK.
+    pub fn set_semihosting_handler(&mut self, handler: impl EnvironmentCallHandler + 'static) {
+        self.semihosting_handler = Some(Box::new(handler));
+    }
+
+    /// Registers the extension implementing the instructions in a custom
+    /// opcode space, which are illegal otherwise
+    pub fn set_custom_instructions(
+        &mut self,
+        opcode: CustomOpcode,
+        extension: impl CustomInstructions + 'static,
+    ) {
+        self.custom.register(opcode, extension);
+        self.instruction_cache.flush();
+        self.blocks.flush();
+    }
+
+    /// Drops all cached instructions. The cache is kept between calls to
+    /// `run` and `step`, and writes through the `AddressSpace` invalidate
+    /// it, so this is only needed before running on a different address
+    /// space.
+    pub fn flush_instruction_cache(&mut self) {
+        self.instruction_cache.flush();
+        self.blocks.flush();
+    }
+
+    /// Formats an instruction, including the custom instructions of the
+    /// registered extensions
+    pub fn disassemble(&self, instruction: &Instruction) -> String {
+        self.custom.disassemble(instruction)
+    }
+
+    /// Selects how `run` executes the program. Tracing always uses the
+    /// interpreter.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+        #[cfg(feature = "jit")]
+        self.jit.set_hot_threshold(match engine {
+            Engine::VerifiedJit => 1,
+            _ => HOT_BLOCK_THRESHOLD,
+        });
+    }
+
+    /// Enables printing every executed instruction to stderr
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = enabled;
+    }
+
+    /// Selects the timing model which estimates the cycles counted by
+    /// mcycle
+    pub fn set_timing_model(&mut self, timing: TimingModel) {
+        self.timing = timing;
+        // Blocks hold the cycles of their instructions
+        self.blocks.flush();
+    }
+
+    /// The cycles estimated by the timing model, see `TimingModel`
+    pub fn get_cycle_counter(&self) -> u64 {
+        self.csr.mcycle()
+    }
+
+    pub fn get_instruction_counter(&self) -> u64 {
+        self.csr.minstret()
+    }
+
+    #[cfg(feature = "debugger")]
+    pub fn add_breakpoint(&mut self, address: Address) {
+        self.breakpoints.insert(address);
+    }
+
+    #[cfg(feature = "debugger")]
     pub fn remove_breakpoint(&mut self, address: Address) {
         self.breakpoints.remove(&address);
     }
 
+    #[cfg(feature = "debugger")]
+    fn has_breakpoints(&self) -> bool {
+        !self.breakpoints.is_empty()
+    }
+
+    #[cfg(not(feature = "debugger"))]
+    fn has_breakpoints(&self) -> bool {
+        false
+    }
+
     #[cfg(feature = "debugger")]
     fn is_breakpoint(&self, address: Address) -> bool {
         self.breakpoints.contains(&address)
@@ -523,9 +2194,40 @@ impl Default for Cpu {
     }
 }
 
+/// How compiled code has to leave a block, the registers afterwards and the
+/// address, size and value of its stores
+#[cfg(feature = "jit")]
+type ExpectedState = (Outcome, [u32; 32], Vec<(Address, u32, u32)>);
+
+#[cfg(feature = "jit")]
+fn read_memory(memory: &AddressSpace, address: Address, size: u32) -> u32 {
+    match size {
+        1 => u32::from(memory.read_byte(address)),
+        2 => u32::from(memory.read_halfword(address)),
+        _ => memory.read_word(address),
+    }
+}
+
+#[cfg(feature = "jit")]
+fn write_memory(memory: &mut AddressSpace, address: Address, size: u32, value: u32) {
+    match size {
+        1 => memory.write_byte(address, value as u8),
+        2 => memory.write_halfword(address, value as u16),
+        _ => memory.write_word(address, value),
+    }
+}
+
+/// Multiplies without carries, as used by CLMUL, CLMULH and CLMULR
+fn carryless_multiply(a: u32, b: u32) -> u64 {
+    (0..32)
+        .filter(|i| (b >> i) & 1 != 0)
+        .fold(0, |product, i| product ^ (u64::from(a) << i))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::instruction::AtomicOrdering;
 
     macro_rules! immediate_test {
         ($instr:ident, $first:expr, $second:expr, $result:expr) => {{
@@ -533,7 +2235,8 @@ mod test {
             let mut cpu = Cpu::new();
             cpu.set_register(2, $first as u32);
 
-            cpu.execute_instruction(&Instruction::$instr(1, 2, $second as u32), 4, &mut memory);
+            cpu.execute_instruction(&Instruction::$instr(1, 2, $second as u32), 4, &mut memory)
+                .unwrap();
             assert_eq!(cpu.get_register(1), $result);
         }};
     }
@@ -545,7 +2248,8 @@ mod test {
             cpu.set_register(2, $first as u32);
             cpu.set_register(3, $second as u32);
 
-            cpu.execute_instruction(&Instruction::$instr(1, 2, 3), 4, &mut memory);
+            cpu.execute_instruction(&Instruction::$instr(1, 2, 3), 4, &mut memory)
+                .unwrap();
             assert_eq!(cpu.get_register(1), $result as u32);
         }};
     }
@@ -557,7 +2261,8 @@ mod test {
             cpu.set_register(2, $first as u32);
             cpu.set_register(3, $second as u32);
 
-            cpu.execute_instruction(&Instruction::$instr(1, 2, 3), 4, &mut memory);
+            cpu.execute_instruction(&Instruction::$instr(1, 2, 3), 4, &mut memory)
+                .unwrap();
             assert_eq!(cpu.get_register(1), $result as u32);
         }};
     }
@@ -603,6 +2308,73 @@ mod test {
         register_test!(SLTU, 0, 1, 1);
     }
 
+    macro_rules! unary_test {
+        ($instr:ident, $first:expr, $result:expr) => {{
+            let mut memory = AddressSpace::new();
+            let mut cpu = Cpu::new();
+            cpu.set_register(2, $first as u32);
+
+            cpu.execute_instruction(&Instruction::$instr(1, 2), 4, &mut memory)
+                .unwrap();
+            assert_eq!(cpu.get_register(1), $result as u32);
+        }};
+    }
+
+    #[test]
+    fn test_zba() {
+        register_test!(SH1ADD, 3, 10, 16);
+        register_test!(SH2ADD, 3, 10, 22);
+        register_test!(SH3ADD, 3, 10, 34);
+        register_test!(SH3ADD, 0x2000_0000, 1, 1);
+    }
+
+    #[test]
+    fn test_zbb() {
+        register_test!(ANDN, 0b1010, 0b0110, 0b1000);
+        register_test!(ORN, 0b1010, 0b0110, 0xFFFF_FFFBu32);
+        register_test!(XNOR, 0b1010, 0b0110, 0xFFFF_FFF3u32);
+        register_test!(MAX, -1i32, 1, 1);
+        register_test!(MAXU, -1i32, 1, -1i32);
+        register_test!(MIN, -1i32, 1, -1i32);
+        register_test!(MINU, -1i32, 1, 1);
+        register_test!(ROL, 0x8000_0001u32, 1, 0x0000_0003);
+        register_test!(ROL, 0x8000_0001u32, 33, 0x0000_0003);
+        register_test!(ROR, 0x8000_0001u32, 1, 0xC000_0000u32);
+        immediate_test!(RORI, 0x0000_00F0, 4, 0x0000_000F);
+
+        unary_test!(CLZ, 0, 32);
+        unary_test!(CLZ, 0x0001_0000, 15);
+        unary_test!(CTZ, 0, 32);
+        unary_test!(CTZ, 0x0001_0000, 16);
+        unary_test!(CPOP, 0xF0F0_0001u32, 9);
+        unary_test!(SEXTB, 0x0000_0080, 0xFFFF_FF80u32);
+        unary_test!(SEXTH, 0x0001_7FFF, 0x0000_7FFF);
+        unary_test!(SEXTH, 0x0000_8000, 0xFFFF_8000u32);
+        unary_test!(ZEXTH, 0xFFFF_8000u32, 0x0000_8000);
+        unary_test!(ORCB, 0x0010_0200, 0x00FF_FF00);
+        unary_test!(REV8, 0x1234_5678, 0x7856_3412);
+    }
+
+    #[test]
+    fn test_zbc() {
+        register_test!(CLMUL, 0b1011, 0b0110, 0b11_1010);
+        register_test!(CLMUL, 0x8000_0001u32, 0x3, 0x8000_0003u32);
+        register_test!(CLMULH, 0x8000_0001u32, 0x3, 0x1);
+        register_test!(CLMULR, 0x8000_0001u32, 0x3, 0x3);
+    }
+
+    #[test]
+    fn test_zbs() {
+        register_test!(BCLR, 0xFF, 35, 0xF7);
+        register_test!(BEXT, 0x10, 4, 1);
+        register_test!(BINV, 0xFF, 8, 0x1FF);
+        register_test!(BSET, 0, 31, 0x8000_0000u32);
+        immediate_test!(BCLRI, 0xFF, 0, 0xFE);
+        immediate_test!(BEXTI, 0x10, 3, 0);
+        immediate_test!(BINVI, 0xFF, 7, 0x7F);
+        immediate_test!(BSETI, 0, 1, 2);
+    }
+
     #[test]
     fn test_mul() {
         register_test_new!(MUL, 0x00001200, 0x00007e00, 0xb6db6db7);
@@ -728,7 +2500,8 @@ mod test {
             let mut cpu = Cpu::new();
             cpu.pc = 80;
 
-            cpu.execute_instruction(&Instruction::JAL(1, offset as u32), 4, &mut memory);
+            cpu.execute_instruction(&Instruction::JAL(1, offset as u32), 4, &mut memory)
+                .unwrap();
             assert_eq!(cpu.get_register(1), 84);
             assert_eq!(cpu.pc, (80 + offset * 2 - 4) as u32);
         }
@@ -738,6 +2511,21 @@ mod test {
     }
 
     #[test]
+    fn test_compressed_jal() {
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+        cpu.pc = 80;
+
+        let wrapped_instruction = WrappedInstruction::new(0x3001); // c.jal -2048
+        let size = wrapped_instruction.size;
+        cpu.execute_instruction(&wrapped_instruction.instruction, size, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(1), 82);
+        assert_eq!(cpu.pc + size, (80 - 2048) as u32);
+    }
+
+    #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn test_jalr() {
         fn t(base: u32, offset: i32) {
             let mut memory = AddressSpace::new();
@@ -745,7 +2533,8 @@ mod test {
             cpu.set_register(2, base);
             cpu.pc = 80;
 
-            cpu.execute_instruction(&Instruction::JALR(1, 2, offset as i32), 4, &mut memory);
+            cpu.execute_instruction(&Instruction::JALR(1, 2, offset as i32), 4, &mut memory)
+                .unwrap();
             assert_eq!(cpu.get_register(1), 84);
             assert_eq!(cpu.pc, ((base as i32 + offset - 4) as u32) & !1u32);
         }
@@ -756,6 +2545,33 @@ mod test {
         t(400, -1);
     }
 
+    #[test]
+    fn test_jump_to_zero() {
+        let mut memory = AddressSpace::new();
+        memory.write_word(0x00, 0x00100073); // ebreak
+        memory.write_word(0x100, 0x00000067); // jalr x0, 0(x0)
+
+        for engine in &[Engine::Interpreter, Engine::Blocks] {
+            let mut cpu = Cpu::new();
+            cpu.set_engine(*engine);
+            cpu.set_pc(0x100);
+            assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+            assert_eq!(cpu.pc, 0);
+        }
+
+        // Jumps and branches below address 0 wrap around
+        let mut cpu = Cpu::new();
+        cpu.execute_instruction(&Instruction::JAL(1, -2i32 as u32), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.pc.wrapping_add(4), 0xFFFF_FFFC);
+        assert_eq!(cpu.get_register(1), 4);
+
+        let mut cpu = Cpu::new();
+        cpu.execute_instruction(&Instruction::BEQ(0, 0, -2i32 as u32), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.pc.wrapping_add(4), 0xFFFF_FFFC);
+    }
+
     macro_rules! branch_test {
         ($instr:ident, $first:expr, $second:expr, $offset:expr, $expect_jump:expr) => {{
             let first = $first as u32;
@@ -767,7 +2583,8 @@ mod test {
             cpu.set_register(3, second);
             cpu.pc = 80;
 
-            cpu.execute_instruction(&Instruction::$instr(2, 3, $offset), 4, &mut memory);
+            cpu.execute_instruction(&Instruction::$instr(2, 3, $offset), 4, &mut memory)
+                .unwrap();
 
             if $expect_jump {
                 assert_eq!((80i32).wrapping_add(2 * $offset) as u32 - 4, cpu.pc);
@@ -817,7 +2634,8 @@ mod test {
 
         cpu.set_register(1, 0xF0);
         cpu.set_register(2, 0xCAFEBABE);
-        cpu.execute_instruction(&Instruction::SB(1, 2, 16), 4, &mut memory);
+        cpu.execute_instruction(&Instruction::SB(1, 2, 16), 4, &mut memory)
+            .unwrap();
         assert_eq!(0, memory.read_byte(0xF0 + 16 - 1));
         assert_eq!(0xBE, memory.read_byte(0xF0 + 16));
         assert_eq!(0, memory.read_byte(0xF0 + 16 + 1));
@@ -830,7 +2648,8 @@ mod test {
 
         cpu.set_register(1, 0xF0);
         cpu.set_register(2, 0xCAFEBABE);
-        cpu.execute_instruction(&Instruction::SH(1, 2, 16), 4, &mut memory);
+        cpu.execute_instruction(&Instruction::SH(1, 2, 16), 4, &mut memory)
+            .unwrap();
         assert_eq!(0, memory.read_byte(0xF0 + 16 - 1));
         assert_eq!(0xBABE, memory.read_halfword(0xF0 + 16));
         assert_eq!(0, memory.read_byte(0xF0 + 16 + 3));
@@ -843,7 +2662,8 @@ mod test {
 
         cpu.set_register(1, 0xF0);
         cpu.set_register(2, 0xCAFEBABE);
-        cpu.execute_instruction(&Instruction::SW(1, 2, 16), 4, &mut memory);
+        cpu.execute_instruction(&Instruction::SW(1, 2, 16), 4, &mut memory)
+            .unwrap();
         assert_eq!(0, memory.read_byte(0xF0 + 16 - 1));
         assert_eq!(0xCAFEBABE, memory.read_word(0xF0 + 16));
         assert_eq!(0, memory.read_byte(0xF0 + 16 + 5));
@@ -856,7 +2676,8 @@ mod test {
             let mut cpu = Cpu::new();
 
             cpu.set_register(2, 0xF0);
-            cpu.execute_instruction(&Instruction::$instr(1, 2, 16), 4, &mut memory);
+            cpu.execute_instruction(&Instruction::$instr(1, 2, 16), 4, &mut memory)
+                .unwrap();
             assert_eq!(cpu.get_register(1), $expected);
         }};
     }
@@ -894,4 +2715,882 @@ mod test {
         cpu.set_register(0, 0xCAFEBABE);
         assert_eq!(cpu.get_register(0), 0);
     }
+
+    #[test]
+    fn test_csr_read_write() {
+        use crate::csr::{MHARTID, MSCRATCH};
+
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+
+        cpu.set_register(2, 0xCAFEBABE);
+        cpu.execute_instruction(&Instruction::CSRRW(1, 2, MSCRATCH), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(1), 0);
+
+        // rd == rs1: the old value must be read before the register is overwritten
+        cpu.execute_instruction(&Instruction::CSRRW(2, 2, MSCRATCH), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(2), 0xCAFEBABE);
+
+        cpu.set_register(3, 0xFF);
+        cpu.execute_instruction(&Instruction::CSRRC(1, 3, MSCRATCH), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(1), 0xCAFEBABE);
+        assert_eq!(cpu.csr.read(MSCRATCH), Ok(0xCAFEBA00));
+
+        cpu.execute_instruction(&Instruction::CSRRSI(1, 0b1010, MSCRATCH), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(1), 0xCAFEBA00);
+        assert_eq!(cpu.csr.read(MSCRATCH), Ok(0xCAFEBA0A));
+
+        cpu.execute_instruction(&Instruction::CSRRCI(1, 0b0010, MSCRATCH), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.csr.read(MSCRATCH), Ok(0xCAFEBA08));
+
+        cpu.execute_instruction(&Instruction::CSRRWI(0, 31, MSCRATCH), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.csr.read(MSCRATCH), Ok(31));
+
+        cpu.execute_instruction(&Instruction::CSRRS(1, 0, MHARTID), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(1), 0);
+    }
+
+    #[test]
+    fn test_csr_read_only() {
+        use crate::csr::MHARTID;
+
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+
+        let result = cpu.execute_instruction(&Instruction::CSRRW(0, 1, MHARTID), 4, &mut memory);
+        assert_eq!(result, Err(Exception::IllegalInstruction));
+
+        // Writing only depends on the register number, not on its value
+        let result = cpu.execute_instruction(&Instruction::CSRRS(1, 2, MHARTID), 4, &mut memory);
+        assert_eq!(result, Err(Exception::IllegalInstruction));
+
+        let result = cpu.execute_instruction(&Instruction::CSRRCI(1, 1, MHARTID), 4, &mut memory);
+        assert_eq!(result, Err(Exception::IllegalInstruction));
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+
+        let result = cpu.execute_instruction(&Instruction::INVALID, 4, &mut memory);
+        assert_eq!(result, Err(Exception::IllegalInstruction));
+
+        let result = cpu.execute_instruction(&Instruction::ECALL, 4, &mut memory);
+        assert_eq!(result, Err(Exception::EnvironmentCallFromMMode));
+
+        let result = cpu.execute_instruction(&Instruction::EBREAK, 4, &mut memory);
+        assert_eq!(result, Err(Exception::Breakpoint));
+
+        cpu.set_register(2, 0x1000_0000);
+        let result = cpu.execute_instruction(&Instruction::LW(1, 2, 4), 4, &mut memory);
+        assert_eq!(result, Err(Exception::LoadAccessFault(0x1000_0004)));
+
+        let result = cpu.execute_instruction(&Instruction::SB(2, 1, -1), 4, &mut memory);
+        assert_eq!(result, Err(Exception::StoreAccessFault(0x0FFF_FFFF)));
+
+        let result = cpu.execute_instruction(
+            &Instruction::AMOADDW(1, 2, 3, AtomicOrdering::Relaxed),
+            4,
+            &mut memory,
+        );
+        assert_eq!(result, Err(Exception::StoreAccessFault(0x1000_0000)));
+    }
+
+    #[test]
+    fn test_load_reserved_store_conditional() {
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+        let lr = Instruction::LRW(1, 2, AtomicOrdering::Acquire);
+        let sc = Instruction::SCW(3, 2, 4, AtomicOrdering::Release);
+        cpu.set_register(2, 0x100);
+        cpu.set_register(4, 42);
+        memory.write_word(0x100, 7);
+
+        // Without a reservation SC fails and doesn't store
+        cpu.execute_instruction(&sc, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(3), 1);
+        assert_eq!(memory.read_word(0x100), 7);
+
+        cpu.execute_instruction(&lr, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(1), 7);
+        cpu.execute_instruction(&sc, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(3), 0);
+        assert_eq!(memory.read_word(0x100), 42);
+
+        // The reservation is used up by the first SC
+        cpu.execute_instruction(&sc, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(3), 1);
+
+        // A store to the reservation set invalidates it
+        cpu.execute_instruction(&lr, 4, &mut memory).unwrap();
+        cpu.execute_instruction(&Instruction::SB(2, 0, 3), 4, &mut memory)
+            .unwrap();
+        cpu.execute_instruction(&sc, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(3), 1);
+
+        // Stores outside of it don't
+        cpu.execute_instruction(&lr, 4, &mut memory).unwrap();
+        cpu.execute_instruction(&Instruction::SW(2, 0, 4), 4, &mut memory)
+            .unwrap();
+        cpu.execute_instruction(&sc, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(3), 0);
+
+        // SC to a different address than the LR fails
+        cpu.execute_instruction(&lr, 4, &mut memory).unwrap();
+        cpu.set_register(2, 0x200);
+        cpu.execute_instruction(&sc, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(3), 1);
+        assert_eq!(memory.read_word(0x200), 0);
+
+        // Traps drop the reservation
+        cpu.execute_instruction(&lr, 4, &mut memory).unwrap();
+        cpu.handle_exception(Exception::IllegalInstruction, &mut memory);
+        cpu.execute_instruction(&sc, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(3), 1);
+
+        cpu.set_register(2, 0x202);
+        let result = cpu.execute_instruction(&lr, 4, &mut memory);
+        assert_eq!(result, Err(Exception::LoadAddressMisaligned(0x202)));
+        let result = cpu.execute_instruction(&sc, 4, &mut memory);
+        assert_eq!(result, Err(Exception::StoreAddressMisaligned(0x202)));
+    }
+
+    #[test]
+    fn test_trap_and_mret() {
+        use crate::csr::{MCAUSE, MEPC, MTVAL, MTVEC};
+
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+        cpu.csr.write(MTVEC, 0x100).unwrap();
+        cpu.pc = 0x80;
+
+        cpu.handle_exception(Exception::LoadAccessFault(0x1000_0000), &mut memory);
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.csr.read(MEPC), Ok(0x80));
+        assert_eq!(cpu.csr.read(MCAUSE), Ok(5));
+        assert_eq!(cpu.csr.read(MTVAL), Ok(0x1000_0000));
+        assert!(cpu.running);
+
+        cpu.csr.write(MEPC, 0x84).unwrap();
+        cpu.execute_instruction(&Instruction::MRET, 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.pc + 4, 0x84);
+    }
+
+    #[test]
+    fn test_trap_in_run() {
+        let mut memory = AddressSpace::new();
+        memory.write_word(0x00, 0x30509073); // csrw mtvec, x1
+        memory.write_word(0x04, 0xFFFF_FFFF); // illegal instruction
+        memory.write_word(0x100, 0x34202173); // csrr x2, mcause
+        memory.write_word(0x104, 0x30501073); // csrw mtvec, x0
+        memory.write_word(0x108, 0x00100073); // ebreak
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(1, 0x100);
+
+        assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+        assert_eq!(cpu.get_register(2), 2);
+        assert_eq!(cpu.pc, 0x108);
+    }
+
+    #[test]
+    fn test_run_with_limits() {
+        let mut memory = AddressSpace::new();
+        memory.write_word(0x00, 0x00128293); // addi x5, x5, 1
+        memory.write_word(0x04, 0xffdff06f); // j -4
+
+        let budget = |max_instructions| RunLimits {
+            max_instructions: Some(max_instructions),
+            deadline: None,
+        };
+
+        for engine in &[Engine::Interpreter, Engine::Blocks] {
+            let mut cpu = Cpu::new();
+            cpu.set_engine(*engine);
+
+            let event = cpu.run_with_limits(&mut memory, budget(1001));
+            assert_eq!(event, CpuEvent::BudgetExhausted);
+            assert_eq!(cpu.get_instruction_counter(), 1001);
+            assert_eq!((cpu.get_register(5), cpu.pc), (501, 0x04));
+
+            // The run continues where it stopped
+            let event = cpu.run_with_limits(&mut memory, budget(2));
+            assert_eq!(event, CpuEvent::BudgetExhausted);
+            assert_eq!((cpu.get_register(5), cpu.pc), (502, 0x04));
+        }
+
+        // Instructions which trap count against the budget, so a program
+        // trapping on every fetch is stopped as well
+        for engine in &[Engine::Interpreter, Engine::Blocks] {
+            let mut cpu = Cpu::new();
+            cpu.set_engine(*engine);
+            cpu.set_pc(0x7000_0000);
+            cpu.write_csr(crate::csr::MTVEC, 0x7000_0000).unwrap();
+
+            let event = cpu.run_with_limits(&mut memory, budget(1000));
+            assert_eq!(event, CpuEvent::BudgetExhausted);
+            assert_eq!(cpu.executed, 1000);
+            assert_eq!(cpu.get_instruction_counter(), 0);
+        }
+
+        // Clearing minstret doesn't reset the budget
+        memory.write_word(0x00, 0xb0201073); // csrw minstret, x0
+        for engine in &[Engine::Interpreter, Engine::Blocks] {
+            let mut cpu = Cpu::new();
+            cpu.set_engine(*engine);
+
+            let event = cpu.run_with_limits(&mut memory, budget(1000));
+            assert_eq!(event, CpuEvent::BudgetExhausted);
+            assert_eq!(cpu.executed, 1000);
+            assert!(cpu.get_instruction_counter() <= 1);
+        }
+    }
+
+    #[cfg(feature = "debugger")]
+    #[test]
+    fn test_run_to_breakpoint() {
+        let mut memory = AddressSpace::new();
+        memory.write_word(0x00, 0x00128293); // addi x5, x5, 1
+        memory.write_word(0x04, 0xffdff06f); // j -4
+
+        let mut cpu = Cpu::new();
+        cpu.add_breakpoint(0x04);
+        for iteration in 1..=3 {
+            let event = cpu.run_with_limits(&mut memory, RunLimits::default());
+            assert_eq!(event, CpuEvent::Breakpoint);
+            assert_eq!((cpu.get_register(5), cpu.pc), (iteration, 0x04));
+        }
+    }
+
+    #[test]
+    fn test_isa() {
+        assert!(Cpu::with_isa(&"rv64imac".parse().unwrap()).is_err());
+        assert!(Cpu::with_isa(&"rv32imacv".parse().unwrap()).is_err());
+
+        // Instructions of disabled extensions are illegal, and without C
+        // jumps to halfword-aligned addresses are misaligned
+        for (instruction, cause) in &[(0x02310233, 2), (0x0060006f, 0)] {
+            let mut memory = AddressSpace::new();
+            memory.write_word(0x00, 0x30509073); // csrw mtvec, x1
+            memory.write_word(0x04, *instruction); // mul x4, x2, x3 or j 0x0a
+            memory.write_word(0x100, 0x34202173); // csrr x2, mcause
+            memory.write_word(0x104, 0x30501073); // csrw mtvec, x0
+            memory.write_word(0x108, 0x00100073); // ebreak
+
+            let mut cpu = Cpu::with_isa(&"rv32i_zicsr".parse().unwrap()).unwrap();
+            cpu.set_register(1, 0x100);
+
+            assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+            assert_eq!(cpu.get_register(2), *cause);
+            assert_eq!(cpu.csr.read(crate::csr::MISA), Ok(0x4014_0100));
+        }
+    }
+
+    #[test]
+    fn test_misaligned_jump() {
+        // Without C, the jump or branch to a halfword-aligned address traps
+        // and the handler skips it
+        let mut memory = AddressSpace::new();
+        memory.write_word(0x00, 0x30509073); // csrw mtvec, x1
+        memory.write_word(0x04, 0x00a002e7); // jalr x5, 10(x0)
+        memory.write_word(0x08, 0x00000363); // beq x0, x0, 6
+        memory.write_word(0x0c, 0x30501073); // csrw mtvec, x0
+        memory.write_word(0x10, 0x00100073); // ebreak
+        memory.write_word(0x100, 0x34102373); // csrr x6, mepc
+        memory.write_word(0x104, 0x343023f3); // csrr x7, mtval
+        memory.write_word(0x108, 0x00430313); // addi x6, x6, 4
+        memory.write_word(0x10c, 0x34131073); // csrw mepc, x6
+        memory.write_word(0x110, 0x00140413); // addi x8, x8, 1
+        memory.write_word(0x114, 0x30200073); // mret
+
+        #[allow(unused_mut)]
+        let mut engines = vec![Engine::Interpreter, Engine::Blocks];
+        #[cfg(feature = "jit")]
+        engines.extend(&[Engine::Jit, Engine::VerifiedJit]);
+        for engine in engines {
+            let mut cpu = Cpu::with_isa(&"rv32i_zicsr".parse().unwrap()).unwrap();
+            cpu.set_engine(engine);
+            cpu.set_register(1, 0x100);
+
+            assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+            assert_eq!(cpu.get_register(8), 2);
+            assert_eq!(cpu.get_register(5), 0);
+            assert_eq!((cpu.get_register(6), cpu.get_register(7)), (0x0c, 0x0e));
+            assert_eq!(cpu.csr.read(crate::csr::MCAUSE), Ok(0));
+        }
+    }
+
+    #[test]
+    fn test_custom_instructions() {
+        use crate::custom::{CustomInstruction, Registers};
+
+        /// Stores rs1 + rs2 to the address in rd
+        struct StoreSum;
+
+        impl CustomInstructions for StoreSum {
+            fn decode(&self, code: u32) -> Option<u32> {
+                Some(code >> 25)
+            }
+
+            fn execute(
+                &mut self,
+                instruction: &CustomInstruction,
+                registers: &mut dyn Registers,
+                memory: &mut AddressSpace,
+            ) -> Result<(), Exception> {
+                let sum = registers.get(instruction.rs1()) + registers.get(instruction.rs2());
+                memory.write_word(registers.get(instruction.rd()) as Address, sum as u32);
+                Ok(())
+            }
+        }
+
+        let mut memory = AddressSpace::new();
+        memory.write_word(0x00, 0x0031_00AB); // custom-1 x1, x2, x3
+        memory.write_word(0x04, 0x00100073); // ebreak
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(1, 0x100);
+        cpu.set_register(2, 20);
+        cpu.set_register(3, 22);
+        assert_eq!(
+            cpu.run(&mut memory),
+            Some(CpuEvent::Fault(Exception::IllegalInstruction))
+        );
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(1, 0x100);
+        cpu.set_register(2, 20);
+        cpu.set_register(3, 22);
+        cpu.set_custom_instructions(CustomOpcode::Custom1, StoreSum);
+        assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+        assert_eq!(memory.read_word(0x100), 42);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // The instruction at 0x04 is replaced with li x2, 2 before the second
+        // iteration, with and without a FENCE.I.
+        for fence in &[0x0000100f, 0x00000013] {
+            let mut memory = AddressSpace::new();
+            memory.write_word(0x00, 0x00118193); // addi x3, x3, 1
+            memory.write_word(0x04, 0x00100113); // li x2, 1
+            memory.write_word(0x08, 0x0063a023); // sw x6, 0(x7)
+            memory.write_word(0x0C, *fence);
+            memory.write_word(0x10, 0xfe4198e3); // bne x3, x4, -16
+            memory.write_word(0x14, 0x00100073); // ebreak
+
+            let mut cpu = Cpu::new();
+            cpu.set_register(4, 2);
+            cpu.set_register(6, 0x00200113); // li x2, 2
+            cpu.set_register(7, 0x04);
+
+            assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+            assert_eq!(cpu.get_register(2), 2);
+        }
+    }
+
+    #[test]
+    fn test_engines_agree() {
+        let program = [
+            0x7d000313, // li x6, 2000
+            0x00000293, // li x5, 0
+            0x00000513, // li x10, 0
+            0x000013b7, // lui x7, 1
+            0x3ff2f413, // andi x8, x5, 1023
+            0x00241413, // slli x8, x8, 2
+            0x00740433, // add x8, x8, x7
+            0x00042483, // lw x9, 0(x8)
+            0x005484b3, // add x9, x9, x5
+            0x00942023, // sw x9, 0(x8)
+            0x00954533, // xor x10, x10, x9
+            0x00128293, // addi x5, x5, 1
+            0xfe6290e3, // bne x5, x6, -32
+            0x02550533, // mul x10, x10, x5
+            0x00100073, // ebreak
+        ];
+
+        #[allow(unused_mut)]
+        let mut engines = vec![Engine::Interpreter, Engine::Blocks];
+        #[cfg(feature = "jit")]
+        engines.extend(&[Engine::Jit, Engine::VerifiedJit]);
+
+        let mut results = Vec::new();
+        for engine in &engines {
+            let mut memory = AddressSpace::new();
+            for (index, word) in program.iter().enumerate() {
+                memory.write_word(index as Address * 4, *word);
+            }
+
+            let mut cpu = Cpu::new();
+            cpu.set_engine(*engine);
+            cpu.set_timing_model(TimingModel::three_stage());
+            assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+
+            let data: Vec<u32> = (0x1000..0x2000)
+                .step_by(4)
+                .map(|address| memory.read_word(address))
+                .collect();
+            let counters = (cpu.get_instruction_counter(), cpu.get_cycle_counter());
+            results.push((cpu.registers, cpu.pc, counters, data));
+        }
+
+        // The load takes two cycles and the mul three. The loop branch costs
+        // a cycle when it is taken as predicted, and two when it falls
+        // through.
+        let cycles = 4 + 2000 * (9 + 2) - 1 + 2 + 3;
+        assert_eq!(results[0].2, (4 + 2000 * 9 + 1, cycles));
+        for result in &results[1..] {
+            assert_eq!(&results[0], result);
+        }
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut memory = AddressSpace::new();
+        memory.write_word(0x00, 0x00300293); // li x5, 3
+        memory.write_word(0x04, 0x0252c333); // div x6, x5, x5
+        memory.write_word(0x08, 0xc0202573); // rdinstret x10
+        memory.write_word(0x0C, 0xc00025f3); // rdcycle x11
+        memory.write_word(0x10, 0xb0201073); // csrw minstret, x0
+        memory.write_word(0x14, 0x00100073); // ebreak
+
+        for engine in &[Engine::Interpreter, Engine::Blocks] {
+            let mut cpu = Cpu::new();
+            cpu.set_engine(*engine);
+            cpu.set_timing_model(TimingModel::three_stage());
+            assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+
+            assert_eq!(cpu.get_register(10), 2);
+            assert_eq!(cpu.get_register(11), 1 + 34 + 1);
+            assert_eq!(cpu.get_instruction_counter(), 0);
+            assert_eq!(cpu.get_cycle_counter(), 1 + 34 + 1 + 1 + 1);
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn test_jit_side_exits() {
+        // Every other iteration accesses the debug device instead of RAM,
+        // and stores to the page holding the code
+        let program = [
+            0x06400313, // li x6, 100
+            0x00000293, // li x5, 0
+            0x000016b7, // lui x13, 1
+            0x00128293, // addi x5, x5, 1
+            0x0012f613, // andi x12, x5, 1
+            0x01d61613, // slli x12, x12, 29
+            0x00d60633, // add x12, x12, x13
+            0x00562223, // sw x5, 4(x12)
+            0x00461403, // lh x8, 4(x12)
+            0x025404b3, // mul x9, x8, x5
+            0x40900023, // sb x9, 1024(x0)
+            0x40004503, // lbu x10, 1024(x0)
+            0x00a585b3, // add x11, x11, x10
+            0xfc629ce3, // bne x5, x6, -40
+            0x00100073, // ebreak
+        ];
+
+        let mut results = Vec::new();
+        for engine in &[Engine::Interpreter, Engine::VerifiedJit] {
+            let mut memory = AddressSpace::new();
+            for (index, word) in program.iter().enumerate() {
+                memory.write_word(index as Address * 4, *word);
+            }
+
+            let mut cpu = Cpu::new();
+            cpu.set_engine(*engine);
+            assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+            results.push((cpu.registers, cpu.pc, cpu.get_instruction_counter()));
+        }
+
+        let sum: u32 = (1..=100).map(|i| (i * i) & 0xFF).sum();
+        assert_eq!(results[0].0[11], sum);
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[test]
+    fn test_code_modified_by_host() {
+        /// Replaces the first instruction with li x2, 2
+        struct PatchHandler;
+
+        impl EnvironmentCallHandler for PatchHandler {
+            fn environment_call(
+                &mut self,
+                _cpu: &mut Cpu,
+                memory: &mut AddressSpace,
+            ) -> EnvironmentCallResult {
+                memory.write_word(0x0400_0000, 0x00200113);
+                EnvironmentCallResult::Return
+            }
+        }
+
+        // Code far above the first megabyte is cached as well
+        let mut memory = AddressSpace::new();
+        memory.write_word(0x0400_0000, 0x00100113); // li x2, 1
+        memory.write_word(0x0400_0004, 0x00118193); // addi x3, x3, 1
+        memory.write_word(0x0400_0008, 0x00000073); // ecall
+        memory.write_word(0x0400_000C, 0xfe419ae3); // bne x3, x4, -12
+        memory.write_word(0x0400_0010, 0x00100073); // ebreak
+
+        let mut cpu = Cpu::new();
+        cpu.set_pc(0x0400_0000);
+        cpu.set_register(4, 2);
+        cpu.set_environment_call_handler(PatchHandler);
+
+        assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+        assert_eq!(cpu.get_register(2), 2);
+        assert_eq!(
+            cpu.instruction_cache
+                .get(0x0400_0004)
+                .map(|cached| cached.size),
+            Some(4)
+        );
+    }
+
+    struct AddHandler;
+
+    impl EnvironmentCallHandler for AddHandler {
+        fn environment_call(
+            &mut self,
+            cpu: &mut Cpu,
+            memory: &mut AddressSpace,
+        ) -> EnvironmentCallResult {
+            match cpu.get_register(17) {
+                1 => {
+                    let sum = cpu.get_register(10) + memory.read_word(cpu.get_register(11));
+                    cpu.set_register(10, sum);
+                    EnvironmentCallResult::Return
+                }
+                2 => EnvironmentCallResult::Halt,
+                _ => EnvironmentCallResult::Unsupported,
+            }
+        }
+    }
+
+    #[test]
+    fn test_environment_call_handler() {
+        use crate::csr::{MCAUSE, MTVEC};
+
+        let mut memory = AddressSpace::new();
+        memory.write_word(0x00, 0x00000073); // ecall
+        memory.write_word(0x04, 0x00200893); // li a7, 2
+        memory.write_word(0x08, 0x00000073); // ecall
+        memory.write_word(0x100, 40);
+        memory.write_word(0x200, 0x30501073); // csrw mtvec, x0
+        memory.write_word(0x204, 0x00100073); // ebreak
+
+        // The first ECALL returns, the second one stops the CPU
+        let mut cpu = Cpu::new();
+        cpu.set_environment_call_handler(AddHandler);
+        cpu.set_register(10, 2);
+        cpu.set_register(11, 0x100);
+        cpu.set_register(17, 1);
+        assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+        assert_eq!(cpu.get_register(10), 42);
+        assert_eq!(cpu.pc, 0x08);
+        assert_eq!(cpu.get_instruction_counter(), 2);
+
+        let mut cpu = Cpu::new();
+        cpu.set_environment_call_handler(AddHandler);
+        cpu.set_register(17, 3);
+        assert_eq!(
+            cpu.run(&mut memory),
+            Some(CpuEvent::Fault(Exception::EnvironmentCallFromMMode))
+        );
+
+        // The program's own trap handler takes precedence
+        let mut cpu = Cpu::new();
+        cpu.set_environment_call_handler(AddHandler);
+        cpu.set_register(17, 1);
+        cpu.csr.write(MTVEC, 0x200).unwrap();
+        assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+        assert_eq!(cpu.get_register(10), 0);
+        assert_eq!(cpu.csr.read(MCAUSE), Ok(11));
+    }
+
+    #[test]
+    fn test_semihosting_call() {
+        use crate::csr::{MEPC, MTVEC};
+
+        let mut memory = AddressSpace::new();
+        memory.write_word(0x00, 0x01f01013); // slli x0, x0, 0x1f
+        memory.write_word(0x04, 0x00100073); // ebreak
+        memory.write_word(0x08, 0x40705013); // srai x0, x0, 7
+        memory.write_word(0x0C, 0x00100073); // ebreak
+        memory.write_word(0x100, 40);
+
+        // Only the EBREAK inside the sequence is a semihosting call, even if
+        // there is a trap handler
+        let mut cpu = Cpu::new();
+        cpu.set_semihosting_handler(AddHandler);
+        cpu.set_register(10, 2);
+        cpu.set_register(11, 0x100);
+        cpu.set_register(17, 1);
+        cpu.csr.write(MTVEC, 0x200).unwrap();
+        memory.write_word(0x200, 0x30501073); // csrw mtvec, x0
+        memory.write_word(0x204, 0x00100073); // ebreak
+        assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+        assert_eq!(cpu.get_register(10), 42);
+        assert_eq!(cpu.csr.read(MEPC), Ok(0x0C));
+
+        // Without a semihosting handler the EBREAK stops the CPU
+        let mut cpu = Cpu::new();
+        assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+        assert_eq!(cpu.pc, 0x04);
+    }
+
+    #[test]
+    fn test_unhandled_exception() {
+        let mut memory = AddressSpace::new();
+        memory.write_word(0x00, 0xFFFF_FFFF);
+
+        let mut cpu = Cpu::new();
+        assert_eq!(
+            cpu.run(&mut memory),
+            Some(CpuEvent::Fault(Exception::IllegalInstruction))
+        );
+        assert_eq!(cpu.pc, 0);
+
+        let mut cpu = Cpu::new();
+        cpu.pc = 0x1000_0000;
+        assert_eq!(
+            cpu.run(&mut memory),
+            Some(CpuEvent::Fault(Exception::InstructionAccessFault(
+                0x1000_0000
+            )))
+        );
+    }
+
+    #[test]
+    fn test_environment_call_privilege() {
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::ECALL, 4, &mut memory),
+            Err(Exception::EnvironmentCallFromMMode)
+        );
+
+        cpu.csr.set_privilege(PrivilegeLevel::Supervisor);
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::ECALL, 4, &mut memory),
+            Err(Exception::EnvironmentCallFromSMode)
+        );
+
+        cpu.csr.set_privilege(PrivilegeLevel::User);
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::ECALL, 4, &mut memory),
+            Err(Exception::EnvironmentCallFromUMode)
+        );
+    }
+
+    #[test]
+    fn test_privileged_instructions() {
+        use crate::csr::MSTATUS;
+
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+        cpu.csr.write(MSTATUS, MSTATUS_TVM | MSTATUS_TSR).unwrap();
+
+        cpu.csr.set_privilege(PrivilegeLevel::Supervisor);
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::MRET, 4, &mut memory),
+            Err(Exception::IllegalInstruction)
+        );
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::SRET, 4, &mut memory),
+            Err(Exception::IllegalInstruction)
+        );
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::SFENCEVMA(0, 0), 4, &mut memory),
+            Err(Exception::IllegalInstruction)
+        );
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::WFI, 4, &mut memory),
+            Ok(())
+        );
+
+        cpu.csr.set_privilege(PrivilegeLevel::User);
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::WFI, 4, &mut memory),
+            Err(Exception::IllegalInstruction)
+        );
+    }
+
+    #[test]
+    fn test_physical_memory_protection() {
+        use crate::csr::{PMPADDR0, PMPCFG0};
+
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+        // Read and execute access to 0x0 - 0x1000 (TOR)
+        cpu.csr.write(PMPADDR0, 0x1000 >> 2).unwrap();
+        cpu.csr.write(PMPCFG0, 0b01_101).unwrap();
+        cpu.csr.set_privilege(PrivilegeLevel::User);
+        cpu.set_register(2, 0x800);
+
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::LW(1, 2, 4), 4, &mut memory),
+            Ok(())
+        );
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::SW(2, 1, 4), 4, &mut memory),
+            Err(Exception::StoreAccessFault(0x804))
+        );
+
+        cpu.set_register(2, 0x1000);
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::LW(1, 2, 0), 4, &mut memory),
+            Err(Exception::LoadAccessFault(0x1000))
+        );
+
+        cpu.pc = 0x1000;
+        assert_eq!(
+            cpu.translate_fetch_address(&mut memory, cpu.pc),
+            Err(Exception::InstructionAccessFault(0x1000))
+        );
+    }
+
+    #[test]
+    fn test_user_mode_with_translation() {
+        use crate::csr::{MEPC, SATP_MODE_SV32};
+
+        let root_table = 0x10_0000;
+        let leaf_table = 0x10_1000;
+
+        let mut memory = AddressSpace::new();
+        // 0x0000_1000 -> 0x0020_0000, user executable page
+        memory.write_word(root_table, (leaf_table >> 12) << 10 | 0b1);
+        memory.write_word(leaf_table + 4, (0x200 << 10) | 0b101_1011);
+        memory.write_word(0x20_0000, 0x00000073); // ecall
+
+        memory.write_word(0x00, 0x18009073); // csrw satp, x1
+        memory.write_word(0x04, 0x34119073); // csrw mepc, x3
+        memory.write_word(0x08, 0x30511073); // csrw mtvec, x2
+        memory.write_word(0x0c, 0x30001073); // csrw mstatus, x0
+        memory.write_word(0x10, 0x30200073); // mret
+        memory.write_word(0x100, 0x34202273); // csrr x4, mcause
+        memory.write_word(0x104, 0x30501073); // csrw mtvec, x0
+        memory.write_word(0x108, 0x00100073); // ebreak
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(1, SATP_MODE_SV32 as u32 | root_table >> 12);
+        cpu.set_register(2, 0x100);
+        cpu.set_register(3, 0x1000);
+
+        assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+        assert_eq!(cpu.get_register(4), 8);
+        assert_eq!(cpu.csr.read(MEPC), Ok(0x1000));
+        assert_eq!(cpu.csr.privilege(), PrivilegeLevel::Machine);
+    }
+
+    #[test]
+    fn test_float_arithmetic() {
+        use crate::csr::{FFLAGS, FRM};
+
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+        cpu.set_register(1, 1.0f32.to_bits());
+        cpu.set_register(2, 3.0f32.to_bits());
+
+        cpu.execute_instruction(&Instruction::FMVWX(1, 1), 4, &mut memory)
+            .unwrap();
+        cpu.execute_instruction(&Instruction::FMVWX(2, 2), 4, &mut memory)
+            .unwrap();
+        cpu.execute_instruction(&Instruction::FDIVS(3, 1, 2, 0), 4, &mut memory)
+            .unwrap();
+        assert_eq!(
+            cpu.get_float_register(3),
+            NAN_BOX | u64::from((1.0f32 / 3.0).to_bits())
+        );
+        assert_eq!(cpu.csr.read(FFLAGS), Ok(1));
+
+        // Rounding down with the dynamic rounding mode in frm
+        cpu.csr.write(FRM, 0b010).unwrap();
+        cpu.execute_instruction(&Instruction::FDIVS(4, 1, 2, 0b111), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_float_register(4), cpu.get_float_register(3) - 1);
+
+        cpu.execute_instruction(&Instruction::FLTS(5, 4, 3), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(5), 1);
+
+        cpu.execute_instruction(&Instruction::FCVTDS(6, 2, 0), 4, &mut memory)
+            .unwrap();
+        cpu.execute_instruction(&Instruction::FMULD(6, 6, 6, 0), 4, &mut memory)
+            .unwrap();
+        cpu.execute_instruction(&Instruction::FCVTWD(7, 6, 0), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_float_register(6), 9.0f64.to_bits());
+        assert_eq!(cpu.get_register(7), 9);
+
+        // Invalid rounding mode in frm
+        cpu.csr.write(FRM, 0b101).unwrap();
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::FADDS(3, 1, 2, 0b111), 4, &mut memory),
+            Err(Exception::IllegalInstruction)
+        );
+    }
+
+    #[test]
+    fn test_float_nan_boxing() {
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+
+        // A double isn't a valid single-precision value
+        cpu.set_float_register(1, 2.0f64.to_bits());
+        cpu.execute_instruction(&Instruction::FSGNJS(2, 1, 1), 4, &mut memory)
+            .unwrap();
+        assert_eq!(
+            cpu.get_float_register(2),
+            NAN_BOX | crate::softfloat::SINGLE.canonical_nan()
+        );
+
+        // FMV.X.W moves the lower bits unchanged
+        cpu.execute_instruction(&Instruction::FMVXW(3, 1), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(3), 0);
+    }
+
+    #[test]
+    fn test_float_loads_and_stores() {
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+        cpu.set_register(1, 0x100);
+        cpu.set_float_register(1, 1.5f64.to_bits());
+
+        cpu.execute_instruction(&Instruction::FSD(1, 1, 8), 4, &mut memory)
+            .unwrap();
+        assert_eq!(memory.read_word(0x10C), (1.5f64.to_bits() >> 32) as u32);
+        cpu.execute_instruction(&Instruction::FLD(2, 1, 8), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_float_register(2), 1.5f64.to_bits());
+
+        cpu.execute_instruction(&Instruction::FLW(3, 1, 8), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_float_register(3), NAN_BOX);
+    }
+
+    #[test]
+    fn test_float_disabled() {
+        use crate::csr::MSTATUS;
+
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu::new();
+        cpu.csr.write(MSTATUS, 0).unwrap();
+
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::FMVWX(1, 1), 4, &mut memory),
+            Err(Exception::IllegalInstruction)
+        );
+    }
 }
@@ -0,0 +1,1281 @@
+use crate::cpu::{CpuEvent, RunLimits, LIMIT_CHECK_INTERVAL};
+use crate::csr::{CsrFile, PrivilegeLevel, MSTATUS_TSR, MSTATUS_TVM, MSTATUS_TW};
+use crate::custom::{CustomExtensions, CustomInstructions, CustomOpcode};
+use crate::error::{EmulatorError, EmulatorResult};
+use crate::exception::Exception;
+use crate::instruction::{Instruction, WrappedInstruction};
+use crate::instruction_cache::InstructionCache;
+use crate::isa::{Extension, Extensions, Isa, Xlen};
+use crate::memory::addressspace::{Address, AddressSpace, MemoryDevice};
+use crate::mmu::AccessType;
+use crate::reservation::Reservation;
+use crate::timing::TimingModel;
+use core::cmp::max;
+use core::cmp::min;
+use std::convert::TryFrom;
+use std::time::Instant;
+
+#[cfg(feature = "debugger")]
+use std::collections::HashSet;
+
+/// A hart implementing RV64IMAC. Virtual memory isn't supported, so
+/// addresses are used as physical addresses, and only the lower 4 GiB of the
+/// address space can be accessed. Environment calls can't be handled by the
+/// host, see `EnvironmentCallHandler`.
+pub struct Cpu64 {
+    registers: [u64; 32],
+    pc: u64,
+    running: bool,
+    csr: CsrFile,
+    reservation: Option<Reservation>,
+    instruction_cache: InstructionCache,
+    timing: TimingModel,
+    /// The instructions counted against the budget of `run_with_limits`,
+    /// including those which trapped. Unlike minstret, the program can't
+    /// write it.
+    executed: u64,
+    isa: Isa,
+    extensions: Extensions,
+    custom: CustomExtensions,
+    trace: bool,
+    fault: Option<Exception>,
+    #[cfg(feature = "debugger")]
+    breakpoints: HashSet<u64>,
+}
+
+impl Cpu64 {
+    pub fn new() -> Cpu64 {
+        Self::from_isa(Isa::supported(Xlen::Rv64))
+    }
+
+    /// Creates a hart implementing `isa`, which must be a subset of the
+    /// extensions supported by the emulator. Instructions of the other
+    /// extensions raise illegal instruction exceptions.
+    pub fn with_isa(isa: &Isa) -> EmulatorResult<Cpu64> {
+        if isa.xlen() != Xlen::Rv64 {
+            return Err(EmulatorError::UnsupportedIsa(format!(
+                "{} (requires rv64)",
+                isa
+            )));
+        }
+        isa.check_supported()
+            .map_err(EmulatorError::UnsupportedIsa)?;
+
+        Ok(Self::from_isa(isa.clone()))
+    }
+
+    fn from_isa(isa: Isa) -> Cpu64 {
+        Self {
+            registers: [0u64; 32],
+            pc: 0u64,
+            running: true,
+            csr: CsrFile::with_isa(&isa),
+            reservation: None,
+            instruction_cache: InstructionCache::default(),
+            timing: TimingModel::default(),
+            executed: 0,
+            extensions: isa.extensions(),
+            isa,
+            custom: CustomExtensions::default(),
+            trace: false,
+            fault: None,
+            #[cfg(feature = "debugger")]
+            breakpoints: HashSet::new(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn reset(&mut self) {
+        *self = Cpu64::from_isa(self.isa.clone());
+    }
+
+    pub fn run(&mut self, memory: &mut AddressSpace) -> Option<CpuEvent> {
+        Some(self.run_with_limits(memory, RunLimits::default()))
+    }
+
+    /// Runs until the program stops, a breakpoint is reached or one of the
+    /// limits is reached, see `Cpu::run_with_limits`
+    pub fn run_with_limits(&mut self, memory: &mut AddressSpace, limits: RunLimits) -> CpuEvent {
+        let budget_end = limits
+            .max_instructions
+            .map_or(u64::MAX, |budget| self.executed.saturating_add(budget));
+
+        while self.running {
+            if self.executed >= budget_end {
+                return CpuEvent::BudgetExhausted;
+            }
+            if limits
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return CpuEvent::Timeout;
+            }
+
+            for _ in 0..LIMIT_CHECK_INTERVAL {
+                if !self.running || self.executed >= budget_end {
+                    break;
+                }
+
+                self.check_for_interrupt(memory);
+                if let Err(exception) = self.run_instruction(memory) {
+                    self.executed += 1;
+                    self.handle_exception(exception);
+                }
+
+                #[cfg(feature = "debugger")]
+                {
+                    if self.running && self.is_breakpoint(self.pc) {
+                        return CpuEvent::Breakpoint;
+                    }
+                }
+            }
+        }
+
+        self.stop_event()
+    }
+
+    fn run_instruction(&mut self, memory: &mut AddressSpace) -> Result<(), Exception> {
+        self.check_fetch_alignment(self.pc)?;
+
+        let address = self.translate_fetch_address(memory, self.pc)?;
+
+        if memory.has_code_writes() {
+            for (written, size) in memory.take_code_writes() {
+                self.instruction_cache.invalidate(written, size);
+            }
+        }
+
+        let wrapped_instruction = match self.instruction_cache.get(address) {
+            Some(cached) => cached.clone(),
+            None => {
+                let mut fetched = self.fetch_instruction(memory, address)?;
+                fetched.instruction = self.custom.decode(fetched.instruction);
+                self.instruction_cache.insert(address, fetched.clone());
+                // The upper half of a 32 bit instruction can start a new page
+                memory.watch_writes(address);
+                memory.watch_writes(address.wrapping_add(fetched.size - 2));
+                fetched
+            }
+        };
+
+        let instruction = &wrapped_instruction.instruction;
+        let size = wrapped_instruction.size;
+
+        if self.trace {
+            eprintln!("0x{:016x}: {}", self.pc, self.disassemble(instruction));
+        }
+
+        let next_pc = self.pc.wrapping_add(u64::from(size));
+        self.execute_instruction(instruction, size, memory)?;
+        self.pc = self.pc.wrapping_add(u64::from(size));
+        let cycles = self.timing.cycles(instruction, self.pc != next_pc);
+        self.retire(1, cycles);
+        Ok(())
+    }
+
+    /// Counts instructions as retired, in minstret and for the budget
+    fn retire(&mut self, instructions: u64, cycles: u64) {
+        self.executed += instructions;
+        self.csr.retire(instructions, cycles);
+    }
+
+    #[cfg(feature = "debugger")]
+    pub fn step(&mut self, memory: &mut AddressSpace) -> Option<CpuEvent> {
+        self.check_for_interrupt(memory);
+
+        if let Err(exception) = self.run_instruction(memory) {
+            self.handle_exception(exception);
+        }
+
+        let breakpoint_hit = self.is_breakpoint(self.pc);
+
+        if !self.running {
+            Some(self.stop_event())
+        } else if breakpoint_hit {
+            Some(CpuEvent::Breakpoint)
+        } else {
+            None
+        }
+    }
+
+    fn stop_event(&mut self) -> CpuEvent {
+        match self.fault.take() {
+            Some(exception) => CpuEvent::Fault(exception),
+            None => CpuEvent::Halted,
+        }
+    }
+
+    fn check_for_interrupt(&mut self, memory: &mut AddressSpace) {
+        self.csr
+            .set_external_interrupt_pending(memory.check_for_interrupt());
+
+        if let Some(cause) = self.csr.pending_interrupt() {
+            self.reservation = None;
+            self.pc = self.csr.enter_trap(self.pc, cause, 0, true);
+        }
+    }
+
+    /// Takes a trap into machine or supervisor mode, or stops the emulator if
+    /// there is no trap handler, just like the 32 bit core.
+    fn handle_exception(&mut self, exception: Exception) {
+        self.reservation = None;
+
+        if self.csr.has_trap_handler(exception.cause()) {
+            self.pc = self
+                .csr
+                .enter_trap(self.pc, exception.cause(), exception.value(), false);
+        } else {
+            if exception != Exception::Breakpoint {
+                self.fault = Some(exception);
+            }
+            self.running = false;
+        }
+    }
+
+    /// Checks an access of `size` bytes against the memory map and PMP.
+    /// Addresses beyond 32 bits are never mapped.
+    fn translate_address(
+        &self,
+        memory: &AddressSpace,
+        address: u64,
+        size: u32,
+        access: AccessType,
+    ) -> Result<Address, Exception> {
+        let privilege = match access {
+            AccessType::Instruction => self.csr.privilege(),
+            _ => self.csr.effective_data_privilege(),
+        };
+
+        match Address::try_from(address) {
+            Ok(physical_address)
+                if memory.is_mapped(physical_address, size)
+                    && self
+                        .csr
+                        .pmp()
+                        .is_permitted(physical_address, size, access, privilege) =>
+            {
+                Ok(physical_address)
+            }
+            _ => Err(access.access_fault(address)),
+        }
+    }
+
+    fn translate_fetch_address(
+        &self,
+        memory: &AddressSpace,
+        address: u64,
+    ) -> Result<Address, Exception> {
+        if address & 1 != 0 {
+            return Err(Exception::InstructionAddressMisaligned(address));
+        }
+
+        self.translate_address(memory, address, 2, AccessType::Instruction)
+    }
+
+    fn fetch_instruction(
+        &self,
+        memory: &AddressSpace,
+        address: Address,
+    ) -> Result<WrappedInstruction, Exception> {
+        let low = u32::from(memory.read_halfword(address));
+        if low & 0b11 != 0b11 {
+            return Ok(WrappedInstruction::decode_rv64(low, self.extensions));
+        }
+
+        let high_address = self.translate_fetch_address(memory, self.pc.wrapping_add(2))?;
+        let high = u32::from(memory.read_halfword(high_address));
+
+        Ok(WrappedInstruction::decode_rv64(
+            high << 16 | low,
+            self.extensions,
+        ))
+    }
+
+    fn translate_load_address(
+        &self,
+        memory: &AddressSpace,
+        address: u64,
+        size: u32,
+    ) -> Result<Address, Exception> {
+        self.translate_address(memory, address, size, AccessType::Load)
+    }
+
+    /// Translates the address of a store, dropping the reservation the store
+    /// touches. Cached instructions are invalidated with the writes recorded
+    /// by the address space.
+    fn translate_store_address(
+        &mut self,
+        memory: &AddressSpace,
+        address: u64,
+        size: u32,
+    ) -> Result<Address, Exception> {
+        let physical_address = self.translate_address(memory, address, size, AccessType::Store)?;
+
+        if let Some(reservation) = self.reservation {
+            if reservation.overlaps(physical_address, size) {
+                self.reservation = None;
+            }
+        }
+        Ok(physical_address)
+    }
+
+    /// Without compressed instructions, jumps and branches can only target
+    /// word-aligned addresses. The jump or branch itself traps, with the
+    /// target in mtval.
+    fn check_fetch_alignment(&self, pc: u64) -> Result<(), Exception> {
+        if pc & 0b10 != 0 && !self.extensions.contains(Extension::C) {
+            Err(Exception::InstructionAddressMisaligned(pc))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn set_pc_for_branch(&mut self, condition: bool, imm: u32, size: u32) -> Result<(), Exception> {
+        if condition {
+            let offset = i64::from(imm as i32) * 2;
+            let target = self.pc.wrapping_add(offset as u64);
+            self.check_fetch_alignment(target)?;
+            self.pc = target.wrapping_sub(u64::from(size));
+        }
+        Ok(())
+    }
+
+    fn calculate_address(&self, base_reg: usize, offset: i32) -> u64 {
+        self.get_register(base_reg)
+            .wrapping_add(i64::from(offset) as u64)
+    }
+
+    fn check_privileged_instruction(&self, trap_bit: u64) -> Result<(), Exception> {
+        match self.csr.privilege() {
+            PrivilegeLevel::User => Err(Exception::IllegalInstruction),
+            PrivilegeLevel::Supervisor if self.csr.mstatus() & trap_bit != 0 => {
+                Err(Exception::IllegalInstruction)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn swap_csr(&mut self, rd: usize, csr: u32, value: u64) -> Result<(), Exception> {
+        let old_value = if rd != 0 { self.csr.read(csr)? } else { 0 };
+        self.csr.write(csr, value)?;
+        self.set_register(rd, old_value);
+        Ok(())
+    }
+
+    fn set_or_clear_csr(
+        &mut self,
+        rd: usize,
+        csr: u32,
+        mask: u64,
+        set: bool,
+        write: bool,
+    ) -> Result<(), Exception> {
+        let old_value = self.csr.read(csr)?;
+
+        if write {
+            let new_value = if set {
+                old_value | mask
+            } else {
+                old_value & !mask
+            };
+            self.csr.write(csr, new_value)?;
+        }
+
+        self.set_register(rd, old_value);
+        Ok(())
+    }
+
+    /// Implements LR.W and LR.D, returning the physical address which is now
+    /// reserved.
+    fn load_reserved(
+        &mut self,
+        memory: &AddressSpace,
+        rs1: usize,
+        size: u32,
+    ) -> Result<Address, Exception> {
+        let address = self.get_register(rs1);
+        if address & u64::from(size - 1) != 0 {
+            return Err(Exception::LoadAddressMisaligned(address));
+        }
+
+        let address = self.translate_load_address(memory, address, size)?;
+        self.reservation = Some(Reservation::new(address, size));
+        Ok(address)
+    }
+
+    /// Implements SC.W and SC.D. The store is only done if the reservation of
+    /// the preceding LR is still valid, otherwise 1 is written to rd.
+    fn store_conditional(
+        &mut self,
+        memory: &mut AddressSpace,
+        rd: usize,
+        rs1: usize,
+        size: u32,
+        store: impl FnOnce(&mut AddressSpace, Address),
+    ) -> Result<(), Exception> {
+        let address = self.get_register(rs1);
+        if address & u64::from(size - 1) != 0 {
+            return Err(Exception::StoreAddressMisaligned(address));
+        }
+
+        // Every SC drops the reservation, whether it succeeds or not
+        let reservation = self.reservation.take();
+        let address = self.translate_store_address(memory, address, size)?;
+        match reservation {
+            Some(reservation) if reservation.covers(address, size) => {
+                store(memory, address);
+                self.set_register(rd, 0);
+            }
+            _ => self.set_register(rd, 1),
+        }
+        Ok(())
+    }
+
+    /// Implements the AMO*.W instructions, which sign-extend the loaded word
+    fn atomic_word(
+        &mut self,
+        memory: &mut AddressSpace,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        operation: impl FnOnce(u32, u32) -> u32,
+    ) -> Result<(), Exception> {
+        let addr = self.translate_store_address(memory, self.get_register(rs1), 4)?;
+        let op1 = memory.read_word(addr);
+        let op2 = self.get_register(rs2) as u32;
+        memory.write_word(addr, operation(op1, op2));
+        self.set_register(rd, sign_extend_word(op1));
+        Ok(())
+    }
+
+    /// Implements the AMO*.D instructions
+    fn atomic_doubleword(
+        &mut self,
+        memory: &mut AddressSpace,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        operation: impl FnOnce(u64, u64) -> u64,
+    ) -> Result<(), Exception> {
+        let addr = self.translate_store_address(memory, self.get_register(rs1), 8)?;
+        let op1 = read_doubleword(memory, addr);
+        let op2 = self.get_register(rs2);
+        write_doubleword(memory, addr, operation(op1, op2));
+        self.set_register(rd, op1);
+        Ok(())
+    }
+
+    /// Executes an instruction decoded by `Instruction::new_rv64`. The F, D
+    /// and bit-manipulation extensions are not available on RV64.
+    pub fn execute_instruction(
+        &mut self,
+        instruction: &Instruction,
+        size: u32,
+        memory: &mut AddressSpace,
+    ) -> Result<(), Exception> {
+        match *instruction {
+            Instruction::LUI(rd, imm) => {
+                self.set_register(rd, sign_extend_word(imm << 12));
+            }
+            Instruction::AUIPC(rd, imm) => {
+                let result = self.pc.wrapping_add(sign_extend_word(imm << 12));
+                self.set_register(rd, result)
+            }
+            Instruction::JAL(rd, imm) => {
+                let result = self.pc.wrapping_add(u64::from(size));
+                self.set_pc_for_branch(true, imm, size)?;
+                self.set_register(rd, result);
+            }
+            Instruction::JALR(rd, rs1, imm) => {
+                let target = self.calculate_address(rs1, imm) & !1;
+                self.check_fetch_alignment(target)?;
+                let result = self.pc.wrapping_add(u64::from(size));
+                self.pc = target.wrapping_sub(u64::from(size));
+                self.set_register(rd, result);
+            }
+            Instruction::BEQ(rs1, rs2, imm) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_pc_for_branch(v1 == v2, imm, size)?;
+            }
+            Instruction::BNE(rs1, rs2, imm) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_pc_for_branch(v1 != v2, imm, size)?;
+            }
+            Instruction::BLT(rs1, rs2, imm) => {
+                let v1 = self.get_register(rs1) as i64;
+                let v2 = self.get_register(rs2) as i64;
+                self.set_pc_for_branch(v1 < v2, imm, size)?;
+            }
+            Instruction::BGE(rs1, rs2, imm) => {
+                let v1 = self.get_register(rs1) as i64;
+                let v2 = self.get_register(rs2) as i64;
+                self.set_pc_for_branch(v1 >= v2, imm, size)?;
+            }
+            Instruction::BLTU(rs1, rs2, imm) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_pc_for_branch(v1 < v2, imm, size)?;
+            }
+            Instruction::BGEU(rs1, rs2, imm) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_pc_for_branch(v1 >= v2, imm, size)?;
+            }
+            Instruction::LB(rd, rs1, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 1)?;
+                let byte = memory.read_byte(addr);
+                self.set_register(rd, byte as i8 as u64)
+            }
+            Instruction::LH(rd, rs1, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 2)?;
+                let halfword = memory.read_halfword(addr);
+                self.set_register(rd, halfword as i16 as u64)
+            }
+            Instruction::LW(rd, rs1, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 4)?;
+                let word = memory.read_word(addr);
+                self.set_register(rd, sign_extend_word(word))
+            }
+            Instruction::LD(rd, rs1, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 8)?;
+                let doubleword = read_doubleword(memory, addr);
+                self.set_register(rd, doubleword)
+            }
+            Instruction::LBU(rd, rs1, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 1)?;
+                let byte = memory.read_byte(addr);
+                self.set_register(rd, u64::from(byte))
+            }
+            Instruction::LHU(rd, rs1, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 2)?;
+                let halfword = memory.read_halfword(addr);
+                self.set_register(rd, u64::from(halfword))
+            }
+            Instruction::LWU(rd, rs1, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_load_address(memory, addr, 4)?;
+                let word = memory.read_word(addr);
+                self.set_register(rd, u64::from(word))
+            }
+            Instruction::SB(rs1, rs2, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_store_address(memory, addr, 1)?;
+                memory.write_byte(addr, self.get_register(rs2) as u8)
+            }
+            Instruction::SH(rs1, rs2, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_store_address(memory, addr, 2)?;
+                memory.write_halfword(addr, self.get_register(rs2) as u16)
+            }
+            Instruction::SW(rs1, rs2, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_store_address(memory, addr, 4)?;
+                memory.write_word(addr, self.get_register(rs2) as u32)
+            }
+            Instruction::SD(rs1, rs2, imm) => {
+                let addr = self.calculate_address(rs1, imm);
+                let addr = self.translate_store_address(memory, addr, 8)?;
+                write_doubleword(memory, addr, self.get_register(rs2))
+            }
+            Instruction::ADDI(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1);
+                self.set_register(rd, v1.wrapping_add(sign_extend_word(imm)));
+            }
+            Instruction::SLTI(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1) as i64;
+                let result = v1 < i64::from(imm as i32);
+                self.set_register(rd, result as u64);
+            }
+            Instruction::SLTIU(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1);
+                let result = v1 < sign_extend_word(imm);
+                self.set_register(rd, result as u64);
+            }
+            Instruction::XORI(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1);
+                self.set_register(rd, v1 ^ sign_extend_word(imm));
+            }
+            Instruction::ORI(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1);
+                self.set_register(rd, v1 | sign_extend_word(imm));
+            }
+            Instruction::ANDI(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1);
+                self.set_register(rd, v1 & sign_extend_word(imm));
+            }
+            Instruction::SLLI(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1);
+                self.set_register(rd, v1 << imm);
+            }
+            Instruction::SRLI(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1);
+                self.set_register(rd, v1 >> imm);
+            }
+            Instruction::SRAI(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1) as i64;
+                self.set_register(rd, (v1 >> imm) as u64);
+            }
+            Instruction::ADD(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, v1.wrapping_add(v2));
+            }
+            Instruction::SUB(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, v1.wrapping_sub(v2));
+            }
+            Instruction::SLL(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, v1 << (v2 & 0x3F));
+            }
+            Instruction::SLT(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as i64;
+                let v2 = self.get_register(rs2) as i64;
+                self.set_register(rd, (v1 < v2) as u64);
+            }
+            Instruction::SLTU(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, (v1 < v2) as u64);
+            }
+            Instruction::XOR(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, v1 ^ v2);
+            }
+            Instruction::SRL(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, v1 >> (v2 & 0x3F));
+            }
+            Instruction::SRA(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as i64;
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, (v1 >> (v2 & 0x3F)) as u64);
+            }
+            Instruction::OR(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, v1 | v2);
+            }
+            Instruction::AND(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, v1 & v2);
+            }
+            Instruction::ADDIW(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1) as u32;
+                self.set_register(rd, sign_extend_word(v1.wrapping_add(imm)));
+            }
+            Instruction::SLLIW(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1) as u32;
+                self.set_register(rd, sign_extend_word(v1 << imm));
+            }
+            Instruction::SRLIW(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1) as u32;
+                self.set_register(rd, sign_extend_word(v1 >> imm));
+            }
+            Instruction::SRAIW(rd, rs1, imm) => {
+                let v1 = self.get_register(rs1) as i32;
+                self.set_register(rd, sign_extend_word((v1 >> imm) as u32));
+            }
+            Instruction::ADDW(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as u32;
+                let v2 = self.get_register(rs2) as u32;
+                self.set_register(rd, sign_extend_word(v1.wrapping_add(v2)));
+            }
+            Instruction::SUBW(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as u32;
+                let v2 = self.get_register(rs2) as u32;
+                self.set_register(rd, sign_extend_word(v1.wrapping_sub(v2)));
+            }
+            Instruction::SLLW(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as u32;
+                let v2 = self.get_register(rs2) as u32;
+                self.set_register(rd, sign_extend_word(v1 << (v2 & 0x1F)));
+            }
+            Instruction::SRLW(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as u32;
+                let v2 = self.get_register(rs2) as u32;
+                self.set_register(rd, sign_extend_word(v1 >> (v2 & 0x1F)));
+            }
+            Instruction::SRAW(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as i32;
+                let v2 = self.get_register(rs2) as u32;
+                self.set_register(rd, sign_extend_word((v1 >> (v2 & 0x1F)) as u32));
+            }
+            Instruction::MUL(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, v1.wrapping_mul(v2));
+            }
+            Instruction::MULH(rd, rs1, rs2) => {
+                let v1 = i128::from(self.get_register(rs1) as i64);
+                let v2 = i128::from(self.get_register(rs2) as i64);
+                self.set_register(rd, ((v1 * v2) >> 64) as u64);
+            }
+            Instruction::MULHSU(rd, rs1, rs2) => {
+                let v1 = i128::from(self.get_register(rs1) as i64);
+                let v2 = i128::from(self.get_register(rs2));
+                self.set_register(rd, ((v1 * v2) >> 64) as u64);
+            }
+            Instruction::MULHU(rd, rs1, rs2) => {
+                let v1 = u128::from(self.get_register(rs1));
+                let v2 = u128::from(self.get_register(rs2));
+                self.set_register(rd, ((v1 * v2) >> 64) as u64);
+            }
+            Instruction::DIV(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as i64;
+                let v2 = self.get_register(rs2) as i64;
+                let result = if v2 == 0 { -1 } else { v1.wrapping_div(v2) };
+                self.set_register(rd, result as u64);
+            }
+            Instruction::DIVU(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, v1.checked_div(v2).unwrap_or(u64::MAX));
+            }
+            Instruction::REM(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as i64;
+                let v2 = self.get_register(rs2) as i64;
+                let result = if v2 == 0 { v1 } else { v1.wrapping_rem(v2) };
+                self.set_register(rd, result as u64);
+            }
+            Instruction::REMU(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1);
+                let v2 = self.get_register(rs2);
+                self.set_register(rd, v1.checked_rem(v2).unwrap_or(v1));
+            }
+            Instruction::MULW(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as u32;
+                let v2 = self.get_register(rs2) as u32;
+                self.set_register(rd, sign_extend_word(v1.wrapping_mul(v2)));
+            }
+            Instruction::DIVW(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as i32;
+                let v2 = self.get_register(rs2) as i32;
+                let result = if v2 == 0 { -1 } else { v1.wrapping_div(v2) };
+                self.set_register(rd, sign_extend_word(result as u32));
+            }
+            Instruction::DIVUW(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as u32;
+                let v2 = self.get_register(rs2) as u32;
+                let result = v1.checked_div(v2).unwrap_or(u32::MAX);
+                self.set_register(rd, sign_extend_word(result));
+            }
+            Instruction::REMW(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as i32;
+                let v2 = self.get_register(rs2) as i32;
+                let result = if v2 == 0 { v1 } else { v1.wrapping_rem(v2) };
+                self.set_register(rd, sign_extend_word(result as u32));
+            }
+            Instruction::REMUW(rd, rs1, rs2) => {
+                let v1 = self.get_register(rs1) as u32;
+                let v2 = self.get_register(rs2) as u32;
+                let result = v1.checked_rem(v2).unwrap_or(v1);
+                self.set_register(rd, sign_extend_word(result));
+            }
+            Instruction::ECALL => {
+                return Err(match self.csr.privilege() {
+                    PrivilegeLevel::User => Exception::EnvironmentCallFromUMode,
+                    PrivilegeLevel::Supervisor => Exception::EnvironmentCallFromSMode,
+                    PrivilegeLevel::Machine => Exception::EnvironmentCallFromMMode,
+                });
+            }
+            Instruction::EBREAK => return Err(Exception::Breakpoint),
+            Instruction::MRET => {
+                if self.csr.privilege() != PrivilegeLevel::Machine {
+                    return Err(Exception::IllegalInstruction);
+                }
+                self.pc = self
+                    .csr
+                    .return_from_machine_trap()
+                    .wrapping_sub(u64::from(size));
+            }
+            Instruction::SRET => {
+                self.check_privileged_instruction(MSTATUS_TSR)?;
+                self.pc = self
+                    .csr
+                    .return_from_supervisor_trap()
+                    .wrapping_sub(u64::from(size));
+            }
+            Instruction::WFI => {
+                self.check_privileged_instruction(MSTATUS_TW)?;
+            }
+            // There is no TLB to flush
+            Instruction::SFENCEVMA(_, _) => {
+                self.check_privileged_instruction(MSTATUS_TVM)?;
+            }
+            Instruction::FENCE(_, _) | Instruction::FENCETSO => {}
+            Instruction::FENCEI => self.instruction_cache.flush(),
+            Instruction::LRW(rd, rs1, _) => {
+                let addr = self.load_reserved(memory, rs1, 4)?;
+                let word = memory.read_word(addr);
+                self.set_register(rd, sign_extend_word(word));
+            }
+            Instruction::SCW(rd, rs1, rs2, _) => {
+                let word = self.get_register(rs2) as u32;
+                self.store_conditional(memory, rd, rs1, 4, |memory, addr| {
+                    memory.write_word(addr, word)
+                })?;
+            }
+            Instruction::LRD(rd, rs1, _) => {
+                let addr = self.load_reserved(memory, rs1, 8)?;
+                let doubleword = read_doubleword(memory, addr);
+                self.set_register(rd, doubleword);
+            }
+            Instruction::SCD(rd, rs1, rs2, _) => {
+                let doubleword = self.get_register(rs2);
+                self.store_conditional(memory, rd, rs1, 8, |memory, addr| {
+                    write_doubleword(memory, addr, doubleword)
+                })?;
+            }
+            Instruction::AMOSWAPW(rd, rs1, rs2, _) => {
+                self.atomic_word(memory, rd, rs1, rs2, |_, op2| op2)?
+            }
+            Instruction::AMOADDW(rd, rs1, rs2, _) => {
+                self.atomic_word(memory, rd, rs1, rs2, u32::wrapping_add)?
+            }
+            Instruction::AMOXORW(rd, rs1, rs2, _) => {
+                self.atomic_word(memory, rd, rs1, rs2, |op1, op2| op1 ^ op2)?
+            }
+            Instruction::AMOANDW(rd, rs1, rs2, _) => {
+                self.atomic_word(memory, rd, rs1, rs2, |op1, op2| op1 & op2)?
+            }
+            Instruction::AMOORW(rd, rs1, rs2, _) => {
+                self.atomic_word(memory, rd, rs1, rs2, |op1, op2| op1 | op2)?
+            }
+            Instruction::AMOMINW(rd, rs1, rs2, _) => {
+                self.atomic_word(memory, rd, rs1, rs2, |op1, op2| {
+                    min(op1 as i32, op2 as i32) as u32
+                })?
+            }
+            Instruction::AMOMAXW(rd, rs1, rs2, _) => {
+                self.atomic_word(memory, rd, rs1, rs2, |op1, op2| {
+                    max(op1 as i32, op2 as i32) as u32
+                })?
+            }
+            Instruction::AMOMINUW(rd, rs1, rs2, _) => {
+                self.atomic_word(memory, rd, rs1, rs2, min)?
+            }
+            Instruction::AMOMAXUW(rd, rs1, rs2, _) => {
+                self.atomic_word(memory, rd, rs1, rs2, max)?
+            }
+            Instruction::AMOSWAPD(rd, rs1, rs2, _) => {
+                self.atomic_doubleword(memory, rd, rs1, rs2, |_, op2| op2)?
+            }
+            Instruction::AMOADDD(rd, rs1, rs2, _) => {
+                self.atomic_doubleword(memory, rd, rs1, rs2, u64::wrapping_add)?
+            }
+            Instruction::AMOXORD(rd, rs1, rs2, _) => {
+                self.atomic_doubleword(memory, rd, rs1, rs2, |op1, op2| op1 ^ op2)?
+            }
+            Instruction::AMOANDD(rd, rs1, rs2, _) => {
+                self.atomic_doubleword(memory, rd, rs1, rs2, |op1, op2| op1 & op2)?
+            }
+            Instruction::AMOORD(rd, rs1, rs2, _) => {
+                self.atomic_doubleword(memory, rd, rs1, rs2, |op1, op2| op1 | op2)?
+            }
+            Instruction::AMOMIND(rd, rs1, rs2, _) => {
+                self.atomic_doubleword(memory, rd, rs1, rs2, |op1, op2| {
+                    min(op1 as i64, op2 as i64) as u64
+                })?
+            }
+            Instruction::AMOMAXD(rd, rs1, rs2, _) => {
+                self.atomic_doubleword(memory, rd, rs1, rs2, |op1, op2| {
+                    max(op1 as i64, op2 as i64) as u64
+                })?
+            }
+            Instruction::AMOMINUD(rd, rs1, rs2, _) => {
+                self.atomic_doubleword(memory, rd, rs1, rs2, min)?
+            }
+            Instruction::AMOMAXUD(rd, rs1, rs2, _) => {
+                self.atomic_doubleword(memory, rd, rs1, rs2, max)?
+            }
+            Instruction::CSRRW(rd, rs1, csr) => {
+                let value = self.get_register(rs1);
+                self.swap_csr(rd, csr, value)?;
+            }
+            Instruction::CSRRS(rd, rs1, csr) => {
+                let mask = self.get_register(rs1);
+                self.set_or_clear_csr(rd, csr, mask, true, rs1 != 0)?;
+            }
+            Instruction::CSRRC(rd, rs1, csr) => {
+                let mask = self.get_register(rs1);
+                self.set_or_clear_csr(rd, csr, mask, false, rs1 != 0)?;
+            }
+            Instruction::CSRRWI(rd, uimm, csr) => {
+                self.swap_csr(rd, csr, u64::from(uimm))?;
+            }
+            Instruction::CSRRSI(rd, uimm, csr) => {
+                self.set_or_clear_csr(rd, csr, u64::from(uimm), true, uimm != 0)?;
+            }
+            Instruction::CSRRCI(rd, uimm, csr) => {
+                self.set_or_clear_csr(rd, csr, u64::from(uimm), false, uimm != 0)?;
+            }
+            Instruction::CUSTOM(custom) => {
+                self.custom.execute(&custom, &mut self.registers, memory)?;
+            }
+            _ => return Err(Exception::IllegalInstruction),
+        }
+
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn get_register(&self, num: usize) -> u64 {
+        self.registers[num]
+    }
+
+    #[inline(always)]
+    pub fn set_register(&mut self, num: usize, value: u64) {
+        if num != 0 {
+            self.registers[num] = value
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get_pc(&self) -> u64 {
+        self.pc
+    }
+
+    #[allow(dead_code)]
+    pub fn set_pc(&mut self, value: u64) {
+        self.pc = value;
+    }
+
+    /// Registers the extension implementing the instructions in a custom
+    /// opcode space, which are illegal otherwise
+    pub fn set_custom_instructions(
+        &mut self,
+        opcode: CustomOpcode,
+        extension: impl CustomInstructions + 'static,
+    ) {
+        self.custom.register(opcode, extension);
+        self.instruction_cache.flush();
+    }
+
+    /// Drops all cached instructions. The cache is kept between calls to
+    /// `run` and `step`, and writes through the `AddressSpace` invalidate
+    /// it, so this is only needed before running on a different address
+    /// space.
+    pub fn flush_instruction_cache(&mut self) {
+        self.instruction_cache.flush();
+    }
+
+    /// Formats an instruction, including the custom instructions of the
+    /// registered extensions
+    pub fn disassemble(&self, instruction: &Instruction) -> String {
+        self.custom.disassemble(instruction)
+    }
+
+    /// Enables printing every executed instruction to stderr
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = enabled;
+    }
+
+    /// Selects the timing model which estimates the cycles counted by
+    /// mcycle
+    pub fn set_timing_model(&mut self, timing: TimingModel) {
+        self.timing = timing;
+    }
+
+    /// The cycles estimated by the timing model, see `TimingModel`
+    pub fn get_cycle_counter(&self) -> u64 {
+        self.csr.mcycle()
+    }
+
+    pub fn get_instruction_counter(&self) -> u64 {
+        self.csr.minstret()
+    }
+
+    #[cfg(feature = "debugger")]
+    pub fn add_breakpoint(&mut self, address: u64) {
+        self.breakpoints.insert(address);
+    }
+
+    #[cfg(feature = "debugger")]
+    pub fn remove_breakpoint(&mut self, address: u64) {
+        self.breakpoints.remove(&address);
+    }
+
+    #[cfg(feature = "debugger")]
+    fn is_breakpoint(&self, address: u64) -> bool {
+        self.breakpoints.contains(&address)
+    }
+}
+
+impl Default for Cpu64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn sign_extend_word(value: u32) -> u64 {
+    i64::from(value as i32) as u64
+}
+
+fn read_doubleword(memory: &AddressSpace, address: Address) -> u64 {
+    let low = memory.read_word(address);
+    let high = memory.read_word(address.wrapping_add(4));
+    u64::from(high) << 32 | u64::from(low)
+}
+
+fn write_doubleword(memory: &mut AddressSpace, address: Address, value: u64) {
+    memory.write_word(address, value as u32);
+    memory.write_word(address.wrapping_add(4), (value >> 32) as u32);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::csr::{MCAUSE, MISA, MSCRATCH, MSTATUS};
+    use crate::instruction::AtomicOrdering;
+
+    macro_rules! register_test {
+        ($instr:ident, $result:expr, $first:expr, $second:expr) => {{
+            let mut memory = AddressSpace::new();
+            let mut cpu = Cpu64::new();
+            cpu.set_register(2, $first as u64);
+            cpu.set_register(3, $second as u64);
+
+            cpu.execute_instruction(&Instruction::$instr(1, 2, 3), 4, &mut memory)
+                .unwrap();
+            assert_eq!(cpu.get_register(1), $result as u64);
+        }};
+    }
+
+    macro_rules! immediate_test {
+        ($instr:ident, $result:expr, $first:expr, $second:expr) => {{
+            let mut memory = AddressSpace::new();
+            let mut cpu = Cpu64::new();
+            cpu.set_register(2, $first as u64);
+
+            cpu.execute_instruction(&Instruction::$instr(1, 2, $second as u32), 4, &mut memory)
+                .unwrap();
+            assert_eq!(cpu.get_register(1), $result as u64);
+        }};
+    }
+
+    #[test]
+    fn test_register_ops() {
+        register_test!(ADD, 0x1_0000_0000u64, 0xFFFF_FFFFu64, 1);
+        register_test!(SUB, -1i64, 0, 1);
+        register_test!(SLL, 1u64 << 40, 1, 40);
+        register_test!(SRL, 1, 1u64 << 63, 63);
+        register_test!(SRA, -1i64, 1u64 << 63, 63);
+        register_test!(SLT, 1, -1i64, 0);
+        register_test!(SLTU, 0, -1i64, 0);
+        register_test!(MULH, -1i64, -1i64, 1);
+        register_test!(MULHU, 1, 1u64 << 32, 1u64 << 32);
+        register_test!(MULHSU, -1i64, -1i64, 1);
+        register_test!(DIV, i64::MIN, i64::MIN, -1i64);
+        register_test!(DIVU, u64::MAX, 1, 0);
+        register_test!(REM, 0, i64::MIN, -1i64);
+        register_test!(REMU, 5, 5, 0);
+    }
+
+    #[test]
+    fn test_immediate_ops() {
+        immediate_test!(ADDI, -1i64, 0, 0xFFFF_FFFFu32);
+        immediate_test!(SLTIU, 1, 5, 0xFFFF_FFFFu32);
+        immediate_test!(
+            XORI,
+            0xFFFF_FFFF_0000_0000u64,
+            0xFFFF_FFFFu32,
+            0xFFFF_FFFFu32
+        );
+        immediate_test!(SLLI, 1u64 << 63, 1, 63);
+        immediate_test!(SRAI, i64::MIN >> 40, 1u64 << 63, 40);
+
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu64::new();
+        cpu.execute_instruction(&Instruction::LUI(1, 0x80000), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(1), 0xFFFF_FFFF_8000_0000);
+    }
+
+    #[test]
+    fn test_word_ops() {
+        // Results are sign-extended from 32 bits
+        register_test!(ADDW, 0xFFFF_FFFF_8000_0000u64, 0x7FFF_FFFF, 1);
+        register_test!(SUBW, 0, 0x1_0000_0000u64, 0);
+        register_test!(SLLW, 0xFFFF_FFFF_8000_0000u64, 1, 31 + 32);
+        register_test!(SRLW, 1, 0x8000_0000u64, 31);
+        register_test!(SRAW, -1i64, 0x8000_0000u64, 31);
+        register_test!(MULW, 0xFFFF_FFFF_8000_0000u64, 0x4000_0000, 2);
+        register_test!(DIVW, 0xFFFF_FFFF_8000_0000u64, 0x8000_0000u64, -1i64);
+        register_test!(DIVUW, -1i64, 5, 0);
+        register_test!(REMW, -1i64, -7i64, 2);
+        register_test!(REMUW, 0xFFFF_FFFF_FFFF_FFFEu64, 0xFFFF_FFFEu64, 0);
+        immediate_test!(ADDIW, 0, 0xFFFF_FFFF_0000_0001u64, 0xFFFF_FFFFu32);
+        immediate_test!(SLLIW, 0xFFFF_FFFF_8000_0000u64, 1, 31);
+        immediate_test!(SRLIW, 0x7FFF_FFFF, 0xFFFF_FFFF_FFFF_FFFFu64, 1);
+        immediate_test!(SRAIW, i32::MIN >> 4, 0x8000_0000u64, 4);
+    }
+
+    #[test]
+    fn test_loads_and_stores() {
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu64::new();
+        cpu.set_register(2, 0x1000);
+        cpu.set_register(3, 0x8000_0000_CAFE_BABE);
+
+        cpu.execute_instruction(&Instruction::SD(2, 3, 8), 4, &mut memory)
+            .unwrap();
+        assert_eq!(memory.read_word(0x1008), 0xCAFE_BABE);
+        assert_eq!(memory.read_word(0x100C), 0x8000_0000);
+
+        cpu.execute_instruction(&Instruction::LD(1, 2, 8), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(1), 0x8000_0000_CAFE_BABE);
+        cpu.execute_instruction(&Instruction::LW(1, 2, 8), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(1), 0xFFFF_FFFF_CAFE_BABE);
+        cpu.execute_instruction(&Instruction::LWU(1, 2, 8), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(1), 0xCAFE_BABE);
+
+        // Only the lower 4 GiB are backed by memory
+        cpu.set_register(2, 0x1_0000_1000);
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::LD(1, 2, 0), 4, &mut memory),
+            Err(Exception::LoadAccessFault(0x1_0000_1000))
+        );
+    }
+
+    #[test]
+    fn test_atomics() {
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu64::new();
+        cpu.set_register(2, 0x1000);
+        cpu.set_register(3, 1);
+        write_doubleword(&mut memory, 0x1000, u64::MAX);
+
+        cpu.execute_instruction(
+            &Instruction::AMOADDD(1, 2, 3, AtomicOrdering::Relaxed),
+            4,
+            &mut memory,
+        )
+        .unwrap();
+        assert_eq!(cpu.get_register(1), u64::MAX);
+        assert_eq!(read_doubleword(&memory, 0x1000), 0);
+
+        cpu.execute_instruction(
+            &Instruction::AMOMIND(1, 2, 3, AtomicOrdering::Relaxed),
+            4,
+            &mut memory,
+        )
+        .unwrap();
+        assert_eq!(read_doubleword(&memory, 0x1000), 0);
+
+        // AMO*.W sign-extends the old value
+        memory.write_word(0x1000, 0x8000_0000);
+        cpu.execute_instruction(
+            &Instruction::AMOMAXUW(1, 2, 3, AtomicOrdering::Relaxed),
+            4,
+            &mut memory,
+        )
+        .unwrap();
+        assert_eq!(cpu.get_register(1), 0xFFFF_FFFF_8000_0000);
+        assert_eq!(memory.read_word(0x1000), 0x8000_0000);
+
+        // SC.D fails without a reservation and succeeds after LR.D
+        let sc = Instruction::SCD(5, 2, 3, AtomicOrdering::Release);
+        cpu.set_register(3, 0x1234_5678_9ABC_DEF0);
+        cpu.execute_instruction(&sc, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(5), 1);
+        assert_eq!(read_doubleword(&memory, 0x1000), 0x8000_0000);
+
+        let lr = Instruction::LRD(4, 2, AtomicOrdering::Acquire);
+        cpu.execute_instruction(&lr, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(4), 0x8000_0000);
+        cpu.execute_instruction(&sc, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(5), 0);
+        assert_eq!(read_doubleword(&memory, 0x1000), 0x1234_5678_9ABC_DEF0);
+
+        // A store to the upper word of the reserved doubleword breaks it
+        cpu.execute_instruction(&lr, 4, &mut memory).unwrap();
+        cpu.execute_instruction(&Instruction::SW(2, 0, 4), 4, &mut memory)
+            .unwrap();
+        cpu.execute_instruction(&sc, 4, &mut memory).unwrap();
+        assert_eq!(cpu.get_register(5), 1);
+
+        cpu.set_register(2, 0x1004);
+        let result = cpu.execute_instruction(&lr, 4, &mut memory);
+        assert_eq!(result, Err(Exception::LoadAddressMisaligned(0x1004)));
+    }
+
+    #[test]
+    fn test_csrs() {
+        let mut memory = AddressSpace::new();
+        let mut cpu = Cpu64::new();
+
+        cpu.execute_instruction(&Instruction::CSRRS(1, 0, MISA), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(1) >> 62, 2);
+
+        cpu.set_register(2, 0xCAFE_BABE_0000_0001);
+        cpu.execute_instruction(&Instruction::CSRRW(0, 2, MSCRATCH), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.csr.read(MSCRATCH), Ok(0xCAFE_BABE_0000_0001));
+
+        // There is no FPU on RV64
+        cpu.execute_instruction(&Instruction::CSRRS(1, 0, MSTATUS), 4, &mut memory)
+            .unwrap();
+        assert_eq!(cpu.get_register(1) & (0b11 << 13), 0);
+        assert_eq!(
+            cpu.execute_instruction(&Instruction::FADDS(1, 2, 3, 0), 4, &mut memory),
+            Err(Exception::IllegalInstruction)
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let mut memory = AddressSpace::new();
+        // li x1, -1; srli x1, x1, 32; c.addiw x1, 1; addw x2, x1, x1; ebreak
+        memory.write_word(0x0, 0xfff00093);
+        memory.write_word(0x4, 0x0200d093);
+        memory.write_halfword(0x8, 0x2085);
+        memory.write_word(0xA, 0x0010813b);
+        memory.write_word(0xE, 0x00100073);
+
+        let mut cpu = Cpu64::new();
+        cpu.set_timing_model(TimingModel {
+            base: 2,
+            ..TimingModel::default()
+        });
+        assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+        assert_eq!(cpu.get_register(1), 0);
+        assert_eq!(cpu.get_register(2), 0);
+        assert_eq!(cpu.get_pc(), 0xE);
+        assert_eq!(cpu.get_instruction_counter(), 4);
+        assert_eq!(cpu.get_cycle_counter(), 8);
+    }
+
+    #[test]
+    fn test_trap() {
+        let mut memory = AddressSpace::new();
+        // An illegal instruction, then mcause is read in the trap handler
+        memory.write_word(0x0, 0x0000_0000);
+        memory.write_word(0x100, 0x342020f3); // csrr x1, mcause
+        memory.write_word(0x104, 0x30501073); // csrw mtvec, x0
+        memory.write_word(0x108, 0x00100073); // ebreak
+
+        let mut cpu = Cpu64::new();
+        cpu.csr.write(crate::csr::MTVEC, 0x100).unwrap();
+        assert_eq!(cpu.run(&mut memory), Some(CpuEvent::Halted));
+        assert_eq!(cpu.get_register(1), 2);
+        assert_eq!(cpu.csr.read(MCAUSE), Ok(2));
+    }
+}
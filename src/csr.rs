@@ -0,0 +1,1002 @@
+use crate::exception::Exception;
+use crate::isa::{Extension, Isa, Xlen};
+use crate::pmp::Pmp;
+
+pub const FFLAGS: u32 = 0x001;
+pub const FRM: u32 = 0x002;
+pub const FCSR: u32 = 0x003;
+
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SCOUNTEREN: u32 = 0x106;
+
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+
+pub const SATP: u32 = 0x180;
+
+pub const CYCLE: u32 = 0xC00;
+pub const INSTRET: u32 = 0xC02;
+pub const CYCLEH: u32 = 0xC80;
+pub const INSTRETH: u32 = 0xC82;
+
+pub const MVENDORID: u32 = 0xF11;
+pub const MARCHID: u32 = 0xF12;
+pub const MIMPID: u32 = 0xF13;
+pub const MHARTID: u32 = 0xF14;
+
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MCOUNTEREN: u32 = 0x306;
+
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+
+pub const MCYCLE: u32 = 0xB00;
+pub const MINSTRET: u32 = 0xB02;
+pub const MCYCLEH: u32 = 0xB80;
+pub const MINSTRETH: u32 = 0xB82;
+
+pub const PMPCFG0: u32 = 0x3A0;
+pub const PMPCFG3: u32 = 0x3A3;
+pub const PMPADDR0: u32 = 0x3B0;
+pub const PMPADDR15: u32 = 0x3BF;
+
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+pub const MSTATUS_SD: u64 = 1 << 31;
+pub const MSTATUS_FS: u64 = 0b11 << 13;
+
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_MEIP: u64 = 1 << 11;
+
+pub const SATP_MODE_SV32: u64 = 1 << 31;
+pub const SATP_PPN_MASK: u64 = 0x3F_FFFF;
+
+const MPP_SHIFT: u32 = 11;
+
+const FS_OFF: u64 = 0;
+const FS_INITIAL: u64 = 0b01 << 13;
+
+const FFLAGS_MASK: u32 = 0b1_1111;
+const FRM_SHIFT: u32 = 5;
+const FRM_MASK: u32 = 0b111;
+const FCSR_MASK: u32 = FRM_MASK << FRM_SHIFT | FFLAGS_MASK;
+
+// On RV64, mstatus.UXL and mstatus.SXL are hardwired to 64 bits
+const MSTATUS_UXL_64: u64 = 2 << 32;
+const MSTATUS_SXL_64: u64 = 2 << 34;
+
+// Bits 63:60 of satp hold the translation mode on RV64
+const SATP_MODE_SHIFT_RV64: u32 = 60;
+
+// pmpaddr holds bits 55:2 of the physical address on RV64
+const PMPADDR_MASK_RV64: u64 = (1 << 54) - 1;
+
+const MSTATUS_WRITE_MASK: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR
+    | MSTATUS_FS;
+
+const SSTATUS_MASK: u64 =
+    MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_FS;
+
+const MIE_WRITE_MASK: u64 = MIP_SSIP | MIP_MSIP | MIP_STIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+
+// Pending bits of supervisor interrupts are injected by machine mode software
+const MIP_WRITE_MASK: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+const SUPERVISOR_INTERRUPTS: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+// Environment calls from M-mode can't be delegated
+const MEDELEG_WRITE_MASK: u64 = 0xB3FF;
+
+// Interrupts in the order of their priority, highest priority first
+const INTERRUPT_PRIORITIES: [u64; 6] = [MIP_MEIP, MIP_MSIP, MIP_MTIP, MIP_SEIP, MIP_SSIP, MIP_STIP];
+
+const MTVEC_MODE_VECTORED: u64 = 1;
+
+// The bits of mcounteren and scounteren enabling cycle and instret
+const COUNTEREN_CY: u64 = 1 << 0;
+const COUNTEREN_IR: u64 = 1 << 2;
+
+#[derive(PartialEq, PartialOrd, Debug, Clone, Copy)]
+pub enum PrivilegeLevel {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl PrivilegeLevel {
+    fn from_bits(bits: u64) -> PrivilegeLevel {
+        match bits & 0b11 {
+            0b00 => PrivilegeLevel::User,
+            0b01 => PrivilegeLevel::Supervisor,
+            _ => PrivilegeLevel::Machine,
+        }
+    }
+}
+
+/// The CSRs of a hart. All CSRs are stored with 64 bits, on RV32 only the
+/// lower 32 bits are used.
+pub struct CsrFile {
+    xlen: Xlen,
+    misa: u64,
+    float_supported: bool,
+    privilege: PrivilegeLevel,
+    mstatus: u64,
+    medeleg: u64,
+    mideleg: u64,
+    mie: u64,
+    mip: u64,
+    mtvec: u64,
+    mcounteren: u64,
+    mscratch: u64,
+    mepc: u64,
+    mcause: u64,
+    mtval: u64,
+    stvec: u64,
+    scounteren: u64,
+    sscratch: u64,
+    sepc: u64,
+    scause: u64,
+    stval: u64,
+    satp: u64,
+    pmp: Pmp,
+    fcsr: u32,
+    mcycle: u64,
+    minstret: u64,
+}
+
+impl CsrFile {
+    pub fn new() -> CsrFile {
+        Self::with_xlen(Xlen::Rv32)
+    }
+
+    pub fn with_xlen(xlen: Xlen) -> CsrFile {
+        Self::with_isa(&Isa::supported(xlen))
+    }
+
+    /// Creates the CSRs of a hart implementing `isa`. Without the F
+    /// extension mstatus.FS is hardwired to Off.
+    pub fn with_isa(isa: &Isa) -> CsrFile {
+        let float_supported = isa.extensions().contains(Extension::F);
+        let fs = if float_supported { FS_INITIAL } else { FS_OFF };
+
+        Self {
+            xlen: isa.xlen(),
+            misa: isa.misa(),
+            float_supported,
+            privilege: PrivilegeLevel::Machine,
+            // An MRET without any further setup stays in machine mode. The
+            // FPU is enabled, so that hard-float programs run without a
+            // runtime that sets up mstatus.FS first.
+            mstatus: MSTATUS_MPP | fs,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            stvec: 0,
+            scounteren: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            pmp: Pmp::new(),
+            fcsr: 0,
+            mcycle: 0,
+            minstret: 0,
+        }
+    }
+
+    pub fn xlen(&self) -> Xlen {
+        self.xlen
+    }
+
+    pub fn privilege(&self) -> PrivilegeLevel {
+        self.privilege
+    }
+
+    pub fn set_privilege(&mut self, privilege: PrivilegeLevel) {
+        self.privilege = privilege;
+    }
+
+    pub fn mstatus(&self) -> u64 {
+        self.mstatus
+    }
+
+    pub fn satp(&self) -> u64 {
+        self.satp
+    }
+
+    pub fn mcycle(&self) -> u64 {
+        self.mcycle
+    }
+
+    pub fn minstret(&self) -> u64 {
+        self.minstret
+    }
+
+    /// Counts retired instructions and the cycles they took
+    #[inline(always)]
+    pub fn retire(&mut self, instructions: u64, cycles: u64) {
+        self.minstret = self.minstret.wrapping_add(instructions);
+        self.mcycle = self.mcycle.wrapping_add(cycles);
+    }
+
+    pub fn pmp(&self) -> &Pmp {
+        &self.pmp
+    }
+
+    /// Floating-point instructions and CSRs are illegal while mstatus.FS is
+    /// Off.
+    pub fn is_float_enabled(&self) -> bool {
+        self.mstatus & MSTATUS_FS != FS_OFF
+    }
+
+    /// Marks the floating-point state as modified
+    pub fn set_float_dirty(&mut self) {
+        self.mstatus |= MSTATUS_FS;
+    }
+
+    /// The dynamic rounding mode in frm
+    pub fn rounding_mode(&self) -> u32 {
+        (self.fcsr >> FRM_SHIFT) & FRM_MASK
+    }
+
+    /// Accumulates the exception flags of a floating-point instruction
+    pub fn accrue_float_flags(&mut self, flags: u32) {
+        if flags != 0 {
+            self.fcsr |= flags & FFLAGS_MASK;
+            self.set_float_dirty();
+        }
+    }
+
+    /// The privilege level loads and stores are executed with, which differs
+    /// from the current one in machine mode if mstatus.MPRV is set.
+    pub fn effective_data_privilege(&self) -> PrivilegeLevel {
+        if self.privilege == PrivilegeLevel::Machine && self.mstatus & MSTATUS_MPRV != 0 {
+            PrivilegeLevel::from_bits(self.mstatus >> MPP_SHIFT)
+        } else {
+            self.privilege
+        }
+    }
+
+    /// Traps from below machine mode are handled in supervisor mode if their
+    /// cause has been delegated.
+    fn trap_target(&self, cause: u32, interrupt: bool) -> PrivilegeLevel {
+        let delegation = if interrupt {
+            self.mideleg
+        } else {
+            self.medeleg
+        };
+
+        if self.privilege != PrivilegeLevel::Machine && (delegation >> cause) & 1 != 0 {
+            PrivilegeLevel::Supervisor
+        } else {
+            PrivilegeLevel::Machine
+        }
+    }
+
+    /// The trap vectors are reset to 0, which is where programs are loaded,
+    /// so a trap handler only exists once software has configured one.
+    pub fn has_trap_handler(&self, cause: u32) -> bool {
+        let tvec = match self.trap_target(cause, false) {
+            PrivilegeLevel::Supervisor => self.stvec,
+            _ => self.mtvec,
+        };
+
+        tvec & !0b11 != 0
+    }
+
+    /// Updates the CSRs for entering a trap and returns the address of the
+    /// trap handler. Only interrupts use the vectored mode of xtvec.
+    pub fn enter_trap(&mut self, pc: u64, cause: u32, value: u64, interrupt: bool) -> u64 {
+        let cause_value = if interrupt {
+            u64::from(cause) | self.most_significant_bit()
+        } else {
+            u64::from(cause)
+        };
+
+        let tvec = match self.trap_target(cause, interrupt) {
+            PrivilegeLevel::Supervisor => {
+                self.sepc = pc & !1;
+                self.scause = cause_value;
+                self.stval = value;
+
+                let spie = if self.mstatus & MSTATUS_SIE != 0 {
+                    MSTATUS_SPIE
+                } else {
+                    0
+                };
+                let spp = if self.privilege == PrivilegeLevel::Supervisor {
+                    MSTATUS_SPP
+                } else {
+                    0
+                };
+                self.mstatus &= !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+                self.mstatus |= spie | spp;
+
+                self.privilege = PrivilegeLevel::Supervisor;
+                self.stvec
+            }
+            _ => {
+                self.mepc = pc & !1;
+                self.mcause = cause_value;
+                self.mtval = value;
+
+                let mpie = if self.mstatus & MSTATUS_MIE != 0 {
+                    MSTATUS_MPIE
+                } else {
+                    0
+                };
+                self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+                self.mstatus |= mpie | (self.privilege as u64) << MPP_SHIFT;
+
+                self.privilege = PrivilegeLevel::Machine;
+                self.mtvec
+            }
+        };
+
+        let base = tvec & !0b11;
+        if interrupt && tvec & 0b11 == MTVEC_MODE_VECTORED {
+            base.wrapping_add(4 * u64::from(cause))
+        } else {
+            base
+        }
+    }
+
+    /// Updates the CSRs for MRET and returns the address to continue at
+    pub fn return_from_machine_trap(&mut self) -> u64 {
+        let mie = if self.mstatus & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+
+        self.privilege = PrivilegeLevel::from_bits(self.mstatus >> MPP_SHIFT);
+        if self.privilege != PrivilegeLevel::Machine {
+            self.mstatus &= !MSTATUS_MPRV;
+        }
+
+        self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPP);
+        self.mstatus |= mie | MSTATUS_MPIE;
+
+        self.mepc
+    }
+
+    /// Updates the CSRs for SRET and returns the address to continue at
+    pub fn return_from_supervisor_trap(&mut self) -> u64 {
+        let sie = if self.mstatus & MSTATUS_SPIE != 0 {
+            MSTATUS_SIE
+        } else {
+            0
+        };
+
+        self.privilege = if self.mstatus & MSTATUS_SPP != 0 {
+            PrivilegeLevel::Supervisor
+        } else {
+            PrivilegeLevel::User
+        };
+
+        self.mstatus &= !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV);
+        self.mstatus |= sie | MSTATUS_SPIE;
+
+        self.sepc
+    }
+
+    pub fn set_external_interrupt_pending(&mut self, pending: bool) {
+        if pending {
+            self.mip |= MIP_MEIP;
+        } else {
+            self.mip &= !MIP_MEIP;
+        }
+    }
+
+    /// Returns the cause of the highest priority interrupt that is pending,
+    /// enabled and not masked by the current privilege level.
+    pub fn pending_interrupt(&self) -> Option<u32> {
+        let pending = self.mip & self.mie;
+
+        let machine_enabled =
+            self.privilege < PrivilegeLevel::Machine || self.mstatus & MSTATUS_MIE != 0;
+        let supervisor_enabled = self.privilege < PrivilegeLevel::Supervisor
+            || (self.privilege == PrivilegeLevel::Supervisor && self.mstatus & MSTATUS_SIE != 0);
+
+        let mut interrupts = 0;
+        if machine_enabled {
+            interrupts |= pending & !self.mideleg;
+        }
+        if supervisor_enabled {
+            interrupts |= pending & self.mideleg;
+        }
+
+        INTERRUPT_PRIORITIES
+            .iter()
+            .find(|&&bit| interrupts & bit != 0)
+            .map(|bit| bit.trailing_zeros())
+    }
+
+    pub fn read(&self, address: u32) -> Result<u64, Exception> {
+        self.check_access(address)?;
+
+        let value = match address {
+            FFLAGS => u64::from(self.fcsr & FFLAGS_MASK),
+            FRM => u64::from(self.rounding_mode()),
+            FCSR => u64::from(self.fcsr),
+            SSTATUS => {
+                self.read_mstatus() & (SSTATUS_MASK | MSTATUS_UXL_64 | self.most_significant_bit())
+            }
+            SIE => self.mie & self.mideleg,
+            STVEC => self.stvec,
+            SCOUNTEREN => self.scounteren,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => self.mip & self.mideleg,
+            SATP => self.satp,
+            CYCLE | MCYCLE => self.lower_xlen_bits(self.mcycle),
+            INSTRET | MINSTRET => self.lower_xlen_bits(self.minstret),
+            CYCLEH | MCYCLEH if self.xlen == Xlen::Rv32 => self.mcycle >> 32,
+            INSTRETH | MINSTRETH if self.xlen == Xlen::Rv32 => self.minstret >> 32,
+            MVENDORID | MARCHID | MIMPID | MHARTID => 0,
+            MSTATUS => self.read_mstatus(),
+            MISA => self.misa,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MCOUNTEREN => self.mcounteren,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip,
+            PMPCFG0..=PMPCFG3 => self.read_pmp_config(address - PMPCFG0)?,
+            PMPADDR0..=PMPADDR15 => self.pmp.read_address((address - PMPADDR0) as usize),
+            _ => return Err(Exception::IllegalInstruction),
+        };
+
+        Ok(value)
+    }
+
+    pub fn write(&mut self, address: u32, value: u64) -> Result<(), Exception> {
+        self.check_access(address)?;
+
+        if is_read_only(address) {
+            return Err(Exception::IllegalInstruction);
+        }
+
+        let value = match self.xlen {
+            Xlen::Rv32 => value & 0xFFFF_FFFF,
+            Xlen::Rv64 => value,
+        };
+        let word = value as u32;
+
+        match address {
+            FFLAGS => {
+                self.fcsr = (self.fcsr & !FFLAGS_MASK) | (word & FFLAGS_MASK);
+                self.set_float_dirty();
+            }
+            FRM => {
+                self.fcsr = (self.fcsr & FFLAGS_MASK) | (word & FRM_MASK) << FRM_SHIFT;
+                self.set_float_dirty();
+            }
+            FCSR => {
+                self.fcsr = word & FCSR_MASK;
+                self.set_float_dirty();
+            }
+            SSTATUS => {
+                let mask = SSTATUS_MASK & self.mstatus_write_mask();
+                self.mstatus = (self.mstatus & !mask) | (value & mask);
+            }
+            SIE => {
+                self.mie = (self.mie & !self.mideleg) | (value & self.mideleg & MIE_WRITE_MASK);
+            }
+            STVEC => self.stvec = value & !0b10,
+            SCOUNTEREN => self.scounteren = value,
+            SSCRATCH => self.sscratch = value,
+            SEPC => self.sepc = value & !1,
+            SCAUSE => self.scause = value,
+            STVAL => self.stval = value,
+            // Only the software interrupt pending bit is writable in S-mode
+            SIP => {
+                let mask = self.mideleg & MIP_SSIP;
+                self.mip = (self.mip & !mask) | (value & mask);
+            }
+            // Only Bare and Sv32 are valid modes on RV32, so any value can be
+            // written. RV64 only supports Bare, writes of other modes are
+            // ignored.
+            SATP => match self.xlen {
+                Xlen::Rv32 => self.satp = value,
+                Xlen::Rv64 if value >> SATP_MODE_SHIFT_RV64 == 0 => self.satp = value,
+                Xlen::Rv64 => {}
+            },
+            MSTATUS => {
+                let mut value = value;
+                // MPP is WARL, the reserved value 0b10 keeps the old mode
+                if (value & MSTATUS_MPP) >> MPP_SHIFT == 0b10 {
+                    value = (value & !MSTATUS_MPP) | (self.mstatus & MSTATUS_MPP);
+                }
+                let mask = self.mstatus_write_mask();
+                self.mstatus = (self.mstatus & !mask) | (value & mask)
+            }
+            // Writes to misa are ignored, the set of extensions is fixed
+            MISA => {}
+            MEDELEG => self.medeleg = value & MEDELEG_WRITE_MASK,
+            MIDELEG => self.mideleg = value & SUPERVISOR_INTERRUPTS,
+            MIE => self.mie = value & MIE_WRITE_MASK,
+            // Only direct (0) and vectored (1) mode are valid
+            MTVEC => self.mtvec = value & !0b10,
+            MCOUNTEREN => self.mcounteren = value,
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value & !1,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            MIP => self.mip = (self.mip & !MIP_WRITE_MASK) | (value & MIP_WRITE_MASK),
+            MCYCLE => self.mcycle = self.replace_lower_xlen_bits(self.mcycle, value),
+            MCYCLEH if self.xlen == Xlen::Rv32 => {
+                self.mcycle = (self.mcycle & 0xFFFF_FFFF) | value << 32
+            }
+            // The written value takes precedence over retiring the CSR
+            // instruction, which is counted afterwards
+            MINSTRET => {
+                self.minstret = self
+                    .replace_lower_xlen_bits(self.minstret, value)
+                    .wrapping_sub(1)
+            }
+            MINSTRETH if self.xlen == Xlen::Rv32 => {
+                self.minstret = ((self.minstret & 0xFFFF_FFFF) | value << 32).wrapping_sub(1)
+            }
+            PMPCFG0..=PMPCFG3 => self.write_pmp_config(address - PMPCFG0, value)?,
+            PMPADDR0..=PMPADDR15 => {
+                let value = match self.xlen {
+                    Xlen::Rv32 => value,
+                    Xlen::Rv64 => value & PMPADDR_MASK_RV64,
+                };
+                self.pmp.write_address((address - PMPADDR0) as usize, value)
+            }
+            _ => return Err(Exception::IllegalInstruction),
+        }
+
+        Ok(())
+    }
+
+    /// On RV32, pmpcfg0-3 each hold the configuration of four entries. On
+    /// RV64, pmpcfg0 and pmpcfg2 hold eight entries each and the odd numbered
+    /// registers don't exist.
+    fn read_pmp_config(&self, index: u32) -> Result<u64, Exception> {
+        let index = index as usize;
+
+        match self.xlen {
+            Xlen::Rv32 => Ok(u64::from(self.pmp.read_config(index))),
+            Xlen::Rv64 if index % 2 == 0 => Ok(u64::from(self.pmp.read_config(index))
+                | u64::from(self.pmp.read_config(index + 1)) << 32),
+            Xlen::Rv64 => Err(Exception::IllegalInstruction),
+        }
+    }
+
+    fn write_pmp_config(&mut self, index: u32, value: u64) -> Result<(), Exception> {
+        let index = index as usize;
+
+        match self.xlen {
+            Xlen::Rv32 => self.pmp.write_config(index, value as u32),
+            Xlen::Rv64 if index % 2 == 0 => {
+                self.pmp.write_config(index, value as u32);
+                self.pmp.write_config(index + 1, (value >> 32) as u32);
+            }
+            Xlen::Rv64 => return Err(Exception::IllegalInstruction),
+        }
+
+        Ok(())
+    }
+
+    /// There is no FPU on RV64, so mstatus.FS is hardwired to Off there
+    fn mstatus_write_mask(&self) -> u64 {
+        if self.float_supported {
+            MSTATUS_WRITE_MASK
+        } else {
+            MSTATUS_WRITE_MASK & !MSTATUS_FS
+        }
+    }
+
+    /// The most significant bit of an XLEN wide CSR, which is the interrupt
+    /// flag of xcause and mstatus.SD
+    fn most_significant_bit(&self) -> u64 {
+        1 << (self.xlen.bits() - 1)
+    }
+
+    /// The part of a 64 bit counter read through its CSR, on RV32 the upper
+    /// half is read through a separate CSR
+    fn lower_xlen_bits(&self, counter: u64) -> u64 {
+        match self.xlen {
+            Xlen::Rv32 => counter & 0xFFFF_FFFF,
+            Xlen::Rv64 => counter,
+        }
+    }
+
+    fn replace_lower_xlen_bits(&self, counter: u64, value: u64) -> u64 {
+        match self.xlen {
+            Xlen::Rv32 => (counter & !0xFFFF_FFFF) | value,
+            Xlen::Rv64 => value,
+        }
+    }
+
+    /// Below machine mode, cycle and instret can only be read if they are
+    /// enabled in mcounteren, and in user mode in scounteren as well
+    fn is_counter_enabled(&self, address: u32) -> bool {
+        let bit = match address {
+            CYCLE | CYCLEH => COUNTEREN_CY,
+            INSTRET | INSTRETH => COUNTEREN_IR,
+            _ => return true,
+        };
+
+        match self.privilege {
+            PrivilegeLevel::Machine => true,
+            PrivilegeLevel::Supervisor => self.mcounteren & bit != 0,
+            PrivilegeLevel::User => self.mcounteren & self.scounteren & bit != 0,
+        }
+    }
+
+    /// SD summarizes whether FS is Dirty. On RV64, UXL and SXL report that
+    /// lower privilege levels run with 64 bits as well.
+    fn read_mstatus(&self) -> u64 {
+        let mut mstatus = self.mstatus;
+
+        if mstatus & MSTATUS_FS == MSTATUS_FS {
+            mstatus |= self.most_significant_bit();
+        }
+        if self.xlen == Xlen::Rv64 {
+            mstatus |= MSTATUS_UXL_64 | MSTATUS_SXL_64;
+        }
+
+        mstatus
+    }
+
+    /// CSRs can only be accessed from the privilege level encoded in bits
+    /// 9:8 of their address or above. Additionally, mstatus.TVM traps
+    /// accesses to satp in supervisor mode, the floating-point CSRs can't be
+    /// accessed while the FPU is off and the counters while they aren't
+    /// enabled for the privilege level.
+    fn check_access(&self, address: u32) -> Result<(), Exception> {
+        let required_privilege = PrivilegeLevel::from_bits(u64::from(address >> 8));
+
+        if self.privilege < required_privilege
+            || (address == SATP
+                && self.privilege == PrivilegeLevel::Supervisor
+                && self.mstatus & MSTATUS_TVM != 0)
+            || ((FFLAGS..=FCSR).contains(&address) && !self.is_float_enabled())
+            || !self.is_counter_enabled(address)
+        {
+            Err(Exception::IllegalInstruction)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for CsrFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CSRs with the top two address bits set are read-only by convention
+fn is_read_only(address: u32) -> bool {
+    (address >> 10) & 0b11 == 0b11
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_only() {
+        let mut csr = CsrFile::new();
+
+        assert_eq!(csr.read(MHARTID), Ok(0));
+        assert_eq!(csr.write(MHARTID, 1), Err(Exception::IllegalInstruction));
+        assert_eq!(csr.write(MVENDORID, 1), Err(Exception::IllegalInstruction));
+    }
+
+    #[test]
+    fn test_unknown_csr() {
+        let mut csr = CsrFile::new();
+
+        assert_eq!(csr.read(0x7FF), Err(Exception::IllegalInstruction));
+        assert_eq!(csr.write(0x7FF, 0), Err(Exception::IllegalInstruction));
+    }
+
+    #[test]
+    fn test_misa() {
+        let mut csr = CsrFile::new();
+
+        assert_eq!(csr.read(MISA), Ok(0x4014_112D));
+        assert_eq!(csr.write(MISA, 0), Ok(()));
+        assert_eq!(csr.read(MISA), Ok(0x4014_112D));
+
+        let mut csr = CsrFile::with_isa(&"rv32imc_zicsr".parse().unwrap());
+        assert_eq!(csr.read(MISA), Ok(0x4014_1104));
+        assert_eq!(csr.read(FCSR), Err(Exception::IllegalInstruction));
+        csr.write(MSTATUS, MSTATUS_FS).unwrap();
+        assert!(!csr.is_float_enabled());
+    }
+
+    #[test]
+    fn test_mstatus() {
+        let mut csr = CsrFile::new();
+
+        csr.write(MSTATUS, !0).unwrap();
+        assert_eq!(csr.read(MSTATUS), Ok(MSTATUS_WRITE_MASK | MSTATUS_SD));
+        assert_eq!(csr.read(SSTATUS), Ok(SSTATUS_MASK | MSTATUS_SD));
+
+        csr.write(MSTATUS, 0).unwrap();
+        assert_eq!(csr.read(MSTATUS), Ok(0));
+
+        // MPP can't be set to the reserved value 0b10
+        csr.write(MSTATUS, 0b10 << MPP_SHIFT).unwrap();
+        assert_eq!(csr.read(MSTATUS), Ok(0));
+    }
+
+    #[test]
+    fn test_trap_entry_and_return() {
+        let mut csr = CsrFile::new();
+        csr.write(MTVEC, 0x1000).unwrap();
+        csr.write(MSTATUS, MSTATUS_MIE).unwrap();
+
+        assert_eq!(csr.enter_trap(0x80, 2, 0, false), 0x1000);
+        assert_eq!(csr.read(MEPC), Ok(0x80));
+        assert_eq!(csr.read(MCAUSE), Ok(2));
+        assert_eq!(csr.read(MSTATUS), Ok(MSTATUS_MPIE | MSTATUS_MPP));
+
+        assert_eq!(csr.return_from_machine_trap(), 0x80);
+        assert_eq!(csr.read(MSTATUS), Ok(MSTATUS_MIE | MSTATUS_MPIE));
+        assert_eq!(csr.privilege(), PrivilegeLevel::Machine);
+    }
+
+    #[test]
+    fn test_privilege_change() {
+        let mut csr = CsrFile::new();
+        csr.write(MTVEC, 0x1000).unwrap();
+        csr.write(MSTATUS, 0).unwrap();
+        csr.write(MEPC, 0x80).unwrap();
+
+        assert_eq!(csr.return_from_machine_trap(), 0x80);
+        assert_eq!(csr.privilege(), PrivilegeLevel::User);
+        assert_eq!(csr.read(MSTATUS), Err(Exception::IllegalInstruction));
+
+        csr.enter_trap(0x84, 8, 0, false);
+        assert_eq!(csr.privilege(), PrivilegeLevel::Machine);
+        assert_eq!(csr.read(MSTATUS), Ok(0));
+    }
+
+    #[test]
+    fn test_delegation() {
+        let mut csr = CsrFile::new();
+        csr.write(MTVEC, 0x1000).unwrap();
+        csr.write(STVEC, 0x2000).unwrap();
+        csr.write(MEDELEG, 1 << 8).unwrap();
+        csr.write(MSTATUS, MSTATUS_SIE).unwrap();
+
+        // Delegation only applies to traps from below machine mode
+        assert_eq!(csr.enter_trap(0x80, 8, 0, false), 0x1000);
+
+        csr.set_privilege(PrivilegeLevel::User);
+        assert_eq!(csr.enter_trap(0x84, 8, 0, false), 0x2000);
+        assert_eq!(csr.privilege(), PrivilegeLevel::Supervisor);
+        assert_eq!(csr.read(SEPC), Ok(0x84));
+        assert_eq!(csr.read(SCAUSE), Ok(8));
+        assert_eq!(csr.read(SSTATUS), Ok(MSTATUS_SPIE));
+
+        // Undelegated exceptions still go to machine mode
+        assert_eq!(csr.enter_trap(0x88, 2, 0, false), 0x1000);
+        assert_eq!(csr.read(MSTATUS).unwrap() & MSTATUS_MPP, 1 << MPP_SHIFT);
+
+        csr.return_from_machine_trap();
+        assert_eq!(csr.privilege(), PrivilegeLevel::Supervisor);
+        assert_eq!(csr.return_from_supervisor_trap(), 0x84);
+        assert_eq!(csr.privilege(), PrivilegeLevel::User);
+    }
+
+    #[test]
+    fn test_csr_privilege() {
+        let mut csr = CsrFile::new();
+        csr.set_privilege(PrivilegeLevel::Supervisor);
+
+        assert_eq!(csr.write(SSCRATCH, 1), Ok(()));
+        assert_eq!(csr.read(MSCRATCH), Err(Exception::IllegalInstruction));
+        assert_eq!(csr.write(SATP, 1), Ok(()));
+
+        csr.set_privilege(PrivilegeLevel::Machine);
+        csr.write(MSTATUS, MSTATUS_TVM).unwrap();
+        csr.set_privilege(PrivilegeLevel::Supervisor);
+        assert_eq!(csr.read(SATP), Err(Exception::IllegalInstruction));
+
+        csr.set_privilege(PrivilegeLevel::User);
+        assert_eq!(csr.read(SSCRATCH), Err(Exception::IllegalInstruction));
+    }
+
+    #[test]
+    fn test_vectored_interrupts() {
+        let mut csr = CsrFile::new();
+        csr.write(MTVEC, 0x1001).unwrap();
+
+        assert_eq!(csr.enter_trap(0x80, 11, 0, true), 0x1000 + 4 * 11);
+        assert_eq!(csr.read(MCAUSE), Ok(1 << 31 | 11));
+
+        // Exceptions always use the base address
+        assert_eq!(csr.enter_trap(0x80, 2, 0, false), 0x1000);
+    }
+
+    #[test]
+    fn test_pending_interrupt() {
+        let mut csr = CsrFile::new();
+        csr.set_external_interrupt_pending(true);
+        assert_eq!(csr.pending_interrupt(), None);
+
+        csr.write(MIE, MIP_MEIP).unwrap();
+        assert_eq!(csr.pending_interrupt(), None);
+
+        csr.write(MSTATUS, MSTATUS_MIE).unwrap();
+        assert_eq!(csr.pending_interrupt(), Some(11));
+
+        csr.set_external_interrupt_pending(false);
+        assert_eq!(csr.pending_interrupt(), None);
+    }
+
+    #[test]
+    fn test_supervisor_interrupts() {
+        let mut csr = CsrFile::new();
+        csr.write(MIDELEG, MIP_STIP).unwrap();
+        csr.write(MIE, MIP_STIP | MIP_MTIP).unwrap();
+        csr.write(MIP, MIP_STIP).unwrap();
+
+        // Delegated interrupts are never taken in machine mode
+        csr.write(MSTATUS, MSTATUS_MIE | MSTATUS_SIE).unwrap();
+        assert_eq!(csr.pending_interrupt(), None);
+
+        csr.set_privilege(PrivilegeLevel::Supervisor);
+        assert_eq!(csr.pending_interrupt(), Some(5));
+        assert_eq!(csr.read(SIP), Ok(MIP_STIP));
+
+        csr.set_privilege(PrivilegeLevel::Machine);
+        csr.write(MSTATUS, 0).unwrap();
+        csr.set_privilege(PrivilegeLevel::Supervisor);
+        assert_eq!(csr.pending_interrupt(), None);
+
+        csr.set_privilege(PrivilegeLevel::User);
+        assert_eq!(csr.pending_interrupt(), Some(5));
+    }
+
+    #[test]
+    fn test_warl_fields() {
+        let mut csr = CsrFile::new();
+
+        csr.write(MEPC, 0x1235).unwrap();
+        assert_eq!(csr.read(MEPC), Ok(0x1234));
+
+        csr.write(MTVEC, 0x1003).unwrap();
+        assert_eq!(csr.read(MTVEC), Ok(0x1001));
+
+        csr.write(MSCRATCH, 0xCAFEBABE).unwrap();
+        assert_eq!(csr.read(MSCRATCH), Ok(0xCAFEBABE));
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut csr = CsrFile::new();
+        csr.retire(3, 0x1_0000_0005);
+        assert_eq!(csr.read(MCYCLE), Ok(5));
+        assert_eq!(csr.read(MCYCLEH), Ok(1));
+        assert_eq!(csr.read(INSTRET), Ok(3));
+        assert_eq!(csr.write(INSTRET, 0), Err(Exception::IllegalInstruction));
+
+        csr.write(MCYCLEH, 2).unwrap();
+        csr.write(MINSTRET, 10).unwrap();
+        csr.retire(1, 1);
+        assert_eq!(csr.mcycle(), 0x2_0000_0006);
+        assert_eq!(csr.minstret(), 10);
+
+        // User mode needs both mcounteren and scounteren
+        csr.write(MCOUNTEREN, COUNTEREN_CY).unwrap();
+        csr.set_privilege(PrivilegeLevel::Supervisor);
+        assert_eq!(csr.read(CYCLE), Ok(6));
+        assert_eq!(csr.read(INSTRET), Err(Exception::IllegalInstruction));
+        csr.set_privilege(PrivilegeLevel::User);
+        assert_eq!(csr.read(CYCLE), Err(Exception::IllegalInstruction));
+
+        let mut csr = CsrFile::with_xlen(Xlen::Rv64);
+        csr.retire(1, 0x1_0000_0000);
+        assert_eq!(csr.read(CYCLE), Ok(0x1_0000_0000));
+        assert_eq!(csr.read(CYCLEH), Err(Exception::IllegalInstruction));
+    }
+
+    #[test]
+    fn test_rv64() {
+        let mut csr = CsrFile::with_xlen(Xlen::Rv64);
+
+        assert_eq!(csr.read(MISA), Ok(0x8000_0000_0014_1105));
+        assert_eq!(csr.read(MSTATUS), Ok(MSTATUS_MPP | 0b1010 << 32));
+        assert_eq!(csr.read(FCSR), Err(Exception::IllegalInstruction));
+
+        csr.write(MSCRATCH, 0xCAFE_BABE_0000_0000).unwrap();
+        assert_eq!(csr.read(MSCRATCH), Ok(0xCAFE_BABE_0000_0000));
+
+        // Only Bare mode is supported
+        csr.write(SATP, 8 << 60).unwrap();
+        assert_eq!(csr.read(SATP), Ok(0));
+
+        // pmpcfg0 holds the configuration of eight entries
+        csr.write(PMPCFG0, 0x1F << 56).unwrap();
+        assert_eq!(csr.read(PMPCFG0), Ok(0x1F << 56));
+        assert_eq!(csr.read(PMPCFG0 + 1), Err(Exception::IllegalInstruction));
+
+        csr.write(MTVEC, 0x1001).unwrap();
+        csr.enter_trap(0x80, 11, 0, true);
+        assert_eq!(csr.read(MCAUSE), Ok(1 << 63 | 11));
+    }
+
+    #[test]
+    fn test_float_csrs() {
+        let mut csr = CsrFile::new();
+
+        csr.write(FCSR, 0xFFFF_FFFF).unwrap();
+        assert_eq!(csr.read(FCSR), Ok(0xFF));
+        assert_eq!(csr.read(FRM), Ok(0b111));
+        assert_eq!(csr.read(FFLAGS), Ok(0b1_1111));
+        assert_eq!(csr.read(MSTATUS).unwrap() & MSTATUS_SD, MSTATUS_SD);
+
+        csr.write(FRM, 0b001).unwrap();
+        csr.write(FFLAGS, 0).unwrap();
+        assert_eq!(csr.read(FCSR), Ok(0b001_00000));
+        csr.accrue_float_flags(0b10);
+        assert_eq!(csr.read(FCSR), Ok(0b001_00010));
+
+        // The FPU can be switched off
+        csr.write(MSTATUS, 0).unwrap();
+        assert!(!csr.is_float_enabled());
+        assert_eq!(csr.read(FCSR), Err(Exception::IllegalInstruction));
+    }
+}
@@ -0,0 +1,269 @@
+use crate::exception::Exception;
+use crate::instruction::Instruction;
+use crate::isa::Xlen;
+use crate::memory::addressspace::AddressSpace;
+
+/// The major opcodes reserved for non-standard extensions
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum CustomOpcode {
+    Custom0,
+    Custom1,
+    Custom2,
+    Custom3,
+}
+
+impl CustomOpcode {
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code & 0b111_1111 {
+            0b000_1011 => Some(CustomOpcode::Custom0),
+            0b010_1011 => Some(CustomOpcode::Custom1),
+            0b101_1011 => Some(CustomOpcode::Custom2),
+            0b111_1011 => Some(CustomOpcode::Custom3),
+            _ => None,
+        }
+    }
+}
+
+/// A 32 bit instruction in one of the custom opcode spaces. The accessors
+/// follow the standard R-type and I-type formats, extensions using other
+/// formats decode the fields from `code` themselves.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct CustomInstruction {
+    pub opcode: CustomOpcode,
+    pub code: u32,
+    /// The operation returned by `CustomInstructions::decode`
+    pub operation: u32,
+}
+
+impl CustomInstruction {
+    pub fn new(opcode: CustomOpcode, code: u32) -> Self {
+        Self {
+            opcode,
+            code,
+            operation: 0,
+        }
+    }
+
+    pub fn rd(&self) -> usize {
+        ((self.code >> 7) & 0b1_1111) as usize
+    }
+
+    pub fn rs1(&self) -> usize {
+        ((self.code >> 15) & 0b1_1111) as usize
+    }
+
+    pub fn rs2(&self) -> usize {
+        ((self.code >> 20) & 0b1_1111) as usize
+    }
+
+    pub fn funct3(&self) -> u32 {
+        (self.code >> 12) & 0b111
+    }
+
+    pub fn funct7(&self) -> u32 {
+        self.code >> 25
+    }
+
+    /// The sign-extended 12 bit immediate of the I-type format
+    pub fn immediate(&self) -> i32 {
+        self.code as i32 >> 20
+    }
+}
+
+/// The integer registers of a hart. Values are XLEN bits wide, on RV32 the
+/// upper 32 bits are ignored when writing. Writes to x0 are discarded.
+pub trait Registers {
+    fn xlen(&self) -> Xlen;
+    fn get(&self, register: usize) -> u64;
+    fn set(&mut self, register: usize, value: u64);
+}
+
+impl Registers for [u32; 32] {
+    fn xlen(&self) -> Xlen {
+        Xlen::Rv32
+    }
+
+    fn get(&self, register: usize) -> u64 {
+        u64::from(self[register])
+    }
+
+    fn set(&mut self, register: usize, value: u64) {
+        if register != 0 {
+            self[register] = value as u32;
+        }
+    }
+}
+
+impl Registers for [u64; 32] {
+    fn xlen(&self) -> Xlen {
+        Xlen::Rv64
+    }
+
+    fn get(&self, register: usize) -> u64 {
+        self[register]
+    }
+
+    fn set(&mut self, register: usize, value: u64) {
+        if register != 0 {
+            self[register] = value;
+        }
+    }
+}
+
+/// Implements instructions in a custom opcode space, e.g. to prototype an
+/// accelerator. Extensions are registered per opcode with
+/// `Cpu::set_custom_instructions` or `Cpu64::set_custom_instructions`.
+///
+/// Instructions are decoded once, the result is cached together with the
+/// standard instructions, so `decode` shouldn't depend on the state of the
+/// extension.
+pub trait CustomInstructions {
+    /// Decodes an instruction into an operation of the extension, which is
+    /// passed to `execute` in `CustomInstruction::operation`. Encodings the
+    /// extension doesn't implement raise illegal instruction exceptions.
+    fn decode(&self, code: u32) -> Option<u32>;
+
+    /// Executes an instruction, afterwards the hart continues with the next
+    /// one unless an exception is returned. Memory is accessed with physical
+    /// addresses.
+    fn execute(
+        &mut self,
+        instruction: &CustomInstruction,
+        registers: &mut dyn Registers,
+        memory: &mut AddressSpace,
+    ) -> Result<(), Exception>;
+
+    /// Formats the instruction for the disassembly and the trace
+    fn disassemble(&self, instruction: &CustomInstruction) -> String {
+        format!(".insn 0x{:08x}", instruction.code)
+    }
+}
+
+/// The extensions registered for the custom opcodes of a hart
+#[derive(Default)]
+pub struct CustomExtensions {
+    extensions: [Option<Box<dyn CustomInstructions>>; 4],
+}
+
+impl CustomExtensions {
+    pub fn register(&mut self, opcode: CustomOpcode, extension: impl CustomInstructions + 'static) {
+        self.extensions[opcode as usize] = Some(Box::new(extension));
+    }
+
+    /// Completes decoding a custom instruction, which `Instruction::new` only
+    /// identifies by its opcode. Without a registered extension implementing
+    /// it the instruction is invalid.
+    pub fn decode(&self, instruction: Instruction) -> Instruction {
+        match instruction {
+            Instruction::CUSTOM(custom) => {
+                let operation = self.extensions[custom.opcode as usize]
+                    .as_ref()
+                    .and_then(|extension| extension.decode(custom.code));
+
+                match operation {
+                    Some(operation) => Instruction::CUSTOM(CustomInstruction {
+                        operation,
+                        ..custom
+                    }),
+                    None => Instruction::INVALID,
+                }
+            }
+            _ => instruction,
+        }
+    }
+
+    pub fn execute(
+        &mut self,
+        instruction: &CustomInstruction,
+        registers: &mut dyn Registers,
+        memory: &mut AddressSpace,
+    ) -> Result<(), Exception> {
+        match &mut self.extensions[instruction.opcode as usize] {
+            Some(extension) => extension.execute(instruction, registers, memory),
+            None => Err(Exception::IllegalInstruction),
+        }
+    }
+
+    /// Formats an instruction, custom instructions are formatted by their
+    /// extension
+    pub fn disassemble(&self, instruction: &Instruction) -> String {
+        match instruction {
+            Instruction::CUSTOM(custom) => match &self.extensions[custom.opcode as usize] {
+                Some(extension) => extension.disassemble(custom),
+                None => format!(".insn 0x{:08x}", custom.code),
+            },
+            _ => format!("{:?}", instruction),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Multiply-accumulate in custom-0: rd += rs1 * rs2
+    struct MultiplyAccumulate;
+
+    impl CustomInstructions for MultiplyAccumulate {
+        fn decode(&self, code: u32) -> Option<u32> {
+            match (code >> 12) & 0b111 {
+                0b000 => Some(1),
+                _ => None,
+            }
+        }
+
+        fn execute(
+            &mut self,
+            instruction: &CustomInstruction,
+            registers: &mut dyn Registers,
+            _memory: &mut AddressSpace,
+        ) -> Result<(), Exception> {
+            let product = registers.get(instruction.rs1()) * registers.get(instruction.rs2());
+            let sum = registers.get(instruction.rd()) + product;
+            registers.set(instruction.rd(), sum);
+            Ok(())
+        }
+
+        fn disassemble(&self, instruction: &CustomInstruction) -> String {
+            format!(
+                "mac x{}, x{}, x{}",
+                instruction.rd(),
+                instruction.rs1(),
+                instruction.rs2()
+            )
+        }
+    }
+
+    #[test]
+    fn test_custom_instructions() {
+        // mac x1, x2, x3 and an unimplemented encoding
+        let mac = Instruction::new(0x0031_008B);
+        let unknown = Instruction::new(0x0031_108B);
+        assert!(matches!(mac, Instruction::CUSTOM(..)));
+
+        let mut extensions = CustomExtensions::default();
+        assert_eq!(extensions.decode(mac.clone()), Instruction::INVALID);
+        assert_eq!(extensions.disassemble(&mac), ".insn 0x0031008b");
+
+        extensions.register(CustomOpcode::Custom0, MultiplyAccumulate);
+        let decoded = extensions.decode(mac);
+        assert_eq!(extensions.decode(unknown), Instruction::INVALID);
+        assert_eq!(extensions.disassemble(&decoded), "mac x1, x2, x3");
+
+        let mut registers = [0u32; 32];
+        registers[1] = 1;
+        registers[2] = 6;
+        registers[3] = 7;
+        let mut memory = AddressSpace::new();
+        match decoded {
+            Instruction::CUSTOM(custom) => {
+                assert_eq!(custom.operation, 1);
+                extensions
+                    .execute(&custom, &mut registers, &mut memory)
+                    .unwrap();
+            }
+            _ => panic!("not a custom instruction: {:?}", decoded),
+        }
+        assert_eq!(registers[1], 43);
+    }
+}
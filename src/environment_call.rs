@@ -0,0 +1,33 @@
+use crate::cpu::Cpu;
+use crate::memory::addressspace::AddressSpace;
+
+/// What the CPU does after an environment call was handled by the host
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum EnvironmentCallResult {
+    /// Continue with the instruction following the ECALL
+    Return,
+    /// Stop the CPU, like EBREAK does
+    Halt,
+    /// Stop the CPU because the program exited with the given status
+    Exit(i32),
+    /// The handler doesn't implement the call, so the ECALL is reported as a
+    /// fault, just like without a handler
+    Unsupported,
+}
+
+/// Implements environment calls on the host, for programs which don't install
+/// a trap handler themselves. The handler is free to define its own calling
+/// convention, it has access to all registers and to the memory.
+///
+/// Handlers are registered with `Cpu::set_environment_call_handler`, or with
+/// `Cpu::set_semihosting_handler` to service semihosting calls. Only the 32
+/// bit core supports them, as the newlib, semihosting and Linux handlers
+/// implement 32 bit ABIs. `Cpu64` stops with a fault on an ECALL without a
+/// trap handler.
+pub trait EnvironmentCallHandler {
+    fn environment_call(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut AddressSpace,
+    ) -> EnvironmentCallResult;
+}
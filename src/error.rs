@@ -7,6 +7,18 @@ pub enum EmulatorError {
 
     #[error("Invalid ELF file: {0}")]
     ElfFormatError(String),
+
+    #[error("Invalid image file: {0}")]
+    ImageFormatError(String),
+
+    #[error("The program requires {}", .0.join(", "))]
+    IncompatibleProgram(Vec<String>),
+
+    #[error("Unsupported ISA: {0}")]
+    UnsupportedIsa(String),
+
+    #[error("{0} and {1} overlap at 0x{2:x}")]
+    OverlappingImages(String, String, u32),
 }
 
 pub type EmulatorResult<R> = Result<R, EmulatorError>;
@@ -0,0 +1,79 @@
+/// Synchronous exceptions, as defined by the privileged specification. The
+/// faulting addresses are virtual addresses, which are 64 bits wide on RV64.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Exception {
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction,
+    Breakpoint,
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
+}
+
+impl Exception {
+    /// The exception code written to mcause
+    pub fn cause(&self) -> u32 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction => 2,
+            Exception::Breakpoint => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EnvironmentCallFromUMode => 8,
+            Exception::EnvironmentCallFromSMode => 9,
+            Exception::EnvironmentCallFromMMode => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+        }
+    }
+
+    pub fn is_environment_call(&self) -> bool {
+        matches!(
+            self,
+            Exception::EnvironmentCallFromUMode
+                | Exception::EnvironmentCallFromSMode
+                | Exception::EnvironmentCallFromMMode
+        )
+    }
+
+    /// The exception specific value written to mtval
+    pub fn value(&self) -> u64 {
+        match *self {
+            Exception::InstructionAddressMisaligned(address)
+            | Exception::InstructionAccessFault(address)
+            | Exception::LoadAddressMisaligned(address)
+            | Exception::LoadAccessFault(address)
+            | Exception::StoreAddressMisaligned(address)
+            | Exception::StoreAccessFault(address)
+            | Exception::InstructionPageFault(address)
+            | Exception::LoadPageFault(address)
+            | Exception::StorePageFault(address) => address,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cause_and_value() {
+        assert_eq!(Exception::IllegalInstruction.cause(), 2);
+        assert_eq!(Exception::IllegalInstruction.value(), 0);
+        assert_eq!(Exception::StoreAccessFault(0x1234).cause(), 7);
+        assert_eq!(Exception::StoreAccessFault(0x1234).value(), 0x1234);
+    }
+}
@@ -1,22 +1,99 @@
 use crate::memory::addressspace::{Address, AddressSpace, MemoryDevice};
+use std::convert::{TryFrom, TryInto};
 use std::net::TcpListener;
 
 use crate::cpu::{Cpu, CpuEvent};
+use crate::cpu64::Cpu64;
+use crate::exception::Exception;
+use gdbstub::arch::riscv::reg::RiscvCoreRegs;
 use gdbstub::arch::Arch;
 use gdbstub::target;
 use gdbstub::target::ext::base;
 use gdbstub::target::ext::base::singlethread::{SingleThreadOps, StopReason};
 use gdbstub::target::ext::base::ResumeAction;
 use gdbstub::target::ext::breakpoints::SwBreakpoint;
-use gdbstub::target::{Target, TargetResult};
+use gdbstub::target::{Target, TargetError, TargetResult};
 use gdbstub::{arch, DisconnectReason, GdbStub, GdbStubError};
 
-struct RISCVTarget {
+/// The operations of the 32 and 64 bit cores used by the GDB target
+trait DebugCpu {
+    type Register: Copy + TryInto<Address>;
+
+    fn step(&mut self, memory: &mut AddressSpace) -> Option<CpuEvent>;
+    fn get_pc(&self) -> Self::Register;
+    fn set_pc(&mut self, value: Self::Register);
+    fn get_register(&self, num: usize) -> Self::Register;
+    fn set_register(&mut self, num: usize, value: Self::Register);
+    fn add_breakpoint(&mut self, address: Self::Register);
+    fn remove_breakpoint(&mut self, address: Self::Register);
+}
+
+macro_rules! impl_debug_cpu {
+    ($cpu:ty, $register:ty) => {
+        impl DebugCpu for $cpu {
+            type Register = $register;
+
+            fn step(&mut self, memory: &mut AddressSpace) -> Option<CpuEvent> {
+                <$cpu>::step(self, memory)
+            }
+
+            fn get_pc(&self) -> $register {
+                <$cpu>::get_pc(self)
+            }
+
+            fn set_pc(&mut self, value: $register) {
+                <$cpu>::set_pc(self, value)
+            }
+
+            fn get_register(&self, num: usize) -> $register {
+                <$cpu>::get_register(self, num)
+            }
+
+            fn set_register(&mut self, num: usize, value: $register) {
+                <$cpu>::set_register(self, num, value)
+            }
+
+            fn add_breakpoint(&mut self, address: $register) {
+                <$cpu>::add_breakpoint(self, address)
+            }
+
+            fn remove_breakpoint(&mut self, address: $register) {
+                <$cpu>::remove_breakpoint(self, address)
+            }
+        }
+    };
+}
+
+impl_debug_cpu!(Cpu, u32);
+impl_debug_cpu!(Cpu64, u64);
+
+struct RISCVTarget<C> {
     memory: AddressSpace,
-    cpu: Cpu,
+    cpu: C,
 }
 
-impl SingleThreadOps for RISCVTarget {
+impl<C: DebugCpu> RISCVTarget<C> {
+    /// Converts a GDB address into a physical address and checks that
+    /// `size` bytes starting at it are mapped.
+    fn mapped_address(&self, address: C::Register, size: usize) -> Option<Address> {
+        let address = address.try_into().ok()?;
+        let size = u32::try_from(size).ok()?;
+
+        if self.memory.is_mapped(address, size) {
+            Some(address)
+        } else {
+            None
+        }
+    }
+}
+
+impl<C> SingleThreadOps for RISCVTarget<C>
+where
+    C: DebugCpu,
+    RISCVTarget<C>: Target<Error = &'static str>,
+    <RISCVTarget<C> as Target>::Arch:
+        Arch<Usize = C::Register, Registers = RiscvCoreRegs<C::Register>>,
+{
     fn resume(
         &mut self,
         action: ResumeAction,
@@ -44,8 +121,11 @@ impl SingleThreadOps for RISCVTarget {
         };
 
         Ok(match event {
-            CpuEvent::Halted => StopReason::Halted,
+            CpuEvent::Halted | CpuEvent::Exited(_) => StopReason::Halted,
             CpuEvent::Breakpoint => StopReason::SwBreak,
+            CpuEvent::Fault(exception) => StopReason::Signal(signal_for_exception(&exception)),
+            // Only runs with limits stop for these
+            CpuEvent::BudgetExhausted | CpuEvent::Timeout => StopReason::GdbInterrupt,
         })
     }
 
@@ -80,6 +160,10 @@ impl SingleThreadOps for RISCVTarget {
         start_addr: <Self::Arch as Arch>::Usize,
         data: &mut [u8],
     ) -> TargetResult<(), Self> {
+        let start_addr = self
+            .mapped_address(start_addr, data.len())
+            .ok_or(TargetError::NonFatal)?;
+
         for (i, byte) in data.iter_mut().enumerate() {
             *byte = self.memory.read_byte(start_addr + i as Address);
         }
@@ -92,6 +176,10 @@ impl SingleThreadOps for RISCVTarget {
         start_addr: <Self::Arch as Arch>::Usize,
         data: &[u8],
     ) -> TargetResult<(), Self> {
+        let start_addr = self
+            .mapped_address(start_addr, data.len())
+            .ok_or(TargetError::NonFatal)?;
+
         for (i, byte) in data.iter().enumerate() {
             self.memory.write_byte(start_addr + i as Address, *byte);
         }
@@ -100,32 +188,82 @@ impl SingleThreadOps for RISCVTarget {
     }
 }
 
-impl SwBreakpoint for RISCVTarget {
-    fn add_sw_breakpoint(&mut self, addr: u32) -> TargetResult<bool, Self> {
+impl<C> SwBreakpoint for RISCVTarget<C>
+where
+    C: DebugCpu,
+    RISCVTarget<C>: Target,
+    <RISCVTarget<C> as Target>::Arch: Arch<Usize = C::Register>,
+{
+    fn add_sw_breakpoint(&mut self, addr: C::Register) -> TargetResult<bool, Self> {
         self.cpu.add_breakpoint(addr);
         Ok(true)
     }
 
-    fn remove_sw_breakpoint(&mut self, addr: u32) -> TargetResult<bool, Self> {
+    fn remove_sw_breakpoint(&mut self, addr: C::Register) -> TargetResult<bool, Self> {
         self.cpu.remove_breakpoint(addr);
         Ok(true)
     }
 }
 
-impl Target for RISCVTarget {
-    type Arch = arch::riscv::Riscv32;
-    type Error = &'static str;
+macro_rules! impl_target {
+    ($cpu:ty, $arch:ty) => {
+        impl Target for RISCVTarget<$cpu> {
+            type Arch = $arch;
+            type Error = &'static str;
 
-    fn base_ops(&mut self) -> base::BaseOps<Self::Arch, Self::Error> {
-        base::BaseOps::SingleThread(self)
-    }
+            fn base_ops(&mut self) -> base::BaseOps<'_, Self::Arch, Self::Error> {
+                base::BaseOps::SingleThread(self)
+            }
+
+            fn sw_breakpoint(
+                &mut self,
+            ) -> Option<target::ext::breakpoints::SwBreakpointOps<'_, Self>> {
+                Some(self)
+            }
+        }
+    };
+}
+
+impl_target!(Cpu, arch::riscv::Riscv32);
+impl_target!(Cpu64, arch::riscv::Riscv64);
+
+/// Maps unhandled exceptions to the POSIX signal numbers GDB expects
+fn signal_for_exception(exception: &Exception) -> u8 {
+    const SIGILL: u8 = 4;
+    const SIGTRAP: u8 = 5;
+    const SIGBUS: u8 = 7;
+    const SIGSEGV: u8 = 11;
 
-    fn sw_breakpoint(&mut self) -> Option<target::ext::breakpoints::SwBreakpointOps<Self>> {
-        Some(self)
+    match exception {
+        Exception::IllegalInstruction => SIGILL,
+        Exception::InstructionAddressMisaligned(_)
+        | Exception::LoadAddressMisaligned(_)
+        | Exception::StoreAddressMisaligned(_) => SIGBUS,
+        Exception::InstructionAccessFault(_)
+        | Exception::LoadAccessFault(_)
+        | Exception::StoreAccessFault(_)
+        | Exception::InstructionPageFault(_)
+        | Exception::LoadPageFault(_)
+        | Exception::StorePageFault(_) => SIGSEGV,
+        Exception::Breakpoint
+        | Exception::EnvironmentCallFromUMode
+        | Exception::EnvironmentCallFromSMode
+        | Exception::EnvironmentCallFromMMode => SIGTRAP,
     }
 }
 
 pub fn start_server(cpu: Cpu, memory: AddressSpace) {
+    run_server(RISCVTarget { memory, cpu });
+}
+
+pub fn start_server_rv64(cpu: Cpu64, memory: AddressSpace) {
+    run_server(RISCVTarget { memory, cpu });
+}
+
+fn run_server<C>(mut target: RISCVTarget<C>)
+where
+    RISCVTarget<C>: Target<Error = &'static str>,
+{
     let sockaddr = format!("localhost:{}", 3000);
     eprintln!("Waiting for a GDB connection on {:?}...", sockaddr);
     let sock = TcpListener::bind(sockaddr).unwrap();
@@ -133,8 +271,6 @@ pub fn start_server(cpu: Cpu, memory: AddressSpace) {
 
     eprintln!("Debugger connected from {}", addr);
 
-    let mut target = RISCVTarget { memory, cpu };
-
     let mut debugger = GdbStub::new(stream);
 
     match debugger.run(&mut target) {
@@ -150,7 +286,7 @@ pub fn start_server(cpu: Cpu, memory: AddressSpace) {
             // conduct some post-mortem debugging
             debugger.run(&mut target).unwrap();
         }
-        Err(e) => panic!(e.to_string()), //return Err(e.into())
+        Err(e) => panic!("{}", e), //return Err(e.into())
     }
 
     eprintln!("Connection closed");
@@ -1,3 +1,5 @@
+use crate::custom::{CustomInstruction, CustomOpcode};
+use crate::isa::{Extension, Extensions};
 use crate::util::sign_extend;
 
 const OPCODE_MASK: u32 = 0b111_1111;
@@ -7,6 +9,44 @@ const FUNCT3_MASK: u32 = 0b111;
 const IMMEDIATE_20_MASK: u32 = 0b1111_1111_1111_1111_1111;
 const IMMEDIATE_12_MASK: u32 = 0b1111_1111_1111;
 
+/// The memory ordering requested by the aq and rl bits of an atomic
+/// instruction.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AtomicOrdering {
+    Relaxed,
+    Acquire,
+    Release,
+    SequentiallyConsistent,
+}
+
+impl AtomicOrdering {
+    fn from_code(code: u32) -> Self {
+        let acquire = code & (1 << 26) != 0;
+        let release = code & (1 << 25) != 0;
+
+        match (acquire, release) {
+            (false, false) => AtomicOrdering::Relaxed,
+            (true, false) => AtomicOrdering::Acquire,
+            (false, true) => AtomicOrdering::Release,
+            (true, true) => AtomicOrdering::SequentiallyConsistent,
+        }
+    }
+
+    pub fn is_acquire(self) -> bool {
+        matches!(
+            self,
+            AtomicOrdering::Acquire | AtomicOrdering::SequentiallyConsistent
+        )
+    }
+
+    pub fn is_release(self) -> bool {
+        matches!(
+            self,
+            AtomicOrdering::Release | AtomicOrdering::SequentiallyConsistent
+        )
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum Instruction {
     LUI(usize, u32),
@@ -46,7 +86,10 @@ pub enum Instruction {
     SRA(usize, usize, usize),
     OR(usize, usize, usize),
     AND(usize, usize, usize),
+    ECALL,
     EBREAK,
+    FENCE(u32, u32),
+    FENCETSO,
     MUL(usize, usize, usize),
     MULH(usize, usize, usize),
     MULHSU(usize, usize, usize),
@@ -55,26 +98,162 @@ pub enum Instruction {
     DIVU(usize, usize, usize),
     REM(usize, usize, usize),
     REMU(usize, usize, usize),
-    LRW(usize, usize, usize),
-    SCW(usize, usize, usize),
-    AMOSWAPW(usize, usize, usize),
-    AMOADDW(usize, usize, usize),
-    AMOXORW(usize, usize, usize),
-    AMOANDW(usize, usize, usize),
-    AMOORW(usize, usize, usize),
-    AMOMINW(usize, usize, usize),
-    AMOMAXW(usize, usize, usize),
-    AMOMINUW(usize, usize, usize),
-    AMOMAXUW(usize, usize, usize),
+    LRW(usize, usize, AtomicOrdering),
+    SCW(usize, usize, usize, AtomicOrdering),
+    AMOSWAPW(usize, usize, usize, AtomicOrdering),
+    AMOADDW(usize, usize, usize, AtomicOrdering),
+    AMOXORW(usize, usize, usize, AtomicOrdering),
+    AMOANDW(usize, usize, usize, AtomicOrdering),
+    AMOORW(usize, usize, usize, AtomicOrdering),
+    AMOMINW(usize, usize, usize, AtomicOrdering),
+    AMOMAXW(usize, usize, usize, AtomicOrdering),
+    AMOMINUW(usize, usize, usize, AtomicOrdering),
+    AMOMAXUW(usize, usize, usize, AtomicOrdering),
+
+    // RV64I, RV64M and RV64A
+    LWU(usize, usize, i32),
+    LD(usize, usize, i32),
+    SD(usize, usize, i32),
+    ADDIW(usize, usize, u32),
+    SLLIW(usize, usize, u32),
+    SRLIW(usize, usize, u32),
+    SRAIW(usize, usize, u32),
+    ADDW(usize, usize, usize),
+    SUBW(usize, usize, usize),
+    SLLW(usize, usize, usize),
+    SRLW(usize, usize, usize),
+    SRAW(usize, usize, usize),
+    MULW(usize, usize, usize),
+    DIVW(usize, usize, usize),
+    DIVUW(usize, usize, usize),
+    REMW(usize, usize, usize),
+    REMUW(usize, usize, usize),
+    LRD(usize, usize, AtomicOrdering),
+    SCD(usize, usize, usize, AtomicOrdering),
+    AMOSWAPD(usize, usize, usize, AtomicOrdering),
+    AMOADDD(usize, usize, usize, AtomicOrdering),
+    AMOXORD(usize, usize, usize, AtomicOrdering),
+    AMOANDD(usize, usize, usize, AtomicOrdering),
+    AMOORD(usize, usize, usize, AtomicOrdering),
+    AMOMIND(usize, usize, usize, AtomicOrdering),
+    AMOMAXD(usize, usize, usize, AtomicOrdering),
+    AMOMINUD(usize, usize, usize, AtomicOrdering),
+    AMOMAXUD(usize, usize, usize, AtomicOrdering),
+
+    // Zba
+    SH1ADD(usize, usize, usize),
+    SH2ADD(usize, usize, usize),
+    SH3ADD(usize, usize, usize),
+
+    // Zbb
+    ANDN(usize, usize, usize),
+    ORN(usize, usize, usize),
+    XNOR(usize, usize, usize),
+    CLZ(usize, usize),
+    CTZ(usize, usize),
+    CPOP(usize, usize),
+    MAX(usize, usize, usize),
+    MAXU(usize, usize, usize),
+    MIN(usize, usize, usize),
+    MINU(usize, usize, usize),
+    SEXTB(usize, usize),
+    SEXTH(usize, usize),
+    ZEXTH(usize, usize),
+    ROL(usize, usize, usize),
+    ROR(usize, usize, usize),
+    RORI(usize, usize, u32),
+    ORCB(usize, usize),
+    REV8(usize, usize),
+
+    // Zbc
+    CLMUL(usize, usize, usize),
+    CLMULH(usize, usize, usize),
+    CLMULR(usize, usize, usize),
+
+    // Zbs
+    BCLR(usize, usize, usize),
+    BCLRI(usize, usize, u32),
+    BEXT(usize, usize, usize),
+    BEXTI(usize, usize, u32),
+    BINV(usize, usize, usize),
+    BINVI(usize, usize, u32),
+    BSET(usize, usize, usize),
+    BSETI(usize, usize, u32),
     MRET,
+    SRET,
+    WFI,
+    SFENCEVMA(usize, usize),
+
+    // Zifencei
+    FENCEI,
 
     // Zicsr
-    CSRRW,
-    CSRRS,
-    CSRRC,
-    CSRRWI,
-    CSRRSI,
-    CSRRCI,
+    CSRRW(usize, usize, u32),
+    CSRRS(usize, usize, u32),
+    CSRRC(usize, usize, u32),
+    CSRRWI(usize, u32, u32),
+    CSRRSI(usize, u32, u32),
+    CSRRCI(usize, u32, u32),
+
+    // RV32F, the last field of arithmetic instructions is the rounding mode
+    FLW(usize, usize, i32),
+    FSW(usize, usize, i32),
+    FMADDS(usize, usize, usize, usize, u32),
+    FMSUBS(usize, usize, usize, usize, u32),
+    FNMSUBS(usize, usize, usize, usize, u32),
+    FNMADDS(usize, usize, usize, usize, u32),
+    FADDS(usize, usize, usize, u32),
+    FSUBS(usize, usize, usize, u32),
+    FMULS(usize, usize, usize, u32),
+    FDIVS(usize, usize, usize, u32),
+    FSQRTS(usize, usize, u32),
+    FSGNJS(usize, usize, usize),
+    FSGNJNS(usize, usize, usize),
+    FSGNJXS(usize, usize, usize),
+    FMINS(usize, usize, usize),
+    FMAXS(usize, usize, usize),
+    FCVTWS(usize, usize, u32),
+    FCVTWUS(usize, usize, u32),
+    FMVXW(usize, usize),
+    FEQS(usize, usize, usize),
+    FLTS(usize, usize, usize),
+    FLES(usize, usize, usize),
+    FCLASSS(usize, usize),
+    FCVTSW(usize, usize, u32),
+    FCVTSWU(usize, usize, u32),
+    FMVWX(usize, usize),
+
+    // RV32D
+    FLD(usize, usize, i32),
+    FSD(usize, usize, i32),
+    FMADDD(usize, usize, usize, usize, u32),
+    FMSUBD(usize, usize, usize, usize, u32),
+    FNMSUBD(usize, usize, usize, usize, u32),
+    FNMADDD(usize, usize, usize, usize, u32),
+    FADDD(usize, usize, usize, u32),
+    FSUBD(usize, usize, usize, u32),
+    FMULD(usize, usize, usize, u32),
+    FDIVD(usize, usize, usize, u32),
+    FSQRTD(usize, usize, u32),
+    FSGNJD(usize, usize, usize),
+    FSGNJND(usize, usize, usize),
+    FSGNJXD(usize, usize, usize),
+    FMIND(usize, usize, usize),
+    FMAXD(usize, usize, usize),
+    FCVTSD(usize, usize, u32),
+    FCVTDS(usize, usize, u32),
+    FEQD(usize, usize, usize),
+    FLTD(usize, usize, usize),
+    FLED(usize, usize, usize),
+    FCLASSD(usize, usize),
+    FCVTWD(usize, usize, u32),
+    FCVTWUD(usize, usize, u32),
+    FCVTDW(usize, usize, u32),
+    FCVTDWU(usize, usize, u32),
+
+    // custom-0 to custom-3, implemented by the extensions registered with the
+    // hart
+    CUSTOM(CustomInstruction),
 
     INVALID,
 }
@@ -98,6 +277,39 @@ impl WrappedInstruction {
             },
         }
     }
+
+    pub fn new_rv64(code: u32) -> Self {
+        match code & 0b11 {
+            0b11 => WrappedInstruction {
+                instruction: Instruction::new_rv64(code),
+                size: 4,
+            },
+            _ => WrappedInstruction {
+                instruction: Instruction::new_compressed_rv64(code as u16),
+                size: 2,
+            },
+        }
+    }
+
+    /// Decodes an instruction for RV32, treating instructions of extensions
+    /// that aren't enabled as invalid.
+    pub fn decode(code: u32, extensions: Extensions) -> Self {
+        WrappedInstruction::new(code).restrict(extensions)
+    }
+
+    /// Decodes an instruction for RV64, treating instructions of extensions
+    /// that aren't enabled as invalid.
+    pub fn decode_rv64(code: u32, extensions: Extensions) -> Self {
+        WrappedInstruction::new_rv64(code).restrict(extensions)
+    }
+
+    fn restrict(mut self, extensions: Extensions) -> Self {
+        let compressed_enabled = self.size == 4 || extensions.contains(Extension::C);
+        if !compressed_enabled || !extensions.contains(self.instruction.extension()) {
+            self.instruction = INVALID;
+        }
+        self
+    }
 }
 
 use Instruction::*;
@@ -152,34 +364,457 @@ impl Instruction {
             0b001_0011 => Instruction::match_arithmetic_immediate(code),
             0b011_0011 => Instruction::match_arithmetic(code),
             0b111_0011 => {
-                let _rd = shift_and_mask(code, 7, REGISTER_MASK);
+                let rd = shift_and_mask(code, 7, REGISTER_MASK);
                 let funct3 = shift_and_mask(code, 12, FUNCT3_MASK);
-                let _rs1 = shift_and_mask(code, 15, REGISTER_MASK);
+                let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
                 let imm12 = shift_and_mask(code, 20, IMMEDIATE_12_MASK);
 
+                let csr = imm12 as u32;
+                let uimm = rs1 as u32;
+
+                let funct7 = shift_and_mask(code, 25, FUNCT7_MASK);
+                let rs2 = shift_and_mask(code, 20, REGISTER_MASK);
+
                 match funct3 {
+                    0b000 if rd != 0 => INVALID,
+                    0b000 if funct7 == 0b000_1001 => SFENCEVMA(rs1, rs2),
+                    0b000 if rs1 != 0 => INVALID,
                     0b000 => match imm12 {
+                        0b0 => ECALL,
                         0b1 => EBREAK,
+                        0b0001_0000_0010 => SRET,
+                        0b0001_0000_0101 => WFI,
                         0b0011_0000_0010 => MRET,
                         _ => INVALID,
                     },
-                    // TODO: Arg decode!
-                    0b001 => CSRRW,
-                    0b010 => CSRRS,
-                    0b011 => CSRRC,
-                    0b101 => CSRRWI,
-                    0b110 => CSRRSI,
-                    0b111 => CSRRCI,
+                    0b001 => CSRRW(rd, rs1, csr),
+                    0b010 => CSRRS(rd, rs1, csr),
+                    0b011 => CSRRC(rd, rs1, csr),
+                    0b101 => CSRRWI(rd, uimm, csr),
+                    0b110 => CSRRSI(rd, uimm, csr),
+                    0b111 => CSRRCI(rd, uimm, csr),
                     _ => INVALID,
                 }
             }
+            0b000_1111 => Instruction::match_misc_mem(code),
             0b010_1111 => Instruction::match_atomic(code),
+            0b000_0111 => Instruction::match_float_load(code),
+            0b010_0111 => Instruction::match_float_store(code),
+            0b100_0011 | 0b100_0111 | 0b100_1011 | 0b100_1111 => {
+                Instruction::match_fused_multiply_add(code)
+            }
+            0b101_0011 => Instruction::match_float_arithmetic(code),
+            _ => match CustomOpcode::from_code(code) {
+                Some(opcode) => CUSTOM(CustomInstruction::new(opcode, code)),
+                None => INVALID,
+            },
+        }
+    }
+
+    /// Decodes an instruction for RV64, where the shift amounts are 6 bits
+    /// wide and OP-IMM-32 and OP-32 hold the instructions operating on 32 bit
+    /// values. All other opcodes are shared with RV32.
+    pub fn new_rv64(code: u32) -> Self {
+        let opcode = code & OPCODE_MASK;
+        let funct3 = shift_and_mask(code, 12, FUNCT3_MASK);
+
+        match (opcode, funct3) {
+            (0b000_0011, 0b110) | (0b000_0011, 0b011) => {
+                let rd = shift_and_mask(code, 7, REGISTER_MASK);
+                let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
+                let immediate = shift_and_mask(code, 20, IMMEDIATE_12_MASK) as i32;
+                let immediate_sign_extended = sign_extend(immediate, 12);
+
+                if funct3 == 0b110 {
+                    LWU(rd, rs1, immediate_sign_extended)
+                } else {
+                    LD(rd, rs1, immediate_sign_extended)
+                }
+            }
+            (0b010_0011, 0b011) => {
+                let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
+                let rs2 = shift_and_mask(code, 20, REGISTER_MASK);
+
+                let mut immediate = shift_and_mask(code, 7, 0b1_1111) as u32;
+                immediate |= (shift_and_mask(code, 25, 0b111_1111) as u32) << 5;
+
+                SD(rs1, rs2, sign_extend(immediate as i32, 12))
+            }
+            (0b001_0011, 0b001) | (0b001_0011, 0b101) => {
+                let rd = shift_and_mask(code, 7, REGISTER_MASK);
+                let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
+                let shift_amount = shift_and_mask(code, 20, 0b11_1111) as u32;
+                let funct6 = shift_and_mask(code, 26, 0b11_1111);
+
+                match (funct3, funct6) {
+                    (0b001, 0b00_0000) => SLLI(rd, rs1, shift_amount),
+                    (0b101, 0b00_0000) => SRLI(rd, rs1, shift_amount),
+                    (0b101, 0b01_0000) => SRAI(rd, rs1, shift_amount),
+                    _ => INVALID,
+                }
+            }
+            (0b001_1011, _) => Instruction::match_arithmetic_immediate_word(code),
+            (0b011_1011, _) => Instruction::match_arithmetic_word(code),
+            (0b010_1111, 0b011) => Instruction::match_atomic_doubleword(code),
+            _ => Instruction::new(code),
+        }
+    }
+
+    /// Whether the instruction belongs to the F or D extension, which can be
+    /// disabled with mstatus.FS.
+    pub fn is_floating_point(&self) -> bool {
+        matches!(
+            self,
+            FLW(..)
+                | FSW(..)
+                | FMADDS(..)
+                | FMSUBS(..)
+                | FNMSUBS(..)
+                | FNMADDS(..)
+                | FADDS(..)
+                | FSUBS(..)
+                | FMULS(..)
+                | FDIVS(..)
+                | FSQRTS(..)
+                | FSGNJS(..)
+                | FSGNJNS(..)
+                | FSGNJXS(..)
+                | FMINS(..)
+                | FMAXS(..)
+                | FCVTWS(..)
+                | FCVTWUS(..)
+                | FMVXW(..)
+                | FEQS(..)
+                | FLTS(..)
+                | FLES(..)
+                | FCLASSS(..)
+                | FCVTSW(..)
+                | FCVTSWU(..)
+                | FMVWX(..)
+                | FLD(..)
+                | FSD(..)
+                | FMADDD(..)
+                | FMSUBD(..)
+                | FNMSUBD(..)
+                | FNMADDD(..)
+                | FADDD(..)
+                | FSUBD(..)
+                | FMULD(..)
+                | FDIVD(..)
+                | FSQRTD(..)
+                | FSGNJD(..)
+                | FSGNJND(..)
+                | FSGNJXD(..)
+                | FMIND(..)
+                | FMAXD(..)
+                | FCVTSD(..)
+                | FCVTDS(..)
+                | FEQD(..)
+                | FLTD(..)
+                | FLED(..)
+                | FCLASSD(..)
+                | FCVTWD(..)
+                | FCVTWUD(..)
+                | FCVTDW(..)
+                | FCVTDWU(..)
+        )
+    }
+
+    /// The extension adding the instruction
+    pub fn extension(&self) -> Extension {
+        match self {
+            MUL(..) | MULH(..) | MULHSU(..) | MULHU(..) | MULW(..) => Extension::Zmmul,
+            DIV(..) | DIVU(..) | REM(..) | REMU(..) | DIVW(..) | DIVUW(..) | REMW(..)
+            | REMUW(..) => Extension::M,
+            LRW(..) | SCW(..) | LRD(..) | SCD(..) => Extension::Zalrsc,
+            AMOSWAPW(..) | AMOADDW(..) | AMOXORW(..) | AMOANDW(..) | AMOORW(..) | AMOMINW(..)
+            | AMOMAXW(..) | AMOMINUW(..) | AMOMAXUW(..) | AMOSWAPD(..) | AMOADDD(..)
+            | AMOXORD(..) | AMOANDD(..) | AMOORD(..) | AMOMIND(..) | AMOMAXD(..) | AMOMINUD(..)
+            | AMOMAXUD(..) => Extension::Zaamo,
+            SH1ADD(..) | SH2ADD(..) | SH3ADD(..) => Extension::Zba,
+            ANDN(..) | ORN(..) | XNOR(..) | CLZ(..) | CTZ(..) | CPOP(..) | MAX(..) | MAXU(..)
+            | MIN(..) | MINU(..) | SEXTB(..) | SEXTH(..) | ZEXTH(..) | ROL(..) | ROR(..)
+            | RORI(..) | ORCB(..) | REV8(..) => Extension::Zbb,
+            CLMUL(..) | CLMULH(..) | CLMULR(..) => Extension::Zbc,
+            BCLR(..) | BCLRI(..) | BEXT(..) | BEXTI(..) | BINV(..) | BINVI(..) | BSET(..)
+            | BSETI(..) => Extension::Zbs,
+            FENCEI => Extension::Zifencei,
+            CSRRW(..) | CSRRS(..) | CSRRC(..) | CSRRWI(..) | CSRRSI(..) | CSRRCI(..) => {
+                Extension::Zicsr
+            }
+            FLD(..) | FSD(..) | FMADDD(..) | FMSUBD(..) | FNMSUBD(..) | FNMADDD(..) | FADDD(..)
+            | FSUBD(..) | FMULD(..) | FDIVD(..) | FSQRTD(..) | FSGNJD(..) | FSGNJND(..)
+            | FSGNJXD(..) | FMIND(..) | FMAXD(..) | FCVTSD(..) | FCVTDS(..) | FEQD(..)
+            | FLTD(..) | FLED(..) | FCLASSD(..) | FCVTWD(..) | FCVTWUD(..) | FCVTDW(..)
+            | FCVTDWU(..) => Extension::D,
+            _ if self.is_floating_point() => Extension::F,
+            _ => Extension::I,
+        }
+    }
+
+    pub fn new_compressed(code: u16) -> Self {
+        let code = u32::from(code);
+        let quadrant = code & 0b11;
+        let funct3 = shift_and_mask(code, 13, FUNCT3_MASK);
+
+        match quadrant {
+            0b00 => Instruction::match_compressed_quadrant_0(code, funct3),
+            0b01 => Instruction::match_compressed_quadrant_1(code, funct3),
+            0b10 => Instruction::match_compressed_quadrant_2(code, funct3),
+            _ => INVALID,
+        }
+    }
+
+    /// Decodes a compressed instruction for RV64, which replaces C.FLW,
+    /// C.FSW, C.FLWSP, C.FSWSP and C.JAL with C.LD, C.SD, C.LDSP, C.SDSP and
+    /// C.ADDIW, and adds C.SUBW and C.ADDW.
+    pub fn new_compressed_rv64(code: u16) -> Self {
+        let code = u32::from(code);
+        let quadrant = code & 0b11;
+        let funct3 = shift_and_mask(code, 13, FUNCT3_MASK);
+
+        let rd = shift_and_mask(code, 7, REGISTER_MASK);
+        let rs2 = shift_and_mask(code, 2, REGISTER_MASK);
+        let rd_compressed = compressed_register(code, 7);
+        let rs2_compressed = compressed_register(code, 2);
+        let bit_12 = shift_and_mask(code, 12, 0b1);
+
+        let mut immediate = bit_12 << 5;
+        immediate |= shift_and_mask(code, 2, 0b1_1111);
+        let immediate_sign_extended = sign_extend(immediate as i32, 6) as u32;
+
+        // C.LD and C.SD share the same offset encoding
+        let mut double_offset = shift_and_mask(code, 10, 0b111) << 3;
+        double_offset |= shift_and_mask(code, 5, 0b11) << 6;
+
+        match (quadrant, funct3) {
+            (0b00, 0b011) => LD(rs2_compressed, rd_compressed, double_offset as i32),
+            (0b00, 0b111) => SD(rd_compressed, rs2_compressed, double_offset as i32),
+            (0b01, 0b001) if rd == 0 => INVALID,
+            // C.ADDIW
+            (0b01, 0b001) => ADDIW(rd, rd, immediate_sign_extended),
+            (0b01, 0b100) => {
+                let funct2 = shift_and_mask(code, 10, 0b11);
+                let shift_amount = immediate as u32;
+
+                match (funct2, bit_12, shift_and_mask(code, 5, 0b11)) {
+                    // C.SRLI
+                    (0b00, _, _) => SRLI(rd_compressed, rd_compressed, shift_amount),
+                    // C.SRAI
+                    (0b01, _, _) => SRAI(rd_compressed, rd_compressed, shift_amount),
+                    // C.SUBW
+                    (0b11, 1, 0b00) => SUBW(rd_compressed, rd_compressed, rs2_compressed),
+                    // C.ADDW
+                    (0b11, 1, 0b01) => ADDW(rd_compressed, rd_compressed, rs2_compressed),
+                    _ => Instruction::new_compressed(code as u16),
+                }
+            }
+            // C.SLLI
+            (0b10, 0b000) => SLLI(rd, rd, immediate as u32),
+            (0b10, 0b011) if rd == 0 => INVALID,
+            (0b10, 0b011) => {
+                let mut offset = bit_12 << 5;
+                offset |= shift_and_mask(code, 5, 0b11) << 3;
+                offset |= shift_and_mask(code, 2, 0b111) << 6;
+
+                // C.LDSP
+                LD(rd, 2, offset as i32)
+            }
+            (0b10, 0b111) => {
+                let mut offset = shift_and_mask(code, 10, 0b111) << 3;
+                offset |= shift_and_mask(code, 7, 0b111) << 6;
+
+                // C.SDSP
+                SD(2, rs2, offset as i32)
+            }
+            _ => Instruction::new_compressed(code as u16),
+        }
+    }
+
+    fn match_compressed_quadrant_0(code: u32, funct3: usize) -> Self {
+        let rd = compressed_register(code, 2);
+        let rs1 = compressed_register(code, 7);
+
+        // C.LW and C.SW share the same offset encoding
+        let mut word_offset = shift_and_mask(code, 10, 0b111) << 3;
+        word_offset |= shift_and_mask(code, 6, 0b1) << 2;
+        word_offset |= shift_and_mask(code, 5, 0b1) << 6;
+
+        // C.FLD and C.FSD share the same offset encoding
+        let mut double_offset = shift_and_mask(code, 10, 0b111) << 3;
+        double_offset |= shift_and_mask(code, 5, 0b11) << 6;
+
+        match funct3 {
+            0b000 => {
+                let mut immediate = shift_and_mask(code, 11, 0b11) << 4;
+                immediate |= shift_and_mask(code, 7, 0b1111) << 6;
+                immediate |= shift_and_mask(code, 6, 0b1) << 2;
+                immediate |= shift_and_mask(code, 5, 0b1) << 3;
+
+                if immediate == 0 {
+                    INVALID
+                } else {
+                    // C.ADDI4SPN
+                    ADDI(rd, 2, immediate as u32)
+                }
+            }
+            0b001 => FLD(rd, rs1, double_offset as i32),
+            0b010 => LW(rd, rs1, word_offset as i32),
+            0b011 => FLW(rd, rs1, word_offset as i32),
+            0b101 => FSD(rs1, rd, double_offset as i32),
+            0b110 => SW(rs1, rd, word_offset as i32),
+            0b111 => FSW(rs1, rd, word_offset as i32),
+            _ => INVALID,
+        }
+    }
+
+    fn match_compressed_quadrant_1(code: u32, funct3: usize) -> Self {
+        let rd = shift_and_mask(code, 7, REGISTER_MASK);
+        let rd_compressed = compressed_register(code, 7);
+        let rs2_compressed = compressed_register(code, 2);
+
+        let mut immediate = shift_and_mask(code, 12, 0b1) << 5;
+        immediate |= shift_and_mask(code, 2, 0b1_1111);
+        let immediate_sign_extended = sign_extend(immediate as i32, 6) as u32;
+
+        match funct3 {
+            // C.ADDI, C.NOP
+            0b000 => ADDI(rd, rd, immediate_sign_extended),
+            // C.JAL
+            0b001 => JAL(1, compressed_jump_offset(code)),
+            // C.LI
+            0b010 => ADDI(rd, 0, immediate_sign_extended),
+            0b011 if rd == 2 => {
+                let mut immediate = shift_and_mask(code, 12, 0b1) << 9;
+                immediate |= shift_and_mask(code, 6, 0b1) << 4;
+                immediate |= shift_and_mask(code, 5, 0b1) << 6;
+                immediate |= shift_and_mask(code, 3, 0b11) << 7;
+                immediate |= shift_and_mask(code, 2, 0b1) << 5;
+
+                if immediate == 0 {
+                    INVALID
+                } else {
+                    // C.ADDI16SP
+                    ADDI(2, 2, sign_extend(immediate as i32, 10) as u32)
+                }
+            }
+            0b011 => {
+                if immediate == 0 || rd == 0 {
+                    INVALID
+                } else {
+                    // C.LUI
+                    LUI(rd, immediate_sign_extended & IMMEDIATE_20_MASK)
+                }
+            }
+            0b100 => {
+                let funct2 = shift_and_mask(code, 10, 0b11);
+                let shift_amount = immediate as u32;
+
+                match funct2 {
+                    0b00 if shift_amount < 32 => SRLI(rd_compressed, rd_compressed, shift_amount),
+                    0b01 if shift_amount < 32 => SRAI(rd_compressed, rd_compressed, shift_amount),
+                    0b10 => ANDI(rd_compressed, rd_compressed, immediate_sign_extended),
+                    0b11 => match (shift_and_mask(code, 12, 0b1), shift_and_mask(code, 5, 0b11)) {
+                        (0, 0b00) => SUB(rd_compressed, rd_compressed, rs2_compressed),
+                        (0, 0b01) => XOR(rd_compressed, rd_compressed, rs2_compressed),
+                        (0, 0b10) => OR(rd_compressed, rd_compressed, rs2_compressed),
+                        (0, 0b11) => AND(rd_compressed, rd_compressed, rs2_compressed),
+                        _ => INVALID,
+                    },
+                    _ => INVALID,
+                }
+            }
+            // C.J
+            0b101 => JAL(0, compressed_jump_offset(code)),
+            // C.BEQZ
+            0b110 => BEQ(rd_compressed, 0, compressed_branch_offset(code)),
+            // C.BNEZ
+            0b111 => BNE(rd_compressed, 0, compressed_branch_offset(code)),
             _ => INVALID,
         }
     }
 
-    pub fn new_compressed(_code: u16) -> Self {
-        INVALID
+    fn match_compressed_quadrant_2(code: u32, funct3: usize) -> Self {
+        let rd = shift_and_mask(code, 7, REGISTER_MASK);
+        let rs2 = shift_and_mask(code, 2, REGISTER_MASK);
+        let bit_12 = shift_and_mask(code, 12, 0b1);
+
+        match funct3 {
+            0b000 => {
+                let shift_amount = (bit_12 << 5 | rs2) as u32;
+
+                if shift_amount < 32 {
+                    // C.SLLI
+                    SLLI(rd, rd, shift_amount)
+                } else {
+                    INVALID
+                }
+            }
+            0b010 => {
+                let mut offset = bit_12 << 5;
+                offset |= shift_and_mask(code, 4, 0b111) << 2;
+                offset |= shift_and_mask(code, 2, 0b11) << 6;
+
+                if rd == 0 {
+                    INVALID
+                } else {
+                    // C.LWSP
+                    LW(rd, 2, offset as i32)
+                }
+            }
+            0b001 => {
+                let mut offset = bit_12 << 5;
+                offset |= shift_and_mask(code, 5, 0b11) << 3;
+                offset |= shift_and_mask(code, 2, 0b111) << 6;
+
+                // C.FLDSP
+                FLD(rd, 2, offset as i32)
+            }
+            0b011 => {
+                let mut offset = bit_12 << 5;
+                offset |= shift_and_mask(code, 4, 0b111) << 2;
+                offset |= shift_and_mask(code, 2, 0b11) << 6;
+
+                // C.FLWSP
+                FLW(rd, 2, offset as i32)
+            }
+            0b100 => match (bit_12, rd, rs2) {
+                (0, 0, 0) => INVALID,
+                // C.JR
+                (0, _, 0) => JALR(0, rd, 0),
+                // C.MV
+                (0, _, _) => ADD(rd, 0, rs2),
+                // C.EBREAK
+                (1, 0, 0) => EBREAK,
+                // C.JALR
+                (1, _, 0) => JALR(1, rd, 0),
+                // C.ADD
+                (1, _, _) => ADD(rd, rd, rs2),
+                _ => INVALID,
+            },
+            0b110 => {
+                let mut offset = shift_and_mask(code, 9, 0b1111) << 2;
+                offset |= shift_and_mask(code, 7, 0b11) << 6;
+
+                // C.SWSP
+                SW(2, rs2, offset as i32)
+            }
+            0b101 => {
+                let mut offset = shift_and_mask(code, 10, 0b111) << 3;
+                offset |= shift_and_mask(code, 7, 0b111) << 6;
+
+                // C.FSDSP
+                FSD(2, rs2, offset as i32)
+            }
+            0b111 => {
+                let mut offset = shift_and_mask(code, 9, 0b1111) << 2;
+                offset |= shift_and_mask(code, 7, 0b11) << 6;
+
+                // C.FSWSP
+                FSW(2, rs2, offset as i32)
+            }
+            _ => INVALID,
+        }
     }
 
     fn match_branch(code: u32) -> Self {
@@ -265,16 +900,27 @@ impl Instruction {
             0b100 => XORI(rd, rs1, immediate_sign_extended),
             0b110 => ORI(rd, rs1, immediate_sign_extended),
             0b111 => ANDI(rd, rs1, immediate_sign_extended),
-            0b001 => SLLI(rd, rs1, shift_amount),
-            0b101 => {
-                if funct7 == 0b010_0000 {
-                    SRAI(rd, rs1, shift_amount)
-                } else if funct7 == 0 {
-                    SRLI(rd, rs1, shift_amount)
-                } else {
-                    INVALID
-                }
-            }
+            0b001 => match (funct7, shift_amount) {
+                (0b000_0000, _) => SLLI(rd, rs1, shift_amount),
+                (0b011_0000, 0b0_0000) => CLZ(rd, rs1),
+                (0b011_0000, 0b0_0001) => CTZ(rd, rs1),
+                (0b011_0000, 0b0_0010) => CPOP(rd, rs1),
+                (0b011_0000, 0b0_0100) => SEXTB(rd, rs1),
+                (0b011_0000, 0b0_0101) => SEXTH(rd, rs1),
+                (0b010_0100, _) => BCLRI(rd, rs1, shift_amount),
+                (0b011_0100, _) => BINVI(rd, rs1, shift_amount),
+                (0b001_0100, _) => BSETI(rd, rs1, shift_amount),
+                _ => INVALID,
+            },
+            0b101 => match (funct7, shift_amount) {
+                (0b010_0000, _) => SRAI(rd, rs1, shift_amount),
+                (0b000_0000, _) => SRLI(rd, rs1, shift_amount),
+                (0b011_0000, _) => RORI(rd, rs1, shift_amount),
+                (0b010_0100, _) => BEXTI(rd, rs1, shift_amount),
+                (0b001_0100, 0b0_0111) => ORCB(rd, rs1),
+                (0b011_0100, 0b1_1000) => REV8(rd, rs1),
+                _ => INVALID,
+            },
             _ => INVALID,
         }
     }
@@ -305,6 +951,45 @@ impl Instruction {
             (0b110, 0b000_0001) => REM(rd, rs1, rs2),
             (0b111, 0b000_0000) => AND(rd, rs1, rs2),
             (0b111, 0b000_0001) => REMU(rd, rs1, rs2),
+            (0b010, 0b001_0000) => SH1ADD(rd, rs1, rs2),
+            (0b100, 0b001_0000) => SH2ADD(rd, rs1, rs2),
+            (0b110, 0b001_0000) => SH3ADD(rd, rs1, rs2),
+            (0b111, 0b010_0000) => ANDN(rd, rs1, rs2),
+            (0b110, 0b010_0000) => ORN(rd, rs1, rs2),
+            (0b100, 0b010_0000) => XNOR(rd, rs1, rs2),
+            (0b110, 0b000_0101) => MAX(rd, rs1, rs2),
+            (0b111, 0b000_0101) => MAXU(rd, rs1, rs2),
+            (0b100, 0b000_0101) => MIN(rd, rs1, rs2),
+            (0b101, 0b000_0101) => MINU(rd, rs1, rs2),
+            (0b100, 0b000_0100) if rs2 == 0 => ZEXTH(rd, rs1),
+            (0b001, 0b011_0000) => ROL(rd, rs1, rs2),
+            (0b101, 0b011_0000) => ROR(rd, rs1, rs2),
+            (0b001, 0b000_0101) => CLMUL(rd, rs1, rs2),
+            (0b011, 0b000_0101) => CLMULH(rd, rs1, rs2),
+            (0b010, 0b000_0101) => CLMULR(rd, rs1, rs2),
+            (0b001, 0b010_0100) => BCLR(rd, rs1, rs2),
+            (0b101, 0b010_0100) => BEXT(rd, rs1, rs2),
+            (0b001, 0b011_0100) => BINV(rd, rs1, rs2),
+            (0b001, 0b001_0100) => BSET(rd, rs1, rs2),
+            _ => INVALID,
+        }
+    }
+
+    /// Decodes FENCE, FENCE.TSO and FENCE.I. The unused rd and rs1 fields
+    /// are ignored, as required by the specification, and unknown fence modes
+    /// are treated like a normal FENCE.
+    fn match_misc_mem(code: u32) -> Self {
+        let funct3 = shift_and_mask(code, 12, FUNCT3_MASK);
+        let successor = shift_and_mask(code, 20, 0b1111) as u32;
+        let predecessor = shift_and_mask(code, 24, 0b1111) as u32;
+        let fence_mode = shift_and_mask(code, 28, 0b1111);
+
+        match funct3 {
+            0b000 if fence_mode == 0b1000 && predecessor == 0b0011 && successor == 0b0011 => {
+                FENCETSO
+            }
+            0b000 => FENCE(predecessor, successor),
+            0b001 => FENCEI,
             _ => INVALID,
         }
     }
@@ -315,23 +1000,207 @@ impl Instruction {
         let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
         let rs2 = shift_and_mask(code, 20, REGISTER_MASK);
         let funct5 = shift_and_mask(code, 27, 0b1_1111);
+        let ordering = AtomicOrdering::from_code(code);
 
         if funct3 != 0b010 {
             return INVALID;
         }
 
         match funct5 {
-            0b0_0010 if rs2 == 0 => LRW(rd, rs1, 0),
-            0b0_0011 => SCW(rd, rs1, rs2),
-            0b0_0001 => AMOSWAPW(rd, rs1, rs2),
-            0b0_0000 => AMOADDW(rd, rs1, rs2),
-            0b0_0100 => AMOXORW(rd, rs1, rs2),
-            0b0_1100 => AMOANDW(rd, rs1, rs2),
-            0b0_1000 => AMOORW(rd, rs1, rs2),
-            0b1_0000 => AMOMINW(rd, rs1, rs2),
-            0b1_0100 => AMOMAXW(rd, rs1, rs2),
-            0b1_1000 => AMOMINUW(rd, rs1, rs2),
-            0b1_1100 => AMOMAXUW(rd, rs1, rs2),
+            0b0_0010 if rs2 == 0 => LRW(rd, rs1, ordering),
+            0b0_0011 => SCW(rd, rs1, rs2, ordering),
+            0b0_0001 => AMOSWAPW(rd, rs1, rs2, ordering),
+            0b0_0000 => AMOADDW(rd, rs1, rs2, ordering),
+            0b0_0100 => AMOXORW(rd, rs1, rs2, ordering),
+            0b0_1100 => AMOANDW(rd, rs1, rs2, ordering),
+            0b0_1000 => AMOORW(rd, rs1, rs2, ordering),
+            0b1_0000 => AMOMINW(rd, rs1, rs2, ordering),
+            0b1_0100 => AMOMAXW(rd, rs1, rs2, ordering),
+            0b1_1000 => AMOMINUW(rd, rs1, rs2, ordering),
+            0b1_1100 => AMOMAXUW(rd, rs1, rs2, ordering),
+            _ => INVALID,
+        }
+    }
+
+    fn match_arithmetic_immediate_word(code: u32) -> Self {
+        let rd = shift_and_mask(code, 7, REGISTER_MASK);
+        let funct3 = shift_and_mask(code, 12, FUNCT3_MASK);
+        let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
+        let immediate = shift_and_mask(code, 20, IMMEDIATE_12_MASK) as u32;
+        let immediate_sign_extended = sign_extend(immediate as i32, 12) as u32;
+
+        let shift_amount = immediate & 0b1_1111;
+        let funct7 = shift_and_mask(code, 25, FUNCT7_MASK);
+
+        match (funct3, funct7) {
+            (0b000, _) => ADDIW(rd, rs1, immediate_sign_extended),
+            (0b001, 0b000_0000) => SLLIW(rd, rs1, shift_amount),
+            (0b101, 0b000_0000) => SRLIW(rd, rs1, shift_amount),
+            (0b101, 0b010_0000) => SRAIW(rd, rs1, shift_amount),
+            _ => INVALID,
+        }
+    }
+
+    fn match_arithmetic_word(code: u32) -> Self {
+        let rd = shift_and_mask(code, 7, REGISTER_MASK);
+        let funct3 = shift_and_mask(code, 12, FUNCT3_MASK);
+        let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
+        let rs2 = shift_and_mask(code, 20, REGISTER_MASK);
+        let funct7 = shift_and_mask(code, 25, FUNCT7_MASK);
+
+        match (funct3, funct7) {
+            (0b000, 0b000_0000) => ADDW(rd, rs1, rs2),
+            (0b000, 0b010_0000) => SUBW(rd, rs1, rs2),
+            (0b000, 0b000_0001) => MULW(rd, rs1, rs2),
+            (0b001, 0b000_0000) => SLLW(rd, rs1, rs2),
+            (0b100, 0b000_0001) => DIVW(rd, rs1, rs2),
+            (0b101, 0b000_0000) => SRLW(rd, rs1, rs2),
+            (0b101, 0b010_0000) => SRAW(rd, rs1, rs2),
+            (0b101, 0b000_0001) => DIVUW(rd, rs1, rs2),
+            (0b110, 0b000_0001) => REMW(rd, rs1, rs2),
+            (0b111, 0b000_0001) => REMUW(rd, rs1, rs2),
+            _ => INVALID,
+        }
+    }
+
+    fn match_atomic_doubleword(code: u32) -> Self {
+        let rd = shift_and_mask(code, 7, REGISTER_MASK);
+        let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
+        let rs2 = shift_and_mask(code, 20, REGISTER_MASK);
+        let funct5 = shift_and_mask(code, 27, 0b1_1111);
+        let ordering = AtomicOrdering::from_code(code);
+
+        match funct5 {
+            0b0_0010 if rs2 == 0 => LRD(rd, rs1, ordering),
+            0b0_0011 => SCD(rd, rs1, rs2, ordering),
+            0b0_0001 => AMOSWAPD(rd, rs1, rs2, ordering),
+            0b0_0000 => AMOADDD(rd, rs1, rs2, ordering),
+            0b0_0100 => AMOXORD(rd, rs1, rs2, ordering),
+            0b0_1100 => AMOANDD(rd, rs1, rs2, ordering),
+            0b0_1000 => AMOORD(rd, rs1, rs2, ordering),
+            0b1_0000 => AMOMIND(rd, rs1, rs2, ordering),
+            0b1_0100 => AMOMAXD(rd, rs1, rs2, ordering),
+            0b1_1000 => AMOMINUD(rd, rs1, rs2, ordering),
+            0b1_1100 => AMOMAXUD(rd, rs1, rs2, ordering),
+            _ => INVALID,
+        }
+    }
+
+    fn match_float_load(code: u32) -> Self {
+        let rd = shift_and_mask(code, 7, REGISTER_MASK);
+        let funct3 = shift_and_mask(code, 12, FUNCT3_MASK);
+        let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
+        let immediate = shift_and_mask(code, 20, IMMEDIATE_12_MASK) as i32;
+        let immediate_sign_extended = sign_extend(immediate, 12);
+
+        match funct3 {
+            0b010 => FLW(rd, rs1, immediate_sign_extended),
+            0b011 => FLD(rd, rs1, immediate_sign_extended),
+            _ => INVALID,
+        }
+    }
+
+    fn match_float_store(code: u32) -> Self {
+        let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
+        let rs2 = shift_and_mask(code, 20, REGISTER_MASK);
+        let funct3 = shift_and_mask(code, 12, FUNCT3_MASK);
+
+        let imm_1_to_5 = shift_and_mask(code, 7, 0b1_1111) as u32;
+        let imm_6_to_12 = shift_and_mask(code, 25, 0b111_1111) as u32;
+
+        let mut immediate = imm_1_to_5;
+        immediate |= imm_6_to_12 << 5;
+
+        let immediate_sign_extended = sign_extend(immediate as i32, 12);
+
+        match funct3 {
+            0b010 => FSW(rs1, rs2, immediate_sign_extended),
+            0b011 => FSD(rs1, rs2, immediate_sign_extended),
+            _ => INVALID,
+        }
+    }
+
+    fn match_fused_multiply_add(code: u32) -> Self {
+        let opcode = code & OPCODE_MASK;
+        let rd = shift_and_mask(code, 7, REGISTER_MASK);
+        let rm = shift_and_mask(code, 12, FUNCT3_MASK) as u32;
+        let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
+        let rs2 = shift_and_mask(code, 20, REGISTER_MASK);
+        let format = shift_and_mask(code, 25, 0b11);
+        let rs3 = shift_and_mask(code, 27, REGISTER_MASK);
+
+        if !is_valid_rounding_mode(rm) {
+            return INVALID;
+        }
+
+        match (opcode, format) {
+            (0b100_0011, 0b00) => FMADDS(rd, rs1, rs2, rs3, rm),
+            (0b100_0111, 0b00) => FMSUBS(rd, rs1, rs2, rs3, rm),
+            (0b100_1011, 0b00) => FNMSUBS(rd, rs1, rs2, rs3, rm),
+            (0b100_1111, 0b00) => FNMADDS(rd, rs1, rs2, rs3, rm),
+            (0b100_0011, 0b01) => FMADDD(rd, rs1, rs2, rs3, rm),
+            (0b100_0111, 0b01) => FMSUBD(rd, rs1, rs2, rs3, rm),
+            (0b100_1011, 0b01) => FNMSUBD(rd, rs1, rs2, rs3, rm),
+            (0b100_1111, 0b01) => FNMADDD(rd, rs1, rs2, rs3, rm),
+            _ => INVALID,
+        }
+    }
+
+    fn match_float_arithmetic(code: u32) -> Self {
+        let rd = shift_and_mask(code, 7, REGISTER_MASK);
+        let funct3 = shift_and_mask(code, 12, FUNCT3_MASK);
+        let rs1 = shift_and_mask(code, 15, REGISTER_MASK);
+        let rs2 = shift_and_mask(code, 20, REGISTER_MASK);
+        let funct7 = shift_and_mask(code, 25, FUNCT7_MASK);
+
+        // For instructions which round, funct3 holds the rounding mode
+        let rm = funct3 as u32;
+        if !is_valid_rounding_mode(rm) {
+            return INVALID;
+        }
+
+        match (funct7, funct3, rs2) {
+            (0b000_0000, _, _) => FADDS(rd, rs1, rs2, rm),
+            (0b000_0100, _, _) => FSUBS(rd, rs1, rs2, rm),
+            (0b000_1000, _, _) => FMULS(rd, rs1, rs2, rm),
+            (0b000_1100, _, _) => FDIVS(rd, rs1, rs2, rm),
+            (0b010_1100, _, 0) => FSQRTS(rd, rs1, rm),
+            (0b001_0000, 0b000, _) => FSGNJS(rd, rs1, rs2),
+            (0b001_0000, 0b001, _) => FSGNJNS(rd, rs1, rs2),
+            (0b001_0000, 0b010, _) => FSGNJXS(rd, rs1, rs2),
+            (0b001_0100, 0b000, _) => FMINS(rd, rs1, rs2),
+            (0b001_0100, 0b001, _) => FMAXS(rd, rs1, rs2),
+            (0b110_0000, _, 0) => FCVTWS(rd, rs1, rm),
+            (0b110_0000, _, 1) => FCVTWUS(rd, rs1, rm),
+            (0b111_0000, 0b000, 0) => FMVXW(rd, rs1),
+            (0b101_0000, 0b010, _) => FEQS(rd, rs1, rs2),
+            (0b101_0000, 0b001, _) => FLTS(rd, rs1, rs2),
+            (0b101_0000, 0b000, _) => FLES(rd, rs1, rs2),
+            (0b111_0000, 0b001, 0) => FCLASSS(rd, rs1),
+            (0b110_1000, _, 0) => FCVTSW(rd, rs1, rm),
+            (0b110_1000, _, 1) => FCVTSWU(rd, rs1, rm),
+            (0b111_1000, 0b000, 0) => FMVWX(rd, rs1),
+
+            (0b000_0001, _, _) => FADDD(rd, rs1, rs2, rm),
+            (0b000_0101, _, _) => FSUBD(rd, rs1, rs2, rm),
+            (0b000_1001, _, _) => FMULD(rd, rs1, rs2, rm),
+            (0b000_1101, _, _) => FDIVD(rd, rs1, rs2, rm),
+            (0b010_1101, _, 0) => FSQRTD(rd, rs1, rm),
+            (0b001_0001, 0b000, _) => FSGNJD(rd, rs1, rs2),
+            (0b001_0001, 0b001, _) => FSGNJND(rd, rs1, rs2),
+            (0b001_0001, 0b010, _) => FSGNJXD(rd, rs1, rs2),
+            (0b001_0101, 0b000, _) => FMIND(rd, rs1, rs2),
+            (0b001_0101, 0b001, _) => FMAXD(rd, rs1, rs2),
+            (0b010_0000, _, 1) => FCVTSD(rd, rs1, rm),
+            (0b010_0001, _, 0) => FCVTDS(rd, rs1, rm),
+            (0b101_0001, 0b010, _) => FEQD(rd, rs1, rs2),
+            (0b101_0001, 0b001, _) => FLTD(rd, rs1, rs2),
+            (0b101_0001, 0b000, _) => FLED(rd, rs1, rs2),
+            (0b111_0001, 0b001, 0) => FCLASSD(rd, rs1),
+            (0b110_0001, _, 0) => FCVTWD(rd, rs1, rm),
+            (0b110_0001, _, 1) => FCVTWUD(rd, rs1, rm),
+            (0b110_1001, _, 0) => FCVTDW(rd, rs1, rm),
+            (0b110_1001, _, 1) => FCVTDWU(rd, rs1, rm),
             _ => INVALID,
         }
     }
@@ -341,7 +1210,45 @@ fn shift_and_mask(code: u32, shift: u32, mask: u32) -> usize {
     ((code >> shift) & mask) as usize
 }
 
+/// The rounding modes 0b101 and 0b110 are reserved
+fn is_valid_rounding_mode(rm: u32) -> bool {
+    rm != 0b101 && rm != 0b110
+}
+
+/// Decodes one of the 3-bit register fields of compressed instructions,
+/// which can only address the registers x8 to x15.
+fn compressed_register(code: u32, shift: u32) -> usize {
+    shift_and_mask(code, shift, 0b111) + 8
+}
+
+/// Decodes the offset of C.J and C.JAL, in the same halfword units as JAL.
+fn compressed_jump_offset(code: u32) -> u32 {
+    let mut offset = shift_and_mask(code, 12, 0b1) << 11;
+    offset |= shift_and_mask(code, 11, 0b1) << 4;
+    offset |= shift_and_mask(code, 9, 0b11) << 8;
+    offset |= shift_and_mask(code, 8, 0b1) << 10;
+    offset |= shift_and_mask(code, 7, 0b1) << 6;
+    offset |= shift_and_mask(code, 6, 0b1) << 7;
+    offset |= shift_and_mask(code, 3, 0b111) << 1;
+    offset |= shift_and_mask(code, 2, 0b1) << 5;
+
+    (sign_extend(offset as i32, 12) >> 1) as u32
+}
+
+/// Decodes the offset of C.BEQZ and C.BNEZ, in the same halfword units as
+/// the regular branch instructions.
+fn compressed_branch_offset(code: u32) -> u32 {
+    let mut offset = shift_and_mask(code, 12, 0b1) << 8;
+    offset |= shift_and_mask(code, 10, 0b11) << 3;
+    offset |= shift_and_mask(code, 5, 0b11) << 6;
+    offset |= shift_and_mask(code, 3, 0b11) << 1;
+    offset |= shift_and_mask(code, 2, 0b1) << 5;
+
+    (sign_extend(offset as i32, 9) >> 1) as u32
+}
+
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)]
 mod test {
     mod load_immediate {
         use super::super::*;
@@ -471,6 +1378,24 @@ mod test {
             immediate_test!(SLLI, 0b0000000_11111_00010_001_00001_0010011, 31);
             immediate_test!(SRLI, 0b0000000_11111_00010_101_00001_0010011, 31);
             immediate_test!(SRAI, 0b0100000_11111_00010_101_00001_0010011, 31);
+            immediate_test!(RORI, 0b0110000_11111_00010_101_00001_0010011, 31);
+            immediate_test!(BCLRI, 0b0100100_00101_00010_001_00001_0010011, 5);
+            immediate_test!(BEXTI, 0b0100100_00101_00010_101_00001_0010011, 5);
+            immediate_test!(BINVI, 0b0110100_00101_00010_001_00001_0010011, 5);
+            immediate_test!(BSETI, 0b0010100_00101_00010_001_00001_0010011, 5);
+        }
+
+        #[test]
+        fn test_unary() {
+            assert_eq!(Instruction::new(0x60011093), Instruction::CLZ(1, 2));
+            assert_eq!(Instruction::new(0x60111093), Instruction::CTZ(1, 2));
+            assert_eq!(Instruction::new(0x60211093), Instruction::CPOP(1, 2));
+            assert_eq!(Instruction::new(0x60411093), Instruction::SEXTB(1, 2));
+            assert_eq!(Instruction::new(0x60511093), Instruction::SEXTH(1, 2));
+            assert_eq!(Instruction::new(0x080140b3), Instruction::ZEXTH(1, 2));
+            assert_eq!(Instruction::new(0x28715093), Instruction::ORCB(1, 2));
+            assert_eq!(Instruction::new(0x69815093), Instruction::REV8(1, 2));
+            assert_eq!(Instruction::new(0x60311093), Instruction::INVALID);
         }
     }
 
@@ -503,36 +1428,391 @@ mod test {
             arith_test!(REMU, 0b0000001_00011_00010_111_00001_0110011);
         }
 
+        #[test]
+        fn test_bit_manipulation() {
+            arith_test!(SH1ADD, 0b0010000_00011_00010_010_00001_0110011);
+            arith_test!(SH2ADD, 0b0010000_00011_00010_100_00001_0110011);
+            arith_test!(SH3ADD, 0b0010000_00011_00010_110_00001_0110011);
+            arith_test!(ANDN, 0b0100000_00011_00010_111_00001_0110011);
+            arith_test!(ORN, 0b0100000_00011_00010_110_00001_0110011);
+            arith_test!(XNOR, 0b0100000_00011_00010_100_00001_0110011);
+            arith_test!(MAX, 0b0000101_00011_00010_110_00001_0110011);
+            arith_test!(MAXU, 0b0000101_00011_00010_111_00001_0110011);
+            arith_test!(MIN, 0b0000101_00011_00010_100_00001_0110011);
+            arith_test!(MINU, 0b0000101_00011_00010_101_00001_0110011);
+            arith_test!(ROL, 0b0110000_00011_00010_001_00001_0110011);
+            arith_test!(ROR, 0b0110000_00011_00010_101_00001_0110011);
+            arith_test!(CLMUL, 0b0000101_00011_00010_001_00001_0110011);
+            arith_test!(CLMULH, 0b0000101_00011_00010_011_00001_0110011);
+            arith_test!(CLMULR, 0b0000101_00011_00010_010_00001_0110011);
+            arith_test!(BCLR, 0b0100100_00011_00010_001_00001_0110011);
+            arith_test!(BEXT, 0b0100100_00011_00010_101_00001_0110011);
+            arith_test!(BINV, 0b0110100_00011_00010_001_00001_0110011);
+            arith_test!(BSET, 0b0010100_00011_00010_001_00001_0110011);
+        }
+
         #[test]
         fn test_atomics() {
+            macro_rules! atomic_test {
+                ($instr:ident,  $code:expr) => {{
+                    assert_eq!(
+                        Instruction::new($code),
+                        Instruction::$instr(1, 2, 3, AtomicOrdering::Relaxed)
+                    );
+                }};
+            }
+
             assert_eq!(
                 Instruction::new(0b0001000_00000_00010_010_00001_0101111),
-                Instruction::LRW(1, 2, 0)
+                Instruction::LRW(1, 2, AtomicOrdering::Relaxed)
+            );
+            atomic_test!(SCW, 0b0001100_00011_00010_010_00001_0101111);
+            atomic_test!(AMOSWAPW, 0b0000100_00011_00010_010_00001_0101111);
+            atomic_test!(AMOADDW, 0b0000000_00011_00010_010_00001_0101111);
+            atomic_test!(AMOXORW, 0b0010000_00011_00010_010_00001_0101111);
+            atomic_test!(AMOANDW, 0b0110000_00011_00010_010_00001_0101111);
+            atomic_test!(AMOORW, 0b0100000_00011_00010_010_00001_0101111);
+            atomic_test!(AMOMINW, 0b1000000_00011_00010_010_00001_0101111);
+            atomic_test!(AMOMAXW, 0b1010000_00011_00010_010_00001_0101111);
+            atomic_test!(AMOMINUW, 0b1100000_00011_00010_010_00001_0101111);
+            atomic_test!(AMOMAXUW, 0b1110000_00011_00010_010_00001_0101111);
+        }
+
+        #[test]
+        fn test_atomic_ordering() {
+            assert_eq!(
+                Instruction::new(0x140120af),
+                Instruction::LRW(1, 2, AtomicOrdering::Acquire)
+            );
+            assert_eq!(
+                Instruction::new(0x1a3120af),
+                Instruction::SCW(1, 2, 3, AtomicOrdering::Release)
+            );
+            assert_eq!(
+                Instruction::new(0x0e3120af),
+                Instruction::AMOSWAPW(1, 2, 3, AtomicOrdering::SequentiallyConsistent)
+            );
+            assert!(AtomicOrdering::SequentiallyConsistent.is_acquire());
+            assert!(AtomicOrdering::SequentiallyConsistent.is_release());
+            assert!(!AtomicOrdering::Acquire.is_release());
+        }
+    }
+
+    mod compressed {
+        use super::super::*;
+
+        macro_rules! compressed_test {
+            ($code:expr, $expected:expr) => {{
+                assert_eq!(Instruction::new_compressed($code), $expected);
+            }};
+        }
+
+        #[test]
+        fn test_quadrant_0() {
+            compressed_test!(0x1fe0, ADDI(8, 2, 1020));
+            compressed_test!(0x5fe8, LW(10, 15, 124));
+            compressed_test!(0xc3a8, SW(15, 10, 64));
+            compressed_test!(0x0000, INVALID);
+        }
+
+        #[test]
+        fn test_quadrant_1() {
+            compressed_test!(0x0001, ADDI(0, 0, 0));
+            compressed_test!(0x1501, ADDI(10, 10, -32i32 as u32));
+            compressed_test!(0x3001, JAL(1, -1024i32 as u32));
+            compressed_test!(0x557d, ADDI(10, 0, -1i32 as u32));
+            compressed_test!(0x7101, ADDI(2, 2, -512i32 as u32));
+            compressed_test!(0x7501, LUI(10, 0xfffe0));
+            compressed_test!(0x80fd, SRLI(9, 9, 31));
+            compressed_test!(0x8485, SRAI(9, 9, 1));
+            compressed_test!(0x98fd, ANDI(9, 9, -1i32 as u32));
+            compressed_test!(0x8c9d, SUB(9, 9, 15));
+            compressed_test!(0x8cbd, XOR(9, 9, 15));
+            compressed_test!(0x8cdd, OR(9, 9, 15));
+            compressed_test!(0x8cfd, AND(9, 9, 15));
+            compressed_test!(0xaffd, JAL(0, 1023));
+            compressed_test!(0xd081, BEQ(9, 0, -128i32 as u32));
+            compressed_test!(0xecfd, BNE(9, 0, 127));
+        }
+
+        #[test]
+        fn test_quadrant_2() {
+            compressed_test!(0x057e, SLLI(10, 10, 31));
+            compressed_test!(0x557e, LW(10, 2, 252));
+            compressed_test!(0x8502, JALR(0, 10, 0));
+            compressed_test!(0x852e, ADD(10, 0, 11));
+            compressed_test!(0x9002, EBREAK);
+            compressed_test!(0x9502, JALR(1, 10, 0));
+            compressed_test!(0x952e, ADD(10, 10, 11));
+            compressed_test!(0xdfaa, SW(2, 10, 252));
+            compressed_test!(0x8002, INVALID);
+        }
+
+        #[test]
+        fn test_float_loads_and_stores() {
+            compressed_test!(0x2480, FLD(8, 9, 8));
+            compressed_test!(0x60c0, FLW(8, 9, 4));
+            compressed_test!(0xa880, FSD(9, 8, 16));
+            compressed_test!(0xe480, FSW(9, 8, 8));
+            compressed_test!(0x20a2, FLD(1, 2, 8));
+            compressed_test!(0x6092, FLW(1, 2, 4));
+            compressed_test!(0xa406, FSD(2, 1, 8));
+            compressed_test!(0xe206, FSW(2, 1, 4));
+        }
+
+        #[test]
+        fn test_wrapped_instruction_size() {
+            assert_eq!(
+                WrappedInstruction::new(0x0000_952e),
+                WrappedInstruction {
+                    instruction: ADD(10, 10, 11),
+                    size: 2
+                }
+            );
+        }
+    }
+
+    mod float {
+        use super::super::*;
+
+        macro_rules! float_test {
+            ($code:expr, $expected:expr) => {{
+                assert_eq!(Instruction::new($code), $expected);
+            }};
+        }
+
+        #[test]
+        fn test_loads_and_stores() {
+            float_test!(0x00812087, FLW(1, 2, 8));
+            float_test!(0xfe32ae27, FSW(5, 3, -4));
+            float_test!(0x01013087, FLD(1, 2, 16));
+            float_test!(0x0032b427, FSD(5, 3, 8));
+            float_test!(0x00814087, INVALID);
+        }
+
+        #[test]
+        fn test_fused_multiply_add() {
+            float_test!(0x203100c3, FMADDS(1, 2, 3, 4, 0));
+            float_test!(0x223170cf, FNMADDD(1, 2, 3, 4, 7));
+            // Reserved rounding mode
+            float_test!(0x203150c3, INVALID);
+        }
+
+        #[test]
+        fn test_arithmetic() {
+            float_test!(0x003110d3, FADDS(1, 2, 3, 1));
+            float_test!(0x5a0372d3, FSQRTD(5, 6, 7));
+            float_test!(0x203120d3, FSGNJXS(1, 2, 3));
+            float_test!(0x2a3110d3, FMAXD(1, 2, 3));
+            float_test!(0xc01332d3, FCVTWUS(5, 6, 3));
+            float_test!(0xc20110d3, FCVTWD(1, 2, 1));
+            float_test!(0xe00302d3, FMVXW(5, 6));
+            float_test!(0xa23100d3, FLED(1, 2, 3));
+            float_test!(0xe00110d3, FCLASSS(1, 2));
+            float_test!(0xd20100d3, FCVTDW(1, 2, 0));
+            float_test!(0xf00100d3, FMVWX(1, 2));
+            float_test!(0x401170d3, FCVTSD(1, 2, 7));
+            float_test!(0x420100d3, FCVTDS(1, 2, 0));
+        }
+    }
+
+    mod rv64 {
+        use super::super::*;
+
+        macro_rules! rv64_test {
+            ($code:expr, $expected:expr) => {{
+                assert_eq!(WrappedInstruction::new_rv64($code).instruction, $expected);
+            }};
+        }
+
+        #[test]
+        fn test_loads_and_stores() {
+            rv64_test!(0xff816083, LWU(1, 2, -8));
+            rv64_test!(0x01013083, LD(1, 2, 16));
+            rv64_test!(0xfe313823, SD(2, 3, -16));
+            // RV32 loads are shared
+            rv64_test!(0x80012083, LW(1, 2, -2048));
+        }
+
+        #[test]
+        fn test_shifts() {
+            rv64_test!(0x03f11093, SLLI(1, 2, 63));
+            rv64_test!(0x02115093, SRLI(1, 2, 33));
+            rv64_test!(0x42815093, SRAI(1, 2, 40));
+            rv64_test!(0x01f1109b, SLLIW(1, 2, 31));
+            rv64_test!(0x0051509b, SRLIW(1, 2, 5));
+            rv64_test!(0x4051509b, SRAIW(1, 2, 5));
+            // The shift amount of SLLIW is limited to 5 bits
+            rv64_test!(0x0201109b, INVALID);
+        }
+
+        #[test]
+        fn test_word_arithmetic() {
+            rv64_test!(0xfff1009b, ADDIW(1, 2, 0xFFFF_FFFF));
+            rv64_test!(0x003100bb, ADDW(1, 2, 3));
+            rv64_test!(0x403100bb, SUBW(1, 2, 3));
+            rv64_test!(0x003110bb, SLLW(1, 2, 3));
+            rv64_test!(0x003150bb, SRLW(1, 2, 3));
+            rv64_test!(0x403150bb, SRAW(1, 2, 3));
+            rv64_test!(0x023100bb, MULW(1, 2, 3));
+            rv64_test!(0x023140bb, DIVW(1, 2, 3));
+            rv64_test!(0x023150bb, DIVUW(1, 2, 3));
+            rv64_test!(0x023160bb, REMW(1, 2, 3));
+            rv64_test!(0x023170bb, REMUW(1, 2, 3));
+        }
+
+        #[test]
+        fn test_atomics() {
+            rv64_test!(0x100130af, LRD(1, 2, AtomicOrdering::Relaxed));
+            rv64_test!(0x183130af, SCD(1, 2, 3, AtomicOrdering::Relaxed));
+            rv64_test!(0x083130af, AMOSWAPD(1, 2, 3, AtomicOrdering::Relaxed));
+            rv64_test!(0x003130af, AMOADDD(1, 2, 3, AtomicOrdering::Relaxed));
+            rv64_test!(
+                0x160130af,
+                LRD(1, 2, AtomicOrdering::SequentiallyConsistent)
             );
-            arith_test!(SCW, 0b0001100_00011_00010_010_00001_0101111);
-            arith_test!(AMOSWAPW, 0b0000100_00011_00010_010_00001_0101111);
-            arith_test!(AMOADDW, 0b0000000_00011_00010_010_00001_0101111);
-            arith_test!(AMOXORW, 0b0010000_00011_00010_010_00001_0101111);
-            arith_test!(AMOANDW, 0b0110000_00011_00010_010_00001_0101111);
-            arith_test!(AMOORW, 0b0100000_00011_00010_010_00001_0101111);
-            arith_test!(AMOMINW, 0b1000000_00011_00010_010_00001_0101111);
-            arith_test!(AMOMAXW, 0b1010000_00011_00010_010_00001_0101111);
-            arith_test!(AMOMINUW, 0b1100000_00011_00010_010_00001_0101111);
-            arith_test!(AMOMAXUW, 0b1110000_00011_00010_010_00001_0101111);
+            rv64_test!(0x1c3130af, SCD(1, 2, 3, AtomicOrdering::Acquire));
+            rv64_test!(0xe03130af, AMOMAXUD(1, 2, 3, AtomicOrdering::Relaxed));
+        }
+
+        #[test]
+        fn test_compressed() {
+            rv64_test!(0x7ce0, LD(8, 9, 248));
+            rv64_test!(0xe588, SD(11, 10, 8));
+            rv64_test!(0x32f5, ADDIW(5, 5, -3i32 as u32));
+            rv64_test!(0x9c05, SUBW(8, 8, 9));
+            rv64_test!(0x9d2d, ADDW(10, 10, 11));
+            rv64_test!(0x12a2, SLLI(5, 5, 40));
+            rv64_test!(0x907d, SRLI(8, 8, 63));
+            rv64_test!(0x9481, SRAI(9, 9, 32));
+            rv64_test!(0x737e, LD(6, 2, 504));
+            rv64_test!(0xe41e, SD(2, 7, 8));
+            // C.ADDIW with rd = x0 is reserved
+            rv64_test!(0x2005, INVALID);
         }
     }
 
     mod other {
         use super::super::*;
 
+        #[test]
+        fn test_ecall() {
+            assert_eq!(Instruction::new(0x00000073), Instruction::ECALL);
+        }
+
         #[test]
         fn test_ebreak() {
             assert_eq!(Instruction::new(0x00100073), Instruction::EBREAK);
         }
 
+        #[test]
+        fn test_fence() {
+            assert_eq!(
+                Instruction::new(0x0ff0000f),
+                Instruction::FENCE(0b1111, 0b1111)
+            );
+            assert_eq!(
+                Instruction::new(0x0310000f),
+                Instruction::FENCE(0b0011, 0b0001)
+            );
+            assert_eq!(
+                Instruction::new(0x0840000f),
+                Instruction::FENCE(0b1000, 0b0100)
+            );
+            assert_eq!(Instruction::new(0x8330000f), Instruction::FENCETSO);
+            assert_eq!(Instruction::new(0x0000100f), Instruction::FENCEI);
+            assert_eq!(Instruction::new(0x0000200f), Instruction::INVALID);
+        }
+
         #[test]
         fn test_mret() {
             assert_eq!(Instruction::new(0x30200073), Instruction::MRET);
         }
+
+        #[test]
+        fn test_supervisor_instructions() {
+            assert_eq!(Instruction::new(0x10200073), Instruction::SRET);
+            assert_eq!(Instruction::new(0x10500073), Instruction::WFI);
+            assert_eq!(
+                Instruction::new(0b0001001_00011_00010_000_00000_1110011),
+                Instruction::SFENCEVMA(2, 3)
+            );
+            assert_eq!(
+                Instruction::new(0b0001001_00011_00010_000_00001_1110011),
+                Instruction::INVALID
+            );
+        }
+
+        #[test]
+        fn test_csr() {
+            assert_eq!(
+                Instruction::new(0b001100000101_00010_001_00001_1110011),
+                Instruction::CSRRW(1, 2, 0x305)
+            );
+            assert_eq!(
+                Instruction::new(0b001100000101_00010_010_00001_1110011),
+                Instruction::CSRRS(1, 2, 0x305)
+            );
+            assert_eq!(
+                Instruction::new(0b001100000101_00010_011_00001_1110011),
+                Instruction::CSRRC(1, 2, 0x305)
+            );
+            assert_eq!(
+                Instruction::new(0b111100010100_11111_101_00001_1110011),
+                Instruction::CSRRWI(1, 31, 0xF14)
+            );
+            assert_eq!(
+                Instruction::new(0b111100010100_11111_110_00001_1110011),
+                Instruction::CSRRSI(1, 31, 0xF14)
+            );
+            assert_eq!(
+                Instruction::new(0b111100010100_11111_111_00001_1110011),
+                Instruction::CSRRCI(1, 31, 0xF14)
+            );
+        }
+    }
+
+    mod extensions {
+        use super::super::*;
+        use crate::isa::Isa;
+
+        #[test]
+        fn test_decode() {
+            let extensions = "rv32i_zmmul_zicsr".parse::<Isa>().unwrap().extensions();
+
+            // mul x1, x2, x3
+            assert_eq!(
+                WrappedInstruction::decode(0x0231_00B3, extensions).instruction,
+                Instruction::MUL(1, 2, 3)
+            );
+            // div x1, x2, x3
+            assert_eq!(
+                WrappedInstruction::decode(0x0231_40B3, extensions).instruction,
+                Instruction::INVALID
+            );
+            // c.addi x1, 1
+            assert_eq!(
+                WrappedInstruction::decode(0x0085, extensions),
+                WrappedInstruction {
+                    instruction: Instruction::INVALID,
+                    size: 2
+                }
+            );
+            // fence.i
+            assert_eq!(
+                WrappedInstruction::decode_rv64(0x0000_100F, extensions).instruction,
+                Instruction::INVALID
+            );
+        }
+
+        #[test]
+        fn test_extension() {
+            assert_eq!(Instruction::ADD(1, 2, 3).extension(), Extension::I);
+            assert_eq!(Instruction::MULW(1, 2, 3).extension(), Extension::Zmmul);
+            assert_eq!(Instruction::REMU(1, 2, 3).extension(), Extension::M);
+            assert_eq!(Instruction::FLW(1, 2, 0).extension(), Extension::F);
+            assert_eq!(Instruction::FCVTSD(1, 2, 0).extension(), Extension::D);
+            assert_eq!(Instruction::CSRRS(1, 2, 0).extension(), Extension::Zicsr);
+        }
     }
 }
@@ -0,0 +1,118 @@
+use crate::instruction::WrappedInstruction;
+use crate::memory::addressspace::Address;
+
+// Physical addresses are split into a 10 bit table index, a 10 bit page
+// index and the offset in the 4 KiB page, which holds one entry per halfword
+const TABLE_SHIFT: u32 = 22;
+const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: Address = 1 << PAGE_SHIFT;
+const INDEX_MASK: Address = 0x3FF;
+const TABLE_SIZE: usize = 1024;
+const PAGE_ENTRIES: usize = PAGE_SIZE as usize / 2;
+
+type Page = Box<[Option<WrappedInstruction>]>;
+type Table = Box<[Option<Page>]>;
+
+/// Decoded instructions indexed by their physical address. Pages are
+/// allocated when the first instruction on them is cached, so code can be
+/// located anywhere in the address space.
+///
+/// Writes have to be reported with `invalidate`, so that programs modifying
+/// their own code execute the new instructions.
+pub struct InstructionCache {
+    tables: Box<[Option<Table>]>,
+}
+
+impl Default for InstructionCache {
+    fn default() -> Self {
+        Self {
+            tables: vec![None; TABLE_SIZE].into_boxed_slice(),
+        }
+    }
+}
+
+impl InstructionCache {
+    pub fn get(&self, address: Address) -> Option<&WrappedInstruction> {
+        let table = self.tables[(address >> TABLE_SHIFT) as usize].as_ref()?;
+        let page = table[((address >> PAGE_SHIFT) & INDEX_MASK) as usize].as_ref()?;
+        page[Self::entry_index(address)].as_ref()
+    }
+
+    pub fn insert(&mut self, address: Address, instruction: WrappedInstruction) {
+        let table = self.tables[(address >> TABLE_SHIFT) as usize]
+            .get_or_insert_with(|| vec![None; TABLE_SIZE].into_boxed_slice());
+        let page = table[((address >> PAGE_SHIFT) & INDEX_MASK) as usize]
+            .get_or_insert_with(|| vec![None; PAGE_ENTRIES].into_boxed_slice());
+        page[Self::entry_index(address)] = Some(instruction);
+    }
+
+    /// Drops the instructions overlapping a write of `size` bytes. A 32 bit
+    /// instruction starting two bytes before the write is affected as well.
+    pub fn invalidate(&mut self, address: Address, size: u32) {
+        let start = address.saturating_sub(2) & !1;
+        let end = address.saturating_add(size);
+
+        for entry_address in (start..end).step_by(2) {
+            let page = self.tables[(entry_address >> TABLE_SHIFT) as usize]
+                .as_mut()
+                .and_then(|table| {
+                    table[((entry_address >> PAGE_SHIFT) & INDEX_MASK) as usize].as_mut()
+                });
+
+            if let Some(page) = page {
+                page[Self::entry_index(entry_address)] = None;
+            }
+        }
+    }
+
+    /// Drops all cached instructions, as required by FENCE.I
+    pub fn flush(&mut self) {
+        for table in self.tables.iter_mut() {
+            *table = None;
+        }
+    }
+
+    fn entry_index(address: Address) -> usize {
+        ((address & (PAGE_SIZE - 1)) >> 1) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_invalidate() {
+        let mut cache = InstructionCache::default();
+        let addi = WrappedInstruction::new(0x00108093);
+        cache.insert(0x10, addi.clone());
+        cache.insert(0x14, addi.clone());
+        cache.insert(0x8000_1000, addi.clone());
+        cache.insert(0xFFFF_FFFE, addi.clone());
+
+        assert_eq!(cache.get(0x10), Some(&addi));
+        assert_eq!(cache.get(0x8000_1000), Some(&addi));
+        assert_eq!(cache.get(0xFFFF_FFFE), Some(&addi));
+        assert_eq!(cache.get(0x8000_2000), None);
+
+        // Overwriting the upper half of the instruction at 0x10
+        cache.invalidate(0x12, 1);
+        assert_eq!(cache.get(0x10), None);
+        assert_eq!(cache.get(0x14), Some(&addi));
+
+        cache.invalidate(0x18, 4);
+        assert_eq!(cache.get(0x14), Some(&addi));
+
+        // Writes to the first bytes of a page reach back to the previous one
+        cache.insert(0x8000_0FFE, addi.clone());
+        cache.invalidate(0x8000_1000, 1);
+        assert_eq!(cache.get(0x8000_0FFE), None);
+        assert_eq!(cache.get(0x8000_1000), None);
+
+        cache.invalidate(0xFFFF_FFFF, 1);
+        assert_eq!(cache.get(0xFFFF_FFFE), None);
+
+        cache.flush();
+        assert_eq!(cache.get(0x14), None);
+    }
+}
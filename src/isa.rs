@@ -0,0 +1,402 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// The width of the integer registers, which is selected by the class of
+/// the loaded ELF file.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Xlen {
+    Rv32,
+    Rv64,
+}
+
+impl Xlen {
+    pub fn bits(self) -> u32 {
+        match self {
+            Xlen::Rv32 => 32,
+            Xlen::Rv64 => 64,
+        }
+    }
+}
+
+// The order of single-letter extensions in ISA strings
+const CANONICAL_ORDER: &str = "iemafdqlcbkjtpvh";
+
+// The extensions abbreviated by G
+const GENERAL: [&str; 7] = ["i", "m", "a", "f", "d", "zicsr", "zifencei"];
+
+/// A base ISA with its extensions, as given by ISA strings like
+/// `rv32imac_zicsr_zifencei`. Version numbers like in `rv32i2p1_m2p0` are
+/// accepted and ignored.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Isa {
+    xlen: Xlen,
+    extensions: BTreeSet<String>,
+}
+
+impl Isa {
+    /// The extensions implemented by the emulator
+    pub fn supported(xlen: Xlen) -> Isa {
+        match xlen {
+            Xlen::Rv32 => "rv32imafdc_zicsr_zifencei_zba_zbb_zbc_zbs",
+            Xlen::Rv64 => "rv64imac_zicsr_zifencei",
+        }
+        .parse()
+        .unwrap()
+    }
+
+    pub fn xlen(&self) -> Xlen {
+        self.xlen
+    }
+
+    /// Checks for an extension given by its lower case name, extensions
+    /// implied by others are included
+    pub fn has(&self, extension: &str) -> bool {
+        self.expanded().contains(extension)
+    }
+
+    /// Returns the extensions of `required` which this ISA lacks, the
+    /// RV32E and RV64E bases are part of the corresponding I base.
+    pub fn missing_extensions(&self, required: &Isa) -> Vec<String> {
+        let available = self.expanded();
+
+        required
+            .extensions
+            .iter()
+            .filter(|&extension| !(extension == "e" && available.contains("i")))
+            .filter(|&extension| !implied_by(extension).is_subset(&available))
+            .cloned()
+            .collect()
+    }
+
+    fn expanded(&self) -> BTreeSet<String> {
+        let mut extensions = self.extensions.clone();
+
+        loop {
+            let mut implied: BTreeSet<String> = extensions
+                .iter()
+                .flat_map(|extension| implied_by(extension))
+                .collect();
+            if extensions.contains("c") && self.xlen == Xlen::Rv32 && extensions.contains("f") {
+                implied.insert("zcf".into());
+            }
+            if extensions.contains("c") && extensions.contains("d") {
+                implied.insert("zcd".into());
+            }
+
+            if implied.is_subset(&extensions) {
+                return extensions;
+            }
+            extensions.extend(implied);
+        }
+    }
+}
+
+/// An extension together with the extensions it implies directly
+fn implied_by(extension: &str) -> BTreeSet<String> {
+    let implied: &[&str] = match extension {
+        "m" => &["zmmul"],
+        "a" => &["zaamo", "zalrsc"],
+        "f" => &["zicsr"],
+        "d" => &["f"],
+        "q" => &["d"],
+        "b" => &["zba", "zbb", "zbs"],
+        "c" => &["zca"],
+        _ => &[],
+    };
+
+    std::iter::once(extension)
+        .chain(implied.iter().copied())
+        .map(String::from)
+        .collect()
+}
+
+impl FromStr for Isa {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("invalid ISA string: {}", s);
+        let lower = s.to_ascii_lowercase();
+
+        let (xlen, rest) = if let Some(rest) = lower.strip_prefix("rv32") {
+            (Xlen::Rv32, rest)
+        } else if let Some(rest) = lower.strip_prefix("rv64") {
+            (Xlen::Rv64, rest)
+        } else {
+            return Err(error());
+        };
+        if !rest.starts_with(['i', 'e', 'g']) {
+            return Err(error());
+        }
+
+        let mut extensions = BTreeSet::new();
+        for part in rest.split('_') {
+            if part.starts_with(['z', 's', 'x']) {
+                let name = strip_version(part);
+                if name.len() < 2 {
+                    return Err(error());
+                }
+                extensions.insert(name.to_string());
+                continue;
+            }
+
+            let mut characters = part.chars().peekable();
+            while let Some(letter) = characters.next() {
+                if !CANONICAL_ORDER.contains(letter) && letter != 'g' {
+                    return Err(error());
+                }
+                extensions.insert(letter.to_string());
+
+                // An optional version like 2p1
+                let mut has_major = false;
+                while characters.peek().is_some_and(char::is_ascii_digit) {
+                    characters.next();
+                    has_major = true;
+                }
+                if has_major && characters.peek() == Some(&'p') {
+                    characters.next();
+                    while characters.peek().is_some_and(char::is_ascii_digit) {
+                        characters.next();
+                    }
+                }
+            }
+        }
+
+        if extensions.remove("g") {
+            extensions.extend(GENERAL.iter().map(|&extension| extension.to_string()));
+        }
+
+        Ok(Isa { xlen, extensions })
+    }
+}
+
+/// Removes a version like `2p0` from the end of a multi-letter extension
+fn strip_version(name: &str) -> &str {
+    let without_minor = match name.rfind('p') {
+        Some(index)
+            if index + 1 < name.len()
+                && name[index + 1..].bytes().all(|b| b.is_ascii_digit())
+                && name[..index].ends_with(|c: char| c.is_ascii_digit()) =>
+        {
+            &name[..index]
+        }
+        _ => name,
+    };
+
+    let trimmed = without_minor.trim_end_matches(|c: char| c.is_ascii_digit());
+    if trimmed.len() < without_minor.len() {
+        trimmed
+    } else {
+        name
+    }
+}
+
+impl fmt::Display for Isa {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rv{}", self.xlen.bits())?;
+        for letter in CANONICAL_ORDER.chars() {
+            if self.extensions.contains(&letter.to_string()) {
+                write!(f, "{}", letter)?;
+            }
+        }
+        for extension in self.extensions.iter().filter(|name| name.len() > 1) {
+            write!(f, "_{}", extension)?;
+        }
+        Ok(())
+    }
+}
+
+/// The extensions implemented by the emulator, which the decoder checks for
+/// every instruction. Each extension only covers the instructions not
+/// belonging to another one, e.g. M only adds division, as multiplication
+/// is part of Zmmul.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Extension {
+    I,
+    Zmmul,
+    M,
+    Zalrsc,
+    Zaamo,
+    F,
+    D,
+    /// Compressed instructions, which additionally need F or D for the
+    /// floating-point loads and stores
+    C,
+    Zicsr,
+    Zifencei,
+    Zba,
+    Zbb,
+    Zbc,
+    Zbs,
+}
+
+impl Extension {
+    const ALL: [Extension; 14] = [
+        Extension::I,
+        Extension::Zmmul,
+        Extension::M,
+        Extension::Zalrsc,
+        Extension::Zaamo,
+        Extension::F,
+        Extension::D,
+        Extension::C,
+        Extension::Zicsr,
+        Extension::Zifencei,
+        Extension::Zba,
+        Extension::Zbb,
+        Extension::Zbc,
+        Extension::Zbs,
+    ];
+
+    /// The name in ISA strings
+    pub fn name(self) -> &'static str {
+        match self {
+            Extension::I => "i",
+            Extension::Zmmul => "zmmul",
+            Extension::M => "m",
+            Extension::Zalrsc => "zalrsc",
+            Extension::Zaamo => "zaamo",
+            Extension::F => "f",
+            Extension::D => "d",
+            Extension::C => "zca",
+            Extension::Zicsr => "zicsr",
+            Extension::Zifencei => "zifencei",
+            Extension::Zba => "zba",
+            Extension::Zbb => "zbb",
+            Extension::Zbc => "zbc",
+            Extension::Zbs => "zbs",
+        }
+    }
+}
+
+/// A set of extensions, cheap to copy and to check
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Extensions(u32);
+
+impl Extensions {
+    pub fn contains(self, extension: Extension) -> bool {
+        self.0 & 1 << extension as u32 != 0
+    }
+}
+
+impl Isa {
+    /// The enabled extensions among those the emulator implements
+    pub fn extensions(&self) -> Extensions {
+        let expanded = self.expanded();
+
+        Extensions(
+            Extension::ALL
+                .iter()
+                .filter(|extension| expanded.contains(extension.name()))
+                .fold(0, |bits, &extension| bits | 1 << extension as u32),
+        )
+    }
+
+    /// The value of misa: MXL, the single-letter extensions and the
+    /// supervisor and user modes
+    pub fn misa(&self) -> u64 {
+        let expanded = self.expanded();
+        let letters = ('a'..='z')
+            .filter(|letter| expanded.contains(&letter.to_string()))
+            .fold(0, |bits, letter| bits | 1 << (letter as u32 - 'a' as u32));
+        let modes = 1 << ('s' as u32 - 'a' as u32) | 1 << ('u' as u32 - 'a' as u32);
+
+        match self.xlen {
+            Xlen::Rv32 => 1 << 30 | modes | letters,
+            Xlen::Rv64 => 2 << 62 | modes | letters,
+        }
+    }
+
+    /// Checks that the emulator implements all extensions, the E base isn't
+    /// supported.
+    pub fn check_supported(&self) -> Result<(), String> {
+        let mut missing = Isa::supported(self.xlen).missing_extensions(self);
+        if !self.extensions.contains("i") {
+            missing.insert(0, "i".to_string());
+            missing.retain(|extension| extension != "e");
+        }
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("{} (missing {})", self, missing.join(", ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let isa: Isa = "rv32imac_zicsr_zifencei".parse().unwrap();
+        assert_eq!(isa.xlen(), Xlen::Rv32);
+        assert!(isa.has("m") && isa.has("zicsr") && isa.has("zmmul"));
+        assert!(!isa.has("f"));
+        assert_eq!(isa.to_string(), "rv32imac_zicsr_zifencei");
+
+        let isa: Isa = "rv32i2p1_m2p0_a2p1_c2p0_zicsr2p0_zve32x1p0"
+            .parse()
+            .unwrap();
+        assert_eq!(isa.to_string(), "rv32imac_zicsr_zve32x");
+
+        let isa: Isa = "RV64GC".parse().unwrap();
+        assert_eq!(isa.to_string(), "rv64imafdc_zicsr_zifencei");
+        assert!(isa.has("zcd"));
+
+        assert!("rv32".parse::<Isa>().is_err());
+        assert!("rv128i".parse::<Isa>().is_err());
+        assert!("rv32iy".parse::<Isa>().is_err());
+    }
+
+    #[test]
+    fn test_missing_extensions() {
+        let supported = Isa::supported(Xlen::Rv32);
+        let required = |s: &str| s.parse::<Isa>().unwrap();
+
+        assert!(supported
+            .missing_extensions(&required("rv32i2p1_m2p0_zmmul1p0_zca1p0_zcf1p0"))
+            .is_empty());
+        assert!(supported
+            .missing_extensions(&required("rv32e_m"))
+            .is_empty());
+        assert_eq!(
+            supported.missing_extensions(&required("rv32gcv_zbkb")),
+            vec!["v", "zbkb"]
+        );
+        assert_eq!(
+            required("rv32e").missing_extensions(&required("rv32im")),
+            vec!["i", "m"]
+        );
+    }
+
+    #[test]
+    fn test_extensions() {
+        let isa: Isa = "rv32imc_zicsr".parse().unwrap();
+        let extensions = isa.extensions();
+        assert!(extensions.contains(Extension::M));
+        assert!(extensions.contains(Extension::Zmmul));
+        assert!(extensions.contains(Extension::C));
+        assert!(!extensions.contains(Extension::Zaamo));
+        assert!(!extensions.contains(Extension::Zifencei));
+
+        assert_eq!(Isa::supported(Xlen::Rv32).misa(), 0x4014_112D);
+        assert_eq!(isa.misa(), 0x4014_1104);
+        assert_eq!(
+            "rv64imac".parse::<Isa>().unwrap().misa(),
+            0x8000_0000_0014_1105
+        );
+
+        assert!(isa.check_supported().is_ok());
+        assert_eq!(
+            "rv32ev".parse::<Isa>().unwrap().check_supported(),
+            Err("rv32ev (missing i, v)".to_string())
+        );
+        assert!("rv64imafd"
+            .parse::<Isa>()
+            .unwrap()
+            .check_supported()
+            .is_err());
+    }
+}
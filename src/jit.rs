@@ -0,0 +1,741 @@
+//! A dynamic binary translator compiling hot basic blocks to x86-64 machine
+//! code, enabled with the `jit` feature on x86-64 Linux hosts.
+//!
+//! The compiled code works on the registers of the hart and accesses the RAM
+//! directly. It leaves the block at the micro-ops it doesn't handle, which
+//! continue in the block engine: interpreted instructions, accesses outside
+//! of the RAM such as MMIO, and stores to pages holding cached instructions,
+//! so that the instruction caches see the writes. Compiled blocks are only
+//! run while data addresses aren't translated and PMP is disabled, so the
+//! direct accesses can't trap.
+
+use crate::block::{Block, Condition, Exit, MicroOp};
+use crate::memory::addressspace::{Address, AddressSpace};
+use crate::memory::RAM_SIZE;
+use std::ptr;
+
+/// By default, blocks are compiled once they have been executed this many
+/// times
+pub const HOT_BLOCK_THRESHOLD: u32 = 16;
+
+/// The size of the machine code buffer of each hart. When it is full, the
+/// blocks are compiled again once they are hot.
+const CODE_BUFFER_SIZE: usize = 16 << 20;
+
+const PAGE_SIZE: u32 = 1 << 12;
+
+// The compiled code returns the pc to continue at in the upper half of rax,
+// and one of these or the index of the micro-op it left the block at in the
+// lower half
+const EXIT_TAKEN: u32 = 0x100;
+const EXIT_FALL_THROUGH: u32 = 0x101;
+const EXIT_INDIRECT: u32 = 0x102;
+
+/// Compiled blocks are called with pointers to the registers, the RAM and
+/// the bitmap of watched pages in rdi, rsi and rdx
+type Entry = unsafe extern "sysv64" fn(*mut u32, *mut u8, *const u64) -> u64;
+
+// The x86-64 registers used as temporaries
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+
+// Condition codes of Jcc and SETcc
+const CC_BELOW: u8 = 0x2;
+const CC_ABOVE_EQUAL: u8 = 0x3;
+const CC_EQUAL: u8 = 0x4;
+const CC_NOT_EQUAL: u8 = 0x5;
+const CC_ABOVE: u8 = 0x7;
+const CC_LESS: u8 = 0xC;
+const CC_GREATER_EQUAL: u8 = 0xD;
+
+// The operations of the immediate group (81 /n), the opcode of the register
+// form is n << 3 | 1
+const ALU_ADD: u8 = 0;
+const ALU_OR: u8 = 1;
+const ALU_AND: u8 = 4;
+const ALU_SUB: u8 = 5;
+const ALU_XOR: u8 = 6;
+const ALU_CMP: u8 = 7;
+
+// The operations of the shift group (C1 /n and D3 /n)
+const SHIFT_LEFT: u8 = 4;
+const SHIFT_RIGHT: u8 = 5;
+const SHIFT_RIGHT_ARITHMETIC: u8 = 7;
+
+/// How compiled code left a block
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Outcome {
+    /// The block was executed up to its exit, continuing at the address
+    Exit(Exit, Address),
+    /// The micro-ops starting at the index are left to the block engine
+    SideExit(usize),
+}
+
+/// Whether compiled code performs a memory access itself. The access has to
+/// be located in RAM, and a store must neither touch a watched page nor cross
+/// into the next page.
+pub fn is_direct_access(memory: &AddressSpace, address: Address, size: u32, store: bool) -> bool {
+    let in_ram = address <= RAM_SIZE as Address - size;
+    if !store {
+        return in_ram;
+    }
+
+    in_ram && address & (PAGE_SIZE - 1) <= PAGE_SIZE - size && !memory.is_watched(address)
+}
+
+/// The address and size of the memory access of a micro-op, and whether it
+/// is a store
+pub fn memory_access(op: &MicroOp, registers: &[u32; 32]) -> Option<(Address, u32, bool)> {
+    let address = |base: u8, offset: i32| registers[base as usize].wrapping_add(offset as u32);
+
+    match *op {
+        MicroOp::LoadByte(_, rs1, offset) | MicroOp::LoadByteUnsigned(_, rs1, offset) => {
+            Some((address(rs1, offset), 1, false))
+        }
+        MicroOp::LoadHalfword(_, rs1, offset) | MicroOp::LoadHalfwordUnsigned(_, rs1, offset) => {
+            Some((address(rs1, offset), 2, false))
+        }
+        MicroOp::LoadWord(_, rs1, offset) => Some((address(rs1, offset), 4, false)),
+        MicroOp::StoreByte(rs1, _, offset) => Some((address(rs1, offset), 1, true)),
+        MicroOp::StoreHalfword(rs1, _, offset) => Some((address(rs1, offset), 2, true)),
+        MicroOp::StoreWord(rs1, _, offset) => Some((address(rs1, offset), 4, true)),
+        _ => None,
+    }
+}
+
+/// Whether compiled code executes a micro-op, all others are interpreted
+pub fn is_compiled(op: &MicroOp) -> bool {
+    !matches!(op, MicroOp::Interpret(..))
+}
+
+/// Whether a jump or branch has a target which isn't word-aligned. Without
+/// compressed instructions such a jump traps, which compiled code leaves to
+/// the block engine, for branches whether they are taken or not.
+pub fn has_misaligned_target(op: &MicroOp, registers: &[u32; 32]) -> bool {
+    let target = match *op {
+        MicroOp::Branch(_, _, _, target) | MicroOp::Jump(_, target) => target,
+        MicroOp::JumpRegister(_, rs1, offset) => {
+            registers[rs1 as usize].wrapping_add(offset as u32)
+        }
+        _ => return false,
+    };
+    target & 0b10 != 0
+}
+
+/// The machine code of a block
+#[derive(Clone, Copy)]
+pub struct CompiledBlock {
+    entry: Entry,
+}
+
+impl CompiledBlock {
+    /// Runs the block on the registers of a hart, accessing the RAM of
+    /// `memory` directly
+    pub fn run(&self, registers: &mut [u32; 32], memory: &mut AddressSpace) -> Outcome {
+        let ram = memory.ram_mut().as_mut_ptr();
+        let watched = memory.watched_pages().as_ptr();
+
+        // The code only accesses the registers, the RAM below RAM_SIZE and
+        // the bit of the bitmap belonging to a RAM page
+        let result = unsafe { (self.entry)(registers.as_mut_ptr(), ram, watched) };
+        let pc = (result >> 32) as Address;
+
+        match result as u32 {
+            EXIT_TAKEN => Outcome::Exit(Exit::Taken, pc),
+            EXIT_FALL_THROUGH => Outcome::Exit(Exit::FallThrough, pc),
+            EXIT_INDIRECT => Outcome::Exit(Exit::Indirect, pc),
+            index => Outcome::SideExit(index as usize),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Translation {
+    /// Counts the executions until the block is hot
+    Cold(u32),
+    Compiled(CompiledBlock),
+    /// Blocks starting with an interpreted instruction aren't compiled
+    Interpreted,
+}
+
+/// The compiled code of the blocks in a `BlockCache`, by their index
+pub struct Jit {
+    translations: Vec<Translation>,
+    generation: u64,
+    hot_threshold: u32,
+    /// Whether jumps to targets which aren't word-aligned trap
+    word_aligned_targets: bool,
+    code: Option<CodeBuffer>,
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Self {
+            translations: Vec::new(),
+            generation: 0,
+            hot_threshold: HOT_BLOCK_THRESHOLD,
+            word_aligned_targets: false,
+            code: None,
+        }
+    }
+}
+
+impl Jit {
+    /// Sets the number of executions after which blocks are compiled
+    pub fn set_hot_threshold(&mut self, executions: u32) {
+        self.hot_threshold = executions;
+    }
+
+    /// Sets whether jumps to targets which aren't word-aligned trap, as
+    /// without compressed instructions
+    pub fn set_word_aligned_targets(&mut self, word_aligned: bool) {
+        self.word_aligned_targets = word_aligned;
+    }
+
+    /// Returns the compiled code of a block, compiling it once it is hot.
+    /// `generation` is the one of the block cache, which reuses the indexes
+    /// after a flush.
+    pub fn get(&mut self, index: usize, block: &Block, generation: u64) -> Option<CompiledBlock> {
+        if generation != self.generation {
+            self.translations.clear();
+            self.generation = generation;
+        }
+        if index >= self.translations.len() {
+            self.translations.resize(index + 1, Translation::Cold(0));
+        }
+
+        match self.translations[index] {
+            Translation::Compiled(compiled) => Some(compiled),
+            Translation::Interpreted => None,
+            Translation::Cold(executions) if executions + 1 < self.hot_threshold => {
+                self.translations[index] = Translation::Cold(executions + 1);
+                None
+            }
+            Translation::Cold(_) => {
+                let compiled = self.compile(block);
+                self.translations[index] =
+                    compiled.map_or(Translation::Interpreted, Translation::Compiled);
+                compiled
+            }
+        }
+    }
+
+    fn compile(&mut self, block: &Block) -> Option<CompiledBlock> {
+        let code = compile(block, self.word_aligned_targets)?;
+        if self.code.is_none() {
+            self.code = CodeBuffer::new();
+        }
+        let buffer = self.code.as_mut()?;
+
+        let entry = match buffer.push(&code) {
+            Some(entry) => entry,
+            None => {
+                buffer.clear();
+                for translation in self.translations.iter_mut() {
+                    if let Translation::Compiled(_) = translation {
+                        *translation = Translation::Cold(0);
+                    }
+                }
+                buffer.push(&code)?
+            }
+        };
+
+        Some(CompiledBlock {
+            entry: unsafe { std::mem::transmute::<*const u8, Entry>(entry) },
+        })
+    }
+}
+
+/// Memory holding machine code, which is only writable while code is added
+struct CodeBuffer {
+    memory: *mut u8,
+    used: usize,
+}
+
+impl CodeBuffer {
+    fn new() -> Option<Self> {
+        let memory = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                CODE_BUFFER_SIZE,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if memory == libc::MAP_FAILED {
+            None
+        } else {
+            Some(Self {
+                memory: memory as *mut u8,
+                used: 0,
+            })
+        }
+    }
+
+    /// Copies code into the buffer, returning its address, or None if the
+    /// buffer is full
+    fn push(&mut self, code: &[u8]) -> Option<*const u8> {
+        if self.used + code.len() > CODE_BUFFER_SIZE {
+            return None;
+        }
+
+        let first_page = self.used & !(PAGE_SIZE as usize - 1);
+        let length = self.used + code.len() - first_page;
+        unsafe {
+            let start = self.memory.add(self.used);
+            self.protect(first_page, length, libc::PROT_READ | libc::PROT_WRITE);
+            ptr::copy_nonoverlapping(code.as_ptr(), start, code.len());
+            self.protect(first_page, length, libc::PROT_READ | libc::PROT_EXEC);
+
+            // Blocks start at 16 byte boundaries
+            self.used = (self.used + code.len() + 15) & !15;
+            Some(start)
+        }
+    }
+
+    fn clear(&mut self) {
+        self.used = 0;
+    }
+
+    unsafe fn protect(&self, offset: usize, length: usize, protection: libc::c_int) {
+        let result = libc::mprotect(
+            self.memory.add(offset) as *mut libc::c_void,
+            length,
+            protection,
+        );
+        assert_eq!(result, 0, "failed to change the protection of JIT code");
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.memory as *mut libc::c_void, CODE_BUFFER_SIZE);
+        }
+    }
+}
+
+/// Translates the micro-ops of a block. Blocks starting with an interpreted
+/// instruction aren't worth compiling. With `word_aligned_targets`, jumps
+/// to targets which aren't word-aligned are left to the block engine.
+fn compile(block: &Block, word_aligned_targets: bool) -> Option<Vec<u8>> {
+    if !is_compiled(&block.ops.first()?.1) {
+        return None;
+    }
+
+    let mut assembler = Assembler::default();
+    for (index, (_, op)) in block.ops.iter().enumerate() {
+        match *op {
+            MicroOp::LoadImmediate(rd, value) => assembler.store_immediate(rd, value),
+            MicroOp::AddImmediate(rd, rs1, imm) => assembler.alu_immediate(ALU_ADD, rd, rs1, imm),
+            MicroOp::SetLessThanImmediate(rd, rs1, imm) => {
+                assembler.compare_immediate(CC_LESS, rd, rs1, imm as u32)
+            }
+            MicroOp::SetLessThanImmediateUnsigned(rd, rs1, imm) => {
+                assembler.compare_immediate(CC_BELOW, rd, rs1, imm)
+            }
+            MicroOp::XorImmediate(rd, rs1, imm) => assembler.alu_immediate(ALU_XOR, rd, rs1, imm),
+            MicroOp::OrImmediate(rd, rs1, imm) => assembler.alu_immediate(ALU_OR, rd, rs1, imm),
+            MicroOp::AndImmediate(rd, rs1, imm) => assembler.alu_immediate(ALU_AND, rd, rs1, imm),
+            MicroOp::ShiftLeftImmediate(rd, rs1, shamt) => {
+                assembler.shift_immediate(SHIFT_LEFT, rd, rs1, shamt)
+            }
+            MicroOp::ShiftRightImmediate(rd, rs1, shamt) => {
+                assembler.shift_immediate(SHIFT_RIGHT, rd, rs1, shamt)
+            }
+            MicroOp::ShiftRightArithmeticImmediate(rd, rs1, shamt) => {
+                assembler.shift_immediate(SHIFT_RIGHT_ARITHMETIC, rd, rs1, shamt)
+            }
+            MicroOp::Add(rd, rs1, rs2) => assembler.alu(ALU_ADD, rd, rs1, rs2),
+            MicroOp::Sub(rd, rs1, rs2) => assembler.alu(ALU_SUB, rd, rs1, rs2),
+            MicroOp::ShiftLeft(rd, rs1, rs2) => assembler.shift(SHIFT_LEFT, rd, rs1, rs2),
+            MicroOp::SetLessThan(rd, rs1, rs2) => assembler.compare(CC_LESS, rd, rs1, rs2),
+            MicroOp::SetLessThanUnsigned(rd, rs1, rs2) => assembler.compare(CC_BELOW, rd, rs1, rs2),
+            MicroOp::Xor(rd, rs1, rs2) => assembler.alu(ALU_XOR, rd, rs1, rs2),
+            MicroOp::ShiftRight(rd, rs1, rs2) => assembler.shift(SHIFT_RIGHT, rd, rs1, rs2),
+            MicroOp::ShiftRightArithmetic(rd, rs1, rs2) => {
+                assembler.shift(SHIFT_RIGHT_ARITHMETIC, rd, rs1, rs2)
+            }
+            MicroOp::Or(rd, rs1, rs2) => assembler.alu(ALU_OR, rd, rs1, rs2),
+            MicroOp::And(rd, rs1, rs2) => assembler.alu(ALU_AND, rd, rs1, rs2),
+            // movsx ecx, byte [rsi + rax]
+            MicroOp::LoadByte(rd, rs1, offset) => {
+                assembler.load(&[0x0F, 0xBE], 1, rd, rs1, offset, index)
+            }
+            // movsx ecx, word [rsi + rax]
+            MicroOp::LoadHalfword(rd, rs1, offset) => {
+                assembler.load(&[0x0F, 0xBF], 2, rd, rs1, offset, index)
+            }
+            // mov ecx, [rsi + rax]
+            MicroOp::LoadWord(rd, rs1, offset) => {
+                assembler.load(&[0x8B], 4, rd, rs1, offset, index)
+            }
+            // movzx ecx, byte [rsi + rax]
+            MicroOp::LoadByteUnsigned(rd, rs1, offset) => {
+                assembler.load(&[0x0F, 0xB6], 1, rd, rs1, offset, index)
+            }
+            // movzx ecx, word [rsi + rax]
+            MicroOp::LoadHalfwordUnsigned(rd, rs1, offset) => {
+                assembler.load(&[0x0F, 0xB7], 2, rd, rs1, offset, index)
+            }
+            // mov [rsi + rax], cl
+            MicroOp::StoreByte(rs1, rs2, offset) => {
+                assembler.store(&[0x88], 1, rs1, rs2, offset, index)
+            }
+            // mov [rsi + rax], cx
+            MicroOp::StoreHalfword(rs1, rs2, offset) => {
+                assembler.store(&[0x66, 0x89], 2, rs1, rs2, offset, index)
+            }
+            // mov [rsi + rax], ecx
+            MicroOp::StoreWord(rs1, rs2, offset) => {
+                assembler.store(&[0x89], 4, rs1, rs2, offset, index)
+            }
+            MicroOp::Branch(_, _, _, target) | MicroOp::Jump(_, target)
+                if word_aligned_targets && target & 0b10 != 0 =>
+            {
+                assembler.return_side_exit(index);
+                return Some(assembler.finish());
+            }
+            MicroOp::Branch(condition, rs1, rs2, target) => {
+                assembler.branch(condition, rs1, rs2, target, block.end);
+                return Some(assembler.finish());
+            }
+            MicroOp::Jump(rd, target) => {
+                assembler.store_immediate(rd, block.end);
+                assembler.return_exit(EXIT_TAKEN, target);
+                return Some(assembler.finish());
+            }
+            MicroOp::JumpRegister(rd, rs1, offset) => {
+                let misaligned_exit = if word_aligned_targets {
+                    Some(index)
+                } else {
+                    None
+                };
+                assembler.jump_register(rd, rs1, offset, block.end, misaligned_exit);
+                return Some(assembler.finish());
+            }
+            MicroOp::Interpret(_) => {
+                assembler.return_side_exit(index);
+                return Some(assembler.finish());
+            }
+        }
+    }
+
+    assembler.return_exit(EXIT_FALL_THROUGH, block.end);
+    Some(assembler.finish())
+}
+
+/// Emits the machine code of a block. The guest registers are kept in
+/// memory, eax and ecx hold the operands of the current micro-op.
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+    /// The jumps to the side exits: the position of their displacement and
+    /// the index of the micro-op
+    side_exits: Vec<(usize, usize)>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, value: u32) {
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// mov reg, [rdi + 4 * register], x0 is always zero
+    fn load_register(&mut self, reg: u8, register: u8) {
+        if register == 0 {
+            self.emit(&[0x31, 0xC0 | reg << 3 | reg]);
+        } else {
+            self.emit(&[0x8B, 0x47 | reg << 3, register * 4]);
+        }
+    }
+
+    /// mov [rdi + 4 * register], reg, writes to x0 are discarded
+    fn store_register(&mut self, register: u8, reg: u8) {
+        if register != 0 {
+            self.emit(&[0x89, 0x47 | reg << 3, register * 4]);
+        }
+    }
+
+    /// mov dword [rdi + 4 * register], value
+    fn store_immediate(&mut self, register: u8, value: u32) {
+        if register != 0 {
+            self.emit(&[0xC7, 0x47, register * 4]);
+            self.emit_u32(value);
+        }
+    }
+
+    /// op reg, value
+    fn emit_alu_immediate(&mut self, operation: u8, reg: u8, value: u32) {
+        self.emit(&[0x81, 0xC0 | operation << 3 | reg]);
+        self.emit_u32(value);
+    }
+
+    fn alu_immediate(&mut self, operation: u8, rd: u8, rs1: u8, imm: u32) {
+        self.load_register(RAX, rs1);
+        self.emit_alu_immediate(operation, RAX, imm);
+        self.store_register(rd, RAX);
+    }
+
+    fn alu(&mut self, operation: u8, rd: u8, rs1: u8, rs2: u8) {
+        self.load_register(RAX, rs1);
+        self.load_register(RCX, rs2);
+        // op eax, ecx
+        self.emit(&[operation << 3 | 1, 0xC0 | RCX << 3 | RAX]);
+        self.store_register(rd, RAX);
+    }
+
+    fn shift_immediate(&mut self, operation: u8, rd: u8, rs1: u8, shamt: u32) {
+        self.load_register(RAX, rs1);
+        self.emit(&[0xC1, 0xC0 | operation << 3 | RAX, shamt as u8]);
+        self.store_register(rd, RAX);
+    }
+
+    /// Shifts by cl, which x86 masks to 5 bits like RISC-V
+    fn shift(&mut self, operation: u8, rd: u8, rs1: u8, rs2: u8) {
+        self.load_register(RAX, rs1);
+        self.load_register(RCX, rs2);
+        self.emit(&[0xD3, 0xC0 | operation << 3 | RAX]);
+        self.store_register(rd, RAX);
+    }
+
+    /// Sets eax to 1 if the condition holds, otherwise to 0
+    fn set_condition(&mut self, condition: u8) {
+        // setcc al and movzx eax, al
+        self.emit(&[0x0F, 0x90 | condition, 0xC0, 0x0F, 0xB6, 0xC0]);
+    }
+
+    fn compare_immediate(&mut self, condition: u8, rd: u8, rs1: u8, imm: u32) {
+        self.load_register(RAX, rs1);
+        self.emit_alu_immediate(ALU_CMP, RAX, imm);
+        self.set_condition(condition);
+        self.store_register(rd, RAX);
+    }
+
+    /// cmp eax, ecx with the values of two registers
+    fn compare_registers(&mut self, rs1: u8, rs2: u8) {
+        self.load_register(RAX, rs1);
+        self.load_register(RCX, rs2);
+        self.emit(&[ALU_CMP << 3 | 1, 0xC0 | RCX << 3 | RAX]);
+    }
+
+    fn compare(&mut self, condition: u8, rd: u8, rs1: u8, rs2: u8) {
+        self.compare_registers(rs1, rs2);
+        self.set_condition(condition);
+        self.store_register(rd, RAX);
+    }
+
+    /// Jcc with a 32 bit displacement, returning the position of the
+    /// displacement
+    fn jump_if(&mut self, condition: u8) -> usize {
+        self.emit(&[0x0F, 0x80 | condition]);
+        let position = self.code.len();
+        self.emit_u32(0);
+        position
+    }
+
+    /// Points the jump with the displacement at `position` to `target`
+    fn patch_to(&mut self, position: usize, target: usize) {
+        let displacement = (target - (position + 4)) as u32;
+        self.code[position..position + 4].copy_from_slice(&displacement.to_le_bytes());
+    }
+
+    /// Leaves the block at micro-op `index` if the condition holds
+    fn side_exit_if(&mut self, condition: u8, index: usize) {
+        let position = self.jump_if(condition);
+        self.side_exits.push((position, index));
+    }
+
+    /// Computes the address of an access in eax, leaving the block if it
+    /// isn't direct, see `is_direct_access`
+    fn address(&mut self, rs1: u8, offset: i32, size: u32, store: bool, index: usize) {
+        self.load_register(RAX, rs1);
+        if offset != 0 {
+            self.emit_alu_immediate(ALU_ADD, RAX, offset as u32);
+        }
+        self.emit_alu_immediate(ALU_CMP, RAX, RAM_SIZE as u32 - size);
+        self.side_exit_if(CC_ABOVE, index);
+
+        if store {
+            // mov ecx, eax and the offset in the page
+            self.emit(&[0x89, 0xC0 | RAX << 3 | RCX]);
+            self.emit_alu_immediate(ALU_AND, RCX, PAGE_SIZE - 1);
+            self.emit_alu_immediate(ALU_CMP, RCX, PAGE_SIZE - size);
+            self.side_exit_if(CC_ABOVE, index);
+
+            // mov ecx, eax, shr ecx, 12 and bt [rdx], rcx
+            self.emit(&[0x89, 0xC0 | RAX << 3 | RCX]);
+            self.emit(&[0xC1, 0xC0 | SHIFT_RIGHT << 3 | RCX, 12]);
+            self.emit(&[0x48, 0x0F, 0xA3, 0x0A]);
+            self.side_exit_if(CC_BELOW, index);
+        }
+    }
+
+    /// Loads into ecx with `opcode` and the operand [rsi + rax]
+    fn load(&mut self, opcode: &[u8], size: u32, rd: u8, rs1: u8, offset: i32, index: usize) {
+        self.address(rs1, offset, size, false, index);
+        self.emit(opcode);
+        self.emit(&[0x0C, 0x06]);
+        self.store_register(rd, RCX);
+    }
+
+    /// Stores ecx with `opcode` and the operand [rsi + rax]
+    fn store(&mut self, opcode: &[u8], size: u32, rs1: u8, rs2: u8, offset: i32, index: usize) {
+        self.address(rs1, offset, size, true, index);
+        self.load_register(RCX, rs2);
+        self.emit(opcode);
+        self.emit(&[0x0C, 0x06]);
+    }
+
+    fn branch(&mut self, condition: Condition, rs1: u8, rs2: u8, target: Address, end: Address) {
+        let condition = match condition {
+            Condition::Equal => CC_EQUAL,
+            Condition::NotEqual => CC_NOT_EQUAL,
+            Condition::Less => CC_LESS,
+            Condition::GreaterEqual => CC_GREATER_EQUAL,
+            Condition::LessUnsigned => CC_BELOW,
+            Condition::GreaterEqualUnsigned => CC_ABOVE_EQUAL,
+        };
+
+        self.compare_registers(rs1, rs2);
+        let taken = self.jump_if(condition);
+        self.return_exit(EXIT_FALL_THROUGH, end);
+        self.patch_to(taken, self.code.len());
+        self.return_exit(EXIT_TAKEN, target);
+    }
+
+    /// Jumps to rs1 + offset, leaving the block at micro-op `misaligned_exit`
+    /// if given and the target isn't word-aligned
+    fn jump_register(
+        &mut self,
+        rd: u8,
+        rs1: u8,
+        offset: i32,
+        end: Address,
+        misaligned_exit: Option<usize>,
+    ) {
+        self.load_register(RAX, rs1);
+        self.emit_alu_immediate(ALU_ADD, RAX, offset as u32);
+        self.emit_alu_immediate(ALU_AND, RAX, !1);
+        if let Some(index) = misaligned_exit {
+            // test eax, 2
+            self.emit(&[0xA9]);
+            self.emit_u32(0b10);
+            self.side_exit_if(CC_NOT_EQUAL, index);
+        }
+        self.store_immediate(rd, end);
+        // shl rax, 32, or rax, EXIT_INDIRECT and ret
+        self.emit(&[0x48, 0xC1, 0xE0, 32]);
+        self.emit(&[0x48, 0x0D]);
+        self.emit_u32(EXIT_INDIRECT);
+        self.emit(&[0xC3]);
+    }
+
+    /// mov rax, pc << 32 | exit and ret
+    fn return_exit(&mut self, exit: u32, pc: Address) {
+        self.emit(&[0x48, 0xB8]);
+        self.emit(&(u64::from(pc) << 32 | u64::from(exit)).to_le_bytes());
+        self.emit(&[0xC3]);
+    }
+
+    /// mov eax, index and ret
+    fn return_side_exit(&mut self, index: usize) {
+        self.emit(&[0xB8]);
+        self.emit_u32(index as u32);
+        self.emit(&[0xC3]);
+    }
+
+    /// Appends the side exits, returning the code
+    fn finish(mut self) -> Vec<u8> {
+        let mut side_exits = std::mem::take(&mut self.side_exits);
+        side_exits.sort_by_key(|&(_, index)| index);
+
+        let mut stub = None;
+        for (position, index) in side_exits {
+            let target = match stub {
+                Some((stub_index, target)) if stub_index == index => target,
+                _ => {
+                    let target = self.code.len();
+                    self.return_side_exit(index);
+                    stub = Some((index, target));
+                    target
+                }
+            };
+            self.patch_to(position, target);
+        }
+        self.code
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instruction::WrappedInstruction;
+    use crate::memory::addressspace::MemoryDevice;
+
+    #[test]
+    fn test_compiled_block() {
+        let code = [
+            (0x100, 0x00108093), // addi x1, x1, 1
+            (0x104, 0x00112023), // sw x1, 0(x2)
+            (0x108, 0xfe309ce3), // bne x1, x3, -8
+        ];
+        let block = Block {
+            start: 0x100,
+            context: 0,
+            ops: code
+                .iter()
+                .map(|&(pc, code)| (pc, MicroOp::new(&WrappedInstruction::new(code), pc)))
+                .collect(),
+            cycles: vec![0, 1, 2, 3],
+            end: 0x10C,
+        };
+
+        let mut jit = Jit::default();
+        jit.set_hot_threshold(2);
+        assert!(jit.get(0, &block, 0).is_none());
+        let compiled = jit.get(0, &block, 0).unwrap();
+
+        let mut memory = AddressSpace::new();
+        let mut registers = [0u32; 32];
+        registers[2] = 0x2000;
+        registers[3] = 2;
+        let taken = compiled.run(&mut registers, &mut memory);
+        assert_eq!(taken, Outcome::Exit(Exit::Taken, 0x100));
+        assert_eq!(memory.read_word(0x2000), 1);
+        let fall_through = compiled.run(&mut registers, &mut memory);
+        assert_eq!(fall_through, Outcome::Exit(Exit::FallThrough, 0x10C));
+        assert_eq!(memory.read_word(0x2000), 2);
+
+        // Stores to watched pages and outside of the RAM are left to the
+        // block engine
+        memory.watch_writes(0x2000);
+        assert_eq!(
+            compiled.run(&mut registers, &mut memory),
+            Outcome::SideExit(1)
+        );
+        assert_eq!(registers[1], 3);
+        assert_eq!(memory.read_word(0x2000), 2);
+        registers[2] = RAM_SIZE as Address - 2;
+        assert_eq!(
+            compiled.run(&mut registers, &mut memory),
+            Outcome::SideExit(1)
+        );
+        assert!(!is_direct_access(
+            &memory,
+            RAM_SIZE as Address - 2,
+            4,
+            false
+        ));
+        assert!(is_direct_access(&memory, RAM_SIZE as Address - 4, 4, false));
+
+        // A flush of the block cache drops the compiled code
+        assert!(jit.get(0, &block, 1).is_none());
+    }
+}
@@ -1,8 +1,29 @@
+#[cfg(all(feature = "jit", not(all(target_arch = "x86_64", target_os = "linux"))))]
+compile_error!("the jit feature requires an x86-64 Linux host");
+
+pub mod block;
 pub mod cpu;
+pub mod cpu64;
+pub mod csr;
+pub mod custom;
+pub mod environment_call;
 pub mod error;
+pub mod exception;
 #[cfg(feature = "debugger")]
 pub mod gdbserver;
 pub mod instruction;
+pub mod instruction_cache;
+pub mod isa;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod linux;
 pub mod loader;
 pub mod memory;
+pub mod mmu;
+pub mod newlib;
+pub mod pmp;
+pub mod reservation;
+pub mod semihosting;
+pub mod softfloat;
+pub mod timing;
 pub mod util;
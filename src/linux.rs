@@ -0,0 +1,1233 @@
+use crate::cpu::Cpu;
+use crate::environment_call::{EnvironmentCallHandler, EnvironmentCallResult};
+use crate::error::EmulatorError::ElfFormatError;
+use crate::error::EmulatorResult;
+use crate::isa::{Isa, Xlen};
+use crate::loader;
+use crate::loader::ProgramHeaders;
+use crate::memory::addressspace::{Address, AddressSpace, MemoryDevice};
+use crate::memory::RAM_SIZE;
+use crate::newlib::{
+    check_buffer, errno, read_all, read_descriptor, read_string, resolve_path, transfer_chunks,
+    write_descriptor, write_memory, Descriptor, EBADF, EFAULT, EINVAL, ENOSYS, TRANSFER_CHUNK_SIZE,
+};
+use std::fs;
+use std::fs::{Metadata, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// System call numbers of riscv32 Linux, passed in a7
+const SYS_GETCWD: u32 = 17;
+const SYS_IOCTL: u32 = 29;
+const SYS_FACCESSAT: u32 = 48;
+const SYS_OPENAT: u32 = 56;
+const SYS_CLOSE: u32 = 57;
+const SYS_LLSEEK: u32 = 62;
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_READV: u32 = 65;
+const SYS_WRITEV: u32 = 66;
+const SYS_EXIT: u32 = 93;
+const SYS_EXIT_GROUP: u32 = 94;
+const SYS_SET_TID_ADDRESS: u32 = 96;
+const SYS_SET_ROBUST_LIST: u32 = 99;
+const SYS_SIGALTSTACK: u32 = 132;
+const SYS_RT_SIGACTION: u32 = 134;
+const SYS_RT_SIGPROCMASK: u32 = 135;
+const SYS_UNAME: u32 = 160;
+const SYS_GETPID: u32 = 172;
+const SYS_GETPPID: u32 = 173;
+const SYS_GETUID: u32 = 174;
+const SYS_GETEUID: u32 = 175;
+const SYS_GETGID: u32 = 176;
+const SYS_GETEGID: u32 = 177;
+const SYS_GETTID: u32 = 178;
+const SYS_BRK: u32 = 214;
+const SYS_MUNMAP: u32 = 215;
+const SYS_MMAP2: u32 = 222;
+const SYS_MPROTECT: u32 = 226;
+const SYS_MADVISE: u32 = 233;
+const SYS_RISCV_FLUSH_ICACHE: u32 = 259;
+const SYS_PRLIMIT64: u32 = 261;
+const SYS_GETRANDOM: u32 = 278;
+const SYS_STATX: u32 = 291;
+const SYS_CLOCK_GETTIME64: u32 = 403;
+const SYS_FUTEX_TIME64: u32 = 422;
+
+const ENOENT: i32 = 2;
+const ENOMEM: i32 = 12;
+const ENOTTY: i32 = 25;
+const ESPIPE: i32 = 29;
+const ERANGE: i32 = 34;
+
+// The flags of open as defined by the generic Linux ABI
+const O_ACCMODE: u32 = 0b11;
+const O_WRONLY: u32 = 1;
+const O_RDWR: u32 = 2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+const AT_FDCWD: i32 = -100;
+const AT_EMPTY_PATH: u32 = 0x1000;
+
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+const TIOCGWINSZ: u32 = 0x5413;
+const CLOCK_REALTIME: u32 = 0;
+const CLOCK_REALTIME_COARSE: u32 = 5;
+const RLIMIT_STACK: u32 = 3;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const STATX_BASIC_STATS: u32 = 0x7ff;
+const STATX_SIZE: u32 = 0x100;
+
+// Entries of the auxiliary vector
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_HWCAP: u32 = 16;
+const AT_SECURE: u32 = 23;
+const AT_RANDOM: u32 = 25;
+const AT_EXECFN: u32 = 31;
+
+// The extensions reported in AT_HWCAP, I, M, A, F, D and C with one bit
+// per letter like in misa
+const HWCAP_MASK: u32 = 1 << 8 | 1 << 12 | 1 << 0 | 1 << 5 | 1 << 3 | 1 << 2;
+
+const PAGE_SIZE: u32 = 4096;
+const STACK_SIZE: u32 = 8 * 1024 * 1024;
+const STACK_TOP: Address = RAM_SIZE as Address;
+
+type SyscallResult = Result<u32, i32>;
+
+/// Loads a statically linked Linux executable and prepares the CPU like the
+/// kernel's `execve` would: the program starts at its entry point, with
+/// argc, argv, envp and the auxiliary vector on the stack at the top of RAM,
+/// and system calls are serviced by `LinuxSyscalls`.
+///
+/// `arguments` includes the program name in `argv[0]`, `environment`
+/// contains `NAME=value` strings. The CPU implements `isa`, which the
+/// program must be compatible with.
+pub fn create_process(
+    path: &str,
+    arguments: &[String],
+    environment: &[String],
+    root: impl Into<PathBuf>,
+    isa: &Isa,
+    memory: &mut AddressSpace,
+) -> EmulatorResult<Cpu> {
+    let mut cpu = Cpu::with_isa(isa)?;
+    let image = loader::load_program(path, memory)?;
+    if image.xlen != Xlen::Rv32 {
+        return Err(ElfFormatError(
+            "Linux emulation supports only 32 bit programs".into(),
+        ));
+    }
+    if image.interpreter.is_some() {
+        return Err(ElfFormatError(
+            "dynamically linked programs aren't supported".into(),
+        ));
+    }
+    image.check_isa(isa)?;
+
+    // The C library finds the TLS template in the program headers
+    let program_headers = image.program_headers.unwrap_or(ProgramHeaders {
+        address: 0,
+        entry_size: 0,
+        count: 0,
+    });
+    let program_break = align_page(image.heap_start)
+        .ok_or_else(|| ElfFormatError("the program ends in the last page".into()))?;
+
+    let mut syscalls = LinuxSyscalls::new(root, program_break);
+    let mut stack = StackBuilder::new(memory, STACK_TOP);
+
+    let execfn = stack.push_string(path);
+    let argv: Vec<Address> = arguments
+        .iter()
+        .map(|argument| stack.push_string(argument))
+        .collect();
+    let envp: Vec<Address> = environment
+        .iter()
+        .map(|variable| stack.push_string(variable))
+        .collect();
+    let mut random = [0u8; 16];
+    syscalls.fill_random(&mut random);
+    let random = stack.push_bytes(&random);
+
+    let auxv = [
+        (AT_PHDR, program_headers.address),
+        (AT_PHENT, u32::from(program_headers.entry_size)),
+        (AT_PHNUM, u32::from(program_headers.count)),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, image.entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_HWCAP, isa.misa() as u32 & HWCAP_MASK),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random),
+        (AT_EXECFN, execfn),
+        (AT_NULL, 0),
+    ];
+
+    let mut words = vec![argv.len() as u32];
+    words.extend(&argv);
+    words.push(0);
+    words.extend(&envp);
+    words.push(0);
+    words.extend(auxv.iter().flat_map(|&(key, value)| vec![key, value]));
+    let stack_pointer = stack.push_words(&words);
+
+    cpu.set_pc(image.entry);
+    cpu.set_register(2, stack_pointer);
+    cpu.set_environment_call_handler(syscalls);
+
+    Ok(cpu)
+}
+
+/// Fills the initial stack from the top down
+struct StackBuilder<'a> {
+    memory: &'a mut AddressSpace,
+    pointer: Address,
+}
+
+impl<'a> StackBuilder<'a> {
+    fn new(memory: &'a mut AddressSpace, top: Address) -> Self {
+        Self {
+            memory,
+            pointer: top,
+        }
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) -> Address {
+        self.pointer -= bytes.len() as Address;
+        for (index, &byte) in bytes.iter().enumerate() {
+            self.memory
+                .write_byte(self.pointer + index as Address, byte);
+        }
+        self.pointer
+    }
+
+    fn push_string(&mut self, string: &str) -> Address {
+        let mut bytes = string.as_bytes().to_vec();
+        bytes.push(0);
+        self.push_bytes(&bytes)
+    }
+
+    /// Pushes the words below a 16 byte aligned stack pointer, as required by
+    /// the calling convention, and returns it
+    fn push_words(&mut self, words: &[u32]) -> Address {
+        self.pointer = (self.pointer - 4 * words.len() as Address) & !0xf;
+        for (index, &word) in words.iter().enumerate() {
+            self.memory
+                .write_word(self.pointer + 4 * index as Address, word);
+        }
+        self.pointer
+    }
+}
+
+/// Implements the Linux system calls used by the C libraries on the host,
+/// similar to QEMU's user mode emulation. The system call number is passed in
+/// a7, the arguments in a0 to a5 and the result, or a negated errno, is
+/// returned in a0.
+///
+/// The program runs as a single thread and never receives signals. Files are
+/// opened relative to a root directory, which is also the working directory,
+/// and anonymous or private file mappings are placed between the heap and
+/// the stack.
+pub struct LinuxSyscalls {
+    root: PathBuf,
+    descriptors: Vec<Option<Descriptor>>,
+    heap_start: Address,
+    program_break: Address,
+    mappings: Vec<(Address, Address)>,
+    start: Instant,
+    random_state: u64,
+}
+
+impl LinuxSyscalls {
+    /// Creates the system call handler for a program whose heap starts at
+    /// `heap_start`, the page following its last segment.
+    pub fn new(root: impl Into<PathBuf>, heap_start: Address) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        Self {
+            root: root.into(),
+            descriptors: vec![
+                Some(Descriptor::Stdin),
+                Some(Descriptor::Stdout),
+                Some(Descriptor::Stderr),
+            ],
+            heap_start,
+            program_break: heap_start,
+            mappings: Vec::new(),
+            start: Instant::now(),
+            random_state: seed,
+        }
+    }
+
+    fn openat(
+        &mut self,
+        memory: &AddressSpace,
+        directory: u32,
+        path: Address,
+        flags: u32,
+    ) -> SyscallResult {
+        let path = self.resolve_at(memory, directory, path)?;
+
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+
+        let file = options.open(path).map_err(errno)?;
+        let descriptor = match self.descriptors.iter().position(Option::is_none) {
+            Some(descriptor) => descriptor,
+            None => {
+                self.descriptors.push(None);
+                self.descriptors.len() - 1
+            }
+        };
+        self.descriptors[descriptor] = Some(Descriptor::File(file));
+
+        Ok(descriptor as u32)
+    }
+
+    fn close(&mut self, descriptor: u32) -> SyscallResult {
+        match self.descriptors.get_mut(descriptor as usize) {
+            Some(entry @ Some(_)) => {
+                *entry = None;
+                Ok(0)
+            }
+            _ => Err(EBADF),
+        }
+    }
+
+    fn read(
+        &mut self,
+        memory: &mut AddressSpace,
+        descriptor: u32,
+        buffer: Address,
+        length: u32,
+    ) -> SyscallResult {
+        check_buffer(memory, buffer, length)?;
+        read_descriptor(memory, self.descriptor(descriptor)?, buffer, length)
+    }
+
+    fn write(
+        &mut self,
+        memory: &AddressSpace,
+        descriptor: u32,
+        buffer: Address,
+        length: u32,
+    ) -> SyscallResult {
+        check_buffer(memory, buffer, length)?;
+        write_descriptor(memory, self.descriptor(descriptor)?, buffer, length)
+    }
+
+    /// Reads into the buffers of an array of `struct iovec`, until one of
+    /// them isn't filled completely
+    fn readv(
+        &mut self,
+        memory: &mut AddressSpace,
+        descriptor: u32,
+        vector: Address,
+        count: u32,
+    ) -> SyscallResult {
+        let mut total = 0;
+        for (base, length) in read_iovec(memory, vector, count)? {
+            let read = self.read(memory, descriptor, base, length)?;
+            total += read;
+            if read < length {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn writev(
+        &mut self,
+        memory: &AddressSpace,
+        descriptor: u32,
+        vector: Address,
+        count: u32,
+    ) -> SyscallResult {
+        let mut total = 0;
+        for (base, length) in read_iovec(memory, vector, count)? {
+            total += self.write(memory, descriptor, base, length)?;
+        }
+        Ok(total)
+    }
+
+    /// `llseek` takes a 64 bit offset in two registers and stores the new
+    /// position at `result`
+    fn llseek(
+        &mut self,
+        memory: &mut AddressSpace,
+        descriptor: u32,
+        offset: i64,
+        result: Address,
+        whence: u32,
+    ) -> SyscallResult {
+        check_buffer(memory, result, 8)?;
+        let position = match whence {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+
+        let position = match self.descriptor(descriptor)? {
+            Descriptor::File(file) => file.seek(position).map_err(errno)?,
+            _ => return Err(ESPIPE),
+        };
+        write_doubleword(memory, result, position);
+        Ok(0)
+    }
+
+    fn ioctl(
+        &mut self,
+        memory: &mut AddressSpace,
+        descriptor: u32,
+        request: u32,
+        argument: Address,
+    ) -> SyscallResult {
+        match (self.descriptor(descriptor)?, request) {
+            (Descriptor::File(_), _) => Err(ENOTTY),
+            (_, TIOCGWINSZ) => {
+                // A terminal with 24 rows and 80 columns
+                check_buffer(memory, argument, 8)?;
+                memory.write_word(argument, 24 | 80 << 16);
+                memory.write_word(argument + 4, 0);
+                Ok(0)
+            }
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// Fills in `struct statx` for a path, or for a descriptor if the path is
+    /// empty and AT_EMPTY_PATH is set
+    fn statx(
+        &mut self,
+        memory: &mut AddressSpace,
+        directory: u32,
+        path: Address,
+        flags: u32,
+        buffer: Address,
+    ) -> SyscallResult {
+        check_buffer(memory, buffer, 256)?;
+
+        let metadata = if flags & AT_EMPTY_PATH != 0 && read_string(memory, path)?.is_empty() {
+            match self.descriptor(directory)? {
+                Descriptor::File(file) => Some(file.metadata().map_err(errno)?),
+                _ => None,
+            }
+        } else {
+            let path = self.resolve_at(memory, directory, path)?;
+            Some(fs::metadata(path).map_err(errno)?)
+        };
+
+        clear_memory(memory, buffer, 256);
+        memory.write_word(buffer, STATX_BASIC_STATS);
+        memory.write_word(buffer + 4, PAGE_SIZE); // stx_blksize
+        memory.write_word(buffer + 16, 1); // stx_nlink
+        match metadata {
+            Some(metadata) => {
+                memory.write_halfword(buffer + 28, file_mode(&metadata) as u16);
+                write_doubleword(memory, buffer + 40, metadata.len());
+                write_doubleword(memory, buffer + 48, (metadata.len() + 511) / 512);
+
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                write_doubleword(memory, buffer + 112, modified.as_secs());
+                memory.write_word(buffer + 120, modified.subsec_nanos());
+            }
+            None => {
+                memory.write_word(buffer, STATX_BASIC_STATS & !STATX_SIZE);
+                memory.write_halfword(buffer + 28, (S_IFCHR | 0o620) as u16);
+            }
+        }
+        Ok(0)
+    }
+
+    fn faccessat(&mut self, memory: &AddressSpace, directory: u32, path: Address) -> SyscallResult {
+        let path = self.resolve_at(memory, directory, path)?;
+        fs::metadata(path).map_err(errno)?;
+        Ok(0)
+    }
+
+    /// The root directory is the working directory of the program
+    fn getcwd(&mut self, memory: &mut AddressSpace, buffer: Address, size: u32) -> SyscallResult {
+        if size < 2 {
+            return Err(ERANGE);
+        }
+        check_buffer(memory, buffer, 2)?;
+        write_memory(memory, buffer, b"/\0")?;
+        Ok(2)
+    }
+
+    /// Writes `struct __kernel_timespec`, with 64 bit seconds and nanoseconds
+    fn clock_gettime(
+        &mut self,
+        memory: &mut AddressSpace,
+        clock: u32,
+        timespec: Address,
+    ) -> SyscallResult {
+        check_buffer(memory, timespec, 16)?;
+
+        let time = match clock {
+            CLOCK_REALTIME | CLOCK_REALTIME_COARSE => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            _ => self.start.elapsed(),
+        };
+        write_doubleword(memory, timespec, time.as_secs());
+        write_doubleword(memory, timespec + 8, time.subsec_nanos().into());
+        Ok(0)
+    }
+
+    fn uname(&mut self, memory: &mut AddressSpace, buffer: Address) -> SyscallResult {
+        const FIELD_LENGTH: usize = 65;
+        let fields = ["Linux", "riscv-emu", "5.15.0", "#1", "riscv32", ""];
+        check_buffer(memory, buffer, (FIELD_LENGTH * fields.len()) as u32)?;
+
+        let mut utsname = vec![0u8; FIELD_LENGTH * fields.len()];
+        for (index, field) in fields.iter().enumerate() {
+            let start = index * FIELD_LENGTH;
+            utsname[start..start + field.len()].copy_from_slice(field.as_bytes());
+        }
+        write_memory(memory, buffer, &utsname)?;
+        Ok(0)
+    }
+
+    /// Reports all resource limits as unlimited, except the stack size
+    fn prlimit(
+        &mut self,
+        memory: &mut AddressSpace,
+        resource: u32,
+        old_limit: Address,
+    ) -> SyscallResult {
+        if old_limit != 0 {
+            check_buffer(memory, old_limit, 16)?;
+            let current = match resource {
+                RLIMIT_STACK => STACK_SIZE.into(),
+                _ => u64::MAX,
+            };
+            write_doubleword(memory, old_limit, current);
+            write_doubleword(memory, old_limit + 8, u64::MAX);
+        }
+        Ok(0)
+    }
+
+    fn getrandom(
+        &mut self,
+        memory: &mut AddressSpace,
+        buffer: Address,
+        length: u32,
+    ) -> SyscallResult {
+        check_buffer(memory, buffer, length)?;
+
+        let mut data = vec![0u8; length.min(TRANSFER_CHUNK_SIZE) as usize];
+        for (offset, chunk_length) in transfer_chunks(length) {
+            let chunk = &mut data[..chunk_length as usize];
+            self.fill_random(chunk);
+            write_memory(memory, buffer.wrapping_add(offset), chunk)?;
+        }
+        Ok(length)
+    }
+
+    /// Moves the program break and returns the new one. The heap can't grow
+    /// into mappings or the stack, the current break is returned if the
+    /// request can't be satisfied.
+    fn brk(&mut self, memory: &mut AddressSpace, address: Address) -> SyscallResult {
+        let available = address >= self.heap_start
+            && address <= STACK_TOP - STACK_SIZE
+            && self
+                .mappings
+                .iter()
+                .all(|&(start, end)| end <= self.heap_start || start >= address);
+
+        if available {
+            // Memory given back earlier has to be cleared again
+            if address > self.program_break {
+                clear_memory(memory, self.program_break, address - self.program_break);
+            }
+            self.program_break = address;
+        }
+
+        Ok(self.program_break)
+    }
+
+    /// Maps zeroed memory, optionally filled with the contents of a file. As
+    /// there's only one process, shared and private mappings are the same,
+    /// and changes aren't written back to the file.
+    fn mmap(
+        &mut self,
+        memory: &mut AddressSpace,
+        address: Address,
+        length: u32,
+        flags: u32,
+        descriptor: u32,
+        page_offset: u32,
+    ) -> SyscallResult {
+        if length == 0 || length > STACK_TOP {
+            return Err(EINVAL);
+        }
+        let length = align_page(length).ok_or(EINVAL)?;
+
+        let anonymous = flags & MAP_ANONYMOUS != 0;
+        if !anonymous && !matches!(self.descriptor(descriptor)?, Descriptor::File(_)) {
+            return Err(EBADF);
+        }
+
+        // Fixed mappings are limited to the RAM below the stack top, so that
+        // they can't replace devices
+        let address = if flags & MAP_FIXED != 0 {
+            if address & (PAGE_SIZE - 1) != 0 {
+                return Err(EINVAL);
+            }
+            if address
+                .checked_add(length)
+                .map_or(true, |end| end > STACK_TOP)
+            {
+                return Err(ENOMEM);
+            }
+            self.unmap(address, length);
+            address
+        } else {
+            self.find_free_range(length).ok_or(ENOMEM)?
+        };
+
+        clear_memory(memory, address, length);
+        if !anonymous {
+            if let Descriptor::File(file) = self.descriptor(descriptor)? {
+                let offset = u64::from(page_offset) * u64::from(PAGE_SIZE);
+                file.seek(SeekFrom::Start(offset)).map_err(errno)?;
+
+                let mut data = vec![0u8; length.min(TRANSFER_CHUNK_SIZE) as usize];
+                for (offset, chunk_length) in transfer_chunks(length) {
+                    let chunk = &mut data[..chunk_length as usize];
+                    let count = read_all(file, chunk).map_err(errno)?;
+                    write_memory(memory, address.wrapping_add(offset), &chunk[..count])?;
+                    if count < chunk.len() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let index = self
+            .mappings
+            .iter()
+            .position(|&(start, _)| start > address)
+            .unwrap_or(self.mappings.len());
+        self.mappings.insert(index, (address, address + length));
+        Ok(address)
+    }
+
+    fn munmap(&mut self, address: Address, length: u32) -> SyscallResult {
+        if address & (PAGE_SIZE - 1) != 0 || length == 0 {
+            return Err(EINVAL);
+        }
+        let length = align_page(length).ok_or(EINVAL)?;
+        if address.checked_add(length).is_none() {
+            return Err(EINVAL);
+        }
+        self.unmap(address, length);
+        Ok(0)
+    }
+
+    /// Removes a range from the mappings, splitting those it only partially
+    /// covers. The range can't wrap around the end of the address space.
+    fn unmap(&mut self, address: Address, length: u32) {
+        let end = address + length;
+        let mut mappings = Vec::new();
+
+        for &(start, mapping_end) in &self.mappings {
+            if mapping_end <= address || start >= end {
+                mappings.push((start, mapping_end));
+                continue;
+            }
+            if start < address {
+                mappings.push((start, address));
+            }
+            if mapping_end > end {
+                mappings.push((end, mapping_end));
+            }
+        }
+        self.mappings = mappings;
+    }
+
+    /// Finds the highest free range below the stack and above the heap
+    fn find_free_range(&self, length: u32) -> Option<Address> {
+        let mut end = STACK_TOP - STACK_SIZE;
+
+        for &(start, mapping_end) in self.mappings.iter().rev() {
+            if mapping_end <= end && end - mapping_end >= length {
+                return Some(end - length);
+            }
+            end = end.min(start);
+        }
+
+        end.checked_sub(length)
+            .filter(|&start| start >= self.program_break)
+    }
+
+    /// Fills a buffer with pseudo-random bytes from a SplitMix64 generator,
+    /// which is good enough for hash seeds and stack canaries but not for
+    /// cryptography
+    fn fill_random(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            self.random_state = self.random_state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut value = self.random_state;
+            value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            value ^= value >> 31;
+            chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+        }
+    }
+
+    /// Resolves a path relative to a directory descriptor. Only absolute
+    /// paths and paths relative to the working directory are supported.
+    fn resolve_at(
+        &self,
+        memory: &AddressSpace,
+        directory: u32,
+        path: Address,
+    ) -> Result<PathBuf, i32> {
+        let path = read_string(memory, path)?;
+        if path.is_empty() {
+            return Err(ENOENT);
+        }
+        if directory as i32 != AT_FDCWD && !path.starts_with('/') {
+            return Err(ENOSYS);
+        }
+        resolve_path(&self.root, &path)
+    }
+
+    fn descriptor(&mut self, descriptor: u32) -> Result<&mut Descriptor, i32> {
+        match self.descriptors.get_mut(descriptor as usize) {
+            Some(Some(descriptor)) => Ok(descriptor),
+            _ => Err(EBADF),
+        }
+    }
+}
+
+impl EnvironmentCallHandler for LinuxSyscalls {
+    fn environment_call(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut AddressSpace,
+    ) -> EnvironmentCallResult {
+        let arguments = [
+            cpu.get_register(10),
+            cpu.get_register(11),
+            cpu.get_register(12),
+            cpu.get_register(13),
+            cpu.get_register(14),
+            cpu.get_register(15),
+        ];
+
+        let result = match cpu.get_register(17) {
+            SYS_EXIT | SYS_EXIT_GROUP => return EnvironmentCallResult::Exit(arguments[0] as i32),
+            SYS_OPENAT => self.openat(memory, arguments[0], arguments[1], arguments[2]),
+            SYS_CLOSE => self.close(arguments[0]),
+            SYS_LLSEEK => {
+                let offset = (u64::from(arguments[1]) << 32 | u64::from(arguments[2])) as i64;
+                self.llseek(memory, arguments[0], offset, arguments[3], arguments[4])
+            }
+            SYS_READ => self.read(memory, arguments[0], arguments[1], arguments[2]),
+            SYS_WRITE => self.write(memory, arguments[0], arguments[1], arguments[2]),
+            SYS_READV => self.readv(memory, arguments[0], arguments[1], arguments[2]),
+            SYS_WRITEV => self.writev(memory, arguments[0], arguments[1], arguments[2]),
+            SYS_IOCTL => self.ioctl(memory, arguments[0], arguments[1], arguments[2]),
+            SYS_STATX => self.statx(
+                memory,
+                arguments[0],
+                arguments[1],
+                arguments[2],
+                arguments[4],
+            ),
+            SYS_FACCESSAT => self.faccessat(memory, arguments[0], arguments[1]),
+            SYS_GETCWD => self.getcwd(memory, arguments[0], arguments[1]),
+            SYS_CLOCK_GETTIME64 => self.clock_gettime(memory, arguments[0], arguments[1]),
+            SYS_UNAME => self.uname(memory, arguments[0]),
+            SYS_PRLIMIT64 => self.prlimit(memory, arguments[1], arguments[3]),
+            SYS_GETRANDOM => self.getrandom(memory, arguments[0], arguments[1]),
+            SYS_BRK => self.brk(memory, arguments[0]),
+            SYS_MMAP2 => self.mmap(
+                memory,
+                arguments[0],
+                arguments[1],
+                arguments[3],
+                arguments[4],
+                arguments[5],
+            ),
+            SYS_MUNMAP => self.munmap(arguments[0], arguments[1]),
+            SYS_RT_SIGACTION => clear_buffer(memory, arguments[2], 16),
+            SYS_RT_SIGPROCMASK => clear_buffer(memory, arguments[2], arguments[3]),
+            SYS_GETPID | SYS_GETTID | SYS_SET_TID_ADDRESS => Ok(std::process::id()),
+            // Protections and memory advice are ignored, there's no other
+            // thread to wait for and signals are never delivered
+            SYS_MPROTECT | SYS_MADVISE | SYS_FUTEX_TIME64 | SYS_SET_ROBUST_LIST
+            | SYS_SIGALTSTACK | SYS_GETPPID | SYS_GETUID | SYS_GETEUID | SYS_GETGID
+            | SYS_GETEGID => Ok(0),
+            // Stores already keep the instruction cache coherent
+            SYS_RISCV_FLUSH_ICACHE => Ok(0),
+            _ => Err(ENOSYS),
+        };
+
+        let value = match result {
+            Ok(value) => value,
+            Err(errno) => (-errno) as u32,
+        };
+        cpu.set_register(10, value);
+
+        EnvironmentCallResult::Return
+    }
+}
+
+fn file_mode(metadata: &Metadata) -> u32 {
+    let permissions = if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    };
+
+    if metadata.is_dir() {
+        S_IFDIR | permissions | 0o111
+    } else {
+        S_IFREG | permissions
+    }
+}
+
+/// Rounds up to a page boundary, None if that is beyond the address space
+fn align_page(address: Address) -> Option<Address> {
+    address
+        .checked_add(PAGE_SIZE - 1)
+        .map(|address| address & !(PAGE_SIZE - 1))
+}
+
+/// Reads the base and length of `count` entries of a `struct iovec` array
+fn read_iovec(
+    memory: &AddressSpace,
+    vector: Address,
+    count: u32,
+) -> Result<Vec<(Address, u32)>, i32> {
+    check_buffer(memory, vector, count.checked_mul(8).ok_or(EINVAL)?)?;
+
+    Ok((0..count)
+        .map(|index| {
+            let entry = vector + 8 * index;
+            (memory.read_word(entry), memory.read_word(entry + 4))
+        })
+        .collect())
+}
+
+/// Zeroes an optional output buffer, like the old signal action or mask
+fn clear_buffer(memory: &mut AddressSpace, buffer: Address, length: u32) -> SyscallResult {
+    if buffer != 0 {
+        check_buffer(memory, buffer, length).map_err(|_| EFAULT)?;
+        clear_memory(memory, buffer, length);
+    }
+    Ok(0)
+}
+
+/// Zeroes memory of the program, whose range has been checked
+fn clear_memory(memory: &mut AddressSpace, address: Address, length: u32) {
+    for offset in 0..length {
+        memory.write_byte(address.wrapping_add(offset), 0);
+    }
+}
+
+fn write_doubleword(memory: &mut AddressSpace, address: Address, value: u64) {
+    memory.write_word(address, value as u32);
+    memory.write_word(address + 4, (value >> 32) as u32);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call(
+        handler: &mut LinuxSyscalls,
+        memory: &mut AddressSpace,
+        number: u32,
+        arguments: &[u32],
+    ) -> i32 {
+        let mut cpu = Cpu::new();
+        cpu.set_register(17, number);
+        for (index, &argument) in arguments.iter().enumerate() {
+            cpu.set_register(10 + index, argument);
+        }
+
+        assert_eq!(
+            handler.environment_call(&mut cpu, memory),
+            EnvironmentCallResult::Return
+        );
+        cpu.get_register(10) as i32
+    }
+
+    fn write_string(memory: &mut AddressSpace, address: Address, s: &str) {
+        write_memory(memory, address, s.as_bytes()).unwrap();
+        memory.write_byte(address + s.len() as Address, 0);
+    }
+
+    #[test]
+    fn test_files() {
+        let root = std::env::temp_dir().join(format!("linux-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let mut memory = AddressSpace::new();
+        let mut handler = LinuxSyscalls::new(&root, 0x10_0000);
+        write_string(&mut memory, 0x100, "data.txt");
+        write_string(&mut memory, 0x200, "hello world");
+        let at = AT_FDCWD as u32;
+
+        let flags = O_WRONLY | O_CREAT | O_TRUNC;
+        let fd = call(&mut handler, &mut memory, SYS_OPENAT, &[at, 0x100, flags]);
+        assert_eq!(fd, 3);
+        // Two iovecs, "hello" and " world"
+        memory.write_word(0x300, 0x200);
+        memory.write_word(0x304, 5);
+        memory.write_word(0x308, 0x205);
+        memory.write_word(0x30c, 6);
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_WRITEV, &[3, 0x300, 2]),
+            11
+        );
+        assert_eq!(call(&mut handler, &mut memory, SYS_CLOSE, &[3]), 0);
+        assert_eq!(
+            fs::read_to_string(root.join("data.txt")).unwrap(),
+            "hello world"
+        );
+
+        let fd = call(&mut handler, &mut memory, SYS_OPENAT, &[at, 0x100, 0]);
+        assert_eq!(fd, 3);
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_LLSEEK, &[3, 0, 6, 0x400, 0]),
+            0
+        );
+        assert_eq!(memory.read_word(0x400), 6);
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_READ, &[3, 0x500, 16]),
+            5
+        );
+        assert_eq!(memory.read_word(0x500), u32::from_le_bytes(*b"worl"));
+
+        write_string(&mut memory, 0x180, "");
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_STATX,
+                &[3, 0x180, AT_EMPTY_PATH, STATX_BASIC_STATS, 0x1000]
+            ),
+            0
+        );
+        assert_eq!(
+            u32::from(memory.read_halfword(0x1000 + 28)),
+            S_IFREG | 0o644
+        );
+        assert_eq!(memory.read_word(0x1000 + 40), 11);
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_STATX,
+                &[at, 0x100, 0, STATX_BASIC_STATS, 0x1000]
+            ),
+            0
+        );
+        assert_eq!(memory.read_word(0x1000 + 40), 11);
+
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_IOCTL,
+                &[3, TIOCGWINSZ, 0x600]
+            ),
+            -ENOTTY
+        );
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_IOCTL,
+                &[1, TIOCGWINSZ, 0x600]
+            ),
+            0
+        );
+        assert_eq!(call(&mut handler, &mut memory, SYS_CLOSE, &[3]), 0);
+        assert_eq!(call(&mut handler, &mut memory, SYS_CLOSE, &[3]), -EBADF);
+
+        write_string(&mut memory, 0x100, "/missing");
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_FACCESSAT, &[at, 0x100, 0]),
+            -ENOENT
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_memory_management() {
+        let mut memory = AddressSpace::new();
+        let mut handler = LinuxSyscalls::new(".", 0x10_0000);
+
+        assert_eq!(call(&mut handler, &mut memory, SYS_BRK, &[0]), 0x10_0000);
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_BRK, &[0x10_2000]),
+            0x10_2000
+        );
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_BRK, &[0x1000_0000]),
+            0x10_2000
+        );
+
+        let anonymous = MAP_ANONYMOUS | 0x2;
+        let first = call(
+            &mut handler,
+            &mut memory,
+            SYS_MMAP2,
+            &[0, 0x1800, 3, anonymous, u32::MAX, 0],
+        ) as Address;
+        assert_eq!(first, STACK_TOP - STACK_SIZE - 0x2000);
+        memory.write_word(first + 0x1000, 0xdead_beef);
+
+        let second = call(
+            &mut handler,
+            &mut memory,
+            SYS_MMAP2,
+            &[0, 0x1000, 3, anonymous, u32::MAX, 0],
+        ) as Address;
+        assert_eq!(second, first - 0x1000);
+
+        // The freed range is reused and cleared
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_MUNMAP, &[first, 0x2000]),
+            0
+        );
+        // Ranges wrapping around the address space are refused
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_MUNMAP,
+                &[0xffff_f000, 0x2000]
+            ),
+            -EINVAL
+        );
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_MUNMAP, &[0x1000, u32::MAX]),
+            -EINVAL
+        );
+
+        let third = call(
+            &mut handler,
+            &mut memory,
+            SYS_MMAP2,
+            &[0, 0x1000, 3, anonymous, u32::MAX, 0],
+        ) as Address;
+        assert_eq!(third, STACK_TOP - STACK_SIZE - 0x1000);
+        assert_eq!(memory.read_word(third), 0);
+
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_MMAP2,
+                &[0, 0, 3, anonymous, 0, 0]
+            ),
+            -EINVAL
+        );
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_MMAP2,
+                &[0, 0x1000, 3, 0x2, 1, 0]
+            ),
+            -EBADF
+        );
+
+        // Fixed mappings replace RAM below the stack top, but not devices
+        let fixed = anonymous | MAP_FIXED;
+        memory.write_word(0x20_0000, 0xdead_beef);
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_MMAP2,
+                &[0x20_0000, 0x1000, 3, fixed, u32::MAX, 0]
+            ),
+            0x20_0000
+        );
+        assert_eq!(memory.read_word(0x20_0000), 0);
+        for &(address, error) in &[
+            (0x20_0800, EINVAL),
+            (STACK_TOP, ENOMEM),
+            (0x2000_0000, ENOMEM),
+            (0x4000_0000, ENOMEM),
+            (0xFFFF_F000, ENOMEM),
+        ] {
+            assert_eq!(
+                call(
+                    &mut handler,
+                    &mut memory,
+                    SYS_MMAP2,
+                    &[address, 0x1000, 3, fixed, u32::MAX, 0]
+                ),
+                -error
+            );
+        }
+    }
+
+    #[test]
+    fn test_other_calls() {
+        let mut memory = AddressSpace::new();
+        let mut handler = LinuxSyscalls::new(".", 0x10_0000);
+
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_CLOCK_GETTIME64, &[0, 0x100]),
+            0
+        );
+        assert!(memory.read_word(0x100) > 1_500_000_000);
+        assert!(memory.read_word(0x108) < 1_000_000_000);
+
+        assert_eq!(call(&mut handler, &mut memory, SYS_UNAME, &[0x200]), 0);
+        assert_eq!(memory.read_word(0x200), u32::from_le_bytes(*b"Linu"));
+        assert_eq!(
+            memory.read_word(0x200 + 4 * 65),
+            u32::from_le_bytes(*b"risc")
+        );
+
+        assert_eq!(call(&mut handler, &mut memory, SYS_GETCWD, &[0x300, 64]), 2);
+        assert_eq!(memory.read_halfword(0x300), u16::from_le_bytes(*b"/\0"));
+
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_GETRANDOM, &[0x400, 32, 0]),
+            32
+        );
+        assert_ne!(memory.read_word(0x400), memory.read_word(0x408));
+
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_WRITE, &[1, 0x1000_0000, 4]),
+            -EFAULT
+        );
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_WRITE, &[1, 0, 0x2000_0001]),
+            -EFAULT
+        );
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_GETRANDOM,
+                &[0x100, u32::MAX, 0]
+            ),
+            -EFAULT
+        );
+
+        // Large buffers are filled a chunk at a time
+        let length = 2 * TRANSFER_CHUNK_SIZE + 8;
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_GETRANDOM,
+                &[0x10_0000, length, 0]
+            ),
+            length as i32
+        );
+        assert_ne!(memory.read_word(0x10_0000 + length - 4), 0);
+        assert_eq!(call(&mut handler, &mut memory, 1234, &[]), -ENOSYS);
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(17, SYS_EXIT_GROUP);
+        cpu.set_register(10, 3);
+        assert_eq!(
+            handler.environment_call(&mut cpu, &mut memory),
+            EnvironmentCallResult::Exit(3)
+        );
+    }
+
+    #[test]
+    fn test_create_process() {
+        let mut memory = AddressSpace::new();
+        let arguments = vec!["fibonacci".to_string(), "10".to_string()];
+        let environment = vec!["HOME=/".to_string()];
+        let cpu = create_process(
+            "tests/programs/fibonacci.elf",
+            &arguments,
+            &environment,
+            ".",
+            &Isa::supported(Xlen::Rv32),
+            &mut memory,
+        )
+        .unwrap();
+
+        let sp = cpu.get_register(2);
+        assert_eq!(sp & 0xf, 0);
+        assert_eq!(memory.read_word(sp), 2);
+        assert_eq!(
+            read_string(&memory, memory.read_word(sp + 4)).unwrap(),
+            "fibonacci"
+        );
+        assert_eq!(
+            read_string(&memory, memory.read_word(sp + 8)).unwrap(),
+            "10"
+        );
+        assert_eq!(memory.read_word(sp + 12), 0);
+        assert_eq!(
+            read_string(&memory, memory.read_word(sp + 16)).unwrap(),
+            "HOME=/"
+        );
+        assert_eq!(memory.read_word(sp + 20), 0);
+
+        // The auxiliary vector follows the environment
+        let mut auxv = sp + 24;
+        let mut entry = None;
+        while memory.read_word(auxv) != AT_NULL {
+            if memory.read_word(auxv) == AT_ENTRY {
+                entry = Some(memory.read_word(auxv + 4));
+            }
+            auxv += 8;
+        }
+        assert_eq!(entry, Some(cpu.get_pc()));
+    }
+}
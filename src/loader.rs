@@ -1,41 +1,1081 @@
-use crate::error::EmulatorError::ElfFormatError;
+use crate::error::EmulatorError::{
+    ElfFormatError, ImageFormatError, IncompatibleProgram, OverlappingImages,
+};
 use crate::error::EmulatorResult;
+use crate::isa::{Isa, Xlen};
 use crate::memory::addressspace::{Address, AddressSpace, MemoryDevice};
 use goblin::elf::header::{machine_to_str, EM_RISCV};
-use goblin::elf::program_header::PT_LOAD;
-use goblin::Object;
+use goblin::elf::program_header::{PF_W, PF_X, PT_LOAD, PT_PHDR};
+use goblin::elf::sym::{STT_FUNC, STT_NOTYPE, STT_OBJECT};
+use goblin::elf::Elf;
+use std::convert::TryFrom;
 use std::fs;
+use std::ops::Range;
 use std::path::Path;
+use std::str::FromStr;
 
-pub fn load_program(path: &str, memory: &mut AddressSpace) -> EmulatorResult<()> {
+// The flags in the ELF header of RISC-V programs
+const EF_RISCV_RVC: u32 = 0x1;
+const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+const EF_RISCV_FLOAT_ABI_SINGLE: u32 = 0x2;
+const EF_RISCV_FLOAT_ABI_DOUBLE: u32 = 0x4;
+const EF_RISCV_FLOAT_ABI_QUAD: u32 = 0x6;
+const EF_RISCV_RVE: u32 = 0x8;
+
+const SHT_RISCV_ATTRIBUTES: u32 = 0x7000_0003;
+const TAG_FILE: u64 = 1;
+const TAG_RISCV_ARCH: u64 = 5;
+
+// Symbols defined by common linker scripts for the initial stack pointer
+const STACK_SYMBOLS: [&str; 4] = ["__stack_top", "_stack_top", "__stack", "_sp"];
+
+/// A loadable segment as it was placed in memory. The bytes between
+/// `file_size` and `memory_size` are zero, as for BSS sections.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Segment {
+    pub address: Address,
+    pub file_size: u32,
+    pub memory_size: u32,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub address: Address,
+    pub size: u32,
+}
+
+/// The location of the program headers in memory, which the C library of
+/// Linux programs uses to find its TLS template
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ProgramHeaders {
+    pub address: Address,
+    pub entry_size: u16,
+    pub count: u16,
+}
+
+/// What an ELF file requires from the ISA, according to its header and its
+/// `.riscv.attributes` section
+#[derive(PartialEq, Debug, Clone)]
+pub struct IsaRequirements {
+    /// `e_flags` of the ELF header
+    pub flags: u32,
+    /// The ISA string the program was compiled for
+    pub arch: Option<String>,
+}
+
+/// Describes a program loaded into memory
+#[derive(Debug, Clone)]
+pub struct LoadedImage {
+    /// The register width, which is given by the ELF class
+    pub xlen: Xlen,
+    /// The address of the first instruction, the CPU has to be reset to it
+    pub entry: Address,
+    pub segments: Vec<Segment>,
+    /// The function and object symbols, sorted by address
+    pub symbols: Vec<Symbol>,
+    pub program_headers: Option<ProgramHeaders>,
+    /// The path of the dynamic linker requested by the program, if any
+    pub interpreter: Option<String>,
+    /// The end of the last segment, where the heap can start
+    pub heap_start: Address,
+    /// The initial stack pointer, if the linker script defines one
+    pub stack_top: Option<Address>,
+    /// Only known for ELF files
+    pub requirements: Option<IsaRequirements>,
+}
+
+impl LoadedImage {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// Checks that the program can run with the given ISA. The ELF class has
+    /// to match the register width, and the compressed instructions, the
+    /// floating point ABI and the extensions of the arch attribute have to be
+    /// available. Programs for RV32E run on RV32I as well.
+    pub fn check_isa(&self, isa: &Isa) -> EmulatorResult<()> {
+        let mut mismatches = Vec::new();
+
+        if self.xlen != isa.xlen() {
+            mismatches.push(format!(
+                "{} bit registers (ELFCLASS{}), but the ISA is {}",
+                self.xlen.bits(),
+                self.xlen.bits(),
+                isa
+            ));
+        }
+
+        if let Some(requirements) = &self.requirements {
+            let flags = requirements.flags;
+            if flags & EF_RISCV_RVC != 0 && !isa.has("c") {
+                mismatches.push("the C extension (EF_RISCV_RVC)".to_string());
+            }
+            let float_abi = match flags & EF_RISCV_FLOAT_ABI {
+                EF_RISCV_FLOAT_ABI_SINGLE => Some(("F", "single")),
+                EF_RISCV_FLOAT_ABI_DOUBLE => Some(("D", "double")),
+                EF_RISCV_FLOAT_ABI_QUAD => Some(("Q", "quad")),
+                _ => None,
+            };
+            if let Some((extension, abi)) = float_abi {
+                if !isa.has(&extension.to_ascii_lowercase()) {
+                    mismatches.push(format!("the {} extension ({}-float ABI)", extension, abi));
+                }
+            }
+            if flags & EF_RISCV_RVE == 0 && !isa.has("i") {
+                mismatches.push("the registers x16 to x31 (no EF_RISCV_RVE)".to_string());
+            }
+
+            if let Some(arch) = &requirements.arch {
+                match arch.parse::<Isa>() {
+                    Ok(required) => {
+                        let missing = isa.missing_extensions(&required);
+                        if !missing.is_empty() {
+                            mismatches.push(format!(
+                                "the extensions {} (arch attribute {})",
+                                missing.join(", "),
+                                arch
+                            ));
+                        }
+                    }
+                    Err(_) => mismatches.push(format!("the unknown arch attribute {}", arch)),
+                }
+            }
+        }
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(IncompatibleProgram(mismatches))
+        }
+    }
+}
+
+/// The file formats of program images
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ImageFormat {
+    Elf,
+    /// A raw memory image without any metadata
+    Binary,
+    IntelHex,
+    SRecord,
+}
+
+impl ImageFormat {
+    /// Detects the format of a file. ELF files are recognized by their magic
+    /// number, the other formats by their extension or, if it's unknown, by
+    /// their records. Everything else is a raw binary.
+    pub fn detect(path: &Path, data: &[u8]) -> ImageFormat {
+        if data.starts_with(b"\x7fELF") {
+            return ImageFormat::Elf;
+        }
+
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("elf") => ImageFormat::Elf,
+            Some("bin") | Some("img") => ImageFormat::Binary,
+            Some("hex") | Some("ihex") | Some("ihx") => ImageFormat::IntelHex,
+            Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => {
+                ImageFormat::SRecord
+            }
+            _ if is_text_records(data, |line| line.starts_with(':')) => ImageFormat::IntelHex,
+            _ if is_text_records(data, |line| {
+                line.starts_with('S') && line[1..].starts_with(|c: char| c.is_ascii_digit())
+            }) =>
+            {
+                ImageFormat::SRecord
+            }
+            _ => ImageFormat::Binary,
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "elf" => Ok(ImageFormat::Elf),
+            "bin" => Ok(ImageFormat::Binary),
+            "hex" => Ok(ImageFormat::IntelHex),
+            "srec" => Ok(ImageFormat::SRecord),
+            _ => Err(format!("unknown image format: {}", s)),
+        }
+    }
+}
+
+/// An image to place in memory together with others, written as
+/// `PATH[@ADDRESS]`
+#[derive(PartialEq, Debug, Clone)]
+pub struct ImageSpec {
+    pub path: String,
+    pub format: Option<ImageFormat>,
+    pub address: Option<Address>,
+}
+
+impl FromStr for ImageSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, address) = match s.rsplit_once('@') {
+            Some((path, address)) => (path, Some(parse_address(address)?)),
+            None => (s, None),
+        };
+
+        Ok(ImageSpec {
+            path: path.to_string(),
+            format: None,
+            address,
+        })
+    }
+}
+
+/// Parses a decimal or, with the prefix 0x, hexadecimal address
+pub fn parse_address(value: &str) -> Result<Address, String> {
+    let result = match value.strip_prefix("0x") {
+        Some(digits) => Address::from_str_radix(digits, 16),
+        None => value.parse(),
+    };
+    result.map_err(|_| format!("invalid address: {}", value))
+}
+
+/// Loads several images into one machine, like a boot ROM, a kernel and its
+/// data, and returns them in the same order. Images must not overlap.
+pub fn load_images(
+    specs: &[ImageSpec],
+    memory: &mut AddressSpace,
+) -> EmulatorResult<Vec<LoadedImage>> {
+    let mut images: Vec<LoadedImage> = Vec::new();
+
+    for (index, spec) in specs.iter().enumerate() {
+        let image = load_image(&spec.path, spec.format, spec.address, memory)?;
+
+        for (other, loaded) in images.iter().enumerate() {
+            if let Some(address) = overlap(&image, loaded) {
+                return Err(OverlappingImages(
+                    specs[other].path.clone(),
+                    specs[index].path.clone(),
+                    address,
+                ));
+            }
+        }
+        images.push(image);
+    }
+
+    Ok(images)
+}
+
+/// Returns the first address used by both images
+fn overlap(first: &LoadedImage, second: &LoadedImage) -> Option<Address> {
+    let range = |segment: &Segment| {
+        u64::from(segment.address)..u64::from(segment.address) + u64::from(segment.memory_size)
+    };
+
+    let mut overlaps = Vec::new();
+    for a in first.segments.iter().map(range) {
+        for b in second.segments.iter().map(range) {
+            if !a.is_empty() && !b.is_empty() && a.start < b.end && b.start < a.end {
+                overlaps.push(a.start.max(b.start) as Address);
+            }
+        }
+    }
+    overlaps.into_iter().min()
+}
+
+/// Loads a program, detecting its format. Raw binaries are placed at
+/// address 0.
+pub fn load_program(path: &str, memory: &mut AddressSpace) -> EmulatorResult<LoadedImage> {
+    load_image(path, None, None, memory)
+}
+
+/// Loads a program in the given format, or the detected one if `format` is
+/// `None`. Raw binaries are placed at `address`, or 0, which is also their
+/// entry point. The other formats contain their addresses, Intel HEX and
+/// S-record files without a start address record start at their lowest
+/// address. If `address` is given for them, the whole image, including its
+/// entry point and symbols, is moved so that it starts there.
+///
+/// ELF segments are zero-filled up to their size in memory.
+pub fn load_image(
+    path: &str,
+    format: Option<ImageFormat>,
+    address: Option<Address>,
+    memory: &mut AddressSpace,
+) -> EmulatorResult<LoadedImage> {
     let path = Path::new(path);
     let buffer = fs::read(path)?;
 
-    match Object::parse(&buffer).unwrap() {
-        Object::Elf(elf) => {
-            if elf.header.e_machine != EM_RISCV {
+    match format.unwrap_or_else(|| ImageFormat::detect(path, &buffer)) {
+        ImageFormat::Elf => {
+            let elf = Elf::parse(&buffer).map_err(|error| ElfFormatError(error.to_string()))?;
+            let image = load_elf(&elf, &buffer, address, memory)?;
+            image.check_isa(&Isa::supported(image.xlen))?;
+            Ok(image)
+        }
+        ImageFormat::Binary => load_chunks(vec![(0, buffer)], Some(0), address, memory),
+        ImageFormat::IntelHex => {
+            let (chunks, entry) = parse_intel_hex(&text(&buffer)?)?;
+            load_chunks(chunks, entry, address, memory)
+        }
+        ImageFormat::SRecord => {
+            let (chunks, entry) = parse_srecord(&text(&buffer)?)?;
+            load_chunks(chunks, entry, address, memory)
+        }
+    }
+}
+
+fn load_elf(
+    elf: &Elf,
+    buffer: &[u8],
+    address: Option<Address>,
+    memory: &mut AddressSpace,
+) -> EmulatorResult<LoadedImage> {
+    if elf.header.e_machine != EM_RISCV {
+        return Err(ElfFormatError(format!(
+            "invalid architecture: {}",
+            machine_to_str(elf.header.e_machine)
+        )));
+    }
+
+    let loadable = || {
+        elf.program_headers
+            .iter()
+            .filter(|phdr| phdr.p_type == PT_LOAD)
+    };
+    // Added to all addresses, wrapping around if the image moves down
+    let offset = match (address, loadable().map(|phdr| phdr.p_vaddr).min()) {
+        (Some(address), Some(lowest)) => u64::from(address).wrapping_sub(lowest),
+        _ => 0,
+    };
+
+    let mut segments = Vec::new();
+    for phdr in loadable() {
+        let data = file_range(phdr.p_offset, phdr.p_filesz)
+            .and_then(|range| buffer.get(range))
+            .ok_or_else(|| {
+                ElfFormatError(format!("segment at 0x{:x} exceeds the file", phdr.p_vaddr))
+            })?;
+        let (file_size, memory_size) =
+            match (u32::try_from(phdr.p_filesz), u32::try_from(phdr.p_memsz)) {
+                (Ok(file_size), Ok(memory_size)) if file_size <= memory_size => {
+                    (file_size, memory_size)
+                }
+                _ => {
+                    return Err(ElfFormatError(format!(
+                        "invalid size of segment at 0x{:x}",
+                        phdr.p_vaddr
+                    )))
+                }
+            };
+
+        let virtual_address = phdr.p_vaddr.wrapping_add(offset);
+        let address = match Address::try_from(virtual_address) {
+            Ok(address) if memory.is_mapped(address, memory_size.max(1)) => address,
+            _ => {
                 return Err(ElfFormatError(format!(
-                    "invalid architecture: {}",
-                    machine_to_str(elf.header.e_machine)
-                )));
+                    "segment at 0x{:x} is outside of memory",
+                    virtual_address
+                )))
             }
+        };
 
-            for loadable_phdr in elf
-                .program_headers
-                .iter()
-                .filter(|phdr| phdr.p_type == PT_LOAD)
-            {
-                let x = &buffer[loadable_phdr.file_range()];
+        for (index, &byte) in data.iter().enumerate() {
+            memory.write_byte(address + index as Address, byte);
+        }
+        for offset in file_size..memory_size {
+            memory.write_byte(address + offset, 0);
+        }
 
-                for (index, &byte) in x.iter().enumerate() {
-                    memory.write_byte((index + loadable_phdr.p_vaddr as usize) as Address, byte);
-                }
+        segments.push(Segment {
+            address,
+            file_size,
+            memory_size,
+            writable: phdr.p_flags & PF_W != 0,
+            executable: phdr.p_flags & PF_X != 0,
+        });
+    }
+
+    let entry = elf.entry.wrapping_add(offset);
+    let entry = Address::try_from(entry)
+        .map_err(|_| ElfFormatError(format!("entry point 0x{:x} is outside of memory", entry)))?;
+
+    let symbols = read_symbols(elf, offset);
+    let stack_top = STACK_SYMBOLS.iter().find_map(|&name| {
+        symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.address)
+    });
+
+    Ok(LoadedImage {
+        xlen: if elf.is_64 { Xlen::Rv64 } else { Xlen::Rv32 },
+        entry,
+        program_headers: find_program_headers(elf, offset, &segments),
+        interpreter: elf.interpreter.map(String::from),
+        heap_start: segments
+            .iter()
+            .map(|segment| segment.address + segment.memory_size)
+            .max()
+            .unwrap_or(0),
+        stack_top,
+        segments,
+        symbols,
+        requirements: Some(IsaRequirements {
+            flags: elf.header.e_flags,
+            arch: read_arch_attribute(elf, buffer),
+        }),
+    })
+}
+
+fn read_symbols(elf: &Elf, offset: u64) -> Vec<Symbol> {
+    let mut symbols: Vec<Symbol> = elf
+        .syms
+        .iter()
+        .filter(|sym| {
+            matches!(sym.st_type(), STT_NOTYPE | STT_FUNC | STT_OBJECT) && sym.st_shndx != 0
+        })
+        .filter_map(|sym| {
+            let name = elf.strtab.get(sym.st_name)?.ok()?;
+            Some(Symbol {
+                name: name.to_string(),
+                address: Address::try_from(sym.st_value.wrapping_add(offset)).ok()?,
+                size: u32::try_from(sym.st_size).ok()?,
+            })
+        })
+        .filter(|symbol| !symbol.name.is_empty())
+        .collect();
+
+    symbols.sort_by_key(|symbol| symbol.address);
+    symbols
+}
+
+/// Reads the ISA string from the file attributes in the `.riscv.attributes`
+/// section. Malformed attributes are ignored.
+fn read_arch_attribute(elf: &Elf, buffer: &[u8]) -> Option<String> {
+    let section = elf
+        .section_headers
+        .iter()
+        .find(|section| section.sh_type == SHT_RISCV_ATTRIBUTES)?;
+    let data = buffer.get(file_range(section.sh_offset, section.sh_size)?)?;
+
+    // Format version 'A', followed by subsections of vendors
+    let mut subsections = data.strip_prefix(b"A")?;
+    while subsections.len() >= 4 {
+        let length = read_length(subsections)?;
+        let subsection = subsections.get(4..length)?;
+        subsections = &subsections[length..];
+
+        let vendor_end = subsection.iter().position(|&byte| byte == 0)?;
+        if &subsection[..vendor_end] != b"riscv" {
+            continue;
+        }
+
+        let mut attributes = &subsection[vendor_end + 1..];
+        while !attributes.is_empty() {
+            let (tag, tag_length) = read_uleb128(attributes)?;
+            // The length includes the tag
+            let length = read_length(attributes.get(tag_length..)?)?;
+            let content = attributes.get(tag_length + 4..length)?;
+            attributes = &attributes[length..];
+
+            if tag == TAG_FILE {
+                return find_arch(content);
             }
         }
-        _ => {
-            return Err(ElfFormatError("Invalid binary".into()));
+    }
+
+    None
+}
+
+/// Finds Tag_RISCV_arch in a list of attributes. Like for all attributes,
+/// odd tags have string values and even tags ULEB128 values.
+fn find_arch(mut attributes: &[u8]) -> Option<String> {
+    while !attributes.is_empty() {
+        let (tag, tag_length) = read_uleb128(attributes)?;
+        attributes = &attributes[tag_length..];
+
+        if tag & 1 == 1 {
+            let end = attributes.iter().position(|&byte| byte == 0)?;
+            if tag == TAG_RISCV_ARCH {
+                return String::from_utf8(attributes[..end].to_vec()).ok();
+            }
+            attributes = &attributes[end + 1..];
+        } else {
+            let (_, value_length) = read_uleb128(attributes)?;
+            attributes = &attributes[value_length..];
         }
     }
 
-    Ok(())
+    None
+}
+
+fn read_length(data: &[u8]) -> Option<usize> {
+    let bytes = data.get(..4)?;
+    let length = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    if length < 4 {
+        None
+    } else {
+        Some(length)
+    }
+}
+
+/// Returns the value and the number of bytes used
+fn read_uleb128(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (index, &byte) in data.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((value, index + 1));
+        }
+    }
+    None
+}
+
+/// The range of a segment or section in the file, None if it overflows.
+/// The `file_range` methods of goblin panic instead.
+fn file_range(offset: u64, size: u64) -> Option<Range<usize>> {
+    let end = offset.checked_add(size)?;
+    Some(usize::try_from(offset).ok()?..usize::try_from(end).ok()?)
+}
+
+/// The program headers are either described by PT_PHDR, or part of the
+/// segment containing the start of the file
+fn find_program_headers(elf: &Elf, offset: u64, segments: &[Segment]) -> Option<ProgramHeaders> {
+    let file_offset = elf.header.e_phoff;
+    let address = match elf
+        .program_headers
+        .iter()
+        .find(|phdr| phdr.p_type == PT_PHDR)
+    {
+        Some(phdr) => phdr.p_vaddr,
+        None => elf
+            .program_headers
+            .iter()
+            .filter(|phdr| phdr.p_type == PT_LOAD)
+            .find(|phdr| {
+                phdr.p_offset <= file_offset
+                    && phdr
+                        .p_offset
+                        .checked_add(phdr.p_filesz)
+                        .is_some_and(|end| file_offset < end)
+            })
+            .map(|phdr| phdr.p_vaddr.wrapping_add(file_offset - phdr.p_offset))?,
+    };
+    let address = Address::try_from(address.wrapping_add(offset)).ok()?;
+
+    if segments
+        .iter()
+        .any(|segment| segment.address <= address && address < segment.address + segment.file_size)
+    {
+        Some(ProgramHeaders {
+            address,
+            entry_size: elf.header.e_phentsize,
+            count: elf.header.e_phnum,
+        })
+    } else {
+        None
+    }
+}
+
+type Chunks = Vec<(Address, Vec<u8>)>;
+
+/// Places the data of an image without ELF metadata in memory, optionally
+/// moved to start at `address`. Contiguous chunks become one segment.
+fn load_chunks(
+    mut chunks: Chunks,
+    entry: Option<Address>,
+    address: Option<Address>,
+    memory: &mut AddressSpace,
+) -> EmulatorResult<LoadedImage> {
+    chunks.sort_by_key(|&(address, _)| address);
+
+    let offset = match (address, chunks.first()) {
+        (Some(address), Some(&(lowest, _))) => address.wrapping_sub(lowest),
+        _ => 0,
+    };
+    for (address, _) in chunks.iter_mut() {
+        *address = address.wrapping_add(offset);
+    }
+    let entry = entry.map(|entry| entry.wrapping_add(offset));
+
+    let mut segments: Vec<Segment> = Vec::new();
+    for (address, data) in chunks {
+        if data.is_empty() {
+            continue;
+        }
+        let size = u32::try_from(data.len())
+            .ok()
+            .filter(|&size| memory.is_mapped(address, size))
+            .ok_or_else(|| {
+                ImageFormatError(format!("data at 0x{:x} is outside of memory", address))
+            })?;
+
+        for (index, &byte) in data.iter().enumerate() {
+            memory.write_byte(address + index as Address, byte);
+        }
+
+        match segments.last_mut() {
+            Some(last) if last.address + last.file_size == address => {
+                last.file_size += size;
+                last.memory_size += size;
+            }
+            _ => segments.push(Segment {
+                address,
+                file_size: size,
+                memory_size: size,
+                writable: true,
+                executable: true,
+            }),
+        }
+    }
+
+    let entry = entry
+        .or_else(|| segments.first().map(|segment| segment.address))
+        .unwrap_or(0);
+    let heap_start = segments
+        .last()
+        .map(|segment| segment.address + segment.memory_size)
+        .unwrap_or(0);
+
+    Ok(LoadedImage {
+        xlen: Xlen::Rv32,
+        entry,
+        segments,
+        symbols: Vec::new(),
+        program_headers: None,
+        interpreter: None,
+        heap_start,
+        stack_top: None,
+        requirements: None,
+    })
+}
+
+/// Parses the records of an Intel HEX file, returning the data and the
+/// start address
+fn parse_intel_hex(text: &str) -> EmulatorResult<(Chunks, Option<Address>)> {
+    let mut chunks = Vec::new();
+    let mut entry = None;
+    let mut base: Address = 0;
+
+    for (number, line) in records(text) {
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| ImageFormatError(format!("line {}: missing ':'", number)))
+            .and_then(|record| hex_bytes(record, number))?;
+        let error = |message: &str| ImageFormatError(format!("line {}: {}", number, message));
+
+        if record.len() < 5 || record.len() != 5 + record[0] as usize {
+            return Err(error("invalid record length"));
+        }
+        if record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(error("invalid checksum"));
+        }
+
+        let offset = u32::from(record[1]) << 8 | u32::from(record[2]);
+        let data = &record[4..record.len() - 1];
+        let value = data
+            .iter()
+            .fold(0u32, |value, &byte| value << 8 | u32::from(byte));
+        match (record[3], data.len()) {
+            (0x00, _) => chunks.push((base.wrapping_add(offset), data.to_vec())),
+            (0x01, _) => break,
+            // Extended segment address, a real mode segment
+            (0x02, 2) => base = value << 4,
+            // Start segment address, CS:IP
+            (0x03, 4) => entry = Some((value >> 16 << 4) + (value & 0xffff)),
+            (0x04, 2) => base = value << 16,
+            (0x05, 4) => entry = Some(value),
+            _ => return Err(error("invalid record type")),
+        }
+    }
+
+    Ok((chunks, entry))
+}
+
+/// Parses the records of a Motorola S-record file, returning the data and
+/// the start address
+fn parse_srecord(text: &str) -> EmulatorResult<(Chunks, Option<Address>)> {
+    let mut chunks = Vec::new();
+    let mut entry = None;
+
+    for (number, line) in records(text) {
+        let error = |message: &str| ImageFormatError(format!("line {}: {}", number, message));
+
+        let mut characters = line.chars();
+        let record_type = match (characters.next(), characters.next()) {
+            (Some('S'), Some(record_type)) => record_type,
+            _ => return Err(error("missing 'S'")),
+        };
+        let record = hex_bytes(
+            line.get(2..).ok_or_else(|| error("invalid record"))?,
+            number,
+        )?;
+
+        if record.is_empty() || record.len() != 1 + record[0] as usize {
+            return Err(error("invalid record length"));
+        }
+        if record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0xff {
+            return Err(error("invalid checksum"));
+        }
+
+        let address_length = match record_type {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(error("invalid record type")),
+        };
+        if record.len() < 2 + address_length {
+            return Err(error("invalid record length"));
+        }
+        let address = record[1..=address_length]
+            .iter()
+            .fold(0u32, |value, &byte| value << 8 | u32::from(byte));
+        let data = &record[1 + address_length..record.len() - 1];
+
+        match record_type {
+            '1' | '2' | '3' => chunks.push((address, data.to_vec())),
+            '7' | '8' | '9' => entry = Some(address),
+            // The header and record counts
+            _ => {}
+        }
+    }
+
+    Ok((chunks, entry))
+}
+
+/// The non-empty lines of a text image with their line numbers
+fn records(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .map(str::trim)
+        .enumerate()
+        .map(|(index, line)| (index + 1, line))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+fn hex_bytes(digits: &str, number: usize) -> EmulatorResult<Vec<u8>> {
+    let error = || ImageFormatError(format!("line {}: invalid hex digits", number));
+    if digits.len() & 1 != 0 || !digits.is_ascii() {
+        return Err(error());
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&digits[index..index + 2], 16).map_err(|_| error()))
+        .collect()
+}
+
+fn text(buffer: &[u8]) -> EmulatorResult<String> {
+    String::from_utf8(buffer.to_vec())
+        .map_err(|_| ImageFormatError("records contain non-ASCII characters".into()))
+}
+
+fn is_text_records(data: &[u8], is_record: impl Fn(&str) -> bool) -> bool {
+    match std::str::from_utf8(data) {
+        Ok(text) => {
+            let mut lines = records(text).peekable();
+            lines.peek().is_some() && lines.all(|(_, line)| line.len() > 1 && is_record(line))
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_program() {
+        let mut memory = AddressSpace::new();
+        // Stale data where the BSS is placed
+        for address in (0..0x10_0000).step_by(4) {
+            memory.write_word(address, 0xffff_ffff);
+        }
+
+        let image = load_program("tests/programs/fibonacci.elf", &mut memory).unwrap();
+        assert_eq!(image.xlen, Xlen::Rv32);
+        assert_eq!(image.entry, image.symbol("_start").unwrap().address);
+        assert!(image.symbol("main").is_some());
+        assert!(image.interpreter.is_none());
+
+        let bss = image
+            .segments
+            .iter()
+            .find(|segment| segment.memory_size > segment.file_size);
+        if let Some(bss) = bss {
+            for offset in bss.file_size..bss.memory_size {
+                assert_eq!(memory.read_byte(bss.address + offset), 0);
+            }
+        }
+        assert!(image
+            .segments
+            .iter()
+            .all(|segment| segment.address + segment.memory_size <= image.heap_start));
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let mut memory = AddressSpace::new();
+
+        let path = std::env::temp_dir().join(format!("loader-test-{}", std::process::id()));
+        let mut data = fs::read("tests/programs/fibonacci.elf").unwrap();
+        data.truncate(100);
+        fs::write(&path, &data).unwrap();
+
+        let result = load_program(path.to_str().unwrap(), &mut memory);
+        assert!(matches!(result, Err(ElfFormatError(_))));
+        fs::remove_file(&path).unwrap();
+
+        assert!(load_program("tests/programs/missing.elf", &mut memory).is_err());
+    }
+
+    #[test]
+    fn test_malformed_program_header() {
+        let mut memory = AddressSpace::new();
+
+        // A 64 bit header with one PT_LOAD segment ending beyond 2^64
+        let mut data = b"\x7fELF\x02\x01\x01".to_vec();
+        data.resize(16, 0);
+        for (value, size) in [
+            (2, 2),
+            (u64::from(EM_RISCV), 2),
+            (1, 4),
+            (0x1000, 8),
+            (64, 8),
+            (0, 8),
+            (0, 4),
+            (64, 2),
+            (56, 2),
+            (1, 2),
+            (64, 2),
+            (0, 2),
+            (0, 2),
+            (u64::from(PT_LOAD), 4),
+            (u64::from(PF_X), 4),
+            (u64::MAX - 0xf, 8),
+            (0x1000, 8),
+            (0x1000, 8),
+            (0x20, 8),
+            (0x20, 8),
+            (0x1000, 8),
+        ]
+        .iter()
+        {
+            data.extend_from_slice(&value.to_le_bytes()[..*size]);
+        }
+
+        let path = write_temporary("malformed.elf", &data);
+        let result = load_program(&path, &mut memory);
+        assert!(matches!(result, Err(ElfFormatError(_))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn write_temporary(name: &str, data: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_detect_format() {
+        let elf = fs::read("tests/programs/fibonacci.elf").unwrap();
+        let detect = |path: &str, data: &[u8]| ImageFormat::detect(Path::new(path), data);
+
+        assert_eq!(detect("program", &elf), ImageFormat::Elf);
+        assert_eq!(detect("program.bin", &elf), ImageFormat::Elf);
+        assert_eq!(detect("flash.hex", b"junk"), ImageFormat::IntelHex);
+        assert_eq!(detect("flash.S19", b"junk"), ImageFormat::SRecord);
+        assert_eq!(detect("flash", b":00000001FF\n"), ImageFormat::IntelHex);
+        assert_eq!(
+            detect("flash", b"S00700007465737438\r\nS70500002004D6\r\n"),
+            ImageFormat::SRecord
+        );
+        assert_eq!(detect("flash.bin", b":00000001FF\n"), ImageFormat::Binary);
+        assert_eq!(detect("flash", &[0x13, 0, 0, 0]), ImageFormat::Binary);
+        assert_eq!(detect("flash", b""), ImageFormat::Binary);
+    }
+
+    #[test]
+    fn test_load_binary() {
+        let mut memory = AddressSpace::new();
+        let path = write_temporary("image.bin", &[0x13, 0, 0, 0, 0x73, 0, 0x10, 0]);
+
+        let image = load_image(&path, None, Some(0x8000), &mut memory).unwrap();
+        assert_eq!(image.entry, 0x8000);
+        assert_eq!(image.heap_start, 0x8008);
+        assert_eq!(memory.read_word(0x8004), 0x00100073);
+
+        assert!(matches!(
+            load_image(&path, None, Some(0x1800_0000), &mut memory),
+            Err(ImageFormatError(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_intel_hex() {
+        let mut memory = AddressSpace::new();
+        let path = write_temporary(
+            "image.hex",
+            b":020000040001F9\n\
+              :08010000130000006F00000075\n\
+              :040108007300100070\n\
+              :0400000500010100F5\n\
+              :00000001FF\n",
+        );
+
+        let image = load_program(&path, &mut memory).unwrap();
+        assert_eq!(image.entry, 0x10100);
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].address, 0x10100);
+        assert_eq!(image.segments[0].file_size, 12);
+        assert_eq!(memory.read_word(0x10104), 0x0000006f);
+        assert_eq!(memory.read_word(0x10108), 0x00100073);
+        fs::remove_file(&path).unwrap();
+
+        let path = write_temporary("corrupt.hex", b":08010000130000006F00000076\n");
+        let error = load_program(&path, &mut memory).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid image file: line 1: invalid checksum"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_srecord() {
+        let mut memory = AddressSpace::new();
+        let path = write_temporary(
+            "image.srec",
+            b"S00700007465737438\n\
+              S3090000200013000000C3\n\
+              S30900002004730010004F\n\
+              S70500002004D6\n",
+        );
+
+        let image = load_program(&path, &mut memory).unwrap();
+        assert_eq!(image.entry, 0x2004);
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(memory.read_word(0x2000), 0x00000013);
+        assert_eq!(memory.read_word(0x2004), 0x00100073);
+        fs::remove_file(&path).unwrap();
+
+        let path = write_temporary("corrupt.srec", b"S4090000200013000000C3\n");
+        assert!(matches!(
+            load_program(&path, &mut memory),
+            Err(ImageFormatError(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_image_spec() {
+        assert_eq!(
+            "rom.bin@0x1000".parse(),
+            Ok(ImageSpec {
+                path: "rom.bin".to_string(),
+                format: None,
+                address: Some(0x1000),
+            })
+        );
+        assert_eq!("kernel.elf".parse::<ImageSpec>().unwrap().address, None);
+        assert!("data.bin@zero".parse::<ImageSpec>().is_err());
+        assert_eq!(parse_address("4096"), Ok(4096));
+    }
+
+    #[test]
+    fn test_load_images() {
+        let mut memory = AddressSpace::new();
+        let rom = write_temporary("rom.bin", &[0x13, 0, 0, 0, 0x6f, 0, 0, 0]);
+        let data = write_temporary("data.bin", &[1, 2, 3, 4]);
+        let elf = "tests/programs/fibonacci.elf".to_string();
+        let spec = |path: &String, address| ImageSpec {
+            path: path.clone(),
+            format: None,
+            address,
+        };
+
+        let mut original_memory = AddressSpace::new();
+        let original = load_program(&elf, &mut original_memory).unwrap();
+        let images = load_images(
+            &[
+                spec(&rom, Some(0x10_0000)),
+                spec(&elf, Some(0x20_0000)),
+                spec(&data, Some(0x30_0000)),
+            ],
+            &mut memory,
+        )
+        .unwrap();
+
+        assert_eq!(images[0].entry, 0x10_0000);
+        assert_eq!(memory.read_word(0x10_0004), 0x6f);
+        assert_eq!(memory.read_word(0x30_0000), 0x0403_0201);
+
+        // The ELF file was moved as a whole
+        let delta = 0x20_0000 - original.segments[0].address;
+        assert_eq!(images[1].entry, original.entry + delta);
+        assert_eq!(
+            images[1].symbol("main").unwrap().address,
+            original.symbol("main").unwrap().address + delta
+        );
+        assert_eq!(
+            memory.read_word(images[1].entry),
+            original_memory.read_word(original.entry)
+        );
+
+        let error = load_images(
+            &[spec(&rom, Some(0x1000)), spec(&data, Some(0x1004))],
+            &mut memory,
+        )
+        .unwrap_err();
+        assert!(matches!(error, OverlappingImages(_, _, 0x1004)));
+
+        fs::remove_file(&rom).unwrap();
+        fs::remove_file(&data).unwrap();
+    }
+
+    #[test]
+    fn test_check_isa() {
+        let mut memory = AddressSpace::new();
+        let path = "tests/programs/fibonacci.elf";
+        let isa = |s: &str| s.parse::<Isa>().unwrap();
+
+        let image = load_program(path, &mut memory).unwrap();
+        let requirements = image.requirements.as_ref().unwrap();
+        assert_eq!(requirements.arch.as_deref(), Some("rv32i2p0_m2p0"));
+        assert!(image.check_isa(&isa("rv32im")).is_ok());
+        assert_eq!(
+            image.check_isa(&isa("rv32ic")).unwrap_err().to_string(),
+            "The program requires the extensions m (arch attribute rv32i2p0_m2p0)"
+        );
+        assert!(matches!(
+            image.check_isa(&isa("rv64imac")),
+            Err(IncompatibleProgram(mismatches)) if mismatches.len() == 1
+        ));
+
+        // Set EF_RISCV_RVC and the double-float ABI
+        let mut data = fs::read(path).unwrap();
+        data[0x24] = (EF_RISCV_RVC | EF_RISCV_FLOAT_ABI_DOUBLE) as u8;
+        let patched = write_temporary("flags.elf", &data);
+        let image = load_program(&patched, &mut memory).unwrap();
+        assert!(image.check_isa(&isa("rv32gc")).is_ok());
+        assert_eq!(
+            image.check_isa(&isa("rv32imf")).unwrap_err().to_string(),
+            "The program requires the C extension (EF_RISCV_RVC), \
+             the D extension (double-float ABI)"
+        );
+
+        // The emulator lacks the Q extension
+        data[0x24] = EF_RISCV_FLOAT_ABI_QUAD as u8;
+        fs::write(&patched, &data).unwrap();
+        assert!(matches!(
+            load_program(&patched, &mut memory),
+            Err(IncompatibleProgram(_))
+        ));
+        fs::remove_file(&patched).unwrap();
+    }
 }
@@ -1,82 +1,440 @@
 use clap::{App, Arg, ArgMatches};
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
-use riscv_emu::cpu::Cpu;
+use riscv_emu::cpu::{Cpu, CpuEvent, Engine, RunLimits};
+use riscv_emu::cpu64::Cpu64;
+use riscv_emu::error::{EmulatorError, EmulatorResult};
+use riscv_emu::isa::{Isa, Xlen};
+use riscv_emu::linux;
 use riscv_emu::loader;
-use riscv_emu::memory::addressspace::AddressSpace;
+use riscv_emu::loader::{parse_address, ImageFormat, ImageSpec};
+use riscv_emu::memory::addressspace::{Address, AddressSpace};
+use riscv_emu::newlib::NewlibSyscalls;
+use riscv_emu::semihosting::Semihosting;
+use riscv_emu::timing::TimingModel;
 
 const NAME: &str = env!("CARGO_PKG_NAME");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 
+/// The exit status when `--max-instructions` or `--timeout` stops the
+/// program, the latter matches timeout(1)
+const EXIT_INSTRUCTION_LIMIT: i32 = 123;
+const EXIT_TIMEOUT: i32 = 124;
+/// The exit status when the program can't be loaded or stops with an
+/// unhandled exception, like timeout(1) failing itself
+const EXIT_FAULT: i32 = 125;
+
+/// The core matching the register width of the loaded program
+enum Core {
+    Rv32(Box<Cpu>),
+    Rv64(Box<Cpu64>),
+}
+
 fn main() {
     let args = parse_commandline();
 
     let mut memory = AddressSpace::new();
 
-    match loader::load_program(&args.path, &mut memory) {
-        Ok(_) => {}
+    let core = match load(&args, &mut memory) {
+        Ok(core) => core,
         Err(error) => {
-            eprintln!("Error: {:?}", error);
-            return;
+            eprintln!("Error: {}", error);
+            std::process::exit(EXIT_FAULT);
         }
     };
 
-    let mut cpu = Cpu::new();
-
     if args.debug_enabled {
         #[cfg(feature = "gdbstub")]
         {
             use riscv_emu::gdbserver;
-            gdbserver::start_server(cpu, memory);
+            match core {
+                Core::Rv32(cpu) => gdbserver::start_server(*cpu, memory),
+                Core::Rv64(cpu) => gdbserver::start_server_rv64(*cpu, memory),
+            }
         }
     } else {
         let before = SystemTime::now();
-        cpu.run(&mut memory);
+        let limits = RunLimits {
+            max_instructions: args.max_instructions,
+            // parse_timeout checked that the deadline can be represented
+            deadline: args
+                .timeout
+                .and_then(|timeout| Instant::now().checked_add(timeout)),
+        };
+        let (event, pc, instructions, cycles) = match core {
+            Core::Rv32(mut cpu) => {
+                let event = cpu.run_with_limits(&mut memory, limits);
+                let pc = u64::from(cpu.get_pc());
+                (
+                    event,
+                    pc,
+                    cpu.get_instruction_counter(),
+                    cpu.get_cycle_counter(),
+                )
+            }
+            Core::Rv64(mut cpu) => {
+                let event = cpu.run_with_limits(&mut memory, limits);
+                let pc = cpu.get_pc();
+                (
+                    event,
+                    pc,
+                    cpu.get_instruction_counter(),
+                    cpu.get_cycle_counter(),
+                )
+            }
+        };
         let after = SystemTime::now();
 
+        match &event {
+            CpuEvent::Fault(exception) => eprintln!(
+                "Error: unhandled exception {:?} at pc=0x{:x}",
+                exception, pc
+            ),
+            CpuEvent::BudgetExhausted => {
+                eprintln!("Error: instruction limit reached at pc=0x{:x}", pc)
+            }
+            CpuEvent::Timeout => eprintln!("Error: timeout at pc=0x{:x}", pc),
+            _ => {}
+        }
+
         let elapsed = after.duration_since(before).unwrap().as_micros();
         eprintln!(
             "\nExecuted {} instructions in {:?} µs",
-            cpu.get_cycle_counter(),
-            elapsed
-        );
-        eprintln!(
-            "Frequency: {} MHz",
-            (cpu.get_cycle_counter() as f64 / elapsed as f64)
+            instructions, elapsed
         );
+        eprintln!("Speed: {:.2} MIPS", instructions as f64 / elapsed as f64);
+        if args.timing.is_some() {
+            eprintln!(
+                "Estimated {} cycles, {:.2} per instruction",
+                cycles,
+                cycles as f64 / instructions as f64
+            );
+        }
+
+        match event {
+            CpuEvent::Exited(status) => std::process::exit(status),
+            CpuEvent::BudgetExhausted => std::process::exit(EXIT_INSTRUCTION_LIMIT),
+            CpuEvent::Timeout => std::process::exit(EXIT_TIMEOUT),
+            CpuEvent::Fault(_) => std::process::exit(EXIT_FAULT),
+            _ => {}
+        }
     }
 }
 
+/// Loads the program and creates a core starting at its entry point. Linux
+/// executables get their initial stack and system calls, 32 bit programs the
+/// host-side handlers for newlib system calls and, if enabled, semihosting.
+/// 64 bit programs don't support host-side handlers, so semihosting is
+/// rejected for them. The core implements the configured ISA, or all
+/// supported extensions.
+fn load(args: &CommandLineArgs, memory: &mut AddressSpace) -> EmulatorResult<Core> {
+    if args.linux_enabled {
+        let isa = args
+            .isa
+            .clone()
+            .unwrap_or_else(|| Isa::supported(Xlen::Rv32));
+        let mut cpu = linux::create_process(
+            &args.path,
+            &program_arguments(args),
+            &args.environment,
+            &args.root,
+            &isa,
+            memory,
+        )?;
+        cpu.set_engine(args.engine);
+        cpu.set_trace(args.trace_enabled);
+        cpu.set_timing_model(args.timing.unwrap_or_default());
+        return Ok(Core::Rv32(Box::new(cpu)));
+    }
+
+    let program = ImageSpec {
+        path: args.path.clone(),
+        format: args.format,
+        address: args.load_address,
+    };
+    let specs: Vec<ImageSpec> = std::iter::once(program)
+        .chain(args.images.iter().cloned())
+        .collect();
+    let images = loader::load_images(&specs, memory)?;
+    let image = &images[0];
+    let reset_vector = args.reset_vector.unwrap_or(image.entry);
+    let isa = args
+        .isa
+        .clone()
+        .unwrap_or_else(|| Isa::supported(image.xlen));
+    image.check_isa(&isa)?;
+
+    let core = match image.xlen {
+        Xlen::Rv32 => {
+            let mut cpu = Cpu::with_isa(&isa)?;
+            cpu.set_pc(reset_vector);
+            cpu.set_engine(args.engine);
+            cpu.set_trace(args.trace_enabled);
+            cpu.set_timing_model(args.timing.unwrap_or_default());
+            cpu.set_environment_call_handler(NewlibSyscalls::new(&args.root, image.heap_start));
+
+            if args.semihosting_enabled {
+                let command_line = program_arguments(args).join(" ");
+                cpu.set_semihosting_handler(Semihosting::new(&args.root, &command_line));
+            }
+            Core::Rv32(Box::new(cpu))
+        }
+        Xlen::Rv64 => {
+            if args.semihosting_enabled {
+                return Err(EmulatorError::UnsupportedIsa(format!(
+                    "{} (semihosting requires rv32)",
+                    isa
+                )));
+            }
+
+            let mut cpu = Cpu64::with_isa(&isa)?;
+            cpu.set_pc(reset_vector.into());
+            cpu.set_trace(args.trace_enabled);
+            cpu.set_timing_model(args.timing.unwrap_or_default());
+            Core::Rv64(Box::new(cpu))
+        }
+    };
+
+    Ok(core)
+}
+
+/// The arguments of the program, starting with its path
+fn program_arguments(args: &CommandLineArgs) -> Vec<String> {
+    std::iter::once(&args.path)
+        .chain(&args.program_args)
+        .cloned()
+        .collect()
+}
+
 struct CommandLineArgs {
     path: String,
+    format: Option<ImageFormat>,
+    load_address: Option<Address>,
+    images: Vec<ImageSpec>,
+    reset_vector: Option<Address>,
+    isa: Option<Isa>,
     debug_enabled: bool,
+    trace_enabled: bool,
+    engine: Engine,
+    timing: Option<TimingModel>,
+    max_instructions: Option<u64>,
+    timeout: Option<Duration>,
+    root: String,
+    semihosting_enabled: bool,
+    linux_enabled: bool,
+    environment: Vec<String>,
+    program_args: Vec<String>,
 }
 
 fn parse_commandline() -> CommandLineArgs {
+    #[allow(unused_mut)]
+    let mut engines = vec!["blocks", "interpreter"];
+    #[cfg(feature = "jit")]
+    engines.extend(&["jit", "jit-verify"]);
+
     let matches: ArgMatches = App::new(NAME)
         .version(VERSION)
         .author(AUTHORS)
         .about(DESCRIPTION)
+        .after_help(
+            "EXIT STATUS:\n    The exit status of the program, 123 if --max-instructions stops it, \
+             124 if --timeout stops it, and 125 if it can't be loaded or faults",
+        )
         .arg(
             Arg::with_name("BINARY")
                 .help("Set the binary file to run")
                 .required(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["elf", "bin", "hex", "srec"])
+                .help("Sets the format of the binary, instead of detecting it"),
+        )
+        .arg(
+            Arg::with_name("load-address")
+                .long("load-address")
+                .value_name("ADDRESS")
+                .validator(|value| parse_address(&value).map(|_| ()))
+                .help("Places the binary at ADDRESS, raw binaries are loaded at 0 otherwise"),
+        )
+        .arg(
+            Arg::with_name("image")
+                .long("image")
+                .value_name("PATH[@ADDRESS]")
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with("linux")
+                .validator(|value| value.parse::<ImageSpec>().map(|_| ()))
+                .help("Loads another image, optionally placed at ADDRESS"),
+        )
+        .arg(
+            Arg::with_name("reset-vector")
+                .long("reset-vector")
+                .value_name("ADDRESS")
+                .conflicts_with("linux")
+                .validator(|value| parse_address(&value).map(|_| ()))
+                .help("Starts at ADDRESS instead of the entry point of the binary"),
+        )
+        .arg(
+            Arg::with_name("isa")
+                .long("isa")
+                .value_name("ISA")
+                .validator(|value| value.parse::<Isa>().map(|_| ()))
+                .help("Restricts the CPU to the extensions of ISA, e.g. rv32imac_zicsr_zifencei"),
+        )
         .arg(
             Arg::with_name("debug")
                 .short("d")
                 .help("Enables gdb-remote support"),
         )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Prints every executed instruction to stderr"),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE")
+                .possible_values(&engines)
+                .default_value("blocks")
+                .help("Selects how 32 bit programs are executed"),
+        )
+        .arg(
+            Arg::with_name("timing")
+                .long("timing")
+                .value_name("MODEL")
+                .validator(|value| value.parse::<TimingModel>().map(|_| ()))
+                .help(
+                    "Estimates the cycles of the program with MODEL, ideal or 3-stage, \
+                     optionally followed by latencies, e.g. 3-stage,divide=18",
+                ),
+        )
+        .arg(
+            Arg::with_name("max-instructions")
+                .long("max-instructions")
+                .value_name("COUNT")
+                .validator(|value| value.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Stops the program after COUNT instructions, with exit status 123"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .validator(|value| parse_timeout(&value).map(|_| ()))
+                .help("Stops the program after SECONDS, with exit status 124"),
+        )
+        .arg(
+            Arg::with_name("root")
+                .long("root")
+                .value_name("DIR")
+                .default_value(".")
+                .help("Sets the directory for files opened by the program"),
+        )
+        .arg(
+            Arg::with_name("semihosting")
+                .long("semihosting")
+                .help("Enables RISC-V semihosting for 32 bit programs"),
+        )
+        .arg(
+            Arg::with_name("linux")
+                .long("linux")
+                .conflicts_with("semihosting")
+                .help("Runs a static Linux executable, translating its system calls"),
+        )
+        .arg(
+            Arg::with_name("env")
+                .long("env")
+                .value_name("NAME=VALUE")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("linux")
+                .help("Sets an environment variable of the Linux program"),
+        )
+        .arg(
+            Arg::with_name("ARGS")
+                .help("Sets the arguments passed to the program")
+                .multiple(true)
+                .last(true),
+        )
         .get_matches();
 
     let path = matches.value_of("BINARY").unwrap();
+    let format = matches
+        .value_of("format")
+        .map(|format| format.parse().unwrap());
+    let load_address = matches
+        .value_of("load-address")
+        .map(|address| parse_address(address).unwrap());
+    let images = matches
+        .values_of("image")
+        .map(|values| values.map(|value| value.parse().unwrap()).collect())
+        .unwrap_or_default();
+    let reset_vector = matches
+        .value_of("reset-vector")
+        .map(|address| parse_address(address).unwrap());
+    let isa = matches.value_of("isa").map(|isa| isa.parse().unwrap());
     let debug_enabled = matches.is_present("debug");
+    let trace_enabled = matches.is_present("trace");
+    let engine = matches.value_of("engine").unwrap().parse().unwrap();
+    let timing = matches
+        .value_of("timing")
+        .map(|model| model.parse().unwrap());
+    let max_instructions = matches
+        .value_of("max-instructions")
+        .map(|count| count.parse().unwrap());
+    let timeout = matches
+        .value_of("timeout")
+        .map(|seconds| parse_timeout(seconds).unwrap());
+    let root = matches.value_of("root").unwrap();
+    let semihosting_enabled = matches.is_present("semihosting");
+    let linux_enabled = matches.is_present("linux");
+    let environment = matches
+        .values_of("env")
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default();
+    let program_args = matches
+        .values_of("ARGS")
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default();
 
     CommandLineArgs {
         path: path.to_string(),
+        format,
+        load_address,
+        images,
+        reset_vector,
+        isa,
         debug_enabled,
+        trace_enabled,
+        engine,
+        timing,
+        max_instructions,
+        timeout,
+        root: root.to_string(),
+        semihosting_enabled,
+        linux_enabled,
+        environment,
+        program_args,
+    }
+}
+
+/// Parses a timeout in seconds, e.g. "2" or "0.5". It has to be short
+/// enough for a deadline to be represented.
+fn parse_timeout(seconds: &str) -> Result<Duration, String> {
+    let timeout = seconds
+        .parse::<f64>()
+        .ok()
+        .and_then(|value| Duration::try_from_secs_f64(value).ok())
+        .ok_or_else(|| format!("invalid timeout: {}", seconds))?;
+
+    match Instant::now().checked_add(timeout) {
+        Some(_) => Ok(timeout),
+        None => Err(format!("timeout out of range: {}", seconds)),
     }
 }
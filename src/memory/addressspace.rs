@@ -1,4 +1,4 @@
-use super::ram::Ram;
+use super::ram::{Ram, RAM_SIZE};
 use super::video::Video;
 use crate::memory::debug::Debug;
 use std::borrow::Borrow;
@@ -22,12 +22,27 @@ pub trait MemoryDevice {
 
     fn offset(&self) -> Address;
 
-    fn check_for_interrupt(&mut self) -> Option<Address>;
+    fn check_for_interrupt(&mut self) -> bool;
+
+    /// The contents of devices backed by plain memory, which may be accessed
+    /// directly instead of through the read and write functions
+    fn as_bytes_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
 }
 
+const UNMAPPED: u32 = u32::MAX;
+
+// Writes are watched with the granularity of 4 KiB pages
+const WATCH_SHIFT: u32 = 12;
+
 pub struct AddressSpace {
     memory_devices: [Box<dyn MemoryDevice>; 3],
     address_lut: [u32; 4096],
+    /// One bit per page holding cached instructions
+    watched_pages: Vec<u64>,
+    /// Writes to watched pages which the instruction cache hasn't seen yet
+    code_writes: Vec<(Address, u32)>,
     // interrupt_flags: Arc<AtomicU32>,
 }
 
@@ -36,7 +51,10 @@ impl AddressSpace {
         let debug_address = (1 << 20) * 512;
         let video_address = (1 << 20) * 1024;
 
-        let mut lut = [0u32; 4096];
+        let mut lut = [UNMAPPED; 4096];
+        for entry in lut.iter_mut().take(RAM_SIZE >> 20) {
+            *entry = 0;
+        }
         lut[512] = 1;
         lut[1024] = 2;
         lut[1025] = 2;
@@ -51,10 +69,74 @@ impl AddressSpace {
                 Box::new(Video::new(video_address, interrupt_flags)),
             ],
             address_lut: lut,
+            watched_pages: vec![0; 1 << (32 - WATCH_SHIFT - 6)],
+            code_writes: Vec::new(),
             // interrupt_flags,
         }
     }
 
+    /// Checks whether all bytes of an access of `size` bytes are backed by a
+    /// memory device. Ranges wrapping around the end of the address space are
+    /// never mapped, an empty range always is.
+    pub fn is_mapped(&self, address: Address, size: u32) -> bool {
+        if size == 0 {
+            return true;
+        }
+        let last_address = match address.checked_add(size - 1) {
+            Some(last_address) => last_address,
+            None => return false,
+        };
+
+        ((address >> 20)..=(last_address >> 20))
+            .all(|entry| self.address_lut[entry as usize] != UNMAPPED)
+    }
+
+    /// Records writes to the page containing `address` from now on, because
+    /// the instructions on it are cached.
+    pub fn watch_writes(&mut self, address: Address) {
+        let page = (address >> WATCH_SHIFT) as usize;
+        self.watched_pages[page / 64] |= 1 << (page % 64);
+    }
+
+    /// Whether any watched page was written since the last call to
+    /// `take_code_writes`
+    #[inline(always)]
+    pub fn has_code_writes(&self) -> bool {
+        !self.code_writes.is_empty()
+    }
+
+    /// Returns the address and size of the writes to watched pages, so that
+    /// the instruction cache can invalidate the modified instructions
+    pub fn take_code_writes(&mut self) -> Vec<(Address, u32)> {
+        std::mem::take(&mut self.code_writes)
+    }
+
+    /// The RAM mapped at address 0, e.g. for code accessing it directly.
+    /// Writes to it bypass the recording of writes to watched pages.
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        self.memory_devices[0]
+            .as_bytes_mut()
+            .expect("RAM is backed by plain memory")
+    }
+
+    /// The bitmap of watched pages, bit `n % 64` of word `n / 64` is set if
+    /// page `n` is watched
+    pub fn watched_pages(&self) -> &[u64] {
+        &self.watched_pages
+    }
+
+    pub fn is_watched(&self, address: Address) -> bool {
+        let page = (address >> WATCH_SHIFT) as usize;
+        self.watched_pages[page / 64] & 1 << (page % 64) != 0
+    }
+
+    #[inline(always)]
+    fn record_write(&mut self, address: Address, size: u32) {
+        if self.is_watched(address) || self.is_watched(address.wrapping_add(size - 1)) {
+            self.code_writes.push((address, size));
+        }
+    }
+
     fn get_device_for_address_mut(&mut self, address: Address) -> &mut dyn MemoryDevice {
         let device_index = self.calculate_device_index(address);
         &mut *self.memory_devices[device_index]
@@ -62,7 +144,7 @@ impl AddressSpace {
 
     fn get_device_for_address(&self, address: Address) -> &dyn MemoryDevice {
         let device_index = self.calculate_device_index(address);
-        &*self.memory_devices[device_index].borrow()
+        self.memory_devices[device_index].borrow()
     }
 
     fn calculate_device_index(&self, address: Address) -> usize {
@@ -94,16 +176,19 @@ impl MemoryDevice for AddressSpace {
     }
 
     fn write_byte(&mut self, address: Address, val: u8) {
+        self.record_write(address, 1);
         let device = self.get_device_for_address_mut(address);
         device.write_byte(address, val)
     }
 
     fn write_halfword(&mut self, address: Address, val: u16) {
+        self.record_write(address, 2);
         let device = self.get_device_for_address_mut(address);
         device.write_halfword(address, val)
     }
 
     fn write_word(&mut self, address: Address, val: u32) {
+        self.record_write(address, 4);
         let device = self.get_device_for_address_mut(address);
         device.write_word(address, val)
     }
@@ -113,15 +198,15 @@ impl MemoryDevice for AddressSpace {
     }
 
     #[inline(always)]
-    fn check_for_interrupt(&mut self) -> Option<Address> {
+    fn check_for_interrupt(&mut self) -> bool {
         // let interrupt_flag: u32 = self.interrupt_flags.load(Ordering::SeqCst);
         // if let Some(nr) = get_interrupt_number(interrupt_flag) {
         //     self.interrupt_flags.store(0, Ordering::SeqCst);
-        //     Some(0x24)
+        //     true
         // } else {
-        //     None
+        //     false
         // }
-        None
+        false
     }
 }
 
@@ -138,7 +223,42 @@ impl MemoryDevice for AddressSpace {
 
 #[cfg(test)]
 mod test {
-    // use super::*;
+    use super::*;
+
+    #[test]
+    fn test_is_mapped() {
+        let memory = AddressSpace::new();
+
+        assert!(memory.is_mapped(0, 4));
+        assert!(memory.is_mapped(RAM_SIZE as Address - 4, 4));
+        assert!(!memory.is_mapped(RAM_SIZE as Address - 2, 4));
+        assert!(!memory.is_mapped(RAM_SIZE as Address, 1));
+        assert!(memory.is_mapped(0x2000_0000, 4));
+        assert!(!memory.is_mapped(0xFFFF_FFFF, 1));
+
+        // Ranges spanning the hole between RAM and the debug device
+        assert!(!memory.is_mapped(0, 0x2000_0001));
+        assert!(!memory.is_mapped(RAM_SIZE as Address - 4, 0x1800_0008));
+        assert!(!memory.is_mapped(0x2000_0000, 0xE000_0000));
+
+        assert!(memory.is_mapped(0, 0));
+        assert!(memory.is_mapped(RAM_SIZE as Address, 0));
+    }
+
+    #[test]
+    fn test_watch_writes() {
+        let mut memory = AddressSpace::new();
+        memory.watch_writes(0x1000);
+
+        memory.write_word(0x2000, 1);
+        assert!(!memory.has_code_writes());
+
+        memory.write_byte(0x1FFF, 1);
+        memory.write_word(0x0FFE, 1);
+        assert!(memory.has_code_writes());
+        assert_eq!(memory.take_code_writes(), vec![(0x1FFF, 1), (0x0FFE, 4)]);
+        assert!(!memory.has_code_writes());
+    }
 
     // #[test]
     // fn test_get_interrupt_number() {
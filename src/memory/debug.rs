@@ -68,7 +68,7 @@ impl MemoryDevice for Debug {
         self.offset
     }
 
-    fn check_for_interrupt(&mut self) -> Option<Address> {
-        None
+    fn check_for_interrupt(&mut self) -> bool {
+        false
     }
 }
@@ -2,3 +2,5 @@ pub mod addressspace;
 mod debug;
 mod ram;
 mod video;
+
+pub use ram::RAM_SIZE;
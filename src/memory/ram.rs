@@ -2,6 +2,8 @@ use super::addressspace::Address;
 use super::addressspace::MemoryDevice;
 use crate::util;
 
+pub const RAM_SIZE: usize = 1024 * 1024 * 128; // 128MB for now
+
 pub struct Ram {
     memory: Vec<u8>,
     offset: Address,
@@ -10,7 +12,7 @@ pub struct Ram {
 impl Ram {
     pub fn new(offset: Address) -> Ram {
         Ram {
-            memory: vec![0; 1024 * 1024 * 128], // 128MB for now,
+            memory: vec![0; RAM_SIZE],
             offset,
         }
     }
@@ -49,8 +51,12 @@ impl MemoryDevice for Ram {
         self.offset
     }
 
-    fn check_for_interrupt(&mut self) -> Option<Address> {
-        None
+    fn check_for_interrupt(&mut self) -> bool {
+        false
+    }
+
+    fn as_bytes_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.memory)
     }
 }
 
@@ -67,6 +73,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn test_halfword_access() {
         for i in 0..4 {
             let mut mem = Ram::new(0);
@@ -77,6 +84,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn test_word_access() {
         for i in 0..4 {
             let mut mem = Ram::new(0);
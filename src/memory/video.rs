@@ -108,8 +108,8 @@ impl MemoryDevice for Video {
         self.offset
     }
 
-    fn check_for_interrupt(&mut self) -> Option<Address> {
-        None
+    fn check_for_interrupt(&mut self) -> bool {
+        false
     }
 }
 
@@ -0,0 +1,394 @@
+use crate::csr::{
+    CsrFile, PrivilegeLevel, MSTATUS_MXR, MSTATUS_SUM, SATP_MODE_SV32, SATP_PPN_MASK,
+};
+use crate::exception::Exception;
+use crate::memory::addressspace::{Address, AddressSpace, MemoryDevice};
+
+const PAGE_SHIFT: u32 = 12;
+const PAGE_OFFSET_MASK: u32 = (1 << PAGE_SHIFT) - 1;
+
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+const PTE_SIZE: u32 = 4;
+const LEVELS: u32 = 2;
+const VPN_BITS: u32 = 10;
+const VPN_MASK: u32 = (1 << VPN_BITS) - 1;
+
+// Physical addresses are limited to 32 bits, although Sv32 supports 34 bits
+const MAX_PPN: u32 = 1 << (32 - PAGE_SHIFT);
+
+const TLB_SIZE: usize = 64;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AccessType {
+    Instruction,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    pub fn page_fault(self, address: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionPageFault(address),
+            AccessType::Load => Exception::LoadPageFault(address),
+            AccessType::Store => Exception::StorePageFault(address),
+        }
+    }
+
+    pub fn access_fault(self, address: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionAccessFault(address),
+            AccessType::Load => Exception::LoadAccessFault(address),
+            AccessType::Store => Exception::StoreAccessFault(address),
+        }
+    }
+
+    pub fn misaligned(self, address: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionAddressMisaligned(address),
+            AccessType::Load => Exception::LoadAddressMisaligned(address),
+            AccessType::Store => Exception::StoreAddressMisaligned(address),
+        }
+    }
+}
+
+/// A cached translation of a single 4 KiB page. Superpages are cached as
+/// separate entries for each of their 4 KiB pages.
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    valid: bool,
+    vpn: u32,
+    ppn: u32,
+    flags: u32,
+    pte_address: Address,
+}
+
+impl TlbEntry {
+    const INVALID: TlbEntry = TlbEntry {
+        valid: false,
+        vpn: 0,
+        ppn: 0,
+        flags: 0,
+        pte_address: 0,
+    };
+}
+
+/// Sv32 address translation with a small direct-mapped TLB
+pub struct Mmu {
+    tlb: [TlbEntry; TLB_SIZE],
+}
+
+impl Mmu {
+    pub fn new() -> Mmu {
+        Self {
+            tlb: [TlbEntry::INVALID; TLB_SIZE],
+        }
+    }
+
+    /// Invalidates all cached translations, as required by SFENCE.VMA and
+    /// writes to satp.
+    pub fn flush(&mut self) {
+        self.tlb = [TlbEntry::INVALID; TLB_SIZE];
+    }
+
+    /// Translates the virtual address of an access of `size` bytes into a
+    /// physical address. Accesses crossing a page boundary are reported as
+    /// misaligned while translation is active.
+    pub fn translate(
+        &mut self,
+        address: Address,
+        size: u32,
+        access: AccessType,
+        csr: &CsrFile,
+        memory: &mut AddressSpace,
+    ) -> Result<Address, Exception> {
+        let privilege = match access {
+            AccessType::Instruction => csr.privilege(),
+            _ => csr.effective_data_privilege(),
+        };
+
+        if privilege == PrivilegeLevel::Machine || csr.satp() & SATP_MODE_SV32 == 0 {
+            return Ok(address);
+        }
+
+        if (address & PAGE_OFFSET_MASK) + size > PAGE_OFFSET_MASK + 1 {
+            return Err(access.misaligned(address.into()));
+        }
+
+        let vpn = address >> PAGE_SHIFT;
+        let index = vpn as usize % TLB_SIZE;
+        let cached = self.tlb[index];
+
+        let mut entry = if cached.valid && cached.vpn == vpn {
+            cached
+        } else {
+            Mmu::walk(address, access, csr, memory)?
+        };
+
+        if !is_permitted(entry.flags, access, privilege, csr.mstatus()) {
+            return Err(access.page_fault(address.into()));
+        }
+
+        let mut required_flags = PTE_A;
+        if access == AccessType::Store {
+            required_flags |= PTE_D;
+        }
+
+        if entry.flags & required_flags != required_flags {
+            let pte = memory.read_word(entry.pte_address) | required_flags;
+            memory.write_word(entry.pte_address, pte);
+            entry.flags = pte & 0xFF;
+        }
+
+        self.tlb[index] = entry;
+
+        Ok(entry.ppn << PAGE_SHIFT | (address & PAGE_OFFSET_MASK))
+    }
+
+    /// Walks the two-level page table and returns the leaf entry for the page
+    /// containing `address`.
+    fn walk(
+        address: Address,
+        access: AccessType,
+        csr: &CsrFile,
+        memory: &AddressSpace,
+    ) -> Result<TlbEntry, Exception> {
+        let vpn = address >> PAGE_SHIFT;
+        let mut table_ppn = (csr.satp() & SATP_PPN_MASK) as u32;
+
+        for level in (0..LEVELS).rev() {
+            if table_ppn >= MAX_PPN {
+                return Err(access.access_fault(address.into()));
+            }
+
+            let vpn_part = (vpn >> (level * VPN_BITS)) & VPN_MASK;
+            let pte_address = (table_ppn << PAGE_SHIFT) + vpn_part * PTE_SIZE;
+
+            // Page table accesses are checked by PMP as supervisor mode loads
+            if !memory.is_mapped(pte_address, PTE_SIZE)
+                || !csr.pmp().is_permitted(
+                    pte_address,
+                    PTE_SIZE,
+                    AccessType::Load,
+                    PrivilegeLevel::Supervisor,
+                )
+            {
+                return Err(access.access_fault(address.into()));
+            }
+
+            let pte = memory.read_word(pte_address);
+            let pte_ppn = pte >> 10;
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(access.page_fault(address.into()));
+            }
+
+            if pte & (PTE_R | PTE_X) == 0 {
+                table_ppn = pte_ppn;
+                continue;
+            }
+
+            // Superpages must be aligned to their size
+            let superpage_mask = (1 << (level * VPN_BITS)) - 1;
+            if pte_ppn & superpage_mask != 0 {
+                return Err(access.page_fault(address.into()));
+            }
+
+            let ppn = pte_ppn | (vpn & superpage_mask);
+            if ppn >= MAX_PPN {
+                return Err(access.access_fault(address.into()));
+            }
+
+            return Ok(TlbEntry {
+                valid: true,
+                vpn,
+                ppn,
+                flags: pte & 0xFF,
+                pte_address,
+            });
+        }
+
+        Err(access.page_fault(address.into()))
+    }
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_permitted(flags: u32, access: AccessType, privilege: PrivilegeLevel, mstatus: u64) -> bool {
+    let permitted = match access {
+        AccessType::Instruction => flags & PTE_X != 0,
+        AccessType::Load => {
+            flags & PTE_R != 0 || (mstatus & MSTATUS_MXR != 0 && flags & PTE_X != 0)
+        }
+        AccessType::Store => flags & PTE_W != 0,
+    };
+
+    let privilege_permitted = match privilege {
+        PrivilegeLevel::User => flags & PTE_U != 0,
+        PrivilegeLevel::Supervisor => {
+            flags & PTE_U == 0 || (access != AccessType::Instruction && mstatus & MSTATUS_SUM != 0)
+        }
+        PrivilegeLevel::Machine => true,
+    };
+
+    permitted && privilege_permitted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::csr::{MSTATUS, SATP};
+
+    const ROOT_TABLE: Address = 0x10_0000;
+    const LEAF_TABLE: Address = 0x10_1000;
+
+    fn setup(privilege: PrivilegeLevel) -> (Mmu, CsrFile, AddressSpace) {
+        let mut memory = AddressSpace::new();
+        let mut csr = CsrFile::new();
+
+        // 0x0040_0000 -> 4 MiB superpage at 0x0080_0000
+        memory.write_word(ROOT_TABLE + 4, (0x800 << 10) | PTE_V | PTE_R | PTE_W);
+        // 0x0000_1000 -> 0x0020_0000 via the leaf table
+        memory.write_word(ROOT_TABLE, (LEAF_TABLE >> 12) << 10 | PTE_V);
+        memory.write_word(LEAF_TABLE + 4, (0x200 << 10) | PTE_V | PTE_R | PTE_X);
+        // 0x0000_2000 -> 0x0030_0000, user page
+        memory.write_word(
+            LEAF_TABLE + 8,
+            (0x300 << 10) | PTE_V | PTE_R | PTE_W | PTE_U,
+        );
+
+        csr.write(SATP, SATP_MODE_SV32 | u64::from(ROOT_TABLE >> 12))
+            .unwrap();
+        csr.set_privilege(privilege);
+
+        (Mmu::new(), csr, memory)
+    }
+
+    #[test]
+    fn test_bare_mode() {
+        let mut memory = AddressSpace::new();
+        let csr = CsrFile::new();
+        let mut mmu = Mmu::new();
+
+        assert_eq!(
+            mmu.translate(0x1234, 4, AccessType::Load, &csr, &mut memory),
+            Ok(0x1234)
+        );
+    }
+
+    #[test]
+    fn test_translation() {
+        let (mut mmu, csr, mut memory) = setup(PrivilegeLevel::Supervisor);
+
+        assert_eq!(
+            mmu.translate(0x1234, 4, AccessType::Instruction, &csr, &mut memory),
+            Ok(0x20_0234)
+        );
+        assert_eq!(
+            mmu.translate(0x0045_6789, 1, AccessType::Store, &csr, &mut memory),
+            Ok(0x0085_6789)
+        );
+        // Cached translation
+        assert_eq!(
+            mmu.translate(0x0045_6000, 4, AccessType::Load, &csr, &mut memory),
+            Ok(0x0085_6000)
+        );
+    }
+
+    #[test]
+    fn test_accessed_and_dirty() {
+        let (mut mmu, csr, mut memory) = setup(PrivilegeLevel::Supervisor);
+
+        mmu.translate(0x0040_0000, 4, AccessType::Load, &csr, &mut memory)
+            .unwrap();
+        let pte = memory.read_word(ROOT_TABLE + 4);
+        assert_eq!(pte & (PTE_A | PTE_D), PTE_A);
+
+        mmu.translate(0x0040_0000, 4, AccessType::Store, &csr, &mut memory)
+            .unwrap();
+        let pte = memory.read_word(ROOT_TABLE + 4);
+        assert_eq!(pte & (PTE_A | PTE_D), PTE_A | PTE_D);
+    }
+
+    #[test]
+    fn test_page_faults() {
+        let (mut mmu, mut csr, mut memory) = setup(PrivilegeLevel::Supervisor);
+
+        // Not mapped
+        assert_eq!(
+            mmu.translate(0x0080_0000, 4, AccessType::Load, &csr, &mut memory),
+            Err(Exception::LoadPageFault(0x0080_0000))
+        );
+        // Not writable
+        assert_eq!(
+            mmu.translate(0x1000, 4, AccessType::Store, &csr, &mut memory),
+            Err(Exception::StorePageFault(0x1000))
+        );
+        // Not executable
+        assert_eq!(
+            mmu.translate(0x0040_0000, 4, AccessType::Instruction, &csr, &mut memory),
+            Err(Exception::InstructionPageFault(0x0040_0000))
+        );
+        // User page accessed from supervisor mode
+        assert_eq!(
+            mmu.translate(0x2000, 4, AccessType::Load, &csr, &mut memory),
+            Err(Exception::LoadPageFault(0x2000))
+        );
+
+        csr.set_privilege(PrivilegeLevel::Machine);
+        csr.write(MSTATUS, MSTATUS_SUM).unwrap();
+        csr.set_privilege(PrivilegeLevel::Supervisor);
+        assert_eq!(
+            mmu.translate(0x2000, 4, AccessType::Load, &csr, &mut memory),
+            Ok(0x30_0000)
+        );
+    }
+
+    #[test]
+    fn test_user_mode() {
+        let (mut mmu, csr, mut memory) = setup(PrivilegeLevel::User);
+
+        assert_eq!(
+            mmu.translate(0x2004, 4, AccessType::Store, &csr, &mut memory),
+            Ok(0x30_0004)
+        );
+        assert_eq!(
+            mmu.translate(0x1000, 4, AccessType::Instruction, &csr, &mut memory),
+            Err(Exception::InstructionPageFault(0x1000))
+        );
+        assert_eq!(
+            mmu.translate(0x2FFE, 4, AccessType::Load, &csr, &mut memory),
+            Err(Exception::LoadAddressMisaligned(0x2FFE))
+        );
+    }
+
+    #[test]
+    fn test_flush() {
+        let (mut mmu, csr, mut memory) = setup(PrivilegeLevel::Supervisor);
+
+        mmu.translate(0x1000, 4, AccessType::Load, &csr, &mut memory)
+            .unwrap();
+        memory.write_word(LEAF_TABLE + 4, 0);
+
+        // The stale translation is used until the TLB is flushed
+        assert!(mmu
+            .translate(0x1000, 4, AccessType::Load, &csr, &mut memory)
+            .is_ok());
+
+        mmu.flush();
+        assert_eq!(
+            mmu.translate(0x1000, 4, AccessType::Load, &csr, &mut memory),
+            Err(Exception::LoadPageFault(0x1000))
+        );
+    }
+}
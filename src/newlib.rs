@@ -0,0 +1,673 @@
+use crate::cpu::Cpu;
+use crate::environment_call::{EnvironmentCallHandler, EnvironmentCallResult};
+use crate::memory::addressspace::{Address, AddressSpace, MemoryDevice};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// System call numbers used by libgloss, passed in a7
+const SYS_OPENAT: u32 = 56;
+const SYS_CLOSE: u32 = 57;
+const SYS_LSEEK: u32 = 62;
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_FSTAT: u32 = 80;
+const SYS_EXIT: u32 = 93;
+const SYS_EXIT_GROUP: u32 = 94;
+const SYS_GETTIMEOFDAY: u32 = 169;
+const SYS_BRK: u32 = 214;
+const SYS_OPEN: u32 = 1024;
+
+pub(crate) const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+pub(crate) const EBADF: i32 = 9;
+const ENOMEM: i32 = 12;
+const EACCES: i32 = 13;
+pub(crate) const EFAULT: i32 = 14;
+const EEXIST: i32 = 17;
+pub(crate) const EINVAL: i32 = 22;
+const ESPIPE: i32 = 29;
+pub(crate) const ENAMETOOLONG: i32 = 36;
+pub(crate) const ENOSYS: i32 = 38;
+
+// The flags of open as defined by newlib
+const O_ACCMODE: u32 = 0b11;
+const O_WRONLY: u32 = 1;
+const O_RDWR: u32 = 2;
+const O_APPEND: u32 = 0x0008;
+const O_CREAT: u32 = 0x0200;
+const O_TRUNC: u32 = 0x0400;
+const O_EXCL: u32 = 0x0800;
+
+const AT_FDCWD: i32 = -100;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFREG: u32 = 0o100000;
+
+pub(crate) const MAX_PATH_LENGTH: u32 = 4096;
+
+/// Buffers of the program are copied through host buffers of at most this
+/// size
+pub(crate) const TRANSFER_CHUNK_SIZE: u32 = 64 << 10;
+
+type SyscallResult = Result<u32, i32>;
+
+pub(crate) enum Descriptor {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Implements the system calls of newlib's libgloss port for RISC-V on the
+/// host, so that programs linked against newlib can use stdio, the heap and
+/// files. The system call number is passed in a7, the arguments in a0 to a3
+/// and the result, or a negated errno, is returned in a0.
+///
+/// Files are opened relative to a root directory, and paths can't leave it
+/// using `..` or symbolic links. Pointers passed by the program are used as
+/// physical addresses.
+pub struct NewlibSyscalls {
+    root: PathBuf,
+    descriptors: Vec<Option<Descriptor>>,
+    heap_start: Address,
+    program_break: Address,
+}
+
+impl NewlibSyscalls {
+    /// Creates the system call handler for a program whose heap starts at
+    /// `heap_start`, usually the end of its BSS section.
+    pub fn new(root: impl Into<PathBuf>, heap_start: Address) -> Self {
+        Self {
+            root: root.into(),
+            descriptors: vec![
+                Some(Descriptor::Stdin),
+                Some(Descriptor::Stdout),
+                Some(Descriptor::Stderr),
+            ],
+            heap_start,
+            program_break: heap_start,
+        }
+    }
+
+    fn open(&mut self, memory: &AddressSpace, path: Address, flags: u32) -> SyscallResult {
+        let path = resolve_path(&self.root, &read_string(memory, path)?)?;
+
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+
+        let file = options.open(path).map_err(errno)?;
+        let descriptor = match self.descriptors.iter().position(Option::is_none) {
+            Some(descriptor) => descriptor,
+            None => {
+                self.descriptors.push(None);
+                self.descriptors.len() - 1
+            }
+        };
+        self.descriptors[descriptor] = Some(Descriptor::File(file));
+
+        Ok(descriptor as u32)
+    }
+
+    fn close(&mut self, descriptor: u32) -> SyscallResult {
+        match self.descriptors.get_mut(descriptor as usize) {
+            Some(entry @ Some(_)) => {
+                *entry = None;
+                Ok(0)
+            }
+            _ => Err(EBADF),
+        }
+    }
+
+    fn read(
+        &mut self,
+        memory: &mut AddressSpace,
+        descriptor: u32,
+        buffer: Address,
+        length: u32,
+    ) -> SyscallResult {
+        check_buffer(memory, buffer, length)?;
+        read_descriptor(memory, self.descriptor(descriptor)?, buffer, length)
+    }
+
+    fn write(
+        &mut self,
+        memory: &AddressSpace,
+        descriptor: u32,
+        buffer: Address,
+        length: u32,
+    ) -> SyscallResult {
+        check_buffer(memory, buffer, length)?;
+        write_descriptor(memory, self.descriptor(descriptor)?, buffer, length)
+    }
+
+    fn lseek(&mut self, descriptor: u32, offset: i32, whence: u32) -> SyscallResult {
+        let position = match whence {
+            0 => SeekFrom::Start(offset as u32 as u64),
+            1 => SeekFrom::Current(offset.into()),
+            2 => SeekFrom::End(offset.into()),
+            _ => return Err(EINVAL),
+        };
+
+        match self.descriptor(descriptor)? {
+            Descriptor::File(file) => match file.seek(position) {
+                Ok(position) if position <= i32::MAX as u64 => Ok(position as u32),
+                Ok(_) => Err(EINVAL),
+                Err(error) => Err(errno(error)),
+            },
+            _ => Err(ESPIPE),
+        }
+    }
+
+    /// Fills in the mode, size and block size of libgloss' `struct
+    /// kernel_stat`, which is all newlib uses.
+    fn fstat(
+        &mut self,
+        memory: &mut AddressSpace,
+        descriptor: u32,
+        stat: Address,
+    ) -> SyscallResult {
+        check_buffer(memory, stat, 64)?;
+
+        let (mode, size) = match self.descriptor(descriptor)? {
+            Descriptor::File(file) => {
+                let size = file.metadata().map_err(errno)?.len();
+                (S_IFREG | 0o644, size)
+            }
+            _ => (S_IFCHR | 0o620, 0),
+        };
+
+        for offset in (0..64).step_by(4) {
+            memory.write_word(stat + offset, 0);
+        }
+        memory.write_word(stat + 16, mode);
+        memory.write_word(stat + 20, 1); // st_nlink
+        memory.write_word(stat + 48, size as u32);
+        memory.write_word(stat + 52, (size >> 32) as u32);
+        memory.write_word(stat + 56, 4096); // st_blksize
+        Ok(0)
+    }
+
+    /// Writes the time as newlib's `struct timeval`, with a 64 bit `tv_sec`
+    fn gettimeofday(&mut self, memory: &mut AddressSpace, timeval: Address) -> SyscallResult {
+        check_buffer(memory, timeval, 12)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        memory.write_word(timeval, now.as_secs() as u32);
+        memory.write_word(timeval + 4, (now.as_secs() >> 32) as u32);
+        memory.write_word(timeval + 8, now.subsec_micros());
+        Ok(0)
+    }
+
+    /// Moves the program break and returns the new one. Like on Linux, the
+    /// current break is returned if the request can't be satisfied.
+    fn brk(&mut self, memory: &AddressSpace, address: Address) -> SyscallResult {
+        if address >= self.heap_start
+            && (address == self.heap_start
+                || memory.is_mapped(self.heap_start, address - self.heap_start))
+        {
+            self.program_break = address;
+        }
+
+        Ok(self.program_break)
+    }
+
+    fn descriptor(&mut self, descriptor: u32) -> Result<&mut Descriptor, i32> {
+        match self.descriptors.get_mut(descriptor as usize) {
+            Some(Some(descriptor)) => Ok(descriptor),
+            _ => Err(EBADF),
+        }
+    }
+}
+
+impl EnvironmentCallHandler for NewlibSyscalls {
+    fn environment_call(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut AddressSpace,
+    ) -> EnvironmentCallResult {
+        let arguments = [
+            cpu.get_register(10),
+            cpu.get_register(11),
+            cpu.get_register(12),
+            cpu.get_register(13),
+        ];
+
+        let result = match cpu.get_register(17) {
+            SYS_EXIT | SYS_EXIT_GROUP => return EnvironmentCallResult::Exit(arguments[0] as i32),
+            SYS_OPEN => self.open(memory, arguments[0], arguments[1]),
+            SYS_OPENAT if arguments[0] as i32 == AT_FDCWD => {
+                self.open(memory, arguments[1], arguments[2])
+            }
+            SYS_OPENAT => Err(EBADF),
+            SYS_CLOSE => self.close(arguments[0]),
+            SYS_LSEEK => self.lseek(arguments[0], arguments[1] as i32, arguments[2]),
+            SYS_READ => self.read(memory, arguments[0], arguments[1], arguments[2]),
+            SYS_WRITE => self.write(memory, arguments[0], arguments[1], arguments[2]),
+            SYS_FSTAT => self.fstat(memory, arguments[0], arguments[1]),
+            SYS_GETTIMEOFDAY => self.gettimeofday(memory, arguments[0]),
+            SYS_BRK => self.brk(memory, arguments[0]),
+            _ => Err(ENOSYS),
+        };
+
+        let value = match result {
+            Ok(value) => value,
+            Err(errno) => (-errno) as u32,
+        };
+        cpu.set_register(10, value);
+
+        EnvironmentCallResult::Return
+    }
+}
+
+/// Resolves a path of the program relative to the root directory. Paths
+/// containing `..` or leading out of the root through symbolic links are
+/// rejected with EACCES. The last component isn't resolved, so that a link
+/// itself can be removed, but it has to point into the root as well.
+pub(crate) fn resolve_path(root: &Path, path: &str) -> Result<PathBuf, i32> {
+    let root = root.canonicalize().map_err(errno)?;
+    let mut resolved = root.clone();
+
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return Err(EACCES),
+        }
+    }
+
+    let resolved = match (resolved.parent(), resolved.file_name()) {
+        (Some(parent), Some(name)) if resolved != root => {
+            parent.canonicalize().map_err(errno)?.join(name)
+        }
+        _ => return Ok(root),
+    };
+    let target = match resolved.canonicalize() {
+        Ok(target) => target,
+        // A file which doesn't exist yet, rather than a dangling link
+        Err(_) if resolved.symlink_metadata().is_err() => resolved.clone(),
+        Err(error) => return Err(errno(error)),
+    };
+
+    if target.starts_with(&root) {
+        Ok(resolved)
+    } else {
+        Err(EACCES)
+    }
+}
+
+/// Checks that all of a buffer of the program is mapped
+pub(crate) fn check_buffer(
+    memory: &AddressSpace,
+    address: Address,
+    length: u32,
+) -> Result<(), i32> {
+    if length == 0 || memory.is_mapped(address, length) {
+        Ok(())
+    } else {
+        Err(EFAULT)
+    }
+}
+
+/// Splits a buffer of `length` bytes into the chunks it is copied in, as
+/// their offsets and lengths
+pub(crate) fn transfer_chunks(length: u32) -> impl Iterator<Item = (u32, u32)> {
+    (0..length)
+        .step_by(TRANSFER_CHUNK_SIZE as usize)
+        .map(move |offset| (offset, (length - offset).min(TRANSFER_CHUNK_SIZE)))
+}
+
+/// Copies memory of the program at `address` into `data`
+pub(crate) fn read_memory(
+    memory: &AddressSpace,
+    address: Address,
+    data: &mut [u8],
+) -> Result<(), i32> {
+    check_buffer(
+        memory,
+        address,
+        u32::try_from(data.len()).map_err(|_| EFAULT)?,
+    )?;
+
+    for (index, byte) in data.iter_mut().enumerate() {
+        *byte = memory.read_byte(address.wrapping_add(index as Address));
+    }
+    Ok(())
+}
+
+/// Copies `data` into memory of the program at `address`
+pub(crate) fn write_memory(
+    memory: &mut AddressSpace,
+    address: Address,
+    data: &[u8],
+) -> Result<(), i32> {
+    check_buffer(
+        memory,
+        address,
+        u32::try_from(data.len()).map_err(|_| EFAULT)?,
+    )?;
+
+    for (index, &byte) in data.iter().enumerate() {
+        memory.write_byte(address.wrapping_add(index as Address), byte);
+    }
+    Ok(())
+}
+
+/// Reads from a descriptor into a buffer of the program, a chunk at a time
+/// until a read returns less than asked for. Returns the number of bytes
+/// read.
+pub(crate) fn read_descriptor(
+    memory: &mut AddressSpace,
+    descriptor: &mut Descriptor,
+    buffer: Address,
+    length: u32,
+) -> Result<u32, i32> {
+    let mut data = vec![0u8; length.min(TRANSFER_CHUNK_SIZE) as usize];
+    let mut count = 0;
+
+    for (offset, chunk_length) in transfer_chunks(length) {
+        let chunk = &mut data[..chunk_length as usize];
+        let read = match descriptor {
+            Descriptor::Stdin => io::stdin().read(chunk),
+            Descriptor::File(file) => file.read(chunk),
+            _ => return Err(EBADF),
+        };
+        let read = match read {
+            Ok(read) => read,
+            Err(_) if count > 0 => break,
+            Err(error) => return Err(errno(error)),
+        };
+
+        write_memory(memory, buffer.wrapping_add(offset), &chunk[..read])?;
+        count += read as u32;
+        if read < chunk.len() {
+            break;
+        }
+    }
+    Ok(count)
+}
+
+/// Writes a buffer of the program to a descriptor, a chunk at a time.
+/// Returns the number of bytes written.
+pub(crate) fn write_descriptor(
+    memory: &AddressSpace,
+    descriptor: &mut Descriptor,
+    buffer: Address,
+    length: u32,
+) -> Result<u32, i32> {
+    let mut data = vec![0u8; length.min(TRANSFER_CHUNK_SIZE) as usize];
+
+    for (offset, chunk_length) in transfer_chunks(length) {
+        let chunk = &mut data[..chunk_length as usize];
+        read_memory(memory, buffer.wrapping_add(offset), chunk)?;
+        match descriptor {
+            Descriptor::Stdout => write_and_flush(&mut io::stdout(), chunk),
+            Descriptor::Stderr => write_and_flush(&mut io::stderr(), chunk),
+            Descriptor::File(file) => file.write_all(chunk),
+            Descriptor::Stdin => return Err(EBADF),
+        }
+        .map_err(errno)?;
+    }
+    Ok(length)
+}
+
+pub(crate) fn read_string(memory: &AddressSpace, address: Address) -> Result<String, i32> {
+    let mut bytes = Vec::new();
+
+    for offset in 0..MAX_PATH_LENGTH {
+        let address = address.wrapping_add(offset);
+        if !memory.is_mapped(address, 1) {
+            return Err(EFAULT);
+        }
+
+        match memory.read_byte(address) {
+            0 => return String::from_utf8(bytes).map_err(|_| ENOENT),
+            byte => bytes.push(byte),
+        }
+    }
+
+    Err(ENAMETOOLONG)
+}
+
+/// Reads until the buffer is full or the end of the file is reached
+pub(crate) fn read_all(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut count = 0;
+
+    while count < buffer.len() {
+        match file.read(&mut buffer[count..])? {
+            0 => break,
+            read => count += read,
+        }
+    }
+
+    Ok(count)
+}
+
+pub(crate) fn write_and_flush(output: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    output.write_all(data)?;
+    output.flush()
+}
+
+pub(crate) fn errno(error: io::Error) -> i32 {
+    match error.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::OutOfMemory => ENOMEM,
+        _ => EIO,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn call(
+        handler: &mut NewlibSyscalls,
+        memory: &mut AddressSpace,
+        number: u32,
+        arguments: &[u32],
+    ) -> i32 {
+        let mut cpu = Cpu::new();
+        cpu.set_register(17, number);
+        for (index, &argument) in arguments.iter().enumerate() {
+            cpu.set_register(10 + index, argument);
+        }
+
+        assert_eq!(
+            handler.environment_call(&mut cpu, memory),
+            EnvironmentCallResult::Return
+        );
+        cpu.get_register(10) as i32
+    }
+
+    fn write_string(memory: &mut AddressSpace, address: Address, s: &str) {
+        for (index, byte) in s.bytes().chain(Some(0)).enumerate() {
+            memory.write_byte(address + index as Address, byte);
+        }
+    }
+
+    #[test]
+    fn test_files() {
+        let root = std::env::temp_dir().join(format!("newlib-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let mut memory = AddressSpace::new();
+        let mut handler = NewlibSyscalls::new(&root, 0x10_0000);
+        write_string(&mut memory, 0x100, "/data.txt");
+        write_string(&mut memory, 0x200, "hello");
+
+        let flags = O_WRONLY | O_CREAT | O_TRUNC;
+        let fd = call(&mut handler, &mut memory, SYS_OPEN, &[0x100, flags, 0o644]);
+        assert_eq!(fd, 3);
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_WRITE, &[3, 0x200, 5]),
+            5
+        );
+        assert_eq!(call(&mut handler, &mut memory, SYS_CLOSE, &[3]), 0);
+        assert_eq!(fs::read_to_string(root.join("data.txt")).unwrap(), "hello");
+
+        let fd = call(
+            &mut handler,
+            &mut memory,
+            SYS_OPENAT,
+            &[AT_FDCWD as u32, 0x100, 0, 0],
+        );
+        assert_eq!(fd, 3);
+        assert_eq!(call(&mut handler, &mut memory, SYS_LSEEK, &[3, 1, 0]), 1);
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_READ, &[3, 0x300, 16]),
+            4
+        );
+        assert_eq!(memory.read_word(0x300), u32::from_le_bytes(*b"ello"));
+
+        assert_eq!(call(&mut handler, &mut memory, SYS_FSTAT, &[3, 0x400]), 0);
+        assert_eq!(memory.read_word(0x410), S_IFREG | 0o644);
+        assert_eq!(memory.read_word(0x430), 5);
+        assert_eq!(call(&mut handler, &mut memory, SYS_FSTAT, &[1, 0x400]), 0);
+        assert_eq!(memory.read_word(0x410) & S_IFCHR, S_IFCHR);
+
+        assert_eq!(call(&mut handler, &mut memory, SYS_CLOSE, &[3]), 0);
+        assert_eq!(call(&mut handler, &mut memory, SYS_CLOSE, &[3]), -EBADF);
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_LSEEK, &[1, 0, 0]),
+            -ESPIPE
+        );
+
+        write_string(&mut memory, 0x100, "/missing");
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_OPEN, &[0x100, 0, 0]),
+            -ENOENT
+        );
+        write_string(&mut memory, 0x100, "../data.txt");
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_OPEN, &[0x100, 0, 0]),
+            -EACCES
+        );
+
+        // Buffers larger than a chunk are copied in several
+        let length = 3 * TRANSFER_CHUNK_SIZE + 5;
+        for offset in 0..length {
+            memory.write_byte(0x10_0000 + offset, offset as u8);
+        }
+        write_string(&mut memory, 0x100, "/large");
+        let flags = O_RDWR | O_CREAT | O_TRUNC;
+        let fd = call(&mut handler, &mut memory, SYS_OPEN, &[0x100, flags, 0o644]);
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_WRITE,
+                &[3, 0x10_0000, length]
+            ),
+            length as i32
+        );
+        assert_eq!(call(&mut handler, &mut memory, SYS_LSEEK, &[3, 0, 0]), 0);
+        assert_eq!(
+            call(
+                &mut handler,
+                &mut memory,
+                SYS_READ,
+                &[3, 0x20_0000, 2 * length]
+            ),
+            length as i32
+        );
+        assert_eq!(memory.read_byte(0x20_0000 + length - 1), (length - 1) as u8);
+        assert_eq!(call(&mut handler, &mut memory, SYS_CLOSE, &[fd as u32]), 0);
+
+        // Symbolic links can't lead out of the root
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(std::env::temp_dir(), root.join("outside")).unwrap();
+            write_string(&mut memory, 0x100, "/outside/escaped.txt");
+            assert_eq!(
+                call(&mut handler, &mut memory, SYS_OPEN, &[0x100, flags, 0o644]),
+                -EACCES
+            );
+
+            std::os::unix::fs::symlink(root.join("large"), root.join("link")).unwrap();
+            write_string(&mut memory, 0x100, "/link");
+            assert_eq!(call(&mut handler, &mut memory, SYS_OPEN, &[0x100, 0, 0]), 3);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_brk() {
+        let mut memory = AddressSpace::new();
+        let mut handler = NewlibSyscalls::new(".", 0x10_0000);
+
+        assert_eq!(call(&mut handler, &mut memory, SYS_BRK, &[0]), 0x10_0000);
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_BRK, &[0x10_1000]),
+            0x10_1000
+        );
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_BRK, &[0x1000_0000]),
+            0x10_1000
+        );
+        // The heap can't span the hole between the RAM and the devices
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_BRK, &[0x2000_0010]),
+            0x10_1000
+        );
+    }
+
+    #[test]
+    fn test_other_calls() {
+        let mut memory = AddressSpace::new();
+        let mut handler = NewlibSyscalls::new(".", 0x10_0000);
+
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_GETTIMEOFDAY, &[0x100, 0]),
+            0
+        );
+        assert!(memory.read_word(0x100) > 1_500_000_000);
+        assert!(memory.read_word(0x108) < 1_000_000);
+
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_WRITE, &[1, 0x1000_0000, 4]),
+            -EFAULT
+        );
+        // Buffers spanning unmapped memory or wrapping around are rejected
+        // as a whole
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_WRITE, &[1, 0, 0x2000_0001]),
+            -EFAULT
+        );
+        assert_eq!(
+            call(&mut handler, &mut memory, SYS_READ, &[0, 0x100, u32::MAX]),
+            -EFAULT
+        );
+        assert_eq!(call(&mut handler, &mut memory, 1234, &[]), -ENOSYS);
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(17, SYS_EXIT);
+        cpu.set_register(10, 3);
+        assert_eq!(
+            handler.environment_call(&mut cpu, &mut memory),
+            EnvironmentCallResult::Exit(3)
+        );
+    }
+}
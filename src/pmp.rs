@@ -0,0 +1,263 @@
+use crate::csr::PrivilegeLevel;
+use crate::memory::addressspace::Address;
+use crate::mmu::AccessType;
+
+pub const PMP_ENTRIES: usize = 16;
+
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 0b11 << 3;
+const PMP_L: u8 = 1 << 7;
+
+const PMP_A_SHIFT: u8 = 3;
+
+// Bits 5 and 6 of each configuration byte are reserved
+const PMP_CFG_WRITE_MASK: u8 = PMP_R | PMP_W | PMP_X | PMP_A | PMP_L;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum AddressMatching {
+    Off,
+    Tor,
+    Na4,
+    Napot,
+}
+
+impl AddressMatching {
+    fn from_config(config: u8) -> AddressMatching {
+        match (config & PMP_A) >> PMP_A_SHIFT {
+            0b00 => AddressMatching::Off,
+            0b01 => AddressMatching::Tor,
+            0b10 => AddressMatching::Na4,
+            _ => AddressMatching::Napot,
+        }
+    }
+}
+
+/// Physical memory protection with 16 entries, configured through the
+/// pmpcfg0-3 and pmpaddr0-15 CSRs.
+pub struct Pmp {
+    config: [u8; PMP_ENTRIES],
+    address: [u64; PMP_ENTRIES],
+}
+
+impl Pmp {
+    pub fn new() -> Pmp {
+        Self {
+            config: [0; PMP_ENTRIES],
+            address: [0; PMP_ENTRIES],
+        }
+    }
+
+    /// Reads pmpcfg`index`, which holds the configuration of four entries
+    pub fn read_config(&self, index: usize) -> u32 {
+        let bytes = &self.config[index * 4..index * 4 + 4];
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    /// Writes pmpcfg`index`, skipping the configuration of locked entries
+    pub fn write_config(&mut self, index: usize, value: u32) {
+        for (i, byte) in value.to_le_bytes().iter().enumerate() {
+            let entry = index * 4 + i;
+            if self.is_locked(entry) {
+                continue;
+            }
+
+            let mut config = byte & PMP_CFG_WRITE_MASK;
+            // The combination W=1, R=0 is reserved
+            if config & PMP_R == 0 {
+                config &= !PMP_W;
+            }
+            self.config[entry] = config;
+        }
+    }
+
+    pub fn read_address(&self, index: usize) -> u64 {
+        self.address[index]
+    }
+
+    /// Writes pmpaddr`index`. Locked entries can't be changed, and neither
+    /// can the lower bound of a locked TOR entry.
+    pub fn write_address(&mut self, index: usize, value: u64) {
+        let lower_bound_locked = index + 1 < PMP_ENTRIES
+            && self.is_locked(index + 1)
+            && AddressMatching::from_config(self.config[index + 1]) == AddressMatching::Tor;
+
+        if !self.is_locked(index) && !lower_bound_locked {
+            self.address[index] = value;
+        }
+    }
+
+    /// Whether any entry is enabled. Otherwise all accesses are permitted in
+    /// machine mode and below.
+    pub fn is_active(&self) -> bool {
+        self.config
+            .iter()
+            .any(|&config| AddressMatching::from_config(config) != AddressMatching::Off)
+    }
+
+    fn is_locked(&self, entry: usize) -> bool {
+        self.config[entry] & PMP_L != 0
+    }
+
+    /// Checks an access of `size` bytes at the physical address `address`.
+    /// The lowest-numbered matching entry decides, and the access fails if it
+    /// only partially covers the access. Machine mode is only restricted by
+    /// locked entries. Accesses from lower privilege levels that match no
+    /// entry fail, unless PMP has not been configured at all.
+    pub fn is_permitted(
+        &self,
+        address: Address,
+        size: u32,
+        access: AccessType,
+        privilege: PrivilegeLevel,
+    ) -> bool {
+        let start = u64::from(address);
+        let end = start + u64::from(size);
+        let mut any_active = false;
+
+        for entry in 0..PMP_ENTRIES {
+            let (base, limit) = match self.range(entry) {
+                Some(range) => range,
+                None => continue,
+            };
+            any_active = true;
+
+            if end <= base || start >= limit {
+                continue;
+            }
+
+            if start < base || end > limit {
+                return false;
+            }
+
+            let config = self.config[entry];
+            if privilege == PrivilegeLevel::Machine && config & PMP_L == 0 {
+                return true;
+            }
+
+            let required = match access {
+                AccessType::Instruction => PMP_X,
+                AccessType::Load => PMP_R,
+                AccessType::Store => PMP_W,
+            };
+            return config & required != 0;
+        }
+
+        privilege == PrivilegeLevel::Machine || !any_active
+    }
+
+    /// The byte address range [base, limit) covered by an entry. pmpaddr
+    /// holds bits 33:2 of the address on RV32 and bits 55:2 on RV64.
+    fn range(&self, entry: usize) -> Option<(u64, u64)> {
+        let address = self.address[entry];
+
+        match AddressMatching::from_config(self.config[entry]) {
+            AddressMatching::Off => None,
+            AddressMatching::Tor => {
+                let base = match entry {
+                    0 => 0,
+                    _ => self.address[entry - 1] << 2,
+                };
+                Some((base, address << 2))
+            }
+            AddressMatching::Na4 => Some((address << 2, (address << 2) + 4)),
+            AddressMatching::Napot => {
+                let trailing_ones = address.trailing_ones();
+                let base = (address & !((1 << trailing_ones) - 1)) << 2;
+                Some((base, base + (1 << (trailing_ones + 3))))
+            }
+        }
+    }
+}
+
+impl Default for Pmp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TOR: u8 = 0b01 << PMP_A_SHIFT;
+    const NA4: u8 = 0b10 << PMP_A_SHIFT;
+    const NAPOT: u8 = 0b11 << PMP_A_SHIFT;
+
+    fn configure(pmp: &mut Pmp, entry: usize, config: u8, address: u64) {
+        pmp.write_address(entry, address);
+        let index = entry / 4;
+        let shift = (entry % 4) * 8;
+        let value = pmp.read_config(index) & !(0xFF << shift) | u32::from(config) << shift;
+        pmp.write_config(index, value);
+    }
+
+    #[test]
+    fn test_unconfigured() {
+        let pmp = Pmp::new();
+
+        assert!(!pmp.is_active());
+        assert!(pmp.is_permitted(0x1000, 4, AccessType::Load, PrivilegeLevel::User));
+        assert!(pmp.is_permitted(0x1000, 4, AccessType::Store, PrivilegeLevel::Machine));
+    }
+
+    #[test]
+    fn test_tor() {
+        let mut pmp = Pmp::new();
+        configure(&mut pmp, 0, TOR | PMP_R | PMP_X, 0x2000 >> 2);
+        assert!(pmp.is_active());
+
+        assert!(pmp.is_permitted(0x0, 4, AccessType::Load, PrivilegeLevel::User));
+        assert!(pmp.is_permitted(0x1FFC, 4, AccessType::Instruction, PrivilegeLevel::User));
+        assert!(!pmp.is_permitted(0x1000, 4, AccessType::Store, PrivilegeLevel::User));
+        // Partially matching accesses fail
+        assert!(!pmp.is_permitted(0x1FFE, 4, AccessType::Load, PrivilegeLevel::User));
+        // No matching entry
+        assert!(!pmp.is_permitted(0x2000, 4, AccessType::Load, PrivilegeLevel::User));
+        assert!(pmp.is_permitted(0x2000, 4, AccessType::Load, PrivilegeLevel::Machine));
+        // Unlocked entries don't apply to machine mode
+        assert!(pmp.is_permitted(0x1000, 4, AccessType::Store, PrivilegeLevel::Machine));
+    }
+
+    #[test]
+    fn test_na4_and_napot() {
+        let mut pmp = Pmp::new();
+        configure(&mut pmp, 0, NA4, 0x1000 >> 2);
+        // 0x1000 - 0x1FFF
+        configure(&mut pmp, 1, NAPOT | PMP_R | PMP_W, (0x1000 >> 2) | 0x1FF);
+
+        assert!(!pmp.is_permitted(0x1000, 4, AccessType::Load, PrivilegeLevel::Supervisor));
+        assert!(pmp.is_permitted(0x1004, 4, AccessType::Store, PrivilegeLevel::Supervisor));
+        assert!(pmp.is_permitted(0x1FFC, 4, AccessType::Load, PrivilegeLevel::Supervisor));
+        assert!(!pmp.is_permitted(0x2000, 1, AccessType::Load, PrivilegeLevel::Supervisor));
+        assert!(!pmp.is_permitted(0x1004, 2, AccessType::Instruction, PrivilegeLevel::User));
+    }
+
+    #[test]
+    fn test_lock() {
+        let mut pmp = Pmp::new();
+        configure(&mut pmp, 0, NA4 | PMP_L, 0x100 >> 2);
+        configure(&mut pmp, 2, TOR | PMP_L | PMP_R, 0x3000 >> 2);
+
+        // Locked entries apply to machine mode
+        assert!(!pmp.is_permitted(0x100, 4, AccessType::Load, PrivilegeLevel::Machine));
+
+        configure(&mut pmp, 0, NA4 | PMP_R, 0x200 >> 2);
+        assert_eq!(pmp.read_config(0) & 0xFF, u32::from(NA4 | PMP_L));
+        assert_eq!(pmp.read_address(0), 0x100 >> 2);
+
+        // pmpaddr1 is the lower bound of the locked TOR entry 2
+        pmp.write_address(1, 0x2000 >> 2);
+        assert_eq!(pmp.read_address(1), 0);
+        pmp.write_address(3, 0x4000 >> 2);
+        assert_eq!(pmp.read_address(3), 0x4000 >> 2);
+    }
+
+    #[test]
+    fn test_reserved_permissions() {
+        let mut pmp = Pmp::new();
+        pmp.write_config(0, u32::from(NA4 | PMP_W | 0b110_0000));
+        assert_eq!(pmp.read_config(0), u32::from(NA4));
+    }
+}
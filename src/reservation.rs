@@ -0,0 +1,65 @@
+use crate::memory::addressspace::Address;
+
+/// The reservation set registered by a load-reserved instruction. Each hart
+/// holds at most one reservation, which covers the naturally aligned word or
+/// doubleword that was loaded.
+///
+/// The emulator executes instructions one at a time and in program order, so
+/// every memory access is visible before the next instruction starts. This
+/// already satisfies the acquire and release semantics requested by the aq
+/// and rl bits, which therefore need no further handling.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Reservation {
+    address: Address,
+    size: u32,
+}
+
+impl Reservation {
+    pub fn new(address: Address, size: u32) -> Self {
+        Self {
+            address: address & !(size - 1),
+            size,
+        }
+    }
+
+    /// Checks whether a store-conditional of `size` bytes at `address` may
+    /// succeed with this reservation.
+    pub fn covers(&self, address: Address, size: u32) -> bool {
+        address >= self.address && u64::from(address) + u64::from(size) <= self.end()
+    }
+
+    /// Checks whether a store to `address` touches the reservation set.
+    pub fn overlaps(&self, address: Address, size: u32) -> bool {
+        u64::from(address) < self.end()
+            && u64::from(self.address) < u64::from(address) + u64::from(size)
+    }
+
+    fn end(&self) -> u64 {
+        u64::from(self.address) + u64::from(self.size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reservation_set() {
+        let reservation = Reservation::new(0x1002, 4);
+        assert_eq!(reservation, Reservation::new(0x1000, 4));
+
+        assert!(reservation.covers(0x1000, 4));
+        assert!(!reservation.covers(0x1004, 4));
+        assert!(!Reservation::new(0x1000, 8).covers(0x1004, 8));
+        assert!(Reservation::new(0x1000, 8).covers(0x1004, 4));
+
+        assert!(reservation.overlaps(0x1003, 1));
+        assert!(reservation.overlaps(0x0FFF, 2));
+        assert!(!reservation.overlaps(0x0FFC, 4));
+        assert!(!reservation.overlaps(0x1004, 2));
+
+        let reservation = Reservation::new(0xFFFF_FFFC, 4);
+        assert!(reservation.covers(0xFFFF_FFFC, 4));
+        assert!(reservation.overlaps(0xFFFF_FFFF, 1));
+    }
+}
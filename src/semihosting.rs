@@ -0,0 +1,624 @@
+use crate::cpu::Cpu;
+use crate::environment_call::{EnvironmentCallHandler, EnvironmentCallResult};
+use crate::memory::addressspace::{Address, AddressSpace, MemoryDevice};
+use crate::newlib::{
+    check_buffer, errno, read_all, read_memory, resolve_path, transfer_chunks, write_and_flush,
+    write_descriptor, write_memory, Descriptor, EBADF, EINVAL, ENAMETOOLONG, ENOSYS,
+    MAX_PATH_LENGTH, TRANSFER_CHUNK_SIZE,
+};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Operation numbers, passed in a0
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_READC: u32 = 0x07;
+const SYS_ISERROR: u32 = 0x08;
+const SYS_ISTTY: u32 = 0x09;
+const SYS_SEEK: u32 = 0x0A;
+const SYS_FLEN: u32 = 0x0C;
+const SYS_REMOVE: u32 = 0x0E;
+const SYS_RENAME: u32 = 0x0F;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_HEAPINFO: u32 = 0x16;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+const SYS_ELAPSED: u32 = 0x30;
+const SYS_TICKFREQ: u32 = 0x31;
+
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+// The pseudo file opened by the program to access the console
+const CONSOLE: &str = ":tt";
+
+const FAILURE: u32 = -1i32 as u32;
+
+/// The ticks returned by SYS_ELAPSED are microseconds
+const TICK_FREQUENCY: u32 = 1_000_000;
+
+/// Implements the ARM-compatible semihosting operations used by RISC-V
+/// programs, like QEMU's `-semihosting`. The operation is passed in a0 and a
+/// pointer to its parameter block in a1, the result is returned in a0.
+///
+/// Files are opened relative to a root directory, and paths can't leave it
+/// using `..` or symbolic links. Pointers passed by the program are used as
+/// physical addresses.
+pub struct Semihosting {
+    root: PathBuf,
+    command_line: String,
+    handles: Vec<Option<Descriptor>>,
+    heap_info: [Address; 4],
+    start: Instant,
+    errno: i32,
+}
+
+impl Semihosting {
+    pub fn new(root: impl Into<PathBuf>, command_line: &str) -> Self {
+        Self {
+            root: root.into(),
+            command_line: command_line.to_string(),
+            handles: Vec::new(),
+            heap_info: [0; 4],
+            start: Instant::now(),
+            errno: 0,
+        }
+    }
+
+    /// Sets the heap base and limit and the stack base and limit returned by
+    /// SYS_HEAPINFO. By default they are zero, which tells the C library to
+    /// use the values from its linker script.
+    pub fn set_heap_info(&mut self, heap_info: [Address; 4]) {
+        self.heap_info = heap_info;
+    }
+
+    /// Runs an operation other than the exits. Unsupported operations fail
+    /// with ENOSYS, so that the program continues with -1 in a0 like on QEMU
+    /// and OpenOCD.
+    fn call(
+        &mut self,
+        memory: &mut AddressSpace,
+        operation: u32,
+        parameter: Address,
+    ) -> Result<u32, i32> {
+        match operation {
+            SYS_OPEN => {
+                let [path, mode, length] = read_parameters(memory, parameter)?;
+                self.open(memory, path, mode, length)
+            }
+            SYS_CLOSE => {
+                let [handle] = read_parameters(memory, parameter)?;
+                self.close(handle)
+            }
+            SYS_WRITEC => {
+                check_buffer(memory, parameter, 1)?;
+                let data = [memory.read_byte(parameter)];
+                write_and_flush(&mut io::stdout(), &data).map_err(errno)?;
+                Ok(0)
+            }
+            SYS_WRITE0 => write_string(memory, parameter, &mut io::stdout()),
+            SYS_WRITE => {
+                let [handle, buffer, length] = read_parameters(memory, parameter)?;
+                self.write(memory, handle, buffer, length)
+            }
+            SYS_READ => {
+                let [handle, buffer, length] = read_parameters(memory, parameter)?;
+                self.read(memory, handle, buffer, length)
+            }
+            SYS_READC => {
+                let mut data = [0u8];
+                match io::stdin().read(&mut data).map_err(errno)? {
+                    0 => Ok(FAILURE),
+                    _ => Ok(data[0].into()),
+                }
+            }
+            SYS_ISERROR => {
+                let [status] = read_parameters(memory, parameter)?;
+                Ok(((status as i32) < 0) as u32)
+            }
+            SYS_ISTTY => {
+                let [handle] = read_parameters(memory, parameter)?;
+                match self.handle(handle)? {
+                    Descriptor::File(_) => Ok(0),
+                    _ => Ok(1),
+                }
+            }
+            SYS_SEEK => {
+                let [handle, position] = read_parameters(memory, parameter)?;
+                match self.handle(handle)? {
+                    Descriptor::File(file) => file
+                        .seek(SeekFrom::Start(position.into()))
+                        .map(|_| 0)
+                        .map_err(errno),
+                    _ => Err(EBADF),
+                }
+            }
+            SYS_FLEN => {
+                let [handle] = read_parameters(memory, parameter)?;
+                match self.handle(handle)? {
+                    Descriptor::File(file) => Ok(file.metadata().map_err(errno)?.len() as u32),
+                    _ => Err(EBADF),
+                }
+            }
+            SYS_REMOVE => {
+                let [path, length] = read_parameters(memory, parameter)?;
+                let path = self.read_path(memory, path, length)?;
+                fs::remove_file(path).map(|_| 0).map_err(errno)
+            }
+            SYS_RENAME => {
+                let [from, from_length, to, to_length] = read_parameters(memory, parameter)?;
+                let from = self.read_path(memory, from, from_length)?;
+                let to = self.read_path(memory, to, to_length)?;
+                fs::rename(from, to).map(|_| 0).map_err(errno)
+            }
+            SYS_CLOCK => Ok((self.start.elapsed().as_millis() / 10) as u32),
+            SYS_TIME => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                Ok(now.as_secs() as u32)
+            }
+            SYS_ERRNO => Ok(self.errno as u32),
+            SYS_GET_CMDLINE => {
+                let [buffer, length] = read_parameters(memory, parameter)?;
+                let mut command_line = self.command_line.clone().into_bytes();
+                command_line.push(0);
+                if command_line.len() as u32 > length {
+                    return Err(EINVAL);
+                }
+
+                write_memory(memory, buffer, &command_line)?;
+                let length = command_line.len() as u32 - 1;
+                memory.write_word(parameter.wrapping_add(4), length);
+                Ok(0)
+            }
+            SYS_HEAPINFO => {
+                let [block] = read_parameters(memory, parameter)?;
+                check_buffer(memory, block, 16)?;
+                for (index, &value) in self.heap_info.iter().enumerate() {
+                    memory.write_word(block.wrapping_add(4 * index as Address), value);
+                }
+                Ok(0)
+            }
+            SYS_ELAPSED => {
+                check_buffer(memory, parameter, 8)?;
+                let ticks = self.start.elapsed().as_micros() as u64;
+                memory.write_word(parameter, ticks as u32);
+                memory.write_word(parameter.wrapping_add(4), (ticks >> 32) as u32);
+                Ok(0)
+            }
+            SYS_TICKFREQ => Ok(TICK_FREQUENCY),
+            _ => Err(ENOSYS),
+        }
+    }
+
+    fn open(
+        &mut self,
+        memory: &AddressSpace,
+        path: Address,
+        mode: u32,
+        length: u32,
+    ) -> Result<u32, i32> {
+        let name = read_name(memory, path, length)?;
+
+        let descriptor = if name == CONSOLE.as_bytes() {
+            match mode {
+                0..=3 => Descriptor::Stdin,
+                4..=7 => Descriptor::Stdout,
+                _ => Descriptor::Stderr,
+            }
+        } else {
+            let path = self.read_path(memory, path, length)?;
+
+            // The modes correspond to those of fopen: r, rb, r+, r+b, w, wb,
+            // w+, w+b, a, ab, a+ and a+b
+            let mut options = OpenOptions::new();
+            match mode >> 1 {
+                0 => options.read(true),
+                1 => options.read(true).write(true),
+                2 => options.write(true).create(true).truncate(true),
+                3 => options.read(true).write(true).create(true).truncate(true),
+                4 => options.append(true).create(true),
+                5 => options.read(true).append(true).create(true),
+                _ => return Err(EINVAL),
+            };
+            Descriptor::File(options.open(path).map_err(errno)?)
+        };
+
+        // Handles start at 1, as 0 isn't a valid handle
+        let index = match self.handles.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                self.handles.push(None);
+                self.handles.len() - 1
+            }
+        };
+        self.handles[index] = Some(descriptor);
+
+        Ok(index as u32 + 1)
+    }
+
+    fn close(&mut self, handle: u32) -> Result<u32, i32> {
+        self.handle(handle)?;
+        self.handles[handle as usize - 1] = None;
+        Ok(0)
+    }
+
+    /// Returns the number of bytes which were not written
+    fn write(
+        &mut self,
+        memory: &AddressSpace,
+        handle: u32,
+        buffer: Address,
+        length: u32,
+    ) -> Result<u32, i32> {
+        check_buffer(memory, buffer, length)?;
+        write_descriptor(memory, self.handle(handle)?, buffer, length)?;
+        Ok(0)
+    }
+
+    /// Returns the number of bytes which were not read
+    fn read(
+        &mut self,
+        memory: &mut AddressSpace,
+        handle: u32,
+        buffer: Address,
+        length: u32,
+    ) -> Result<u32, i32> {
+        check_buffer(memory, buffer, length)?;
+        let descriptor = self.handle(handle)?;
+
+        let mut data = vec![0u8; length.min(TRANSFER_CHUNK_SIZE) as usize];
+        let mut count = 0;
+        for (offset, chunk_length) in transfer_chunks(length) {
+            let chunk = &mut data[..chunk_length as usize];
+            let read = match descriptor {
+                Descriptor::Stdin => io::stdin().read(chunk),
+                Descriptor::File(file) => read_all(file, chunk),
+                _ => return Err(EBADF),
+            }
+            .map_err(errno)?;
+
+            write_memory(memory, buffer.wrapping_add(offset), &chunk[..read])?;
+            count += read as u32;
+            if read < chunk.len() {
+                break;
+            }
+        }
+        Ok(length - count)
+    }
+
+    fn handle(&mut self, handle: u32) -> Result<&mut Descriptor, i32> {
+        match self.handles.get_mut((handle as usize).wrapping_sub(1)) {
+            Some(Some(descriptor)) => Ok(descriptor),
+            _ => Err(EBADF),
+        }
+    }
+
+    fn read_path(&self, memory: &AddressSpace, path: Address, length: u32) -> Result<PathBuf, i32> {
+        let path = String::from_utf8(read_name(memory, path, length)?).map_err(|_| EINVAL)?;
+        resolve_path(&self.root, &path)
+    }
+}
+
+impl EnvironmentCallHandler for Semihosting {
+    fn environment_call(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut AddressSpace,
+    ) -> EnvironmentCallResult {
+        let operation = cpu.get_register(10);
+        let parameter = cpu.get_register(11);
+
+        let result = match operation {
+            // On RV32 the reason is passed directly instead of a block
+            SYS_EXIT if parameter == ADP_STOPPED_APPLICATION_EXIT => {
+                return EnvironmentCallResult::Exit(0)
+            }
+            SYS_EXIT => return EnvironmentCallResult::Exit(1),
+            SYS_EXIT_EXTENDED => match read_parameters(memory, parameter) {
+                Ok([ADP_STOPPED_APPLICATION_EXIT, status]) => {
+                    return EnvironmentCallResult::Exit(status as i32)
+                }
+                Ok(_) => return EnvironmentCallResult::Exit(1),
+                Err(errno) => Err(errno),
+            },
+            _ => self.call(memory, operation, parameter),
+        };
+
+        let value = match result {
+            Ok(value) => value,
+            Err(errno) => {
+                self.errno = errno;
+                FAILURE
+            }
+        };
+        cpu.set_register(10, value);
+
+        EnvironmentCallResult::Return
+    }
+}
+
+/// Reads the words of a parameter block
+fn read_parameters<const N: usize>(memory: &AddressSpace, block: Address) -> Result<[u32; N], i32> {
+    check_buffer(memory, block, 4 * N as u32)?;
+
+    let mut parameters = [0; N];
+    for (index, parameter) in parameters.iter_mut().enumerate() {
+        *parameter = memory.read_word(block.wrapping_add(4 * index as Address));
+    }
+    Ok(parameters)
+}
+
+/// Reads a file name, which is passed with its length
+fn read_name(memory: &AddressSpace, address: Address, length: u32) -> Result<Vec<u8>, i32> {
+    if length > MAX_PATH_LENGTH {
+        return Err(ENAMETOOLONG);
+    }
+
+    let mut name = vec![0u8; length as usize];
+    read_memory(memory, address, &mut name)?;
+    Ok(name)
+}
+
+/// Writes a NUL terminated string of any length to `output`, a chunk at a
+/// time
+fn write_string(
+    memory: &AddressSpace,
+    address: Address,
+    output: &mut impl Write,
+) -> Result<u32, i32> {
+    let mut chunk = Vec::new();
+
+    for offset in 0..u32::MAX {
+        let address = address.wrapping_add(offset);
+        check_buffer(memory, address, 1)?;
+        match memory.read_byte(address) {
+            0 => break,
+            byte => chunk.push(byte),
+        }
+
+        if chunk.len() == TRANSFER_CHUNK_SIZE as usize {
+            write_and_flush(output, &chunk).map_err(errno)?;
+            chunk.clear();
+        }
+    }
+
+    write_and_flush(output, &chunk).map_err(errno)?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::RAM_SIZE;
+    use crate::newlib::{read_string, EFAULT, ENOENT};
+
+    const RETURN: EnvironmentCallResult = EnvironmentCallResult::Return;
+
+    /// Runs an operation with the given parameter block and returns the
+    /// result of the call and a0
+    fn call(
+        semihosting: &mut Semihosting,
+        memory: &mut AddressSpace,
+        operation: u32,
+        parameters: &[u32],
+    ) -> (EnvironmentCallResult, u32) {
+        const BLOCK: Address = 0x1000;
+        for (index, &parameter) in parameters.iter().enumerate() {
+            memory.write_word(BLOCK + 4 * index as Address, parameter);
+        }
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(10, operation);
+        cpu.set_register(11, BLOCK);
+        let result = semihosting.environment_call(&mut cpu, memory);
+        (result, cpu.get_register(10))
+    }
+
+    fn write_bytes(memory: &mut AddressSpace, address: Address, data: &[u8]) {
+        for (index, &byte) in data.iter().enumerate() {
+            memory.write_byte(address + index as Address, byte);
+        }
+    }
+
+    #[test]
+    fn test_files() {
+        let root = std::env::temp_dir().join(format!("semihosting-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let mut memory = AddressSpace::new();
+        let mut semihosting = Semihosting::new(&root, "");
+        write_bytes(&mut memory, 0x100, b"out.bin");
+        write_bytes(&mut memory, 0x200, b"abcdef");
+
+        // wb
+        let result = call(&mut semihosting, &mut memory, SYS_OPEN, &[0x100, 5, 7]);
+        assert_eq!(result, (RETURN, 1));
+        let result = call(&mut semihosting, &mut memory, SYS_WRITE, &[1, 0x200, 6]);
+        assert_eq!(result, (RETURN, 0));
+        let result = call(&mut semihosting, &mut memory, SYS_ISTTY, &[1]);
+        assert_eq!(result, (RETURN, 0));
+        let result = call(&mut semihosting, &mut memory, SYS_CLOSE, &[1]);
+        assert_eq!(result, (RETURN, 0));
+        assert_eq!(fs::read(root.join("out.bin")).unwrap(), b"abcdef");
+
+        // rb
+        let result = call(&mut semihosting, &mut memory, SYS_OPEN, &[0x100, 1, 7]);
+        assert_eq!(result, (RETURN, 1));
+        let result = call(&mut semihosting, &mut memory, SYS_FLEN, &[1]);
+        assert_eq!(result, (RETURN, 6));
+        let result = call(&mut semihosting, &mut memory, SYS_SEEK, &[1, 2]);
+        assert_eq!(result, (RETURN, 0));
+        let result = call(&mut semihosting, &mut memory, SYS_READ, &[1, 0x300, 8]);
+        assert_eq!(result, (RETURN, 4));
+        assert_eq!(memory.read_word(0x300), u32::from_le_bytes(*b"cdef"));
+
+        let result = call(&mut semihosting, &mut memory, SYS_CLOSE, &[1]);
+        assert_eq!(result, (RETURN, 0));
+        let result = call(&mut semihosting, &mut memory, SYS_CLOSE, &[1]);
+        assert_eq!(result, (RETURN, FAILURE));
+        let result = call(&mut semihosting, &mut memory, SYS_ERRNO, &[]);
+        assert_eq!(result, (RETURN, EBADF as u32));
+
+        let result = call(&mut semihosting, &mut memory, SYS_REMOVE, &[0x100, 7]);
+        assert_eq!(result, (RETURN, 0));
+        assert!(!root.join("out.bin").exists());
+        let result = call(&mut semihosting, &mut memory, SYS_REMOVE, &[0x100, 7]);
+        assert_eq!(result, (RETURN, FAILURE));
+        let result = call(&mut semihosting, &mut memory, SYS_ERRNO, &[]);
+        assert_eq!(result, (RETURN, ENOENT as u32));
+
+        // Buffers are copied a chunk at a time, and rejected as a whole if
+        // they aren't mapped
+        let length = 2 * TRANSFER_CHUNK_SIZE + 3;
+        for offset in 0..length {
+            memory.write_byte(0x10_0000 + offset, offset as u8);
+        }
+        // w+b
+        let result = call(&mut semihosting, &mut memory, SYS_OPEN, &[0x100, 7, 7]);
+        assert_eq!(result, (RETURN, 1));
+        let result = call(
+            &mut semihosting,
+            &mut memory,
+            SYS_WRITE,
+            &[1, 0x10_0000, length],
+        );
+        assert_eq!(result, (RETURN, 0));
+        let result = call(&mut semihosting, &mut memory, SYS_SEEK, &[1, 0]);
+        assert_eq!(result, (RETURN, 0));
+        let result = call(
+            &mut semihosting,
+            &mut memory,
+            SYS_READ,
+            &[1, 0x20_0000, length + 5],
+        );
+        assert_eq!(result, (RETURN, 5));
+        assert_eq!(memory.read_byte(0x20_0000 + length - 1), (length - 1) as u8);
+        let result = call(
+            &mut semihosting,
+            &mut memory,
+            SYS_WRITE,
+            &[1, 0, 0x2000_0001],
+        );
+        assert_eq!(result, (RETURN, FAILURE));
+        let result = call(
+            &mut semihosting,
+            &mut memory,
+            SYS_READ,
+            &[1, 0x100, u32::MAX],
+        );
+        assert_eq!(result, (RETURN, FAILURE));
+        let result = call(&mut semihosting, &mut memory, SYS_CLOSE, &[1]);
+        assert_eq!(result, (RETURN, 0));
+
+        let result = call(
+            &mut semihosting,
+            &mut memory,
+            SYS_OPEN,
+            &[0x100, 0, u32::MAX],
+        );
+        assert_eq!(result, (RETURN, FAILURE));
+
+        write_bytes(&mut memory, 0x100, b"../x");
+        let result = call(&mut semihosting, &mut memory, SYS_OPEN, &[0x100, 0, 4]);
+        assert_eq!(result, (RETURN, FAILURE));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_console() {
+        let mut memory = AddressSpace::new();
+        let mut semihosting = Semihosting::new(".", "");
+        write_bytes(&mut memory, 0x100, b":tt");
+
+        let result = call(&mut semihosting, &mut memory, SYS_OPEN, &[0x100, 0, 3]);
+        assert_eq!(result, (RETURN, 1));
+        let result = call(&mut semihosting, &mut memory, SYS_OPEN, &[0x100, 4, 3]);
+        assert_eq!(result, (RETURN, 2));
+        let result = call(&mut semihosting, &mut memory, SYS_ISTTY, &[2]);
+        assert_eq!(result, (RETURN, 1));
+        let result = call(&mut semihosting, &mut memory, SYS_WRITE, &[1, 0x100, 3]);
+        assert_eq!(result, (RETURN, FAILURE));
+
+        // Strings aren't limited to the length of paths
+        let length = TRANSFER_CHUNK_SIZE + MAX_PATH_LENGTH;
+        for offset in 0..length {
+            memory.write_byte(0x1_0000 + offset, b'a');
+        }
+        let mut output = Vec::new();
+        assert_eq!(write_string(&memory, 0x1_0000, &mut output), Ok(0));
+        assert_eq!(output.len(), length as usize);
+        assert!(output.iter().all(|&byte| byte == b'a'));
+
+        // A string without NUL fails at the end of memory
+        let end = RAM_SIZE as Address;
+        memory.write_byte(end - 1, b'a');
+        let mut output = Vec::new();
+        assert_eq!(write_string(&memory, end - 1, &mut output), Err(EFAULT));
+    }
+
+    #[test]
+    fn test_command_line_and_heap_info() {
+        let mut memory = AddressSpace::new();
+        let mut semihosting = Semihosting::new(".", "program --verbose");
+        semihosting.set_heap_info([0x10_0000, 0x20_0000, 0x800_0000, 0x700_0000]);
+
+        let result = call(&mut semihosting, &mut memory, SYS_GET_CMDLINE, &[0x100, 64]);
+        assert_eq!(result, (RETURN, 0));
+        assert_eq!(memory.read_word(0x1004), 17);
+        assert_eq!(read_string(&memory, 0x100), Ok("program --verbose".into()));
+
+        let result = call(&mut semihosting, &mut memory, SYS_GET_CMDLINE, &[0x100, 17]);
+        assert_eq!(result, (RETURN, FAILURE));
+
+        let result = call(&mut semihosting, &mut memory, SYS_HEAPINFO, &[0x200]);
+        assert_eq!(result, (RETURN, 0));
+        assert_eq!(memory.read_word(0x200), 0x10_0000);
+        assert_eq!(memory.read_word(0x20C), 0x700_0000);
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut memory = AddressSpace::new();
+        let mut semihosting = Semihosting::new(".", "");
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(10, SYS_EXIT);
+        cpu.set_register(11, ADP_STOPPED_APPLICATION_EXIT);
+        assert_eq!(
+            semihosting.environment_call(&mut cpu, &mut memory),
+            EnvironmentCallResult::Exit(0)
+        );
+        cpu.set_register(11, 0x20023); // ADP_Stopped_RunTimeErrorUnknown
+        assert_eq!(
+            semihosting.environment_call(&mut cpu, &mut memory),
+            EnvironmentCallResult::Exit(1)
+        );
+
+        let result = call(
+            &mut semihosting,
+            &mut memory,
+            SYS_EXIT_EXTENDED,
+            &[ADP_STOPPED_APPLICATION_EXIT, 42],
+        );
+        assert_eq!(result.0, EnvironmentCallResult::Exit(42));
+
+        // Unsupported operations fail and the program continues
+        let result = call(&mut semihosting, &mut memory, 0x12, &[]); // SYS_SYSTEM
+        assert_eq!(result, (RETURN, FAILURE));
+        let result = call(&mut semihosting, &mut memory, SYS_ERRNO, &[]);
+        assert_eq!(result, (RETURN, ENOSYS as u32));
+    }
+}
@@ -0,0 +1,1015 @@
+//! IEEE 754 binary32 and binary64 arithmetic in software, so that results
+//! and exception flags don't depend on the rounding mode of the host.
+//!
+//! Values are passed around as raw bits in the low bits of a `u64`. Every
+//! finite value is unpacked into an integer significand and an exponent,
+//! calculated exactly (or with a sticky bit jammed into the least significant
+//! bit) and rounded once by `round_pack`.
+
+use core::cmp::Ordering;
+
+pub const FLAG_INEXACT: u32 = 1 << 0;
+pub const FLAG_UNDERFLOW: u32 = 1 << 1;
+pub const FLAG_OVERFLOW: u32 = 1 << 2;
+pub const FLAG_DIVIDE_BY_ZERO: u32 = 1 << 3;
+pub const FLAG_INVALID: u32 = 1 << 4;
+
+// Finite significands are normalized to this bit before adding, which leaves
+// enough room for the carry and plenty of guard bits below.
+const NORMALIZED_MSB: u32 = 125;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum RoundingMode {
+    NearestEven,
+    TowardZero,
+    Down,
+    Up,
+    NearestMaxMagnitude,
+}
+
+impl RoundingMode {
+    /// Decodes the rm field of an instruction or the frm CSR
+    pub fn from_bits(bits: u32) -> Option<RoundingMode> {
+        match bits {
+            0b000 => Some(RoundingMode::NearestEven),
+            0b001 => Some(RoundingMode::TowardZero),
+            0b010 => Some(RoundingMode::Down),
+            0b011 => Some(RoundingMode::Up),
+            0b100 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Format {
+    exponent_bits: u32,
+    fraction_bits: u32,
+}
+
+pub const SINGLE: Format = Format {
+    exponent_bits: 8,
+    fraction_bits: 23,
+};
+
+pub const DOUBLE: Format = Format {
+    exponent_bits: 11,
+    fraction_bits: 52,
+};
+
+/// A finite value `significand * 2^exponent`
+#[derive(Clone, Copy)]
+struct Unpacked {
+    sign: bool,
+    exponent: i32,
+    significand: u128,
+}
+
+impl Format {
+    fn bias(self) -> i32 {
+        (1 << (self.exponent_bits - 1)) - 1
+    }
+
+    fn min_exponent(self) -> i32 {
+        1 - self.bias()
+    }
+
+    fn max_biased_exponent(self) -> u64 {
+        (1 << self.exponent_bits) - 1
+    }
+
+    fn sign_bit(self) -> u64 {
+        1 << (self.exponent_bits + self.fraction_bits)
+    }
+
+    fn fraction_mask(self) -> u64 {
+        (1 << self.fraction_bits) - 1
+    }
+
+    fn quiet_bit(self) -> u64 {
+        1 << (self.fraction_bits - 1)
+    }
+
+    fn biased_exponent(self, bits: u64) -> u64 {
+        (bits >> self.fraction_bits) & self.max_biased_exponent()
+    }
+
+    fn sign(self, bits: u64) -> bool {
+        bits & self.sign_bit() != 0
+    }
+
+    fn with_sign(self, sign: bool) -> u64 {
+        if sign {
+            self.sign_bit()
+        } else {
+            0
+        }
+    }
+
+    pub fn canonical_nan(self) -> u64 {
+        self.max_biased_exponent() << self.fraction_bits | self.quiet_bit()
+    }
+
+    fn infinity(self, sign: bool) -> u64 {
+        self.with_sign(sign) | self.max_biased_exponent() << self.fraction_bits
+    }
+
+    fn max_finite(self, sign: bool) -> u64 {
+        self.infinity(sign) - 1
+    }
+
+    fn is_nan(self, bits: u64) -> bool {
+        self.biased_exponent(bits) == self.max_biased_exponent() && bits & self.fraction_mask() != 0
+    }
+
+    fn is_signaling_nan(self, bits: u64) -> bool {
+        self.is_nan(bits) && bits & self.quiet_bit() == 0
+    }
+
+    fn is_infinite(self, bits: u64) -> bool {
+        self.biased_exponent(bits) == self.max_biased_exponent() && bits & self.fraction_mask() == 0
+    }
+
+    fn is_zero(self, bits: u64) -> bool {
+        bits & !self.sign_bit() == 0
+    }
+
+    fn unpack(self, bits: u64) -> Unpacked {
+        let biased_exponent = self.biased_exponent(bits) as i32;
+        let fraction = u128::from(bits & self.fraction_mask());
+        let fraction_bits = self.fraction_bits as i32;
+
+        if biased_exponent == 0 {
+            Unpacked {
+                sign: self.sign(bits),
+                exponent: self.min_exponent() - fraction_bits,
+                significand: fraction,
+            }
+        } else {
+            Unpacked {
+                sign: self.sign(bits),
+                exponent: biased_exponent - self.bias() - fraction_bits,
+                significand: fraction | 1 << self.fraction_bits,
+            }
+        }
+    }
+
+    /// Rounds an exact value to this format. A sticky bit may be jammed into
+    /// the least significant bit of the significand, as long as it is at
+    /// least two bits below the rounding position. Tininess is detected
+    /// after rounding, as required by RISC-V.
+    fn round_pack(
+        self,
+        sign: bool,
+        exponent: i32,
+        significand: u128,
+        rounding_mode: RoundingMode,
+        flags: &mut u32,
+    ) -> u64 {
+        if significand == 0 {
+            return self.with_sign(sign);
+        }
+
+        let fraction_bits = self.fraction_bits as i32;
+        let msb = 127 - significand.leading_zeros() as i32;
+        let value_exponent = exponent + msb;
+
+        let mut quantum = value_exponent.max(self.min_exponent()) - fraction_bits;
+        let (mut rounded, inexact) =
+            round_significand(significand, quantum - exponent, sign, rounding_mode);
+
+        if rounded >> (self.fraction_bits + 1) != 0 {
+            rounded >>= 1;
+            quantum += 1;
+        }
+
+        if inexact {
+            *flags |= FLAG_INEXACT;
+
+            if value_exponent < self.min_exponent() {
+                // Rounding with an unbounded exponent range may still carry
+                // the result up to the smallest normal number
+                let (unbounded, _) = round_significand(
+                    significand,
+                    value_exponent - fraction_bits - exponent,
+                    sign,
+                    rounding_mode,
+                );
+                if value_exponent < self.min_exponent() - 1
+                    || unbounded >> (self.fraction_bits + 1) == 0
+                {
+                    *flags |= FLAG_UNDERFLOW;
+                }
+            }
+        }
+
+        let rounded = rounded as u64;
+        if rounded < 1 << self.fraction_bits {
+            return self.with_sign(sign) | rounded;
+        }
+
+        let biased_exponent = (quantum + fraction_bits + self.bias()) as u64;
+        if biased_exponent >= self.max_biased_exponent() {
+            *flags |= FLAG_OVERFLOW | FLAG_INEXACT;
+
+            let to_infinity = match rounding_mode {
+                RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => true,
+                RoundingMode::TowardZero => false,
+                RoundingMode::Down => sign,
+                RoundingMode::Up => !sign,
+            };
+            return if to_infinity {
+                self.infinity(sign)
+            } else {
+                self.max_finite(sign)
+            };
+        }
+
+        self.with_sign(sign)
+            | biased_exponent << self.fraction_bits
+            | (rounded & self.fraction_mask())
+    }
+
+    /// Handles NaN operands, returning the canonical NaN if there are any
+    fn propagate_nan(self, operands: &[u64], flags: &mut u32) -> Option<u64> {
+        if operands.iter().any(|&bits| self.is_signaling_nan(bits)) {
+            *flags |= FLAG_INVALID;
+        }
+
+        if operands.iter().any(|&bits| self.is_nan(bits)) {
+            Some(self.canonical_nan())
+        } else {
+            None
+        }
+    }
+
+    fn invalid(self, flags: &mut u32) -> u64 {
+        *flags |= FLAG_INVALID;
+        self.canonical_nan()
+    }
+
+    /// Adds two finite values, either of which may be zero
+    fn add_unpacked(
+        self,
+        a: Unpacked,
+        b: Unpacked,
+        rounding_mode: RoundingMode,
+        flags: &mut u32,
+    ) -> u64 {
+        if a.significand == 0 && b.significand == 0 {
+            let sign = if rounding_mode == RoundingMode::Down {
+                a.sign || b.sign
+            } else {
+                a.sign && b.sign
+            };
+            return self.with_sign(sign);
+        } else if b.significand == 0 {
+            return self.round_pack(a.sign, a.exponent, a.significand, rounding_mode, flags);
+        } else if a.significand == 0 {
+            return self.round_pack(b.sign, b.exponent, b.significand, rounding_mode, flags);
+        }
+
+        let (larger, smaller) = {
+            let a = normalize(a);
+            let b = normalize(b);
+            if a.exponent >= b.exponent {
+                (a, b)
+            } else {
+                (b, a)
+            }
+        };
+
+        let shift = (larger.exponent - smaller.exponent) as u32;
+        let smaller_significand = shift_right_jam(smaller.significand, shift);
+
+        if larger.sign == smaller.sign {
+            let significand = larger.significand + smaller_significand;
+            return self.round_pack(
+                larger.sign,
+                larger.exponent,
+                significand,
+                rounding_mode,
+                flags,
+            );
+        }
+
+        let (sign, significand) = match larger.significand.cmp(&smaller_significand) {
+            Ordering::Greater => (larger.sign, larger.significand - smaller_significand),
+            Ordering::Less => (smaller.sign, smaller_significand - larger.significand),
+            Ordering::Equal => return self.with_sign(rounding_mode == RoundingMode::Down),
+        };
+
+        self.round_pack(sign, larger.exponent, significand, rounding_mode, flags)
+    }
+
+    pub fn add(self, a: u64, b: u64, rounding_mode: RoundingMode, flags: &mut u32) -> u64 {
+        if let Some(nan) = self.propagate_nan(&[a, b], flags) {
+            return nan;
+        }
+
+        match (self.is_infinite(a), self.is_infinite(b)) {
+            (true, true) if self.sign(a) != self.sign(b) => self.invalid(flags),
+            (true, _) => a,
+            (_, true) => b,
+            _ => self.add_unpacked(self.unpack(a), self.unpack(b), rounding_mode, flags),
+        }
+    }
+
+    pub fn sub(self, a: u64, b: u64, rounding_mode: RoundingMode, flags: &mut u32) -> u64 {
+        if let Some(nan) = self.propagate_nan(&[a, b], flags) {
+            return nan;
+        }
+        self.add(a, b ^ self.sign_bit(), rounding_mode, flags)
+    }
+
+    pub fn mul(self, a: u64, b: u64, rounding_mode: RoundingMode, flags: &mut u32) -> u64 {
+        if let Some(nan) = self.propagate_nan(&[a, b], flags) {
+            return nan;
+        }
+
+        let sign = self.sign(a) != self.sign(b);
+
+        if self.is_infinite(a) || self.is_infinite(b) {
+            if self.is_zero(a) || self.is_zero(b) {
+                return self.invalid(flags);
+            }
+            return self.infinity(sign);
+        }
+
+        let a = self.unpack(a);
+        let b = self.unpack(b);
+
+        self.round_pack(
+            sign,
+            a.exponent + b.exponent,
+            a.significand * b.significand,
+            rounding_mode,
+            flags,
+        )
+    }
+
+    /// Calculates `a * b + c` with a single rounding. The product and the
+    /// addend can be negated to implement FMSUB, FNMSUB and FNMADD.
+    #[allow(clippy::too_many_arguments)]
+    pub fn fused_multiply_add(
+        self,
+        a: u64,
+        b: u64,
+        c: u64,
+        negate_product: bool,
+        negate_addend: bool,
+        rounding_mode: RoundingMode,
+        flags: &mut u32,
+    ) -> u64 {
+        let infinity_times_zero =
+            (self.is_infinite(a) && self.is_zero(b)) || (self.is_zero(a) && self.is_infinite(b));
+
+        // Invalid even if the addend is a quiet NaN
+        if infinity_times_zero {
+            *flags |= FLAG_INVALID;
+        }
+
+        if let Some(nan) = self.propagate_nan(&[a, b, c], flags) {
+            return nan;
+        } else if infinity_times_zero {
+            return self.canonical_nan();
+        }
+
+        let product_sign = (self.sign(a) != self.sign(b)) != negate_product;
+        let addend_sign = self.sign(c) != negate_addend;
+
+        let product_infinite = self.is_infinite(a) || self.is_infinite(b);
+        match (product_infinite, self.is_infinite(c)) {
+            (true, true) if product_sign != addend_sign => return self.invalid(flags),
+            (true, _) => return self.infinity(product_sign),
+            (_, true) => return self.infinity(addend_sign),
+            _ => {}
+        }
+
+        let a = self.unpack(a);
+        let b = self.unpack(b);
+        let product = Unpacked {
+            sign: product_sign,
+            exponent: a.exponent + b.exponent,
+            significand: a.significand * b.significand,
+        };
+
+        let mut addend = self.unpack(c);
+        addend.sign = addend_sign;
+
+        self.add_unpacked(product, addend, rounding_mode, flags)
+    }
+
+    pub fn div(self, a: u64, b: u64, rounding_mode: RoundingMode, flags: &mut u32) -> u64 {
+        if let Some(nan) = self.propagate_nan(&[a, b], flags) {
+            return nan;
+        }
+
+        let sign = self.sign(a) != self.sign(b);
+
+        match (self.is_infinite(a), self.is_infinite(b)) {
+            (true, true) => return self.invalid(flags),
+            (true, false) => return self.infinity(sign),
+            (false, true) => return self.with_sign(sign),
+            _ => {}
+        }
+
+        match (self.is_zero(a), self.is_zero(b)) {
+            (true, true) => return self.invalid(flags),
+            (false, true) => {
+                *flags |= FLAG_DIVIDE_BY_ZERO;
+                return self.infinity(sign);
+            }
+            (true, false) => return self.with_sign(sign),
+            _ => {}
+        }
+
+        let a = normalize(self.unpack(a));
+        let b = self.unpack(b);
+
+        let quotient = a.significand / b.significand;
+        let sticky = a.significand % b.significand != 0;
+
+        self.round_pack(
+            sign,
+            a.exponent - b.exponent,
+            quotient | sticky as u128,
+            rounding_mode,
+            flags,
+        )
+    }
+
+    pub fn sqrt(self, a: u64, rounding_mode: RoundingMode, flags: &mut u32) -> u64 {
+        if let Some(nan) = self.propagate_nan(&[a], flags) {
+            return nan;
+        } else if self.is_zero(a) {
+            return a;
+        } else if self.sign(a) {
+            return self.invalid(flags);
+        } else if self.is_infinite(a) {
+            return a;
+        }
+
+        let mut a = self.unpack(a);
+
+        // The exponent has to be even to be halved
+        let mut shift = NORMALIZED_MSB - (127 - a.significand.leading_zeros());
+        if (a.exponent - shift as i32) % 2 != 0 {
+            shift -= 1;
+        }
+        a.significand <<= shift;
+        a.exponent -= shift as i32;
+
+        let (root, remainder) = integer_sqrt(a.significand);
+
+        self.round_pack(
+            false,
+            a.exponent / 2,
+            root | (remainder != 0) as u128,
+            rounding_mode,
+            flags,
+        )
+    }
+
+    /// Implements FMIN and FMAX, where -0 is considered smaller than +0 and a
+    /// single NaN operand is ignored.
+    pub fn min_max(self, a: u64, b: u64, max: bool, flags: &mut u32) -> u64 {
+        if self.is_signaling_nan(a) || self.is_signaling_nan(b) {
+            *flags |= FLAG_INVALID;
+        }
+
+        match (self.is_nan(a), self.is_nan(b)) {
+            (true, true) => return self.canonical_nan(),
+            (true, false) => return b,
+            (false, true) => return a,
+            _ => {}
+        }
+
+        let a_smaller = match self.compare(a, b) {
+            Ordering::Less => true,
+            Ordering::Greater => false,
+            Ordering::Equal => self.sign(a),
+        };
+
+        if a_smaller != max {
+            a
+        } else {
+            b
+        }
+    }
+
+    /// Compares two numbers which aren't NaN. Both zeros are equal.
+    fn compare(self, a: u64, b: u64) -> Ordering {
+        if self.is_zero(a) && self.is_zero(b) {
+            return Ordering::Equal;
+        }
+
+        match (self.sign(a), self.sign(b)) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => a.cmp(&b),
+            (true, true) => b.cmp(&a),
+        }
+    }
+
+    /// FEQ is a quiet comparison, which only signals for signaling NaNs
+    pub fn eq(self, a: u64, b: u64, flags: &mut u32) -> bool {
+        if self.propagate_nan(&[a, b], flags).is_some() {
+            return false;
+        }
+        self.compare(a, b) == Ordering::Equal
+    }
+
+    /// FLT is a signaling comparison, which signals for any NaN
+    pub fn lt(self, a: u64, b: u64, flags: &mut u32) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            *flags |= FLAG_INVALID;
+            return false;
+        }
+        self.compare(a, b) == Ordering::Less
+    }
+
+    /// FLE is a signaling comparison, which signals for any NaN
+    pub fn le(self, a: u64, b: u64, flags: &mut u32) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            *flags |= FLAG_INVALID;
+            return false;
+        }
+        self.compare(a, b) != Ordering::Greater
+    }
+
+    /// The FCLASS mask with exactly one of the bits 0 to 9 set
+    pub fn classify(self, a: u64) -> u32 {
+        let sign = self.sign(a);
+        let subnormal = self.biased_exponent(a) == 0;
+
+        let bit = if self.is_infinite(a) {
+            if sign {
+                0
+            } else {
+                7
+            }
+        } else if self.is_zero(a) {
+            if sign {
+                3
+            } else {
+                4
+            }
+        } else if self.is_signaling_nan(a) {
+            8
+        } else if self.is_nan(a) {
+            9
+        } else if subnormal {
+            if sign {
+                2
+            } else {
+                5
+            }
+        } else if sign {
+            1
+        } else {
+            6
+        };
+
+        1 << bit
+    }
+
+    /// Converts to a signed or unsigned 32 bit integer. Out of range values
+    /// and NaNs saturate and raise the invalid flag.
+    pub fn to_integer(
+        self,
+        a: u64,
+        signed: bool,
+        rounding_mode: RoundingMode,
+        flags: &mut u32,
+    ) -> u32 {
+        let sign = self.sign(a);
+        let (min, max) = if signed {
+            (i32::MIN as u32, i32::MAX as u32)
+        } else {
+            (0, u32::MAX)
+        };
+
+        if self.is_nan(a) {
+            *flags |= FLAG_INVALID;
+            return max;
+        } else if self.is_infinite(a) {
+            *flags |= FLAG_INVALID;
+            return if sign { min } else { max };
+        }
+
+        let a = self.unpack(a);
+        let (magnitude, inexact) = if a.exponent >= 0 {
+            // Anything shifted by more than 32 bits is out of range anyway
+            (a.significand << a.exponent.min(40), false)
+        } else {
+            round_significand(a.significand, -a.exponent, sign, rounding_mode)
+        };
+
+        let in_range = match (signed, sign) {
+            (true, true) => magnitude <= 1 << 31,
+            (true, false) => magnitude < 1 << 31,
+            (false, true) => magnitude == 0,
+            (false, false) => magnitude <= u128::from(u32::MAX),
+        };
+
+        if !in_range {
+            *flags |= FLAG_INVALID;
+            return if sign { min } else { max };
+        }
+
+        if inexact {
+            *flags |= FLAG_INEXACT;
+        }
+
+        if sign {
+            (magnitude as u32).wrapping_neg()
+        } else {
+            magnitude as u32
+        }
+    }
+
+    /// Converts a signed or unsigned 32 bit integer
+    pub fn from_integer(
+        self,
+        value: u32,
+        signed: bool,
+        rounding_mode: RoundingMode,
+        flags: &mut u32,
+    ) -> u64 {
+        let sign = signed && (value as i32) < 0;
+        let magnitude = if sign { value.wrapping_neg() } else { value };
+
+        self.round_pack(sign, 0, u128::from(magnitude), rounding_mode, flags)
+    }
+
+    /// Converts a value of the format `from` into this format
+    pub fn convert(
+        self,
+        from: Format,
+        a: u64,
+        rounding_mode: RoundingMode,
+        flags: &mut u32,
+    ) -> u64 {
+        if from.propagate_nan(&[a], flags).is_some() {
+            return self.canonical_nan();
+        } else if from.is_infinite(a) {
+            return self.infinity(from.sign(a));
+        }
+
+        let a = from.unpack(a);
+        self.round_pack(a.sign, a.exponent, a.significand, rounding_mode, flags)
+    }
+}
+
+fn normalize(value: Unpacked) -> Unpacked {
+    let shift = NORMALIZED_MSB - (127 - value.significand.leading_zeros());
+    Unpacked {
+        sign: value.sign,
+        exponent: value.exponent - shift as i32,
+        significand: value.significand << shift,
+    }
+}
+
+/// Shifts right, jamming any bits shifted out into the least significant bit
+fn shift_right_jam(value: u128, shift: u32) -> u128 {
+    if shift == 0 {
+        value
+    } else if shift >= 128 {
+        (value != 0) as u128
+    } else {
+        let sticky = value & ((1 << shift) - 1) != 0;
+        value >> shift | sticky as u128
+    }
+}
+
+/// Divides the significand by 2^shift and rounds the result to an integer.
+/// Returns the rounded value and whether it is inexact.
+fn round_significand(
+    significand: u128,
+    shift: i32,
+    sign: bool,
+    rounding_mode: RoundingMode,
+) -> (u128, bool) {
+    if shift <= 0 {
+        return (significand << -shift, false);
+    }
+
+    // Only a sticky bit remains, which is below half of the last place
+    let (significand, shift) = if shift >= 128 {
+        ((significand != 0) as u128, 2)
+    } else {
+        (significand, shift as u32)
+    };
+
+    let kept = significand >> shift;
+    let remainder = significand & ((1 << shift) - 1);
+    let half = 1 << (shift - 1);
+
+    let increment = match rounding_mode {
+        RoundingMode::NearestEven => remainder > half || (remainder == half && kept & 1 != 0),
+        RoundingMode::NearestMaxMagnitude => remainder >= half,
+        RoundingMode::TowardZero => false,
+        RoundingMode::Down => sign && remainder != 0,
+        RoundingMode::Up => !sign && remainder != 0,
+    };
+
+    (kept + increment as u128, remainder != 0)
+}
+
+/// Calculates the integer square root and the remainder
+fn integer_sqrt(value: u128) -> (u128, u128) {
+    let mut remainder = value;
+    let mut root = 0u128;
+    let mut bit = 1u128 << 126;
+
+    while bit > value {
+        bit >>= 2;
+    }
+
+    while bit != 0 {
+        if remainder >= root + bit {
+            remainder -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+
+    (root, remainder)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RNE: RoundingMode = RoundingMode::NearestEven;
+
+    fn single(value: f32) -> u64 {
+        u64::from(value.to_bits())
+    }
+
+    fn double(value: f64) -> u64 {
+        value.to_bits()
+    }
+
+    #[test]
+    fn test_add() {
+        let mut flags = 0;
+        assert_eq!(
+            SINGLE.add(single(1.5), single(2.25), RNE, &mut flags),
+            single(3.75)
+        );
+        assert_eq!(flags, 0);
+
+        assert_eq!(
+            DOUBLE.add(double(0.1), double(0.2), RNE, &mut flags),
+            double(0.1 + 0.2)
+        );
+        assert_eq!(flags, FLAG_INEXACT);
+
+        let mut flags = 0;
+        assert_eq!(
+            SINGLE.sub(single(1.0), single(1.0), RNE, &mut flags),
+            single(0.0)
+        );
+        assert_eq!(
+            SINGLE.sub(single(1.0), single(1.0), RoundingMode::Down, &mut flags),
+            single(-0.0)
+        );
+        assert_eq!(
+            DOUBLE.add(double(1.0), double(-1e-300), RNE, &mut flags),
+            double(1.0)
+        );
+        assert_eq!(flags, FLAG_INEXACT);
+    }
+
+    #[test]
+    fn test_rounding_modes() {
+        // 1 + 2^-24 is exactly halfway between 1 and the next single
+        let a = single(1.0);
+        let b = single(f32::EPSILON / 2.0);
+        let mut flags = 0;
+
+        assert_eq!(SINGLE.add(a, b, RNE, &mut flags), single(1.0));
+        assert_eq!(
+            SINGLE.add(a, b, RoundingMode::NearestMaxMagnitude, &mut flags),
+            single(1.0 + f32::EPSILON)
+        );
+        assert_eq!(
+            SINGLE.add(a, b, RoundingMode::Up, &mut flags),
+            single(1.0 + f32::EPSILON)
+        );
+        assert_eq!(
+            SINGLE.add(a, b, RoundingMode::TowardZero, &mut flags),
+            single(1.0)
+        );
+        assert_eq!(
+            SINGLE.sub(single(-1.0), b, RoundingMode::Down, &mut flags),
+            single(-1.0 - f32::EPSILON)
+        );
+    }
+
+    #[test]
+    fn test_mul_and_div() {
+        let mut flags = 0;
+        assert_eq!(
+            DOUBLE.mul(double(3.0), double(-0.5), RNE, &mut flags),
+            double(-1.5)
+        );
+        assert_eq!(
+            DOUBLE.div(double(1.0), double(3.0), RNE, &mut flags),
+            double(1.0 / 3.0)
+        );
+        assert_eq!(flags, FLAG_INEXACT);
+
+        let mut flags = 0;
+        assert_eq!(
+            SINGLE.div(single(1.0), single(0.0), RNE, &mut flags),
+            single(f32::INFINITY)
+        );
+        assert_eq!(flags, FLAG_DIVIDE_BY_ZERO);
+
+        let mut flags = 0;
+        assert_eq!(
+            SINGLE.div(single(0.0), single(0.0), RNE, &mut flags),
+            SINGLE.canonical_nan()
+        );
+        assert_eq!(flags, FLAG_INVALID);
+    }
+
+    #[test]
+    fn test_overflow_and_underflow() {
+        let mut flags = 0;
+        assert_eq!(
+            SINGLE.mul(single(f32::MAX), single(2.0), RNE, &mut flags),
+            single(f32::INFINITY)
+        );
+        assert_eq!(flags, FLAG_OVERFLOW | FLAG_INEXACT);
+
+        assert_eq!(
+            SINGLE.mul(
+                single(f32::MAX),
+                single(2.0),
+                RoundingMode::TowardZero,
+                &mut flags
+            ),
+            single(f32::MAX)
+        );
+
+        let mut flags = 0;
+        let tiny = SINGLE.mul(single(1e-30), single(1e-10), RNE, &mut flags);
+        assert_eq!(tiny, single(1e-30 * 1e-10));
+        assert_eq!(flags, FLAG_UNDERFLOW | FLAG_INEXACT);
+
+        // Exact subnormal results don't underflow
+        let mut flags = 0;
+        let min_subnormal = f32::from_bits(1);
+        assert_eq!(
+            SINGLE.div(single(min_subnormal * 2.0), single(2.0), RNE, &mut flags),
+            single(min_subnormal)
+        );
+        assert_eq!(flags, 0);
+    }
+
+    #[test]
+    fn test_fused_multiply_add() {
+        let mut flags = 0;
+        let a = double(0.1);
+        let b = double(10.0);
+        let c = double(-1.0);
+
+        assert_eq!(
+            DOUBLE.fused_multiply_add(a, b, c, false, false, RNE, &mut flags),
+            double(0.1f64.mul_add(10.0, -1.0))
+        );
+        assert_eq!(
+            DOUBLE.fused_multiply_add(a, b, c, true, true, RNE, &mut flags),
+            double(-(0.1f64.mul_add(10.0, -1.0)))
+        );
+
+        let mut flags = 0;
+        assert_eq!(
+            SINGLE.fused_multiply_add(
+                single(f32::INFINITY),
+                single(0.0),
+                SINGLE.canonical_nan(),
+                false,
+                false,
+                RNE,
+                &mut flags
+            ),
+            SINGLE.canonical_nan()
+        );
+        assert_eq!(flags, FLAG_INVALID);
+    }
+
+    #[test]
+    fn test_sqrt() {
+        let mut flags = 0;
+        assert_eq!(
+            DOUBLE.sqrt(double(2.0), RNE, &mut flags),
+            double(2f64.sqrt())
+        );
+        assert_eq!(flags, FLAG_INEXACT);
+
+        let mut flags = 0;
+        assert_eq!(SINGLE.sqrt(single(16.0), RNE, &mut flags), single(4.0));
+        assert_eq!(SINGLE.sqrt(single(-0.0), RNE, &mut flags), single(-0.0));
+        assert_eq!(flags, 0);
+
+        assert_eq!(
+            SINGLE.sqrt(single(-1.0), RNE, &mut flags),
+            SINGLE.canonical_nan()
+        );
+        assert_eq!(flags, FLAG_INVALID);
+    }
+
+    #[test]
+    fn test_compare_and_min_max() {
+        let mut flags = 0;
+        assert!(SINGLE.eq(single(0.0), single(-0.0), &mut flags));
+        assert!(SINGLE.le(single(-0.0), single(0.0), &mut flags));
+        assert!(!SINGLE.lt(single(-0.0), single(0.0), &mut flags));
+        assert!(DOUBLE.lt(double(-2.0), double(-1.0), &mut flags));
+        assert_eq!(flags, 0);
+
+        assert!(!SINGLE.eq(SINGLE.canonical_nan(), single(1.0), &mut flags));
+        assert_eq!(flags, 0);
+        assert!(!SINGLE.lt(SINGLE.canonical_nan(), single(1.0), &mut flags));
+        assert_eq!(flags, FLAG_INVALID);
+
+        let mut flags = 0;
+        assert_eq!(
+            SINGLE.min_max(single(0.0), single(-0.0), false, &mut flags),
+            single(-0.0)
+        );
+        assert_eq!(
+            SINGLE.min_max(SINGLE.canonical_nan(), single(2.0), true, &mut flags),
+            single(2.0)
+        );
+        assert_eq!(flags, 0);
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut flags = 0;
+        assert_eq!(
+            SINGLE.to_integer(single(-2.5), true, RNE, &mut flags),
+            -2i32 as u32
+        );
+        assert_eq!(
+            SINGLE.to_integer(single(2.5), true, RoundingMode::Up, &mut flags),
+            3
+        );
+        assert_eq!(flags, FLAG_INEXACT);
+
+        let mut flags = 0;
+        assert_eq!(DOUBLE.to_integer(double(-1.0), false, RNE, &mut flags), 0);
+        assert_eq!(flags, FLAG_INVALID);
+
+        let mut flags = 0;
+        assert_eq!(
+            DOUBLE.to_integer(double(3e9), true, RNE, &mut flags),
+            i32::MAX as u32
+        );
+        assert_eq!(
+            SINGLE.to_integer(SINGLE.canonical_nan(), false, RNE, &mut flags),
+            u32::MAX
+        );
+        assert_eq!(flags, FLAG_INVALID);
+
+        let mut flags = 0;
+        assert_eq!(
+            SINGLE.from_integer(16_777_217, false, RNE, &mut flags),
+            single(16_777_216.0)
+        );
+        assert_eq!(flags, FLAG_INEXACT);
+        assert_eq!(
+            DOUBLE.from_integer(-5i32 as u32, true, RNE, &mut flags),
+            double(-5.0)
+        );
+
+        let mut flags = 0;
+        assert_eq!(
+            SINGLE.convert(DOUBLE, double(0.1), RNE, &mut flags),
+            single(0.1)
+        );
+        assert_eq!(flags, FLAG_INEXACT);
+        assert_eq!(
+            DOUBLE.convert(SINGLE, single(0.1), RNE, &mut flags),
+            double(f64::from(0.1f32))
+        );
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(SINGLE.classify(single(f32::NEG_INFINITY)), 1 << 0);
+        assert_eq!(SINGLE.classify(single(-1.0)), 1 << 1);
+        assert_eq!(SINGLE.classify(single(-0.0)), 1 << 3);
+        assert_eq!(DOUBLE.classify(double(f64::MIN_POSITIVE / 2.0)), 1 << 5);
+        assert_eq!(DOUBLE.classify(DOUBLE.canonical_nan()), 1 << 9);
+        assert_eq!(DOUBLE.classify(0x7FF0_0000_0000_0001), 1 << 8);
+    }
+}
@@ -0,0 +1,254 @@
+use crate::block::{Exit, MicroOp};
+use crate::instruction::Instruction;
+use crate::memory::addressspace::Address;
+use std::str::FromStr;
+
+/// How conditional branches are predicted before they are resolved
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum BranchPrediction {
+    NotTaken,
+    /// Backward branches, which usually close loops, are predicted taken and
+    /// forward branches not taken
+    BackwardTaken,
+}
+
+/// Estimates the cycles a program takes on an in-order core, which `mcycle`
+/// counts while `minstret` counts the instructions. Every instruction takes
+/// the latency of its class, taken branches and jumps as well as
+/// mispredicted branches cost additional cycles. Caches, wait states of
+/// memory and stalls between dependent instructions aren't modelled.
+///
+/// The default model takes one cycle per instruction.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct TimingModel {
+    /// The instructions which aren't covered by another class
+    pub base: u64,
+    pub multiply: u64,
+    /// Divisions and remainders
+    pub divide: u64,
+    /// Loads, load-reserved and AMOs
+    pub load: u64,
+    /// Stores and store-conditional
+    pub store: u64,
+    /// Added to taken branches and jumps, which redirect the fetch
+    pub taken_branch: u64,
+    /// Added to branches whose prediction was wrong
+    pub misprediction_penalty: u64,
+    pub prediction: BranchPrediction,
+}
+
+impl Default for TimingModel {
+    fn default() -> Self {
+        Self {
+            base: 1,
+            multiply: 1,
+            divide: 1,
+            load: 1,
+            store: 1,
+            taken_branch: 0,
+            misprediction_penalty: 0,
+            prediction: BranchPrediction::NotTaken,
+        }
+    }
+}
+
+/// The classes of instructions with their own latency
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Class {
+    Base,
+    Multiply,
+    Divide,
+    Load,
+    Store,
+    Jump,
+    Branch { backward: bool },
+}
+
+impl TimingModel {
+    /// A 3-stage in-order pipeline (fetch, decode, execute) with an
+    /// iterative divider. Loads stall for a cycle, branches are predicted
+    /// statically when they are decoded, and mispredicted ones are resolved
+    /// in the execute stage.
+    pub fn three_stage() -> Self {
+        Self {
+            base: 1,
+            multiply: 3,
+            divide: 34,
+            load: 2,
+            store: 1,
+            taken_branch: 1,
+            misprediction_penalty: 2,
+            prediction: BranchPrediction::BackwardTaken,
+        }
+    }
+
+    /// The cycles of an instruction without the costs of taking a branch
+    pub fn latency(&self, instruction: &Instruction) -> u64 {
+        match classify(instruction) {
+            Class::Multiply => self.multiply,
+            Class::Divide => self.divide,
+            Class::Load => self.load,
+            Class::Store => self.store,
+            Class::Base | Class::Jump | Class::Branch { .. } => self.base,
+        }
+    }
+
+    /// The cycles of an instruction, which changed the pc to another
+    /// instruction than the next one if `taken` is set
+    pub fn cycles(&self, instruction: &Instruction, taken: bool) -> u64 {
+        let penalty = match classify(instruction) {
+            Class::Jump => self.taken_branch,
+            Class::Branch { backward } => self.branch_penalty(backward, taken),
+            _ => 0,
+        };
+        self.latency(instruction) + penalty
+    }
+
+    /// The cycles added to the latency of the micro-op at `pc` which left a
+    /// block through `exit`
+    pub fn exit_penalty(&self, op: &MicroOp, pc: Address, exit: Exit) -> u64 {
+        match op {
+            MicroOp::Branch(_, _, _, target) => {
+                self.branch_penalty(*target <= pc, exit == Exit::Taken)
+            }
+            MicroOp::Jump(..) | MicroOp::JumpRegister(..) => self.taken_branch,
+            _ => 0,
+        }
+    }
+
+    fn branch_penalty(&self, backward: bool, taken: bool) -> u64 {
+        let predicted_taken = self.prediction == BranchPrediction::BackwardTaken && backward;
+        let mut penalty = if taken { self.taken_branch } else { 0 };
+        if predicted_taken != taken {
+            penalty += self.misprediction_penalty;
+        }
+        penalty
+    }
+}
+
+/// Parses a named model, "ideal" or "3-stage", optionally followed by
+/// latencies overriding its own, e.g. "3-stage,divide=18,prediction=not-taken"
+impl FromStr for TimingModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let mut model = match parts.next() {
+            Some("ideal") => TimingModel::default(),
+            Some("3-stage") => TimingModel::three_stage(),
+            _ => return Err(format!("unknown timing model: {}", s)),
+        };
+
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected NAME=VALUE: {}", part))?;
+
+            if key == "prediction" {
+                model.prediction = match value {
+                    "not-taken" => BranchPrediction::NotTaken,
+                    "backward-taken" => BranchPrediction::BackwardTaken,
+                    _ => return Err(format!("unknown branch prediction: {}", value)),
+                };
+                continue;
+            }
+
+            let cycles = value
+                .parse()
+                .map_err(|_| format!("invalid number of cycles: {}", part))?;
+            match key {
+                "base" => model.base = cycles,
+                "multiply" => model.multiply = cycles,
+                "divide" => model.divide = cycles,
+                "load" => model.load = cycles,
+                "store" => model.store = cycles,
+                "taken-branch" => model.taken_branch = cycles,
+                "misprediction-penalty" => model.misprediction_penalty = cycles,
+                _ => return Err(format!("unknown instruction class: {}", key)),
+            }
+        }
+
+        Ok(model)
+    }
+}
+
+fn classify(instruction: &Instruction) -> Class {
+    use Instruction::*;
+
+    match *instruction {
+        MUL(..) | MULH(..) | MULHSU(..) | MULHU(..) | MULW(..) => Class::Multiply,
+        DIV(..) | DIVU(..) | REM(..) | REMU(..) | DIVW(..) | DIVUW(..) | REMW(..) | REMUW(..) => {
+            Class::Divide
+        }
+        LB(..) | LH(..) | LW(..) | LBU(..) | LHU(..) | LWU(..) | LD(..) | FLW(..) | FLD(..)
+        | LRW(..) | LRD(..) | AMOSWAPW(..) | AMOADDW(..) | AMOXORW(..) | AMOANDW(..)
+        | AMOORW(..) | AMOMINW(..) | AMOMAXW(..) | AMOMINUW(..) | AMOMAXUW(..) | AMOSWAPD(..)
+        | AMOADDD(..) | AMOXORD(..) | AMOANDD(..) | AMOORD(..) | AMOMIND(..) | AMOMAXD(..)
+        | AMOMINUD(..) | AMOMAXUD(..) => Class::Load,
+        SB(..) | SH(..) | SW(..) | SD(..) | FSW(..) | FSD(..) | SCW(..) | SCD(..) => Class::Store,
+        JAL(..) | JALR(..) => Class::Jump,
+        // The offset of branches is given in halfwords
+        BEQ(_, _, imm)
+        | BNE(_, _, imm)
+        | BLT(_, _, imm)
+        | BGE(_, _, imm)
+        | BLTU(_, _, imm)
+        | BGEU(_, _, imm) => Class::Branch {
+            backward: imm as i32 <= 0,
+        },
+        _ => Class::Base,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::Condition;
+
+    #[test]
+    fn test_three_stage() {
+        let model = TimingModel::three_stage();
+        let div = Instruction::DIV(1, 2, 3);
+        let lw = Instruction::LW(1, 2, 0);
+        let add = Instruction::ADD(1, 2, 3);
+        let jal = Instruction::JAL(1, 8);
+        let backward = Instruction::BNE(1, 2, (-4i32) as u32);
+        let forward = Instruction::BEQ(1, 2, 4);
+
+        assert_eq!(model.cycles(&div, false), 34);
+        assert_eq!(model.cycles(&lw, false), 2);
+        assert_eq!(model.cycles(&add, false), 1);
+        assert_eq!(model.cycles(&jal, true), 2);
+        assert_eq!(model.cycles(&backward, true), 2);
+        assert_eq!(model.cycles(&backward, false), 3);
+        assert_eq!(model.cycles(&forward, false), 1);
+        assert_eq!(model.cycles(&forward, true), 4);
+
+        // The block engine sees the resolved target
+        let branch = MicroOp::Branch(Condition::NotEqual, 1, 2, 0x100);
+        assert_eq!(model.exit_penalty(&branch, 0x108, Exit::Taken), 1);
+        assert_eq!(model.exit_penalty(&branch, 0x108, Exit::FallThrough), 2);
+        assert_eq!(model.exit_penalty(&branch, 0xF0, Exit::Taken), 3);
+
+        let ideal = TimingModel::default();
+        for instruction in [div, lw, add, jal, backward, forward].iter() {
+            assert_eq!(ideal.cycles(instruction, true), 1);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("ideal".parse(), Ok(TimingModel::default()));
+        assert_eq!("3-stage".parse(), Ok(TimingModel::three_stage()));
+
+        let model: TimingModel = "3-stage,divide=18,prediction=not-taken".parse().unwrap();
+        assert_eq!(model.divide, 18);
+        assert_eq!(model.prediction, BranchPrediction::NotTaken);
+        assert_eq!(model.load, 2);
+
+        assert!("5-stage".parse::<TimingModel>().is_err());
+        assert!("ideal,load".parse::<TimingModel>().is_err());
+        assert!("ideal,load=x".parse::<TimingModel>().is_err());
+        assert!("ideal,fetch=2".parse::<TimingModel>().is_err());
+    }
+}
@@ -1,4 +1,6 @@
-use riscv_emu::cpu::Cpu;
+#[cfg(feature = "jit")]
+use riscv_emu::cpu::Engine;
+use riscv_emu::cpu::{Cpu, CpuEvent, RunLimits};
 use riscv_emu::loader;
 use riscv_emu::memory::addressspace::{Address, AddressSpace, MemoryDevice};
 
@@ -10,8 +12,12 @@ const DEBUG_BASE_OUTPUT: Address = DEBUG_BASE_OUTPUT_LENGTH + 4;
 const DEBUG_BASE_INPUT_LENGTH: Address = DEBUG_BASE + 2 * 1024;
 const DEBUG_BASE_INPUT: Address = DEBUG_BASE_INPUT_LENGTH + 4;
 
+/// Programs which don't halt after this many instructions fail the test
+const MAX_INSTRUCTIONS: u64 = 100_000_000;
+
 pub struct TestRun {
     memory: AddressSpace,
+    entry: Address,
     write_address: Address,
 }
 
@@ -23,10 +29,11 @@ pub struct TestRunResult {
 impl TestRun {
     pub fn new(path: &str) -> Self {
         let mut memory = AddressSpace::new();
-        loader::load_program(path, &mut memory).unwrap();
+        let image = loader::load_program(path, &mut memory).unwrap();
 
         Self {
             memory,
+            entry: image.entry,
             write_address: DEBUG_BASE_INPUT,
         }
     }
@@ -67,7 +74,21 @@ impl TestRun {
             self.write_address - DEBUG_BASE_INPUT,
         );
         let mut cpu = Cpu::new();
-        cpu.run(&mut self.memory);
+        // Checks the compiled code of every block the programs execute
+        #[cfg(feature = "jit")]
+        cpu.set_engine(Engine::VerifiedJit);
+        cpu.set_pc(self.entry);
+        let limits = RunLimits {
+            max_instructions: Some(MAX_INSTRUCTIONS),
+            deadline: None,
+        };
+        let event = cpu.run_with_limits(&mut self.memory, limits);
+        assert_ne!(
+            event,
+            CpuEvent::BudgetExhausted,
+            "the program didn't halt after {} instructions",
+            MAX_INSTRUCTIONS
+        );
 
         TestRunResult {
             memory: self.memory,